@@ -1,6 +1,10 @@
 //! A thin wrapper of [prost](https://docs.rs/prost/0.6.1/prost/)
 //! 这是一个对 prost 库（Rust 的 Protocol Buffers 实现）的轻量级封装模块。
 
+use std::error;
+use std::fmt;
+use std::io::{self, Read, Write};
+
 /// A labcodec message.
 /// 定义当前库通用的 Message 特征（Trait）。
 /// 要求：所有实现此特征的类型，必须同时满足 `prost::Message`（基本 Protobuf 功能）和 `Default`（支持默认值）。
@@ -22,6 +26,56 @@ pub type EncodeError = prost::EncodeError;
 /// 类型别名：将 prost 的解码错误类型重新导出。
 pub type DecodeError = prost::DecodeError;
 
+/// An error of encoding to a writer or decoding from a reader.
+/// 区分 I/O 错误与编解码错误，调用者可以据此判断是连接出了问题还是数据本身有问题。
+#[derive(Debug)]
+pub enum CodecError {
+    /// Reading or writing failed.
+    Io(io::Error),
+    /// The message can't be encoded.
+    Encode(EncodeError),
+    /// The data read is not a valid message.
+    Decode(DecodeError),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Io(e) => write!(f, "I/O error: {}", e),
+            CodecError::Encode(e) => write!(f, "encode error: {}", e),
+            CodecError::Decode(e) => write!(f, "decode error: {}", e),
+        }
+    }
+}
+
+impl error::Error for CodecError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            CodecError::Io(e) => Some(e),
+            CodecError::Encode(e) => Some(e),
+            CodecError::Decode(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for CodecError {
+    fn from(e: io::Error) -> CodecError {
+        CodecError::Io(e)
+    }
+}
+
+impl From<EncodeError> for CodecError {
+    fn from(e: EncodeError) -> CodecError {
+        CodecError::Encode(e)
+    }
+}
+
+impl From<DecodeError> for CodecError {
+    fn from(e: DecodeError) -> CodecError {
+        CodecError::Decode(e)
+    }
+}
+
 /// Encodes the message to a `Vec<u8>`.
 /// 泛型函数：接受任何实现了 Message 特征的类型 M。
 /// 参数 message: 要编码的消息引用。
@@ -41,6 +95,22 @@ pub fn encode<M: Message>(message: &M, buf: &mut Vec<u8>) -> Result<(), EncodeEr
     Ok(())
 }
 
+/// Encodes the message into `buf`, replacing its content.
+/// 与 `encode` 不同，这里会先清空缓冲区，再按 `encoded_len` 精确预留空间。
+/// 同一个缓冲区反复使用时，容量足够后就不会再重新分配内存。
+pub fn encode_to<M: Message>(message: &M, buf: &mut Vec<u8>) -> Result<(), EncodeError> {
+    buf.clear();
+    // 缓冲区已清空，reserve_exact 保证容量至少为编码长度，且不会多分配
+    buf.reserve_exact(message.encoded_len());
+    message.encode(buf)
+}
+
+/// Returns the length of the encoded message in bytes.
+/// 调用者可以据此自行预先分配缓冲区。
+pub fn encoded_len<M: Message>(message: &M) -> usize {
+    message.encoded_len()
+}
+
 /// Decodes an message from the buffer.
 /// 解码函数：从字节切片中恢复出消息结构体 M。
 pub fn decode<M: Message>(buf: &[u8]) -> Result<M, DecodeError> {
@@ -48,6 +118,60 @@ pub fn decode<M: Message>(buf: &[u8]) -> Result<M, DecodeError> {
     M::decode(buf)
 }
 
+/// Encodes the message with a varint length prefix and appends it to `buf`.
+/// 多条消息可以依次追加到同一个缓冲区中，再用 `decode_delimited` 逐条读出。
+pub fn encode_delimited<M: Message>(message: &M, buf: &mut Vec<u8>) -> Result<(), EncodeError> {
+    let len = message.encoded_len();
+    buf.reserve(prost::length_delimiter_len(len) + len);
+    message.encode_length_delimited(buf)
+}
+
+/// Decodes a message written by `encode_delimited` and advances `buf` past it.
+/// 缓冲区恰好读完时返回 `Ok(None)`；只剩半条消息时返回错误。
+pub fn decode_delimited<M: Message>(buf: &mut &[u8]) -> Result<Option<M>, DecodeError> {
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    // `&mut &[u8]` 实现了 `Buf`，解码时会把切片向前推进
+    M::decode_length_delimited(buf).map(Some)
+}
+
+/// Writes the message to `writer` with a varint length prefix.
+/// 格式与 `encode_delimited` 相同。
+pub fn encode_to_writer<M: Message, W: Write>(
+    message: &M,
+    writer: &mut W,
+) -> Result<(), CodecError> {
+    let mut buf = vec![];
+    encode_delimited(message, &mut buf)?;
+    writer.write_all(&buf)?;
+    Ok(())
+}
+
+/// Reads a message written by `encode_to_writer` from `reader`.
+/// 只读取一帧所需的字节，读取端可以继续读后面的消息。
+pub fn decode_from_reader<M: Message, R: Read>(reader: &mut R) -> Result<M, CodecError> {
+    // varint 每个字节的最高位表示后面是否还有字节，最长 10 个字节
+    let mut len_buf = Vec::with_capacity(10);
+    loop {
+        let mut byte = [0; 1];
+        reader.read_exact(&mut byte)?;
+        len_buf.push(byte[0]);
+        if byte[0] < 0x80 || len_buf.len() == 10 {
+            break;
+        }
+    }
+    let len = prost::decode_length_delimiter(&len_buf[..])?;
+    // 长度前缀来自不可信的输入，不能按它预先分配内存：
+    // 通过 `take` 只读取实际到达的字节，损坏的前缀最多分配已读到的数据量
+    let mut buf = Vec::new();
+    reader.by_ref().take(len as u64).read_to_end(&mut buf)?;
+    if buf.len() != len {
+        return Err(CodecError::Io(io::ErrorKind::UnexpectedEof.into()));
+    }
+    Ok(decode(&buf)?)
+}
+
 #[cfg(test)] // 只有在运行 `cargo test` 时才编译以下模块
 mod tests {
     // 定义一个名为 fixture 的子模块，用于模拟生成的代码
@@ -64,7 +188,12 @@ mod tests {
     }
 
     // 引入父模块定义的 encode 和 decode 函数以便测试
-    use super::{decode, encode};
+    use std::io::{self, Cursor};
+
+    use super::{
+        decode, decode_delimited, decode_from_reader, encode, encode_delimited, encode_to,
+        encode_to_writer, encoded_len, CodecError,
+    };
 
     #[test] // 标记这是一个测试函数
     fn test_basic_encode_decode() {
@@ -109,4 +238,108 @@ mod tests {
         // 这也验证了我们在最上面定义的 trait Message: Default 约束的必要性。
         assert_eq!(msg, msg1);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_encode_to_reuses_buffer() {
+        let msg = fixture::Msg {
+            r#type: fixture::msg::Type::Get as _,
+            id: 7,
+            name: "reused".to_owned(),
+            paylad: vec![vec![1; 16]; 4],
+        };
+
+        // 先放入一些旧数据，encode_to 应当覆盖而不是追加
+        let mut buf = vec![0xff; 3];
+        encode_to(&msg, &mut buf).unwrap();
+        let capacity = buf.capacity();
+        for _ in 0..10_000 {
+            encode_to(&msg, &mut buf).unwrap();
+        }
+
+        // 长度与编码长度一致，且反复编码没有触发重新分配
+        assert_eq!(buf.len(), encoded_len(&msg));
+        assert_eq!(buf.capacity(), capacity);
+        assert_eq!(decode::<fixture::Msg>(&buf).unwrap(), msg);
+    }
+
+    #[test]
+    fn test_delimited_stream() {
+        let msgs = vec![
+            fixture::Msg {
+                r#type: fixture::msg::Type::Put as _,
+                id: 1,
+                name: "first".to_owned(),
+                paylad: vec![vec![1; 200]],
+            },
+            // 空消息编码后长度为 0，只有一个长度前缀
+            fixture::Msg::default(),
+            fixture::Msg {
+                r#type: fixture::msg::Type::Del as _,
+                id: u64::MAX,
+                name: "third".to_owned(),
+                paylad: vec![],
+            },
+        ];
+
+        let mut buf = vec![];
+        for msg in &msgs {
+            encode_delimited(msg, &mut buf).unwrap();
+        }
+
+        let mut rest = &buf[..];
+        for msg in &msgs {
+            let decoded: fixture::Msg = decode_delimited(&mut rest).unwrap().unwrap();
+            assert_eq!(&decoded, msg);
+        }
+        assert_eq!(decode_delimited::<fixture::Msg>(&mut rest).unwrap(), None);
+
+        // 截断的消息应当报错，而不是当作结束
+        let mut truncated = &buf[..buf.len() - 1];
+        for _ in 0..2 {
+            decode_delimited::<fixture::Msg>(&mut truncated).unwrap();
+        }
+        assert!(decode_delimited::<fixture::Msg>(&mut truncated).is_err());
+    }
+
+    #[test]
+    fn test_reader_writer() {
+        let msg = fixture::Msg {
+            r#type: fixture::msg::Type::Put as _,
+            id: 42,
+            name: "the answer".to_owned(),
+            // 足够长，使长度前缀占用多个字节
+            paylad: vec![vec![7; 300]],
+        };
+
+        let mut buf = vec![];
+        encode_to_writer(&msg, &mut buf).unwrap();
+        encode_to_writer(&fixture::Msg::default(), &mut buf).unwrap();
+
+        let mut reader = Cursor::new(buf);
+        let msg1: fixture::Msg = decode_from_reader(&mut reader).unwrap();
+        assert_eq!(msg, msg1);
+        let msg2: fixture::Msg = decode_from_reader(&mut reader).unwrap();
+        assert_eq!(msg2, fixture::Msg::default());
+
+        // 读到末尾是 I/O 错误，而数据损坏是解码错误
+        match decode_from_reader::<fixture::Msg, _>(&mut reader) {
+            Err(CodecError::Io(_)) => {}
+            res => panic!("expected an I/O error, got {:?}", res),
+        }
+        let mut reader = Cursor::new(vec![2, 0xff, 0xff]);
+        match decode_from_reader::<fixture::Msg, _>(&mut reader) {
+            Err(CodecError::Decode(_)) => {}
+            res => panic!("expected a decode error, got {:?}", res),
+        }
+
+        // 超大的长度前缀后只跟了几个字节：应当报错，而不是按前缀分配内存
+        let mut buf = vec![];
+        prost::encode_length_delimiter(usize::MAX >> 1, &mut buf).unwrap();
+        buf.extend_from_slice(&[1, 2, 3]);
+        let mut reader = Cursor::new(buf);
+        match decode_from_reader::<fixture::Msg, _>(&mut reader) {
+            Err(CodecError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+            res => panic!("expected an I/O error, got {:?}", res),
+        }
+    }
+}
@@ -32,6 +32,7 @@ fn main() -> Result<()> {
                 .about("移除给定的键")
                 .arg(Arg::with_name("KEY").help("字符串键").required(true)),
         )
+        .subcommand(SubCommand::with_name("compact").about("压缩日志，清除过时的条目"))
         .get_matches();
 
     // 根据子命令执行相应操作
@@ -66,6 +67,11 @@ fn main() -> Result<()> {
                 Err(e) => return Err(e),
             }
         }
+        ("compact", Some(_)) => {
+            let mut store = KvStore::open(current_dir()?)?;
+            let reclaimed = store.compact()?;
+            println!("Reclaimed {} bytes", reclaimed);
+        }
         _ => unreachable!(),
     }
     Ok(())
@@ -1,21 +1,21 @@
-use failure::Fail;
 use std::io;
+use thiserror::Error;
 
 /// kvs 项目的错误类型。
-#[derive(Fail, Debug)]
+#[derive(Error, Debug)]
 pub enum KvsError {
     /// IO 错误。
-    #[fail(display = "{}", _0)]
-    Io(#[cause] io::Error),
+    #[error("{0}")]
+    Io(#[source] io::Error),
     /// 序列化或反序列化错误。
-    #[fail(display = "{}", _0)]
-    Serde(#[cause] serde_json::Error),
+    #[error("{0}")]
+    Serde(#[source] serde_json::Error),
     /// 移除不存在的键。
-    #[fail(display = "Key not found")]
+    #[error("Key not found")]
     KeyNotFound,
     /// 意外的命令类型。
     /// 这可能表示日志文件损坏或程序存在错误。
-    #[fail(display = "Unexpected command type")]
+    #[error("Unexpected command type")]
     UnexpectedCommandType,
 }
 
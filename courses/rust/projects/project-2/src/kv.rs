@@ -200,7 +200,9 @@ impl KvStore {
     /// 日志压缩，也就是垃圾回收
     /// 把散落在多个旧日志文件中的有效数据找出来，合并到新的文件中，然后把旧文件全部删除掉，从而释放 disk space
     /// 搬家，需要的东西打包带到新家，剩下的垃圾，留在旧房子，然后把房子拆了
-    pub fn compact(&mut self) -> Result<()> {
+    ///
+    /// 返回压缩回收的字节数。
+    pub fn compact(&mut self) -> Result<u64> {
         // 将当前代数增加 2。current_gen + 1 用于压缩后的新文件。
         // 1。准备压缩专用文件的代号 id = N + 1
         let compaction_gen = self.current_gen + 1;
@@ -258,17 +260,21 @@ impl KvStore {
             .cloned()
             .collect();
 
-        // 遍历删除
+        // 遍历删除，同时统计旧文件的总大小
+        let mut stale_len = 0;
         for stale_gen in stale_gens {
             // 从内存的 readers 缓存中删除
             self.readers.remove(&stale_gen);
 
             // 从 disk 物理删除文件
-            fs::remove_file(log_path(&self.path, stale_gen))?;
+            let stale_path = log_path(&self.path, stale_gen);
+            stale_len += fs::metadata(&stale_path)?.len();
+            fs::remove_file(stale_path)?;
         }
         self.uncompacted = 0;
 
-        Ok(())
+        // 旧文件的大小减去搬进 N+1.log 的有效数据，就是回收的空间
+        Ok(stale_len.saturating_sub(new_pos))
     }
 
     /// 使用给定的代数创建一个新的日志文件，并将读取器添加到 readers 映射中。
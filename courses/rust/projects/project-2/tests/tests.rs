@@ -1,7 +1,9 @@
 use assert_cmd::prelude::*;
-use kvs::{KvStore, Result};
+use kvs::{KvStore, KvsError, Result};
 use predicates::ord::eq;
 use predicates::str::{contains, is_empty, PredicateStrExt};
+use std::error::Error;
+use std::io;
 use std::process::Command;
 use tempfile::TempDir;
 use walkdir::WalkDir;
@@ -182,6 +184,44 @@ fn cli_invalid_subcommand() {
         .failure();
 }
 
+// `kvs compact` 应该清除被覆盖的旧值，使日志文件变小。
+#[test]
+fn cli_compact() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let dir_size = || -> u64 {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|entry| entry.unwrap().metadata().unwrap().len())
+            .sum()
+    };
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    for iter in 0..100 {
+        store.set("key1".to_owned(), format!("value{}", iter))?;
+    }
+    drop(store);
+    let size_before = dir_size();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["compact"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Reclaimed"));
+    assert!(dir_size() < size_before);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value99").trim());
+
+    Ok(())
+}
+
 // 应该能够获取之前存储的值。
 #[test]
 fn get_stored_value() -> Result<()> {
@@ -306,3 +346,14 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// 包装的底层错误应当可以通过 `source` 取回。
+#[test]
+fn error_source() {
+    let err = KvsError::Io(io::Error::new(io::ErrorKind::NotFound, "no such log"));
+    let source = err.source().expect("an I/O error has a source");
+    let io_err = source.downcast_ref::<io::Error>().unwrap();
+    assert_eq!(io_err.kind(), io::ErrorKind::NotFound);
+    assert_eq!(err.to_string(), "no such log");
+    assert!(KvsError::KeyNotFound.source().is_none());
+}
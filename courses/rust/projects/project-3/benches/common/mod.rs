@@ -0,0 +1,101 @@
+//! 基准测试共用的数据生成工具，用法是在基准测试文件中声明 `mod common;`。
+//!
+//! 所有数据都由固定种子的 `StdRng` 生成，同一个种子在每次运行中产生完全相同的序列，
+//! 不同引擎、不同次运行的结果因此可以直接比较。`StdRng` 的算法只在 `rand` 的同一版本内
+//! 保证不变，`Cargo.lock` 锁定了它的版本。
+
+// 每个基准测试只用到其中一部分
+#![allow(dead_code)]
+
+use rand::distributions::Alphanumeric;
+use rand::prelude::*;
+use std::env;
+
+/// 未设置 `KVS_BENCH_SEED` 时使用的种子
+pub const DEFAULT_SEED: u64 = 0x5eed;
+
+/// 基准测试使用的种子，可以通过环境变量 `KVS_BENCH_SEED` 指定
+pub fn seed() -> u64 {
+    env::var("KVS_BENCH_SEED")
+        .ok()
+        .and_then(|seed| seed.parse().ok())
+        .unwrap_or(DEFAULT_SEED)
+}
+
+/// 由 `seed` 确定的随机数生成器
+pub fn rng(seed: u64) -> StdRng {
+    StdRng::seed_from_u64(seed)
+}
+
+/// 生成 `count` 个键值对，键和值分别是长度为 `key_len` 和 `value_len` 的随机字母数字串
+///
+/// 键的长度不太短时几乎不会重复，但并不保证互不相同。
+pub fn key_values(
+    seed: u64,
+    count: usize,
+    key_len: usize,
+    value_len: usize,
+) -> Vec<(String, String)> {
+    let mut rng = rng(seed);
+    (0..count)
+        .map(|_| {
+            (
+                random_string(&mut rng, key_len),
+                random_string(&mut rng, value_len),
+            )
+        })
+        .collect()
+}
+
+fn random_string(rng: &mut StdRng, len: usize) -> String {
+    rng.sample_iter(&Alphanumeric).take(len).collect()
+}
+
+/// Zipf 分布：在 `0..n` 中取值，取到第 `k` 个（从 0 开始）的概率正比于 `1 / (k + 1)^theta`
+///
+/// 用来模拟少数热点键承担大部分访问的负载。`theta` 越大越集中，YCSB 的默认值是 0.99，
+/// 为 0 时退化为均匀分布。
+pub struct Zipf {
+    // 累积分布函数，`cdf[k]` 是取值不超过 `k` 的概率
+    cdf: Vec<f64>,
+}
+
+impl Zipf {
+    /// 创建 `0..n` 上参数为 `theta` 的 Zipf 分布，`n` 必须大于 0
+    pub fn new(n: usize, theta: f64) -> Zipf {
+        assert!(n > 0, "Zipf distribution needs at least one item");
+        let weights: Vec<f64> = (1..=n).map(|k| 1.0 / (k as f64).powf(theta)).collect();
+        let total: f64 = weights.iter().sum();
+        let mut sum = 0.0;
+        let cdf = weights
+            .into_iter()
+            .map(|weight| {
+                sum += weight;
+                sum / total
+            })
+            .collect();
+        Zipf { cdf }
+    }
+
+    /// 按分布取一个值
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> usize {
+        let u: f64 = rng.gen();
+        let k = match self.cdf.binary_search_by(|p| p.partial_cmp(&u).unwrap()) {
+            Ok(k) | Err(k) => k,
+        };
+        // 浮点误差可能使最后一项略小于 1
+        k.min(self.cdf.len() - 1)
+    }
+}
+
+/// 生成 `count` 次对 `0..n` 的访问下标，下标的热度服从参数为 `theta` 的 Zipf 分布
+///
+/// 热度排名被随机打乱后再映射到下标，热点键因此散布在整个键空间中，
+/// 而不是集中在最先写入的那些键上。
+pub fn zipf_accesses(seed: u64, n: usize, theta: f64, count: usize) -> Vec<usize> {
+    let mut rng = rng(seed);
+    let mut ranked: Vec<usize> = (0..n).collect();
+    ranked.shuffle(&mut rng);
+    let zipf = Zipf::new(n, theta);
+    (0..count).map(|_| ranked[zipf.sample(&mut rng)]).collect()
+}
@@ -0,0 +1,47 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use kvs::{KvStore, KvsEngine, SledKvsEngine};
+use tempfile::TempDir;
+
+mod common;
+
+const KEYS: usize = 1 << 14;
+const ACCESSES: usize = 1 << 16;
+
+// 读取服从 Zipf 分布的键，模拟少数热点键承担大部分读取的负载
+fn zipf_get_bench(c: &mut Criterion) {
+    let seed = common::seed();
+    let pairs = common::key_values(seed, KEYS, 16, 100);
+    let accesses = common::zipf_accesses(seed, KEYS, 0.99, ACCESSES);
+
+    let mut group = c.benchmark_group("zipf_get_bench");
+    group.bench_function("kvs", |b| {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = KvStore::open(temp_dir.path()).unwrap();
+        load(&mut store, &pairs);
+        let mut accesses = accesses.iter().cycle();
+        b.iter(|| {
+            let key = &pairs[*accesses.next().unwrap()].0;
+            black_box(store.get(key.clone()).unwrap());
+        })
+    });
+    group.bench_function("sled", |b| {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = SledKvsEngine::new(sled::open(&temp_dir).unwrap());
+        load(&mut db, &pairs);
+        let mut accesses = accesses.iter().cycle();
+        b.iter(|| {
+            let key = &pairs[*accesses.next().unwrap()].0;
+            black_box(db.get(key.clone()).unwrap());
+        })
+    });
+    group.finish();
+}
+
+fn load<E: KvsEngine>(engine: &mut E, pairs: &[(String, String)]) {
+    for (key, value) in pairs {
+        engine.set(key.clone(), value.clone()).unwrap();
+    }
+}
+
+criterion_group!(benches, zipf_get_bench);
+criterion_main!(benches);
@@ -75,6 +75,19 @@ enum Command {
         )]
         addr: SocketAddr,
     },
+    /// 压缩服务端的存储
+    #[structopt(name = "compact", about = "Compact the storage of the server")]
+    Compact {
+        /// 服务器地址
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            raw(value_name = "ADDRESS_FORMAT"),
+            raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
 }
 
 fn main() {
@@ -103,6 +116,11 @@ fn run(opt: Opt) -> Result<()> {
             let mut client = KvsClient::connect(addr)?;
             client.remove(key)?;
         }
+        Command::Compact { addr } => {
+            let mut client = KvsClient::connect(addr)?;
+            let reclaimed = client.compact()?;
+            println!("Reclaimed {} bytes", reclaimed);
+        }
     }
     Ok(())
 }
@@ -1,44 +1,139 @@
-use crate::common::{GetResponse, RemoveResponse, Request, SetResponse};
+use crate::common::{
+    read_frame, write_frame, CompactResponse, GetResponse, RemoveResponse, Request, SetResponse,
+};
 use crate::{KvsError, Result};
-use serde::Deserialize;
-use serde_json::de::{Deserializer, IoRead};
-use std::io::{BufReader, BufWriter, Write};
+use log::debug;
+use serde::de::DeserializeOwned;
+use std::io::{self, BufReader, BufWriter, Write};
 use std::net::{TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+
+/// `KvsClient::get_many` 每批最多发送的请求数。
+const PIPELINE_DEPTH: usize = 1024;
 
 /// 键值存储客户端。
 pub struct KvsClient {
-    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
+    reader: BufReader<TcpStream>,
     writer: BufWriter<TcpStream>,
 }
 
 impl KvsClient {
     /// 连接到指定的地址以访问 `KvsServer`。
     pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
-        let tcp_reader = TcpStream::connect(addr)?;
+        KvsClient::from_stream(TcpStream::connect(addr)?)
+    }
+
+    /// 连接到指定的地址以访问 `KvsServer`，超过 `timeout` 仍未连上则放弃。
+    ///
+    /// 该超时同样作用于之后连接上的每次读写，因此服务端卡住时会返回 `KvsError::Timeout`，而不是一直阻塞。
+    pub fn connect_timeout<A: ToSocketAddrs>(addr: A, timeout: Duration) -> Result<Self> {
+        let mut last_err = None;
+        for addr in addr.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(tcp) => {
+                    tcp.set_read_timeout(Some(timeout))?;
+                    tcp.set_write_timeout(Some(timeout))?;
+                    return KvsClient::from_stream(tcp);
+                }
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err
+            .map(KvsError::from)
+            .unwrap_or_else(|| KvsError::StringError("No address to connect to".to_owned())))
+    }
+
+    /// 连接到指定的地址以访问 `KvsServer`，最多尝试 `attempts` 次。
+    ///
+    /// 两次尝试之间的等待时间从 `backoff` 开始，每失败一次翻倍。全部失败时返回最后一次的错误。
+    pub fn connect_with_retry<A: ToSocketAddrs>(
+        addr: A,
+        attempts: u32,
+        backoff: Duration,
+    ) -> Result<Self> {
+        let mut delay = backoff;
+        let mut attempt = 1;
+        loop {
+            match KvsClient::connect(&addr) {
+                Err(KvsError::Io(ref e)) if attempt < attempts => {
+                    debug!("Connection attempt {} failed: {}", attempt, e);
+                }
+                res => return res,
+            }
+            thread::sleep(delay);
+            delay *= 2;
+            attempt += 1;
+        }
+    }
+
+    fn from_stream(tcp_reader: TcpStream) -> Result<Self> {
         let tcp_writer = tcp_reader.try_clone()?;
         Ok(KvsClient {
-            reader: Deserializer::from_reader(BufReader::new(tcp_reader)),
+            reader: BufReader::new(tcp_reader),
             writer: BufWriter::new(tcp_writer),
         })
     }
 
+    fn send(&mut self, req: &Request) -> Result<()> {
+        write_frame(&mut self.writer, req)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn receive<T: DeserializeOwned>(&mut self) -> Result<T> {
+        read_frame(&mut self.reader)?.ok_or_else(|| {
+            let err = io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed");
+            KvsError::Io(err)
+        })
+    }
+
     /// 从服务端获取给定键的值。
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        serde_json::to_writer(&mut self.writer, &Request::Get { key })?;
-        self.writer.flush()?;
-        let resp = GetResponse::deserialize(&mut self.reader)?;
-        match resp {
+        self.send(&Request::Get { key })?;
+        match self.receive::<GetResponse>()? {
             GetResponse::Ok(value) => Ok(value),
             GetResponse::Err(msg) => Err(KvsError::StringError(msg)),
         }
     }
 
+    /// 以流水线方式从服务端获取多个键的值，返回的值与 `keys` 一一对应。
+    ///
+    /// 先写出一批请求、只 flush 一次，再按顺序读取这批响应，从而分摊往返延迟。
+    /// 每批最多 `PIPELINE_DEPTH` 个请求，避免双方的发送缓冲区都被写满而互相等待。
+    ///
+    /// # Errors
+    ///
+    /// 任一键的读取失败时返回第一个错误，但仍会读完这一批的所有响应，使连接保持可用。
+    pub fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let mut values = Vec::with_capacity(keys.len());
+        let mut first_err = None;
+        for chunk in keys.chunks(PIPELINE_DEPTH) {
+            for key in chunk {
+                let key = key.clone();
+                write_frame(&mut self.writer, &Request::Get { key })?;
+            }
+            self.writer.flush()?;
+            for _ in chunk {
+                match self.receive::<GetResponse>()? {
+                    GetResponse::Ok(value) => values.push(value),
+                    GetResponse::Err(msg) => {
+                        first_err.get_or_insert(KvsError::StringError(msg));
+                        values.push(None);
+                    }
+                }
+            }
+            if let Some(e) = first_err {
+                return Err(e);
+            }
+        }
+        Ok(values)
+    }
+
     /// 在服务端设置字符串键的值。
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Set { key, value })?;
-        self.writer.flush()?;
-        let resp = SetResponse::deserialize(&mut self.reader)?;
-        match resp {
+        self.send(&Request::Set { key, value })?;
+        match self.receive::<SetResponse>()? {
             SetResponse::Ok(_) => Ok(()),
             SetResponse::Err(msg) => Err(KvsError::StringError(msg)),
         }
@@ -46,12 +141,20 @@ impl KvsClient {
 
     /// 在服务端删除一个字符串键。
     pub fn remove(&mut self, key: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Remove { key })?;
-        self.writer.flush()?;
-        let resp = RemoveResponse::deserialize(&mut self.reader)?;
-        match resp {
+        self.send(&Request::Remove { key })?;
+        match self.receive::<RemoveResponse>()? {
             RemoveResponse::Ok(_) => Ok(()),
+            RemoveResponse::KeyNotFound => Err(KvsError::KeyNotFound),
             RemoveResponse::Err(msg) => Err(KvsError::StringError(msg)),
         }
     }
+
+    /// 让服务端压缩存储引擎，返回回收的字节数。
+    pub fn compact(&mut self) -> Result<u64> {
+        self.send(&Request::Compact)?;
+        match self.receive::<CompactResponse>()? {
+            CompactResponse::Ok(reclaimed) => Ok(reclaimed),
+            CompactResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
 }
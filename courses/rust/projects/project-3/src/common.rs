@@ -1,4 +1,7 @@
+use crate::{KvsError, Result};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
 
 /// 定义网络协议支持的请求类型
 #[derive(Debug, Serialize, Deserialize)]
@@ -9,6 +12,8 @@ pub enum Request {
     Set { key: String, value: String },
     /// 移除给定的键
     Remove { key: String },
+    /// 压缩存储引擎
+    Compact,
 }
 
 /// Get 请求的响应结果
@@ -34,6 +39,61 @@ pub enum SetResponse {
 pub enum RemoveResponse {
     /// 成功
     Ok(()),
+    /// 要删除的键不存在，客户端将其还原为 `KvsError::KeyNotFound`
+    KeyNotFound,
     /// 失败，包含错误消息字符串
     Err(String),
 }
+
+/// Compact 请求的响应结果
+#[derive(Debug, Serialize, Deserialize)]
+pub enum CompactResponse {
+    /// 成功，包含回收的字节数
+    Ok(u64),
+    /// 失败，包含错误消息字符串
+    Err(String),
+}
+
+/// 一帧负载的最大字节数
+///
+/// `read_frame` 在分配负载之前拒绝更长的帧，避免对端发来的错误长度耗尽内存。
+pub const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+
+/// 将 `value` 写为一帧：4 字节大端长度，后跟 JSON 负载。
+///
+/// 不会 flush，调用者可以连续写入多帧后再统一 flush。
+pub fn write_frame<W: Write, T: Serialize>(writer: &mut W, value: &T) -> Result<()> {
+    let payload = serde_json::to_vec(value)?;
+    if payload.len() > MAX_FRAME_LEN {
+        return Err(frame_too_large(payload.len()));
+    }
+    writer.write_all(&(payload.len() as u32).to_be_bytes())?;
+    writer.write_all(&payload)?;
+    Ok(())
+}
+
+/// 读取由 `write_frame` 写入的一帧。
+///
+/// 如果流在新的一帧开始前结束，返回 `None`。
+pub fn read_frame<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<Option<T>> {
+    let mut len_buf = [0; 4];
+    match reader.read_exact(&mut len_buf) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_be_bytes(len_buf) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(frame_too_large(len));
+    }
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(serde_json::from_slice(&payload)?))
+}
+
+fn frame_too_large(len: usize) -> KvsError {
+    KvsError::StringError(format!(
+        "Frame of {} bytes exceeds the limit of {} bytes",
+        len, MAX_FRAME_LEN
+    ))
+}
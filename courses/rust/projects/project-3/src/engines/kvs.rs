@@ -90,8 +90,8 @@ impl KvStore {
     /// 清理日志中的陈旧条目。
     ///
     /// 压缩过程会将索引中引用的所有当前有效命令写入一个新的代数文件中，
-    /// 然后删除旧的、不再被引用的日志文件。
-    pub fn compact(&mut self) -> Result<()> {
+    /// 然后删除旧的、不再被引用的日志文件。返回回收的字节数。
+    pub fn compact(&mut self) -> Result<u64> {
         // 增加当前代数 2。这里 current_gen + 1 用于存放压缩后的新文件，
         // current_gen + 2 则作为新的活动写入日志。
         let compaction_gen = self.current_gen + 1;
@@ -101,6 +101,7 @@ impl KvStore {
         let mut compaction_writer = self.new_log_file(compaction_gen)?;
 
         let mut new_pos = 0; // 在新日志文件中的偏移量
+
         // 遍历所有索引，只将最新的、活跃的值搬迁到新日志
         for cmd_pos in &mut self.index.values_mut() {
             let reader = self
@@ -127,14 +128,18 @@ impl KvStore {
             .filter(|&&gen| gen < compaction_gen)
             .cloned()
             .collect();
+        let mut stale_len = 0;
         for stale_gen in stale_gens {
             self.readers.remove(&stale_gen);
-            fs::remove_file(log_path(&self.path, stale_gen))?;
+            let stale_path = log_path(&self.path, stale_gen);
+            stale_len += fs::metadata(&stale_path)?.len();
+            fs::remove_file(stale_path)?;
         }
 
         self.uncompacted = 0;
 
-        Ok(())
+        // 陈旧日志的总大小减去搬迁的有效数据，即为回收的空间
+        Ok(stale_len.saturating_sub(new_pos))
     }
 
     /// Create a new log file with given generation number and add the reader to the readers map.
@@ -221,6 +226,10 @@ impl KvsEngine for KvStore {
             Err(KvsError::KeyNotFound)
         }
     }
+
+    fn compact(&mut self) -> Result<u64> {
+        KvStore::compact(self)
+    }
 }
 
 /// Create a new log file with given generation number and add the reader to the readers map.
@@ -20,6 +20,11 @@ pub trait KvsEngine {
     ///
     /// 如果键不存在，则返回 `KvsError::KeyNotFound`。
     fn remove(&mut self, key: String) -> Result<()>;
+
+    /// 压缩存储，回收被覆盖或删除的数据占用的空间。
+    ///
+    /// 返回回收的字节数。
+    fn compact(&mut self) -> Result<u64>;
 }
 
 mod kvs;
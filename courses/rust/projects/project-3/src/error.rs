@@ -1,37 +1,63 @@
-use failure::Fail;
 use std::io;
 use std::string::FromUtf8Error;
+use thiserror::Error;
 
 /// kvs 的错误类型
-#[derive(Fail, Debug)]
+#[derive(Error, Debug)]
 pub enum KvsError {
     /// IO 错误
-    #[fail(display = "IO error: {}", _0)]
-    Io(#[cause] io::Error),
+    #[error("IO error: {0}")]
+    Io(#[source] io::Error),
     /// 序列化或反序列化错误
-    #[fail(display = "serde_json error: {}", _0)]
-    Serde(#[cause] serde_json::Error),
+    #[error("serde_json error: {0}")]
+    Serde(#[source] serde_json::Error),
     /// 移除不存在的键时触发的错误
-    #[fail(display = "Key not found")]
+    #[error("Key not found")]
     KeyNotFound,
     /// 非预期的命令类型错误
     /// 这通常表示日志损坏或程序存在逻辑缺陷
-    #[fail(display = "Unexpected command type")]
+    #[error("Unexpected command type")]
     UnexpectedCommandType,
     /// 键或值不是合法的 UTF-8 序列
-    #[fail(display = "UTF-8 error: {}", _0)]
-    Utf8(#[cause] FromUtf8Error),
+    #[error("UTF-8 error: {0}")]
+    Utf8(#[source] FromUtf8Error),
     /// Sled 存储引擎返回的错误
-    #[fail(display = "sled error: {}", _0)]
-    Sled(#[cause] sled::Error),
+    #[error("sled error: {0}")]
+    Sled(#[source] sled::Error),
     /// 包含自定义字符串消息的错误
-    #[fail(display = "{}", _0)]
+    #[error("{0}")]
     StringError(String),
+    /// 连接或套接字操作超时
+    #[error("Operation timed out")]
+    Timeout,
+}
+
+impl KvsError {
+    /// 判断该错误是否是暂时性的，即稍后重试同一操作可能会成功。
+    ///
+    /// 超时以及连接被重置等网络抖动返回 `true`；
+    /// `KeyNotFound` 等确定性的错误返回 `false`，重试也不会改变结果。
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            KvsError::Io(err) => matches!(
+                err.kind(),
+                io::ErrorKind::TimedOut
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::WouldBlock
+            ),
+            KvsError::Timeout => true,
+            _ => false,
+        }
+    }
 }
 
 impl From<io::Error> for KvsError {
     fn from(err: io::Error) -> KvsError {
-        KvsError::Io(err)
+        match err.kind() {
+            // 套接字超时在不同平台上会以这两种错误之一报告
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => KvsError::Timeout,
+            _ => KvsError::Io(err),
+        }
     }
 }
 
@@ -1,7 +1,8 @@
-use crate::common::{GetResponse, RemoveResponse, Request, SetResponse};
-use crate::{KvsEngine, Result};
+use crate::common::{
+    read_frame, write_frame, CompactResponse, GetResponse, RemoveResponse, Request, SetResponse,
+};
+use crate::{KvsEngine, KvsError, Result};
 use log::{debug, error};
-use serde_json::Deserializer;
 use std::io::{BufReader, BufWriter, Write};
 use std::net::{TcpListener, TcpStream, ToSocketAddrs};
 
@@ -34,21 +35,19 @@ impl<E: KvsEngine> KvsServer<E> {
 
     fn serve(&mut self, tcp: TcpStream) -> Result<()> {
         let peer_addr = tcp.peer_addr()?;
-        let reader = BufReader::new(&tcp);
+        let mut reader = BufReader::new(&tcp);
         let mut writer = BufWriter::new(&tcp);
-        let req_reader = Deserializer::from_reader(reader).into_iter::<Request>();
 
         macro_rules! send_resp {
             ($resp:expr) => {{
                 let resp = $resp;
-                serde_json::to_writer(&mut writer, &resp)?;
+                write_frame(&mut writer, &resp)?;
                 writer.flush()?;
                 debug!("Response sent to {}: {:?}", peer_addr, resp);
-            };};
+            }};
         }
 
-        for req in req_reader {
-            let req = req?;
+        while let Some(req) = read_frame::<_, Request>(&mut reader)? {
             debug!("Receive request from {}: {:?}", peer_addr, req);
             match req {
                 Request::Get { key } => send_resp!(match self.engine.get(key) {
@@ -61,8 +60,13 @@ impl<E: KvsEngine> KvsServer<E> {
                 }),
                 Request::Remove { key } => send_resp!(match self.engine.remove(key) {
                     Ok(_) => RemoveResponse::Ok(()),
+                    Err(KvsError::KeyNotFound) => RemoveResponse::KeyNotFound,
                     Err(e) => RemoveResponse::Err(format!("{}", e)),
                 }),
+                Request::Compact => send_resp!(match self.engine.compact() {
+                    Ok(reclaimed) => CompactResponse::Ok(reclaimed),
+                    Err(e) => CompactResponse::Err(format!("{}", e)),
+                }),
             };
         }
         Ok(())
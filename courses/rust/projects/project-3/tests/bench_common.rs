@@ -0,0 +1,41 @@
+#[path = "../benches/common/mod.rs"]
+mod common;
+
+use std::collections::HashMap;
+
+// The same seed should generate the same data, and different seeds different data.
+#[test]
+fn seeded_generators_are_deterministic() {
+    assert_eq!(
+        common::key_values(7, 100, 16, 100),
+        common::key_values(7, 100, 16, 100)
+    );
+    assert_ne!(
+        common::key_values(7, 100, 16, 100),
+        common::key_values(8, 100, 16, 100)
+    );
+    assert_eq!(
+        common::zipf_accesses(7, 1000, 0.99, 10_000),
+        common::zipf_accesses(7, 1000, 0.99, 10_000)
+    );
+    assert_ne!(
+        common::zipf_accesses(7, 1000, 0.99, 10_000),
+        common::zipf_accesses(8, 1000, 0.99, 10_000)
+    );
+}
+
+// Zipfian accesses should concentrate on a few hot keys, and uniform ones not.
+#[test]
+fn zipf_accesses_are_skewed() {
+    let hottest = |theta| {
+        let mut counts = HashMap::new();
+        for i in common::zipf_accesses(7, 1000, theta, 10_000) {
+            assert!(i < 1000);
+            *counts.entry(i).or_insert(0) += 1;
+        }
+        counts.values().copied().max().unwrap()
+    };
+    // the hottest key gets about 1 / H(1000, 0.99), or 13%, of the accesses
+    assert!(hottest(0.99) > 1000, "{}", hottest(0.99));
+    assert!(hottest(0.0) < 100, "{}", hottest(0.0));
+}
@@ -1,4 +1,5 @@
 use assert_cmd::prelude::*;
+use kvs::{KvStore, KvsEngine};
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
 use std::process::Command;
@@ -335,3 +336,56 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_compact() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(temp_dir.path()).unwrap();
+    for iter in 0..100 {
+        store
+            .set("key1".to_owned(), format!("value{}", iter))
+            .unwrap();
+    }
+    drop(store);
+
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let dir_size = || -> u64 {
+        fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().metadata().unwrap().len())
+            .sum()
+    };
+    let size_before = dir_size();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["compact", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Reclaimed"));
+    assert!(dir_size() < size_before);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value99\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
@@ -1,4 +1,4 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{KvStore, KvsEngine, KvsError, Result, SledKvsEngine};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -69,6 +69,29 @@ fn remove_non_existent_key() -> Result<()> {
     Ok(())
 }
 
+// Removing a missing key should fail with `KeyNotFound` in every engine.
+fn check_remove_not_found(mut engine: impl KvsEngine) -> Result<()> {
+    assert!(matches!(
+        engine.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.remove("key1".to_owned())?;
+    assert!(matches!(
+        engine.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    Ok(())
+}
+
+#[test]
+fn remove_not_found_engines() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_remove_not_found(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_remove_not_found(SledKvsEngine::new(sled::open(temp_dir.path())?))
+}
+
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -0,0 +1,81 @@
+use kvs::{KvStore, KvsClient, KvsEngine, KvsError, KvsServer, Result, SledKvsEngine};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Start a `KvStore` server on `addr` in a background thread.
+fn start_server(temp_dir: &TempDir, addr: &'static str) -> Result<()> {
+    let engine = KvStore::open(temp_dir.path())?;
+    thread::spawn(move || {
+        KvsServer::new(engine).run(addr).unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+    Ok(())
+}
+
+// Pipelined gets should return the values in the order of the keys.
+#[test]
+fn get_many_ordering() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4301";
+    start_server(&temp_dir, addr)?;
+
+    let mut client = KvsClient::connect(addr)?;
+    for key_id in 0..100 {
+        client.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    // More keys than a single pipelined batch, in a scrambled order with missing keys
+    let key_ids: Vec<usize> = (0..3000).map(|i| (i * 7919) % 150).collect();
+    let keys = key_ids.iter().map(|id| format!("key{}", id)).collect();
+    let values = client.get_many(keys)?;
+    assert_eq!(values.len(), key_ids.len());
+    for (id, value) in key_ids.iter().zip(values) {
+        let expected = if *id < 100 {
+            Some(format!("value{}", id))
+        } else {
+            None
+        };
+        assert_eq!(value, expected);
+    }
+
+    // The connection is still in sync afterwards
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(client.get_many(vec![])?.is_empty());
+    Ok(())
+}
+
+// Removing a missing key through the network should give `KeyNotFound` for every engine.
+fn check_remove_not_found(
+    engine: impl KvsEngine + Send + 'static,
+    addr: &'static str,
+) -> Result<()> {
+    thread::spawn(move || {
+        KvsServer::new(engine).run(addr).unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr)?;
+    match client.remove("missing".to_owned()) {
+        Err(KvsError::KeyNotFound) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+    client.set("key".to_owned(), "value".to_owned())?;
+    client.remove("key".to_owned())?;
+    match client.remove("key".to_owned()) {
+        Err(KvsError::KeyNotFound) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+    Ok(())
+}
+
+#[test]
+fn remove_not_found_engines() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_remove_not_found(KvStore::open(temp_dir.path())?, "127.0.0.1:4302")?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_remove_not_found(
+        SledKvsEngine::new(sled::open(temp_dir.path())?),
+        "127.0.0.1:4303",
+    )
+}
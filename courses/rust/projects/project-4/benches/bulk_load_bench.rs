@@ -0,0 +1,44 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use kvs::{KvStore, KvsEngine, MemoryLogs};
+use tempfile::TempDir;
+
+const KEYS: usize = 1_000_000;
+const VALUE_BYTES: usize = 100;
+
+// Measure loading a million keys into a fresh store, with and without a capacity hint,
+// on disk and in memory.
+fn bulk_load_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bulk_load_bench");
+    group.sample_size(10);
+    let value = "x".repeat(VALUE_BYTES);
+    for &in_memory in &[false, true] {
+        for &hint in &[false, true] {
+            let name = format!(
+                "{}_{}",
+                if in_memory { "memory" } else { "file" },
+                if hint { "hint" } else { "no_hint" }
+            );
+            group.bench_function(name, |b| {
+                b.iter(|| {
+                    let temp_dir = TempDir::new().unwrap();
+                    let logs = MemoryLogs::new();
+                    let store = if in_memory {
+                        KvStore::open_in_memory(&logs).unwrap()
+                    } else {
+                        KvStore::open(temp_dir.path()).unwrap()
+                    };
+                    if hint {
+                        store.hint_capacity(KEYS, VALUE_BYTES).unwrap();
+                    }
+                    for key_i in 0..KEYS {
+                        store.set(format!("key{}", key_i), value.clone()).unwrap();
+                    }
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bulk_load_bench);
+criterion_main!(benches);
@@ -0,0 +1,36 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use kvs::{KvStore, KvStoreOptions, KvsEngine};
+use rand::prelude::*;
+use tempfile::TempDir;
+
+// Compare `get` latency with buffered reads and memory-mapped reads.
+fn get_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get_bench");
+    for &mmap_reads in &[false, true] {
+        let name = if mmap_reads { "mmap" } else { "buffered" };
+        for i in &[8, 12, 16] {
+            group.bench_with_input(format!("{}_{}", name, i), i, |b, i| {
+                let temp_dir = TempDir::new().unwrap();
+                let options = KvStoreOptions::default()
+                    .with_path(temp_dir.path())
+                    .with_mmap_reads(mmap_reads);
+                let store = KvStore::open_with_options(options).unwrap();
+                for key_i in 1..(1 << i) {
+                    store
+                        .set(format!("key{}", key_i), "value".to_string())
+                        .unwrap();
+                }
+                let mut rng = SmallRng::from_seed([0; 16]);
+                b.iter(|| {
+                    store
+                        .get(format!("key{}", rng.gen_range(1, 1 << i)))
+                        .unwrap();
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, get_bench);
+criterion_main!(benches);
@@ -0,0 +1,33 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use kvs::{KvStore, KvsEngine};
+use rand::prelude::*;
+use tempfile::TempDir;
+
+// Measure the per-`get` overhead with the values spread over more and more generations,
+// all of them kept open by the reader between compactions.
+fn read_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("read_bench");
+    for &gens in &[1, 16, 64] {
+        group.bench_with_input(format!("gens_{}", gens), &gens, |b, &gens| {
+            let temp_dir = TempDir::new().unwrap();
+            for gen in 0..gens {
+                let store = KvStore::open(temp_dir.path()).unwrap();
+                for key_i in 0..64 {
+                    store
+                        .set(format!("key{}_{}", gen, key_i), "value".to_string())
+                        .unwrap();
+                }
+            }
+            let store = KvStore::open(temp_dir.path()).unwrap();
+            let mut rng = SmallRng::from_seed([0; 16]);
+            b.iter(|| {
+                let key = format!("key{}_{}", rng.gen_range(0, gens), rng.gen_range(0, 64));
+                store.get(key).unwrap();
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, read_bench);
+criterion_main!(benches);
@@ -0,0 +1,50 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use kvs::{KvStore, KvStoreOptions, KvsEngine};
+use std::thread;
+use tempfile::TempDir;
+
+const THREADS: usize = 4;
+const INCREMENTS: usize = 100;
+
+// Measure concurrent increments with a single write lock against striped ones, with all
+// the threads on one hot key or each on its own keys.
+fn write_lock_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("write_lock_bench");
+    for &stripes in &[1, 16] {
+        for &hot in &[true, false] {
+            let workload = if hot { "hot_key" } else { "spread" };
+            let name = format!("stripes_{}_{}", stripes, workload);
+            group.bench_function(name, |b| {
+                let temp_dir = TempDir::new().unwrap();
+                let options = KvStoreOptions::default()
+                    .with_path(temp_dir.path())
+                    .with_write_lock_stripes(stripes);
+                let store = KvStore::open_with_options(options).unwrap();
+                b.iter(|| {
+                    let handles: Vec<_> = (0..THREADS)
+                        .map(|thread_id| {
+                            let store = store.clone();
+                            thread::spawn(move || {
+                                for i in 0..INCREMENTS {
+                                    let key = if hot {
+                                        "hot".to_owned()
+                                    } else {
+                                        format!("key{}_{}", thread_id, i % 16)
+                                    };
+                                    store.increment(key, 1).unwrap();
+                                }
+                            })
+                        })
+                        .collect();
+                    for handle in handles {
+                        handle.join().unwrap();
+                    }
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, write_lock_bench);
+criterion_main!(benches);
@@ -0,0 +1,145 @@
+use crate::Result;
+#[cfg(not(feature = "tracing"))]
+use log::error;
+use serde::Serialize;
+use std::collections::hash_map::DefaultHasher;
+use std::fs::OpenOptions;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+#[cfg(feature = "tracing")]
+use tracing::error;
+
+/// A record of every request served by a `KvsServer`, see `KvsServer::with_access_log`.
+///
+/// Each request is written as one line with the time, the address of the client, the
+/// operation, its key if it has a single one, and the status of the response. Unlike
+/// the debug logs, the records are written whatever the log level. Clones write to the
+/// same sink.
+#[derive(Clone)]
+pub struct AccessLog {
+    sink: Arc<Mutex<Box<dyn Write + Send>>>,
+    format: AccessLogFormat,
+    keys: AccessLogKeys,
+}
+
+/// How the records of an `AccessLog` are written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    /// A line of `name=value` fields, e.g.
+    /// `ts_ms=1700000000000 peer=127.0.0.1:50000 op=get key="a" status=ok`.
+    Text,
+    /// A JSON object per line, with the same fields.
+    Json,
+}
+
+/// How the keys appear in the records of an `AccessLog`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessLogKeys {
+    /// As they are.
+    Plain,
+    /// As the hex of a hash, so the records of the same key can be matched without
+    /// showing it. The hash is not cryptographic and only stable within a build.
+    Hashed,
+    /// Left out.
+    Redacted,
+}
+
+// A line of the access log.
+#[derive(Serialize)]
+struct AccessRecord<'a> {
+    // milliseconds since the Unix epoch
+    ts_ms: u128,
+    peer: &'a str,
+    op: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    status: &'a str,
+}
+
+impl AccessLog {
+    /// Creates an access log writing to `sink`, as `Text` with `Plain` keys.
+    pub fn new<W: Write + Send + 'static>(sink: W) -> AccessLog {
+        AccessLog {
+            sink: Arc::new(Mutex::new(Box::new(sink))),
+            format: AccessLogFormat::Text,
+            keys: AccessLogKeys::Plain,
+        }
+    }
+
+    /// Creates an access log writing to the standard error.
+    pub fn stderr() -> AccessLog {
+        AccessLog::new(io::stderr())
+    }
+
+    /// Creates an access log appending to the file at `path`, which is created if it
+    /// doesn't exist.
+    pub fn file<P: AsRef<Path>>(path: P) -> Result<AccessLog> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path.as_ref())?;
+        Ok(AccessLog::new(file))
+    }
+
+    /// Sets how the records are written.
+    pub fn with_format(mut self, format: AccessLogFormat) -> AccessLog {
+        self.format = format;
+        self
+    }
+
+    /// Sets how the keys appear in the records.
+    pub fn with_keys(mut self, keys: AccessLogKeys) -> AccessLog {
+        self.keys = keys;
+        self
+    }
+
+    // Write the record of a request. A record that can't be written is logged as an
+    // error, the request is served anyway.
+    pub(crate) fn record(&self, peer: &str, op: &str, key: Option<&str>, status: &str) {
+        let key = key.and_then(|key| match self.keys {
+            AccessLogKeys::Plain => Some(key.to_owned()),
+            AccessLogKeys::Hashed => Some(hash_key(key)),
+            AccessLogKeys::Redacted => None,
+        });
+        let record = AccessRecord {
+            ts_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis()),
+            peer,
+            op,
+            key,
+            status,
+        };
+        let line = match self.format {
+            AccessLogFormat::Text => record.to_text(),
+            AccessLogFormat::Json => {
+                serde_json::to_string(&record).expect("access records serialize")
+            }
+        };
+        let mut sink = self.sink.lock().unwrap();
+        if let Err(e) = writeln!(sink, "{}", line).and_then(|_| sink.flush()) {
+            error!("Access log cannot be written: {}", e);
+        }
+    }
+}
+
+impl AccessRecord<'_> {
+    fn to_text(&self) -> String {
+        let mut line = format!("ts_ms={} peer={} op={}", self.ts_ms, self.peer, self.op);
+        // quoted, a key may contain spaces
+        if let Some(key) = &self.key {
+            line.push_str(&format!(" key={:?}", key));
+        }
+        line.push_str(&format!(" status={}", self.status));
+        line
+    }
+}
+
+fn hash_key(key: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
@@ -1,5 +1,5 @@
 use clap::AppSettings;
-use kvs::{KvsClient, Result};
+use kvs::{JsonCodec, KvsClient, Result};
 use std::net::SocketAddr;
 use std::process::exit;
 use structopt::StructOpt;
@@ -59,6 +59,17 @@ enum Command {
         )]
         addr: SocketAddr,
     },
+    #[structopt(name = "stats", about = "Print the storage statistics of the server")]
+    Stats {
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
 }
 
 fn main() {
@@ -71,7 +82,7 @@ fn main() {
 
 // 详细中文注释（补充）：
 // 1. CLI 行为概述：
-//    - `kvs-client` 提供三个子命令：`get`、`set`、`rm`，分别对应对远端 `KvsServer` 的三种操作。
+//    - `kvs-client` 提供 `get`、`set`、`rm` 三个读写子命令，分别对应对远端 `KvsServer` 的三种操作；`stats` 则打印服务端存储引擎的统计信息。
 //    - 每个子命令都接受一个可选的 `--addr` 参数，用来指定服务器地址；默认地址为 `127.0.0.1:4000`，便于本地调试。
 // 2. 错误处理语义：
 //    - 主函数捕获 `run` 返回的 `Result`，如果有错误则打印到标准错误并以非零状态退出；这在脚本或 CI 中很方便。
@@ -82,7 +93,7 @@ fn main() {
 fn run(opt: Opt) -> Result<()> {
     match opt.command {
         Command::Get { key, addr } => {
-            let mut client = KvsClient::connect(addr)?;
+            let mut client = KvsClient::connect(addr, JsonCodec)?;
             if let Some(value) = client.get(key)? {
                 println!("{}", value);
             } else {
@@ -90,13 +101,21 @@ fn run(opt: Opt) -> Result<()> {
             }
         }
         Command::Set { key, value, addr } => {
-            let mut client = KvsClient::connect(addr)?;
+            let mut client = KvsClient::connect(addr, JsonCodec)?;
             client.set(key, value)?;
         }
         Command::Remove { key, addr } => {
-            let mut client = KvsClient::connect(addr)?;
+            let mut client = KvsClient::connect(addr, JsonCodec)?;
             client.remove(key)?;
         }
+        Command::Stats { addr } => {
+            let mut client = KvsClient::connect(addr, JsonCodec)?;
+            let stats = client.stats()?;
+            println!("keys: {}", stats.key_count);
+            println!("total log bytes: {}", stats.total_log_bytes);
+            println!("uncompacted bytes: {}", stats.uncompacted_bytes);
+            println!("generations: {}", stats.generation_count);
+        }
     }
     Ok(())
 }
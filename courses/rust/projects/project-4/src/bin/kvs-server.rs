@@ -43,9 +43,7 @@ arg_enum! {
 }
 
 fn main() {
-    env_logger::builder()
-        .filter_level(LevelFilter::Info)
-        .init();
+    env_logger::builder().filter_level(LevelFilter::Info).init();
     let mut opt = Opt::from_args();
     // 详细中文注释（补充）：
     // 1. 入口流程概览：
@@ -78,23 +76,31 @@ fn run(opt: Opt) -> Result<()> {
     info!("Storage engine: {}", engine);
     info!("Listening on {}", opt.addr);
 
-    // write engine to engine file
-    fs::write(current_dir()?.join("engine"), format!("{}", engine))?;
-
     let pool = RayonThreadPool::new(num_cpus::get() as u32)?;
 
+    // The engine locks the directory, so only the server holding the lock writes the
+    // engine file
     match engine {
-        Engine::kvs => run_with(KvStore::open(env::current_dir()?)?, pool, opt.addr),
+        Engine::kvs => run_with(KvStore::open(env::current_dir()?)?, engine, pool, opt.addr),
         Engine::sled => run_with(
-            SledKvsEngine::new(sled::open(env::current_dir()?)?),
+            SledKvsEngine::open(env::current_dir()?)?,
+            engine,
             pool,
             opt.addr,
         ),
     }
 }
 
-pub fn run_with<E: KvsEngine, P: ThreadPool>(engine: E, pool: P, addr: SocketAddr) -> Result<()> {
-    let server = KvsServer::new(engine, pool);
+fn run_with<E: KvsEngine, P: ThreadPool>(
+    engine: E,
+    name: Engine,
+    pool: P,
+    addr: SocketAddr,
+) -> Result<()> {
+    // write engine to engine file
+    fs::write(current_dir()?.join("engine"), format!("{}", name))?;
+
+    let server = KvsServer::new(engine, pool, JsonCodec);
     server.run(addr)
 }
 
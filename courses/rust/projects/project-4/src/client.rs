@@ -1,72 +1,560 @@
-use crate::common::{GetResponse, RemoveResponse, Request, SetResponse};
-use crate::{KvsError, Result};
-use serde::Deserialize;
-use serde_json::de::{Deserializer, IoRead};
-use std::io::{BufReader, BufWriter, Write};
-use std::net::{TcpStream, ToSocketAddrs};
+use crate::codec::Codec;
+use crate::common::{
+    read_frame, write_frame, BatchResponse, Envelope, ExistsResponse, GetOrSetResponse,
+    GetResponse, HealthResponse, IncrementResponse, Op, OpResult, PingResponse, PongInfo,
+    RemoveResponse, Request, ScanResponse, SetIfAbsentResponse, SetResponse, StatsResponse,
+};
+use crate::{KvsError, Result, Stats};
+use crossbeam::channel::{self, Receiver, Sender};
+use log::debug;
+#[cfg(feature = "tls")]
+use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerName, StreamOwned};
+use serde::de::DeserializeOwned;
+#[cfg(feature = "tls")]
+use std::convert::TryFrom;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::ops::{Deref, DerefMut};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::Path;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
 /// Key value store client
-pub struct KvsClient {
-    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
-    writer: BufWriter<TcpStream>,
+pub struct KvsClient<C: Codec> {
+    // frames are written whole to the inner stream, so only reads are buffered
+    stream: BufReader<Box<dyn Stream>>,
+    codec: C,
+    // the id of the last request sent
+    last_id: u64,
+    // set when a request or response failed halfway, leaving the stream unusable
+    broken: bool,
 }
 
 // 详细中文注释（补充）：
 // 1. `KvsClient` 的职责：作为同步（阻塞）客户端连接到 `KvsServer`，发送 `Request` 并读取 `Response`。
 // 2. 读写分工：
-//    - `writer`：负责将 `Request` 通过 `serde_json::to_writer` 序列化并写入 TCP 流，然后 `flush()` 将数据发送到服务器。
-//    - `reader`：使用 `serde_json::de::Deserializer` 的流式解析器从 TCP 流中反序列化响应，这样可以从同一连接连续读取多个响应。
+//    - 写：通过 `write_frame` 把 `Request` 序列化成一帧（4 字节大端长度 + 负载），一次性写入底层流并 `flush()`。
+//    - 读：通过 `read_frame` 先读长度再读负载，按帧反序列化响应，这样可以从同一连接连续读取多个响应。
+//    - 底层流可以是明文的 `TcpStream`，也可以是启用 `tls` feature 后的 TLS 流，二者都只需实现 `Read + Write`。
 // 3. 同步/阻塞语义：
 //    - 该客户端是同步设计，所有方法（`get/set/remove`）都会阻塞直到完成网络往返（写入请求并读取响应）。
 //    - 对于需要高并发的场景，应考虑使用异步客户端或在外部使用线程池进行并发调用。
 // 4. 错误处理与语义：
-//    - 服务端通过 `GetResponse::Err(String)` 等将业务错误（例如 key not found）传回，客户端将其转换为 `KvsError::StringError`。
+//    - 服务端通过 `GetResponse::Err(ServerError)` 等将分类后的错误传回，客户端将其转换回 `KvsError`，
+//      例如删除不存在的 key 会得到 `KvsError::KeyNotFound`，其它错误则为带原始信息的 `KvsError::StringError`。
 //    - 网络错误或反序列化错误会被转换为 `KvsError` 并上抛给调用者。
 // 5. 对 Rust 新手的建议：
-//    - 注意 `TcpStream::try_clone()`：它并不复制底层连接，而是创建一个共享句柄，读写可以分开处理（本例将读、写句柄分别包装）。
-//    - `Deserializer::from_reader` 是流式的 JSON 解析器，适合在二进制流中连续读取多个 JSON 值而不必把整个响应读到内存。
-
-impl KvsClient {
-    /// Connect to `addr` to access `KvsServer`.
-    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
-        let tcp_reader = TcpStream::connect(addr)?;
-        let tcp_writer = tcp_reader.try_clone()?;
-        Ok(KvsClient {
-            reader: Deserializer::from_reader(BufReader::new(tcp_reader)),
-            writer: BufWriter::new(tcp_writer),
-        })
+//    - TLS 流无法像 `TcpStream::try_clone()` 那样拆成读、写两个句柄，所以客户端只持有一个流，读写都经过它。
+//    - 长度前缀让每条消息都有明确的边界，之后要加压缩等处理也只需改动帧的负载。
+
+impl<C: Codec> KvsClient<C> {
+    /// Connect to `addr` to access a `KvsServer` using the same codec.
+    ///
+    /// # Errors
+    ///
+    /// It returns an error if the server uses a different codec.
+    pub fn connect<A: ToSocketAddrs>(addr: A, codec: C) -> Result<Self> {
+        KvsClient::from_stream(TcpStream::connect(addr)?, codec)
+    }
+
+    /// Connect to `addr` to access `KvsServer`, giving up after `timeout`.
+    ///
+    /// The timeout also applies to every read and write on the connection afterwards, so
+    /// a stalled server results in `KvsError::Timeout` instead of a hang.
+    pub fn connect_timeout<A: ToSocketAddrs>(addr: A, timeout: Duration, codec: C) -> Result<Self> {
+        let mut last_err = None;
+        for addr in addr.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(tcp) => {
+                    tcp.set_read_timeout(Some(timeout))?;
+                    tcp.set_write_timeout(Some(timeout))?;
+                    return KvsClient::from_stream(tcp, codec);
+                }
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err
+            .map(KvsError::from)
+            .unwrap_or_else(|| KvsError::StringError("No address to connect to".to_owned())))
+    }
+
+    /// Connect to `addr` to access `KvsServer`, trying up to `attempts` times.
+    ///
+    /// The delay between two attempts starts at `backoff` and doubles after each failure.
+    /// The error of the last attempt is returned if all of them fail.
+    pub fn connect_with_retry<A: ToSocketAddrs>(
+        addr: A,
+        attempts: u32,
+        backoff: Duration,
+        codec: C,
+    ) -> Result<Self> {
+        let mut delay = backoff;
+        let mut attempt = 1;
+        loop {
+            match KvsClient::connect(&addr, codec.clone()) {
+                Err(KvsError::Io(ref e)) if attempt < attempts => {
+                    debug!("Connection attempt {} failed: {}", attempt, e);
+                }
+                res => return res,
+            }
+            thread::sleep(delay);
+            delay *= 2;
+            attempt += 1;
+        }
+    }
+
+    /// Connect to `addr` to access a `KvsServer` running with `KvsServer::run_tls`.
+    ///
+    /// The certificate of the server must be valid for `server_name` and signed by one of
+    /// `root_certs`.
+    #[cfg(feature = "tls")]
+    pub fn connect_tls<A: ToSocketAddrs>(
+        addr: A,
+        server_name: &str,
+        root_certs: RootCertStore,
+        codec: C,
+    ) -> Result<Self> {
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(root_certs)
+            .with_no_client_auth();
+        let name = ServerName::try_from(server_name)
+            .map_err(|_| KvsError::StringError(format!("Invalid server name: {}", server_name)))?;
+        let conn = ClientConnection::new(Arc::new(config), name)?;
+        KvsClient::from_stream(StreamOwned::new(conn, TcpStream::connect(addr)?), codec)
+    }
+
+    /// Connect to the Unix domain socket at `path` to access a `KvsServer` running with
+    /// `KvsServer::run_unix`.
+    #[cfg(unix)]
+    pub fn connect_unix<P: AsRef<Path>>(path: P, codec: C) -> Result<Self> {
+        KvsClient::from_stream(UnixStream::connect(path)?, codec)
+    }
+
+    fn from_stream<S: Read + Write + Send + 'static>(stream: S, codec: C) -> Result<Self> {
+        let mut client = KvsClient {
+            stream: BufReader::new(Box::new(stream)),
+            codec,
+            last_id: 0,
+            broken: false,
+        };
+        client.handshake()?;
+        Ok(client)
+    }
+
+    // Exchange the codec IDs with the server.
+    fn handshake(&mut self) -> Result<()> {
+        self.stream.get_mut().write_all(&[C::ID])?;
+        self.stream.get_mut().flush()?;
+        let mut server_codec = [0; 1];
+        self.stream.read_exact(&mut server_codec)?;
+        if server_codec[0] != C::ID {
+            return Err(KvsError::StringError(format!(
+                "Server codec {} doesn't match client codec {}",
+                server_codec[0],
+                C::ID
+            )));
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, req: &Request) -> Result<()> {
+        // ids start from 1, 0 is left to requests without an id
+        self.last_id += 1;
+        let payload = self.codec.encode(&Envelope {
+            id: self.last_id,
+            body: req,
+        })?;
+        let res = write_frame(self.stream.get_mut(), &payload);
+        self.broken |= res.is_err();
+        res
+    }
+
+    // Read the response to the last request sent.
+    fn receive<T: DeserializeOwned>(&mut self) -> Result<T> {
+        let res = self.read_response();
+        self.broken |= res.is_err();
+        res
+    }
+
+    fn read_response<T: DeserializeOwned>(&mut self) -> Result<T> {
+        match read_frame(&mut self.stream)? {
+            Some(payload) => {
+                let resp: Envelope<T> = self.codec.decode(&payload)?;
+                if resp.id != self.last_id {
+                    return Err(KvsError::ProtocolDesync {
+                        expected: self.last_id,
+                        found: resp.id,
+                    });
+                }
+                Ok(resp.body)
+            }
+            None => {
+                let err = io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed");
+                Err(KvsError::Io(err))
+            }
+        }
+    }
+
+    /// Returns whether the connection failed while sending a request or reading its
+    /// response.
+    ///
+    /// A broken client may be out of sync with the server and should be dropped.
+    pub fn is_broken(&self) -> bool {
+        self.broken
     }
 
     /// Get the value of a given key from the server.
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        serde_json::to_writer(&mut self.writer, &Request::Get { key })?;
-        self.writer.flush()?;
-        let resp = GetResponse::deserialize(&mut self.reader)?;
-        match resp {
+        self.send(&Request::Get { key })?;
+        match self.receive::<GetResponse>()? {
             GetResponse::Ok(value) => Ok(value),
-            GetResponse::Err(msg) => Err(KvsError::StringError(msg)),
+            GetResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Check whether a given key exists in the server, without fetching its value.
+    pub fn exists(&mut self, key: String) -> Result<bool> {
+        self.send(&Request::Exists { key })?;
+        match self.receive::<ExistsResponse>()? {
+            ExistsResponse::Ok(exists) => Ok(exists),
+            ExistsResponse::Err(err) => Err(err.into()),
         }
     }
 
     /// Set the value of a string key in the server.
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Set { key, value })?;
-        self.writer.flush()?;
-        let resp = SetResponse::deserialize(&mut self.reader)?;
-        match resp {
+        self.send(&Request::Set { key, value })?;
+        match self.receive::<SetResponse>()? {
             SetResponse::Ok(_) => Ok(()),
-            SetResponse::Err(msg) => Err(KvsError::StringError(msg)),
+            SetResponse::Err(err) => Err(err.into()),
         }
     }
 
     /// Remove a string key in the server.
     pub fn remove(&mut self, key: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Remove { key })?;
-        self.writer.flush()?;
-        let resp = RemoveResponse::deserialize(&mut self.reader)?;
-        match resp {
+        self.send(&Request::Remove { key })?;
+        match self.receive::<RemoveResponse>()? {
             RemoveResponse::Ok(_) => Ok(()),
-            RemoveResponse::Err(msg) => Err(KvsError::StringError(msg)),
+            RemoveResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Execute a batch of operations in the server with a single round trip.
+    ///
+    /// The operations are executed in order and one `OpResult` is returned for each of
+    /// them. A failing operation does not abort the rest of the batch. With a `KvStore`
+    /// no other write to the keys of the batch comes in between its operations, see
+    /// `KvsEngine::batch`.
+    pub fn batch(&mut self, ops: Vec<Op>) -> Result<Vec<OpResult>> {
+        self.send(&Request::Batch(ops))?;
+        match self.receive::<BatchResponse>()? {
+            BatchResponse::Ok(results) => Ok(results),
+            BatchResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Start a pipeline of operations, sent to the server with a single round trip by
+    /// `Pipeline::execute`.
+    pub fn pipeline(&mut self) -> Pipeline<'_, C> {
+        Pipeline {
+            client: self,
+            ops: Vec::new(),
+        }
+    }
+
+    /// Get the key/value pairs with keys from `start` (inclusive) to `end` (exclusive)
+    /// in key order, at most `limit` of them.
+    ///
+    /// `None` leaves that side of the range unbounded. The pairs come back in a single
+    /// response, so set a `limit` when the range may be large.
+    pub fn scan(
+        &mut self,
+        start: Option<String>,
+        end: Option<String>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, String)>> {
+        self.send(&Request::Scan { start, end, limit })?;
+        match self.receive::<ScanResponse>()? {
+            ScanResponse::Ok(pairs) => Ok(pairs),
+            ScanResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Add `delta` to the integer value of a key in the server and return the new value.
+    ///
+    /// A missing key counts as 0. The server does the addition atomically, so concurrent
+    /// clients don't lose each other's increments.
+    pub fn increment(&mut self, key: String, delta: i64) -> Result<i64> {
+        self.send(&Request::Increment { key, delta })?;
+        match self.receive::<IncrementResponse>()? {
+            IncrementResponse::Ok(value) => Ok(value),
+            IncrementResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Subtract `delta` from the integer value of a key in the server and return the new
+    /// value, atomically like `increment`.
+    pub fn decrement(&mut self, key: String, delta: i64) -> Result<i64> {
+        self.send(&Request::Decrement { key, delta })?;
+        match self.receive::<IncrementResponse>()? {
+            IncrementResponse::Ok(value) => Ok(value),
+            IncrementResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Set a counter in the server, expiring after `ttl` if it's given.
+    ///
+    /// Increments and decrements keep the expiry, and start again from 0 once the counter
+    /// has expired, e.g. to count requests within a time window.
+    pub fn set_counter(&mut self, key: String, value: i64, ttl: Option<Duration>) -> Result<()> {
+        let ttl_ms = ttl.map(|ttl| ttl.as_millis() as u64);
+        self.send(&Request::SetCounter { key, value, ttl_ms })?;
+        match self.receive::<SetResponse>()? {
+            SetResponse::Ok(_) => Ok(()),
+            SetResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Get the value of a key in the server, or set it to `default` if it doesn't exist.
+    ///
+    /// The server does both atomically, so concurrent clients on a missing key all get
+    /// the value of whichever set it first.
+    pub fn get_or_set(&mut self, key: String, default: String) -> Result<String> {
+        self.send(&Request::GetOrSet { key, default })?;
+        match self.receive::<GetOrSetResponse>()? {
+            GetOrSetResponse::Ok(value) => Ok(value),
+            GetOrSetResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Set the value of a key in the server only if it doesn't exist, and return whether
+    /// it's set.
+    ///
+    /// The server checks and sets atomically, so of concurrent clients on a missing key
+    /// exactly one gets `true`, e.g. to take a lock.
+    pub fn set_if_absent(&mut self, key: String, value: String) -> Result<bool> {
+        self.send(&Request::SetIfAbsent { key, value })?;
+        match self.receive::<SetIfAbsentResponse>()? {
+            SetIfAbsentResponse::Ok(set) => Ok(set),
+            SetIfAbsentResponse::Err(err) => Err(err.into()),
         }
     }
+
+    /// Check that the server is alive, without touching its storage engine.
+    pub fn ping(&mut self) -> Result<PongInfo> {
+        self.send(&Request::Ping)?;
+        match self.receive::<PingResponse>()? {
+            PingResponse::Pong {
+                version,
+                uptime_secs,
+            } => Ok(PongInfo {
+                version,
+                uptime_secs,
+            }),
+        }
+    }
+
+    /// Check the health of the server.
+    ///
+    /// The server runs a write/read probe against its storage engine, so a successful
+    /// check means the server is able to serve requests.
+    pub fn health(&mut self) -> Result<()> {
+        self.send(&Request::Health)?;
+        match self.receive::<HealthResponse>()? {
+            HealthResponse::Ok(_) => Ok(()),
+            HealthResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Get the storage statistics of the server's engine.
+    pub fn stats(&mut self) -> Result<Stats> {
+        self.send(&Request::Stats)?;
+        match self.receive::<StatsResponse>()? {
+            StatsResponse::Ok(info) => Ok(info.into()),
+            StatsResponse::Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// Operations queued on a `KvsClient`, sent together as a batch.
+///
+/// ```no_run
+/// # use kvs::{JsonCodec, KvsClient, Result};
+/// # fn main() -> Result<()> {
+/// let mut client = KvsClient::connect("127.0.0.1:4000", JsonCodec)?;
+/// let results = client
+///     .pipeline()
+///     .set("key".to_owned(), "value".to_owned())
+///     .get("key".to_owned())
+///     .execute()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Pipeline<'a, C: Codec> {
+    client: &'a mut KvsClient<C>,
+    ops: Vec<Op>,
+}
+
+impl<'a, C: Codec> Pipeline<'a, C> {
+    /// Queue getting the value of a given key.
+    pub fn get(mut self, key: String) -> Self {
+        self.ops.push(Op::Get { key });
+        self
+    }
+
+    /// Queue setting the value of a string key.
+    pub fn set(mut self, key: String, value: String) -> Self {
+        self.ops.push(Op::Set { key, value });
+        self
+    }
+
+    /// Queue removing a string key.
+    pub fn remove(mut self, key: String) -> Self {
+        self.ops.push(Op::Remove { key });
+        self
+    }
+
+    /// Queue setting the value of a string key to `new` only if its current value is
+    /// `expected`, `None` meaning that the key must not exist.
+    pub fn compare_and_swap(mut self, key: String, expected: Option<String>, new: String) -> Self {
+        self.ops.push(Op::CompareAndSwap { key, expected, new });
+        self
+    }
+
+    /// Send the queued operations and return one `OpResult` for each of them, in the
+    /// order they were queued.
+    ///
+    /// A failing operation does not abort the rest, see `KvsClient::batch`.
+    pub fn execute(self) -> Result<Vec<OpResult>> {
+        self.client.batch(self.ops)
+    }
+}
+
+/// A fixed set of `KvsClient` connections to one server, shared between threads.
+///
+/// `checkout` hands out a connection, which goes back to the pool when the returned
+/// `PooledClient` is dropped. Broken connections are reconnected on their next checkout.
+///
+/// ```no_run
+/// # use kvs::{JsonCodec, KvsClientPool, Result};
+/// # fn main() -> Result<()> {
+/// let pool = KvsClientPool::connect("127.0.0.1:4000", 4, JsonCodec)?;
+/// pool.checkout()?.set("key".to_owned(), "value".to_owned())?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct KvsClientPool<C: Codec> {
+    addrs: Vec<SocketAddr>,
+    codec: C,
+    // `None` stands for a connection that has to be reconnected
+    sender: Sender<Option<KvsClient<C>>>,
+    receiver: Receiver<Option<KvsClient<C>>>,
+}
+
+impl<C: Codec> KvsClientPool<C> {
+    /// Open `size` connections to `addr`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is 0.
+    pub fn connect<A: ToSocketAddrs>(addr: A, size: usize, codec: C) -> Result<Self> {
+        assert!(size > 0, "a pool needs at least one connection");
+        let addrs: Vec<_> = addr.to_socket_addrs()?.collect();
+        let (sender, receiver) = channel::bounded(size);
+        for _ in 0..size {
+            let client = KvsClient::connect(&addrs[..], codec.clone())?;
+            sender
+                .send(Some(client))
+                .expect("the pool owns the receiver");
+        }
+        Ok(KvsClientPool {
+            addrs,
+            codec,
+            sender,
+            receiver,
+        })
+    }
+
+    /// Take a connection out of the pool, waiting for one to be returned if all of them
+    /// are in use.
+    ///
+    /// # Errors
+    ///
+    /// It returns an error if a broken connection can't be reconnected. The pool keeps
+    /// its size and tries again on a later checkout.
+    pub fn checkout(&self) -> Result<PooledClient<'_, C>> {
+        let slot = self.receiver.recv().expect("the pool owns the sender");
+        let client = match slot {
+            Some(client) if !client.is_broken() => client,
+            _ => match KvsClient::connect(&self.addrs[..], self.codec.clone()) {
+                Ok(client) => client,
+                Err(e) => {
+                    self.put_back(None);
+                    return Err(e);
+                }
+            },
+        };
+        Ok(PooledClient {
+            pool: self,
+            client: Some(client),
+        })
+    }
+
+    /// Get the value of a given key with a pooled connection.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.checkout()?.get(key)
+    }
+
+    /// Set the value of a string key with a pooled connection.
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.checkout()?.set(key, value)
+    }
+
+    /// Remove a string key with a pooled connection.
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.checkout()?.remove(key)
+    }
+
+    fn put_back(&self, slot: Option<KvsClient<C>>) {
+        // there are never more slots than the capacity, so this doesn't block
+        self.sender.send(slot).expect("the pool owns the receiver");
+    }
 }
+
+/// A connection checked out of a `KvsClientPool`, returned to it on drop.
+pub struct PooledClient<'a, C: Codec> {
+    pool: &'a KvsClientPool<C>,
+    client: Option<KvsClient<C>>,
+}
+
+impl<'a, C: Codec> Deref for PooledClient<'a, C> {
+    type Target = KvsClient<C>;
+
+    fn deref(&self) -> &KvsClient<C> {
+        self.client.as_ref().expect("client taken before drop")
+    }
+}
+
+impl<'a, C: Codec> DerefMut for PooledClient<'a, C> {
+    fn deref_mut(&mut self) -> &mut KvsClient<C> {
+        self.client.as_mut().expect("client taken before drop")
+    }
+}
+
+impl<'a, C: Codec> Drop for PooledClient<'a, C> {
+    fn drop(&mut self) {
+        let slot = self.client.take().filter(|client| !client.is_broken());
+        self.pool.put_back(slot);
+    }
+}
+
+// A connection to the server, either a plain `TcpStream` or a TLS stream.
+trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
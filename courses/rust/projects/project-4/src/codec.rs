@@ -0,0 +1,51 @@
+use crate::Result;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// The serialization format of the messages between `KvsClient` and `KvsServer`.
+///
+/// Requests and responses are both encoded with the codec. The client sends the `ID` of
+/// its codec right after connecting and the server answers with its own, so a client
+/// and a server using different codecs fail before any request is sent.
+pub trait Codec: Clone + Send + 'static {
+    /// The byte identifying the codec in the handshake.
+    const ID: u8;
+
+    /// Encodes a request or a response.
+    fn encode<T: Serialize>(&self, message: &T) -> Result<Vec<u8>>;
+
+    /// Decodes a request or a response.
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T>;
+}
+
+/// Encodes messages as JSON.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    const ID: u8 = 1;
+
+    fn encode<T: Serialize>(&self, message: &T) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(message)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+/// Encodes messages with bincode, which is more compact than JSON.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BincodeCodec;
+
+impl Codec for BincodeCodec {
+    const ID: u8 = 2;
+
+    fn encode<T: Serialize>(&self, message: &T) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(message)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
@@ -1,4 +1,7 @@
+use crate::{KvsError, Result, Stats};
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{self, Read, Write};
 
 // 详细中文注释（补充）：
 // 1. 协议设计：`Request` 与 `*Response` 枚举定义了客户端与服务器之间的 JSON-RPC 式消息格式（但没有使用完整的 JSON-RPC 标准），
@@ -6,31 +9,434 @@ use serde::{Deserialize, Serialize};
 // 2. 向后兼容性与版本：
 //    - 在设计协议时应注意兼容性（新增变体或字段时要考虑旧客户端/服务器如何处理）。当前简单实现假定客户端与服务器版本一致。
 // 3. 错误表达：
-//    - 对于 `GetResponse::Err(String)` 等变体，服务器会把错误信息打包成字符串返回；客户端收到后将其映射为 `KvsError::StringError`。
+//    - 对于 `GetResponse::Err(ServerError)` 等变体，服务器会把错误分类后返回；客户端收到后将其映射回对应的 `KvsError`
+//      （例如 `ServerError::KeyNotFound` 对应 `KvsError::KeyNotFound`），其余错误映射为带原始信息的 `KvsError::StringError`。
 // 4. 对 Rust 新手的建议：
 //    - 使用 `serde` 时，枚举的序列化形式是可控的（tagged、untagged 等），默认行为在本仓库里足够直观，但如果需要与其他语言互通，可显式指定序列化策略。
 
+/// A message on the wire: a request or a response together with its id.
+///
+/// The server copies the id of a request into its response, so the client can tell
+/// when the responses get out of step with the requests. A missing id reads as 0,
+/// which keeps requests from clients that don't send one valid.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Envelope<T> {
+    #[serde(default)]
+    pub id: u64,
+    pub body: T,
+}
+
+/// Just the id of an `Envelope`, to answer a request whose body can't be decoded.
+#[derive(Debug, Deserialize)]
+pub struct EnvelopeId {
+    #[serde(default)]
+    pub id: u64,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    Get {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: String,
+    },
+    Remove {
+        key: String,
+    },
+    Exists {
+        key: String,
+    },
+    Batch(Vec<Op>),
+    Health,
+    // `start` is inclusive and `end` exclusive, `None` leaves that side unbounded
+    Scan {
+        start: Option<String>,
+        end: Option<String>,
+        limit: Option<usize>,
+    },
+    Increment {
+        key: String,
+        delta: i64,
+    },
+    Decrement {
+        key: String,
+        delta: i64,
+    },
+    SetCounter {
+        key: String,
+        value: i64,
+        // the TTL in milliseconds, no expiry if absent
+        ttl_ms: Option<u64>,
+    },
+    GetOrSet {
+        key: String,
+        default: String,
+    },
+    SetIfAbsent {
+        key: String,
+        value: String,
+    },
+    Ping,
+    Stats,
+}
+
+impl Request {
+    // The name of the operation and its key, as written in the access log. Requests on
+    // no key or on many have none.
+    pub(crate) fn op_and_key(&self) -> (&'static str, Option<&str>) {
+        match self {
+            Request::Get { key } => ("get", Some(key)),
+            Request::Set { key, .. } => ("set", Some(key)),
+            Request::Remove { key } => ("remove", Some(key)),
+            Request::Exists { key } => ("exists", Some(key)),
+            Request::Batch(_) => ("batch", None),
+            Request::Health => ("health", None),
+            Request::Scan { .. } => ("scan", None),
+            Request::Increment { key, .. } => ("increment", Some(key)),
+            Request::Decrement { key, .. } => ("decrement", Some(key)),
+            Request::SetCounter { key, .. } => ("set_counter", Some(key)),
+            Request::GetOrSet { key, .. } => ("get_or_set", Some(key)),
+            Request::SetIfAbsent { key, .. } => ("set_if_absent", Some(key)),
+            Request::Ping => ("ping", None),
+            Request::Stats => ("stats", None),
+        }
+    }
+
+    // Whether the request reads or writes a key starting with `prefix`, alone or in a
+    // batch.
+    pub(crate) fn uses_key_prefix(&self, prefix: &str) -> bool {
+        match self {
+            Request::Batch(ops) => ops.iter().any(|op| op.key().starts_with(prefix)),
+            req => req
+                .op_and_key()
+                .1
+                .is_some_and(|key| key.starts_with(prefix)),
+        }
+    }
+}
+
+/// A single operation in a batch request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Op {
+    /// Gets the value of a key.
+    Get {
+        /// The key to read
+        key: String,
+    },
+    /// Sets the value of a key.
+    Set {
+        /// The key to write
+        key: String,
+        /// The new value
+        value: String,
+    },
+    /// Removes a key.
+    Remove {
+        /// The key to remove
+        key: String,
+    },
+    /// Sets the value of a key to `new` only if its current value is `expected`, like
+    /// `KvsEngine::compare_and_swap`.
+    CompareAndSwap {
+        /// The key to write
+        key: String,
+        /// The value the key must have, `None` if it must not exist
+        expected: Option<String>,
+        /// The new value
+        new: String,
+    },
+}
+
+impl Op {
+    /// Returns the key the operation reads or writes.
+    pub(crate) fn key(&self) -> &str {
+        match self {
+            Op::Get { key }
+            | Op::Set { key, .. }
+            | Op::Remove { key }
+            | Op::CompareAndSwap { key, .. } => key,
+        }
+    }
+}
+
+/// The result of a single operation in a batch request.
+///
+/// Results are returned in the same order as the operations were sent.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OpResult {
+    /// The value read by an `Op::Get`.
+    Get(Option<String>),
+    /// An `Op::Set` succeeded.
+    Set,
+    /// An `Op::Remove` succeeded.
+    Remove,
+    /// Whether an `Op::CompareAndSwap` set the value.
+    CompareAndSwap(bool),
+    /// The operation failed.
+    Err(ServerError),
+}
+
+/// An error returned by the server for a request or a batch operation.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ServerError {
+    /// The key to remove doesn't exist.
+    KeyNotFound,
+    /// The request can't be served as sent, e.g. a value over the size limit.
+    InvalidCommand(String),
+    /// The server failed to serve the request, e.g. on an I/O error.
+    Internal(String),
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ServerError::KeyNotFound => write!(f, "{}", KvsError::KeyNotFound),
+            ServerError::InvalidCommand(msg) | ServerError::Internal(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl From<KvsError> for ServerError {
+    fn from(err: KvsError) -> ServerError {
+        match err {
+            KvsError::KeyNotFound => ServerError::KeyNotFound,
+            KvsError::Unsupported
+            | KvsError::NotANumber
+            | KvsError::Overflow
+            | KvsError::KeyTooLarge { .. }
+            | KvsError::ValueTooLarge { .. } => ServerError::InvalidCommand(err.to_string()),
+            _ => ServerError::Internal(err.to_string()),
+        }
+    }
+}
+
+impl ServerError {
+    // The status of a response with this error, as written in the access log.
+    pub(crate) fn status(&self) -> &'static str {
+        match self {
+            ServerError::KeyNotFound => "not_found",
+            ServerError::InvalidCommand(_) => "invalid",
+            ServerError::Internal(_) => "error",
+        }
+    }
+}
+
+// The status of a response, as written in the access log.
+pub(crate) trait ResponseStatus {
+    fn status(&self) -> &'static str;
+}
+
+macro_rules! impl_response_status {
+    ($($resp:ident),*) => {$(
+        impl ResponseStatus for $resp {
+            fn status(&self) -> &'static str {
+                match self {
+                    $resp::Ok(_) => "ok",
+                    $resp::Err(e) => e.status(),
+                }
+            }
+        }
+    )*};
+}
+
+impl_response_status!(
+    GetResponse,
+    SetResponse,
+    RemoveResponse,
+    ExistsResponse,
+    HealthResponse,
+    BatchResponse,
+    ScanResponse,
+    IncrementResponse,
+    GetOrSetResponse,
+    SetIfAbsentResponse,
+    StatsResponse,
+    MalformedResponse
+);
+
+impl ResponseStatus for PingResponse {
+    fn status(&self) -> &'static str {
+        "ok"
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum GetResponse {
     Ok(Option<String>),
-    Err(String),
+    Err(ServerError),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum SetResponse {
     Ok(()),
-    Err(String),
+    Err(ServerError),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum RemoveResponse {
     Ok(()),
-    Err(String),
+    Err(ServerError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ExistsResponse {
+    Ok(bool),
+    Err(ServerError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum HealthResponse {
+    Ok(()),
+    Err(ServerError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum BatchResponse {
+    Ok(Vec<OpResult>),
+    Err(ServerError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ScanResponse {
+    Ok(Vec<(String, String)>),
+    Err(ServerError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum PingResponse {
+    Pong { version: String, uptime_secs: u64 },
+}
+
+/// The answer of a server to `KvsClient::ping`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PongInfo {
+    /// The version of the server crate.
+    pub version: String,
+    /// How long the server has been running, in seconds.
+    pub uptime_secs: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum IncrementResponse {
+    Ok(i64),
+    Err(ServerError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum GetOrSetResponse {
+    Ok(String),
+    Err(ServerError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SetIfAbsentResponse {
+    Ok(bool),
+    Err(ServerError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum StatsResponse {
+    Ok(StatsInfo),
+    Err(ServerError),
+}
+
+/// The answer to a request that can't be decoded.
+///
+/// The response the client expects is unknown, but all of them share this `Err` variant
+/// at the same place, so it decodes as any of them.
+#[derive(Debug, Serialize)]
+pub enum MalformedResponse {
+    // never sent, it only keeps `Err` at variant index 1 like the other responses for
+    // bincode, which encodes variants by index
+    #[allow(dead_code)]
+    Ok(()),
+    Err(ServerError),
+}
+
+/// `Stats` as sent on the wire, with the counts fixed to 64 bits.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StatsInfo {
+    pub key_count: u64,
+    pub total_log_bytes: u64,
+    pub uncompacted_bytes: u64,
+    pub generation_count: u64,
+}
+
+impl From<Stats> for StatsInfo {
+    fn from(stats: Stats) -> StatsInfo {
+        StatsInfo {
+            key_count: stats.key_count as u64,
+            total_log_bytes: stats.total_log_bytes,
+            uncompacted_bytes: stats.uncompacted_bytes,
+            generation_count: stats.generation_count as u64,
+        }
+    }
+}
+
+impl From<StatsInfo> for Stats {
+    fn from(info: StatsInfo) -> Stats {
+        Stats {
+            key_count: info.key_count as usize,
+            total_log_bytes: info.total_log_bytes,
+            uncompacted_bytes: info.uncompacted_bytes,
+            generation_count: info.generation_count as usize,
+        }
+    }
+}
+
+/// The largest payload of a frame, in bytes.
+///
+/// `read_frame` rejects a longer frame before allocating its payload, so that a bad
+/// length from a peer can't exhaust the memory.
+pub const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+
+/// Writes `payload` as a frame: a 4-byte big-endian length followed by the payload.
+///
+/// The frame is written with a single `write_all`, so that an unbuffered stream sends it
+/// in one piece.
+pub fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> Result<()> {
+    queue_frame(writer, payload)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes `payload` as a frame like `write_frame`, without flushing `writer`.
+pub fn queue_frame<W: Write>(writer: &mut W, payload: &[u8]) -> Result<()> {
+    if payload.len() > MAX_FRAME_LEN {
+        return Err(frame_too_large(payload.len()));
+    }
+    let len = payload.len() as u32;
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame)?;
+    Ok(())
+}
+
+/// Reads a frame written by `write_frame`.
+///
+/// Returns the payload, or `None` if the stream ends before a new frame.
+pub fn read_frame<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut len_buf = [0; 4];
+    match reader.read_exact(&mut len_buf) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_be_bytes(len_buf) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(frame_too_large(len));
+    }
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
+fn frame_too_large(len: usize) -> KvsError {
+    KvsError::StringError(format!(
+        "Frame of {} bytes exceeds the limit of {} bytes",
+        len, MAX_FRAME_LEN
+    ))
 }
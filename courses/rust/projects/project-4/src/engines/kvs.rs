@@ -1,22 +1,60 @@
-use std::cell::RefCell;
-use std::collections::BTreeMap;
+use std::cell::{Cell, RefCell};
+use std::cmp::Reverse;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{btree_map, BTreeMap, BTreeSet, HashMap};
+use std::convert::TryInto;
 use std::ffi::OsStr;
+use std::fmt;
 use std::fs::{self, File, OpenOptions};
+use std::hash::{Hash, Hasher};
 use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::ops::Range;
+use std::ops::{Bound, Range};
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use crossbeam_skiplist::SkipMap;
-use log::error;
+use crossbeam::channel;
+use crossbeam_skiplist::{map, SkipMap};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use log::{error, warn};
+#[cfg(feature = "mmap")]
+use memmap2::Mmap;
 use serde::{Deserialize, Serialize};
-use serde_json::Deserializer;
 
-use super::KvsEngine;
+use super::{
+    is_self_check_key, lock_file, read_export_magic, read_export_pair, write_export_pair,
+    KvsEngine, EXPORT_MAGIC, LOCK_FILE, SELF_CHECK_PREFIX,
+};
+use crate::common::{Op, OpResult};
+use crate::thread_pool::{SharedQueueThreadPool, ThreadPool};
 use crate::{KvsError, Result};
 
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+// The default number of locks the writes to keys are striped over.
+const WRITE_LOCK_STRIPES: usize = 16;
+// How many bytes of a generation are replayed between two progress reports.
+const RECOVERY_PROGRESS_INTERVAL: u64 = 64 * 1024;
+// How many bytes a compaction copies between two progress reports.
+const COMPACTION_PROGRESS_INTERVAL: u64 = 64 * 1024;
+// `KvStore::hint_capacity` doesn't grow the write buffer beyond it.
+const MAX_WRITE_BUFFER: usize = 1024 * 1024;
+// `KvStore::hint_capacity` doesn't reserve more than this for a store in memory.
+const MAX_MEMORY_RESERVE: usize = 256 * 1024 * 1024;
+// The longest record looked for after a corrupt length, see `contains_record`.
+const MAX_RESYNC_LEN: usize = 1024 * 1024;
+
+const DEFAULT_LOG_EXTENSION: &str = "log";
+// The extension of the value logs, after the log extension unless it's the default one.
+const VALUE_LOG_EXTENSION: &str = "vlog";
+// The file recording the `LogFormat` of the logs in a directory.
+const FORMAT_FILE: &str = "FORMAT";
+// The file committing the generations staged by `KvStore::replace_contents_from`.
+const REPLACE_FILE: &str = "REPLACE";
+// The directory `KvStore::replace_contents_from` stages the new generations in.
+const STAGING_DIR: &str = "staging";
 
 /// The `KvStore` stores string key/value pairs.
 ///
@@ -39,7 +77,7 @@ const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
 #[derive(Clone)]
 pub struct KvStore {
     // directory for the log and other data
-    path: Arc<PathBuf>,
+    path: Arc<LogDir>,
     // map generation number to the file reader
     // skipMap 提供高并发的全局无锁访问，减少锁的竞争，也可以按顺序遍历
     // ConcurrentSkipListMap
@@ -50,6 +88,169 @@ pub struct KvStore {
     // 写入必须是串行的，所以要回销 读：走index + reader 无锁 ，写 走writer 互斥锁，串行化
     // 里面的 reader 在 压缩时使用
     writer: Arc<Mutex<KvStoreWriter>>,
+    // taken by the writes of a key before the writer lock
+    key_locks: Arc<KeyLocks>,
+    compactor: Arc<Compactor>,
+    // the latest background compaction thread, joined when the last `KvStore` is dropped
+    background: Arc<Mutex<Option<JoinHandle<()>>>>,
+    // the periodic maintenance thread, stopped when the last `KvStore` is dropped
+    _maintenance: Option<Arc<Maintenance>>,
+    // the lock on the directory, released when the last `KvStore` is dropped, or `None`
+    // in memory
+    _lock: Option<Arc<File>>,
+}
+
+/// Coordinates the compactions of a store.
+#[derive(Default)]
+struct Compactor {
+    // held by whoever compacts or otherwise rewrites log files, so that they never overlap
+    lock: Mutex<()>,
+    // whether a background compaction is scheduled but not started yet
+    scheduled: AtomicBool,
+}
+
+/// Locks serializing the writes to each key, striped over a fixed number of mutexes.
+///
+/// A write takes the lock of its key first, and the writer lock only to append to the
+/// log. So a read-modify-write like `increment` reads the current value under the key
+/// lock alone, while writes to keys of other stripes go on.
+struct KeyLocks {
+    stripes: Vec<Mutex<()>>,
+}
+
+impl KeyLocks {
+    fn new(stripes: usize) -> KeyLocks {
+        KeyLocks {
+            stripes: (0..stripes.max(1)).map(|_| Mutex::new(())).collect(),
+        }
+    }
+
+    fn stripe(&self, key: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() % self.stripes.len() as u64) as usize
+    }
+
+    fn lock(&self, key: &str) -> MutexGuard<'_, ()> {
+        self.stripes[self.stripe(key)].lock().unwrap()
+    }
+
+    // Lock the stripes of all the keys, in stripe order so that two callers never wait
+    // for each other.
+    fn lock_keys<'a, I: IntoIterator<Item = &'a str>>(&self, keys: I) -> Vec<MutexGuard<'_, ()>> {
+        let stripes: BTreeSet<usize> = keys.into_iter().map(|key| self.stripe(key)).collect();
+        stripes
+            .into_iter()
+            .map(|stripe| self.stripes[stripe].lock().unwrap())
+            .collect()
+    }
+
+    fn lock_all(&self) -> Vec<MutexGuard<'_, ()>> {
+        self.stripes
+            .iter()
+            .map(|stripe| stripe.lock().unwrap())
+            .collect()
+    }
+}
+
+/// The generations kept for the live snapshots of a store, see `KvStore::snapshot`.
+///
+/// A pinned generation is not removed when it becomes stale, nor any after it.
+#[derive(Default)]
+struct SnapshotPins {
+    // the first log generation and the first value log generation each snapshot reads
+    // from, by snapshot id
+    gens: Mutex<BTreeMap<u64, (u64, u64)>>,
+    next_id: AtomicU64,
+}
+
+impl SnapshotPins {
+    // Pin the generations from `gen` and the value logs from `vlog_gen`, returning the
+    // id to unpin them with.
+    fn pin(&self, gen: u64, vlog_gen: u64) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.gens.lock().unwrap().insert(id, (gen, vlog_gen));
+        id
+    }
+
+    fn unpin(&self, id: u64) {
+        self.gens.lock().unwrap().remove(&id);
+    }
+
+    // The generations before `safe_point` that may be removed end at the returned one.
+    fn removable_before(&self, safe_point: u64) -> u64 {
+        let gens = self.gens.lock().unwrap();
+        gens.values()
+            .map(|&(gen, _)| gen)
+            .fold(safe_point, u64::min)
+    }
+
+    // Like `removable_before`, for the value logs.
+    fn removable_vlogs_before(&self, safe_point: u64) -> u64 {
+        let gens = self.gens.lock().unwrap();
+        gens.values()
+            .map(|&(_, vlog_gen)| vlog_gen)
+            .fold(safe_point, u64::min)
+    }
+}
+
+/// The thread running the periodic maintenance of a store.
+///
+/// It holds the parts of the store it needs rather than a `KvStore`, so that it doesn't
+/// keep the store alive.
+struct Maintenance {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Maintenance {
+    fn start(
+        interval: Duration,
+        writer: Arc<Mutex<KvStoreWriter>>,
+        reader: KvStoreReader,
+        compactor: Arc<Compactor>,
+    ) -> Maintenance {
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || {
+                let mut next_run = Instant::now() + interval;
+                while !stop.load(Ordering::SeqCst) {
+                    // woken up early on drop, or spuriously
+                    let now = Instant::now();
+                    if now < next_run {
+                        thread::park_timeout(next_run - now);
+                        continue;
+                    }
+                    next_run = now + interval;
+
+                    if let Err(e) = writer.lock().unwrap().sync() {
+                        error!("Maintenance sync failed: {}", e);
+                    }
+                    let _guard = compactor.lock.lock().unwrap();
+                    if let Err(e) = compact(&writer, &reader, 0) {
+                        error!("Maintenance compaction failed: {}", e);
+                    }
+                }
+            })
+        };
+        Maintenance {
+            stop,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for Maintenance {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            handle.thread().unpark();
+            if handle.join().is_err() {
+                error!("Maintenance thread panicked");
+            }
+        }
+    }
 }
 
 // 详细中文注释（补充）：
@@ -70,6 +271,452 @@ pub struct KvStore {
 //    - 理解 `KvStoreWriter` 和 `KvStoreReader` 的职责分离：writer 负责写入与 compaction，reader 负责按需打开/读取文件。
 //    - `Arc`/`Mutex`/`RefCell`/`SkipMap` 是关键的并发原语，分别用于跨线程共享、互斥串行化、内部可变性和并发索引。
 
+/// Options to open a `KvStore` with.
+///
+/// ```rust
+/// # use kvs::{KvStore, KvStoreOptions, Result};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// let options = KvStoreOptions::default()
+///     .with_path(current_dir()?)
+///     .with_compaction_threshold(64 * 1024 * 1024);
+/// let store = KvStore::open_with_options(options)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    path: PathBuf,
+    compaction_threshold: u64,
+    compaction_strategy: CompactionStrategy,
+    sync_policy: SyncPolicy,
+    log_format: LogFormat,
+    compression: Compression,
+    max_key_bytes: Option<usize>,
+    max_value_bytes: Option<usize>,
+    log_extension: String,
+    truncate_corrupt: bool,
+    create_dir: bool,
+    replay_threads: u32,
+    maintenance_interval: Option<Duration>,
+    external_value_threshold: Option<usize>,
+    write_lock_stripes: usize,
+    #[cfg(feature = "mmap")]
+    mmap_reads: bool,
+    // set by `KvStore::open_in_memory`
+    memory: Option<MemoryLogs>,
+}
+
+/// How commands are serialized in the log.
+///
+/// The format is recorded in a `FORMAT` file when a store is first opened, and opening
+/// the store with another format fails with `KvsError::FormatMismatch`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable JSON.
+    Json,
+    /// Compact binary encoding by bincode.
+    Bincode,
+}
+
+impl LogFormat {
+    // The name of the format in the `FORMAT` file.
+    fn name(self) -> &'static str {
+        match self {
+            LogFormat::Json => "json",
+            LogFormat::Bincode => "bincode",
+        }
+    }
+
+    // Check that the logs in `dir` are written in this format. If the format isn't
+    // recorded yet, `record` writes it.
+    fn check(self, dir: &LogDir, record: bool) -> Result<()> {
+        if let Some(logs) = &dir.memory {
+            let mut format = logs.format.lock().unwrap();
+            let recorded = *format;
+            return match recorded {
+                Some(found) if found != self => Err(KvsError::FormatMismatch {
+                    requested: self.name().to_owned(),
+                    found: found.name().to_owned(),
+                }),
+                Some(_) => Ok(()),
+                None => {
+                    if record {
+                        *format = Some(self);
+                    }
+                    Ok(())
+                }
+            };
+        }
+        match fs::read_to_string(dir.format_path()) {
+            Ok(found) if found.trim() == self.name() => Ok(()),
+            Ok(found) => Err(KvsError::FormatMismatch {
+                requested: self.name().to_owned(),
+                found: found.trim().to_owned(),
+            }),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                if record {
+                    fs::write(dir.format_path(), self.name())?;
+                }
+                Ok(())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn serialize(self, cmd: &Command) -> Result<Vec<u8>> {
+        match self {
+            LogFormat::Json => Ok(serde_json::to_vec(cmd)?),
+            LogFormat::Bincode => Ok(bincode::serialize(cmd)?),
+        }
+    }
+
+    fn deserialize(self, payload: &[u8]) -> Result<Command> {
+        match self {
+            LogFormat::Json => Ok(serde_json::from_slice(payload)?),
+            LogFormat::Bincode => Ok(bincode::deserialize(payload)?),
+        }
+    }
+}
+
+/// How values are compressed before they are written to the log.
+///
+/// Each record tells whether its value is compressed, so a store can be reopened with
+/// another setting and still read the values written before.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// Values are stored as they are.
+    None,
+    /// Values are compressed by zstd with the given level.
+    Zstd(i32),
+}
+
+impl Compression {
+    // Returns the bytes to store for `value` and whether they are compressed.
+    fn compress(self, value: Vec<u8>) -> Result<(Vec<u8>, bool)> {
+        match self {
+            Compression::None => Ok((value, false)),
+            Compression::Zstd(level) => {
+                let compressed = zstd::encode_all(&value[..], level)?;
+                // small or random values may grow, keep those as they are
+                if compressed.len() < value.len() {
+                    Ok((compressed, true))
+                } else {
+                    Ok((value, false))
+                }
+            }
+        }
+    }
+}
+
+/// When a `KvStore` syncs the active log to the disk.
+///
+/// A write is always flushed to the OS before `set` or `remove` returns, so it survives
+/// a crash of the process. Only a crash of the whole machine may lose writes that are
+/// not synced yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Leave syncing to the OS.
+    ///
+    /// This is the fastest policy, but an OS crash or a power loss may lose any write
+    /// that the OS hasn't written back yet.
+    Never,
+    /// Sync the log after each write.
+    ///
+    /// A write is durable once it returns, at the cost of one `fdatasync` per write.
+    EveryWrite,
+    /// Sync the log on a write if the last sync is older than the given interval.
+    ///
+    /// At most the writes of one interval can be lost, and also the writes after the
+    /// last one if no more writes come. Use `KvStore::flush` to make them durable.
+    Interval(Duration),
+}
+
+/// How a `KvStore` compacts its logs once the stale data exceeds the compaction
+/// threshold.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompactionStrategy {
+    /// Copy all the live data into a new generation and remove all the older ones.
+    ///
+    /// All the stale data is reclaimed, but every compaction rewrites the whole store.
+    Full,
+    /// Merge only the oldest `max_gens` generations, if at least `dead_ratio` of their
+    /// size is stale data. The newer generations are left untouched.
+    ///
+    /// The log being written is closed on every compaction, so that it can be merged
+    /// later. The merged data goes to a new generation, behind the untouched ones, so
+    /// the next compaction looks at those first. If the oldest generations have too
+    /// little stale data, nothing is merged until more stale data is written.
+    Tiered {
+        /// The maximum number of generations merged at once.
+        max_gens: usize,
+        /// The fraction of stale data, from 0 to 1, above which they are merged.
+        dead_ratio: f64,
+    },
+}
+
+/// The progress of the log replay when a `KvStore` is opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecoveryProgress {
+    /// The generation being replayed.
+    pub current_gen: u64,
+    /// The number of bytes replayed so far, over all the generations.
+    pub bytes_processed: u64,
+    /// The total number of bytes to replay.
+    ///
+    /// Compressed generations count with their uncompressed size.
+    pub total_bytes: u64,
+}
+
+/// The progress of a compaction, see `KvStore::compact_with_progress`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionProgress {
+    /// The number of bytes copied to the compaction file so far.
+    pub bytes_copied: u64,
+    /// The total number of bytes to copy, i.e. the size of the live records.
+    pub total_bytes: u64,
+}
+
+/// Storage statistics of a `KvStore`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stats {
+    /// The number of live keys.
+    pub key_count: usize,
+    /// The total size in bytes of all the log files.
+    pub total_log_bytes: u64,
+    /// The number of bytes of stale commands that a compaction would reclaim.
+    pub uncompacted_bytes: u64,
+    /// The number of log generations on the disk.
+    pub generation_count: usize,
+}
+
+/// Where a value is stored, returned by `KvStore::get_with_meta`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValueMeta {
+    /// The generation of the log file holding the value.
+    pub generation: u64,
+    /// The offset of the record of the value in the log file.
+    pub offset: u64,
+    /// The length in bytes of the record of the value.
+    pub length: u64,
+}
+
+/// The location of a record in the log, reported by `KvStore::verify`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RecordLocation {
+    /// The generation of the log file holding the record.
+    pub generation: u64,
+    /// The offset of the record in the log file.
+    pub offset: u64,
+}
+
+/// The problems found by `KvStore::verify`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// The number of generations scanned.
+    pub generations: usize,
+    /// The number of records that passed their checksum and could be decoded.
+    pub valid_records: u64,
+    /// The records failing their checksum or that can't be decoded, in log order.
+    pub corrupt_records: Vec<RecordLocation>,
+    /// The keys of the index that don't point to a valid record of the key, with the
+    /// location they point to.
+    pub orphaned_keys: Vec<(String, RecordLocation)>,
+    /// The latest records of keys in the logs that the index doesn't point to: data a
+    /// reopen would bring back but the store doesn't serve.
+    pub unreferenced_records: Vec<RecordLocation>,
+    /// The total length of `unreferenced_records`.
+    pub unreferenced_bytes: u64,
+}
+
+impl VerifyReport {
+    /// Returns whether no problem was found.
+    pub fn is_ok(&self) -> bool {
+        self.corrupt_records.is_empty()
+            && self.orphaned_keys.is_empty()
+            && self.unreferenced_records.is_empty()
+    }
+}
+
+/// A group of writes applied together by `KvStore::write_batch`.
+///
+/// The writes are applied in the order they are added.
+#[derive(Debug, Default)]
+pub struct WriteBatch {
+    cmds: Vec<Command>,
+}
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    /// Adds setting the value of a string key to a string.
+    pub fn set(&mut self, key: String, value: String) {
+        self.set_bytes(key, value.into_bytes());
+    }
+
+    /// Adds setting the value of a string key to raw bytes.
+    pub fn set_bytes(&mut self, key: String, value: Vec<u8>) {
+        self.cmds.push(Command::set(key, value, None));
+    }
+
+    /// Adds removing a given key.
+    pub fn remove(&mut self, key: String) {
+        self.cmds.push(Command::remove(key));
+    }
+
+    /// Returns the number of writes in the batch.
+    pub fn len(&self) -> usize {
+        self.cmds.len()
+    }
+
+    /// Returns `true` if the batch has no writes.
+    pub fn is_empty(&self) -> bool {
+        self.cmds.is_empty()
+    }
+}
+
+impl KvStoreOptions {
+    /// Sets the directory of the store. It defaults to the current directory.
+    pub fn with_path(mut self, path: impl Into<PathBuf>) -> KvStoreOptions {
+        self.path = path.into();
+        self
+    }
+
+    /// Sets how many bytes of stale commands trigger a compaction.
+    /// It defaults to 1 MiB.
+    pub fn with_compaction_threshold(mut self, threshold: u64) -> KvStoreOptions {
+        self.compaction_threshold = threshold;
+        self
+    }
+
+    /// Sets how the logs are compacted. It defaults to `CompactionStrategy::Full`.
+    ///
+    /// `KvStore::compact` follows it too.
+    pub fn with_compaction_strategy(mut self, strategy: CompactionStrategy) -> KvStoreOptions {
+        self.compaction_strategy = strategy;
+        self
+    }
+
+    /// Sets the extension of the log files, without the dot. It defaults to `log`.
+    ///
+    /// A store only picks up the log files with its extension, so stores with different
+    /// extensions can share a directory.
+    pub fn with_log_extension(mut self, extension: impl Into<String>) -> KvStoreOptions {
+        self.log_extension = extension.into();
+        self
+    }
+
+    /// Sets how commands are serialized in the log. It defaults to `LogFormat::Json`.
+    pub fn with_log_format(mut self, format: LogFormat) -> KvStoreOptions {
+        self.log_format = format;
+        self
+    }
+
+    /// Sets the maximum size in bytes of a key. There is no limit by default.
+    ///
+    /// Writing a larger key fails with `KvsError::KeyTooLarge`.
+    pub fn with_max_key_bytes(mut self, max: usize) -> KvStoreOptions {
+        self.max_key_bytes = Some(max);
+        self
+    }
+
+    /// Sets the maximum size in bytes of a value, before compression. There is no limit
+    /// by default.
+    ///
+    /// Writing a larger value fails with `KvsError::ValueTooLarge`. Values already in the
+    /// log are still read whatever their size.
+    pub fn with_max_value_bytes(mut self, max: usize) -> KvStoreOptions {
+        self.max_value_bytes = Some(max);
+        self
+    }
+
+    /// Sets how values are compressed in the log. It defaults to `Compression::None`.
+    ///
+    /// It only applies to the values written afterwards. Compaction copies the stored
+    /// bytes as they are, so values are never compressed again.
+    pub fn with_compression(mut self, compression: Compression) -> KvStoreOptions {
+        self.compression = compression;
+        self
+    }
+
+    /// Stores the values larger than `threshold` bytes, after compression, in separate
+    /// value logs named `<gen>.vlog`, and only a pointer to them in the log. By default
+    /// all the values are stored in the log.
+    ///
+    /// Compaction then copies the pointers instead of the large values. The space taken
+    /// by overwritten external values is reclaimed by `KvStore::gc_value_logs` instead.
+    pub fn with_external_value_threshold(mut self, threshold: usize) -> KvStoreOptions {
+        self.external_value_threshold = Some(threshold);
+        self
+    }
+
+    /// Sets when the log is synced to the disk. It defaults to `SyncPolicy::Never`.
+    pub fn with_sync_policy(mut self, policy: SyncPolicy) -> KvStoreOptions {
+        self.sync_policy = policy;
+        self
+    }
+
+    /// Runs maintenance on a background thread at the given interval: the log is synced,
+    /// and compacted if there is any stale data, whatever the compaction threshold.
+    ///
+    /// It defaults to no periodic maintenance.
+    pub fn with_maintenance_interval(mut self, interval: Duration) -> KvStoreOptions {
+        self.maintenance_interval = Some(interval);
+        self
+    }
+
+    /// Sets how many locks the writes to different keys are spread over. It defaults to
+    /// 16, and is at least 1.
+    ///
+    /// Appends to the log are serialized whatever the number. More locks mostly let
+    /// read-modify-writes like `compare_and_swap` and `increment` on different keys read
+    /// their current values at the same time.
+    pub fn with_write_lock_stripes(mut self, stripes: usize) -> KvStoreOptions {
+        self.write_lock_stripes = stripes.max(1);
+        self
+    }
+
+    /// Sets whether values are read through memory-mapped log files instead of buffered
+    /// file reads. It defaults to `false`.
+    ///
+    /// A lookup is then a copy out of the mapped memory, without a `seek` and `read`
+    /// system call, which pays off for read-heavy workloads. The log files must not be
+    /// truncated by another process while they are mapped.
+    #[cfg(feature = "mmap")]
+    pub fn with_mmap_reads(mut self, enabled: bool) -> KvStoreOptions {
+        self.mmap_reads = enabled;
+        self
+    }
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> KvStoreOptions {
+        KvStoreOptions {
+            path: PathBuf::from("."),
+            compaction_threshold: COMPACTION_THRESHOLD,
+            compaction_strategy: CompactionStrategy::Full,
+            sync_policy: SyncPolicy::Never,
+            log_format: LogFormat::Json,
+            compression: Compression::None,
+            max_key_bytes: None,
+            max_value_bytes: None,
+            log_extension: DEFAULT_LOG_EXTENSION.to_owned(),
+            truncate_corrupt: false,
+            create_dir: true,
+            replay_threads: 1,
+            maintenance_interval: None,
+            external_value_threshold: None,
+            write_lock_stripes: WRITE_LOCK_STRIPES,
+            #[cfg(feature = "mmap")]
+            mmap_reads: false,
+            memory: None,
+        }
+    }
+}
+
 impl KvStore {
     /// Opens a `KvStore` with the given path.
     ///
@@ -81,10 +728,169 @@ impl KvStore {
     /// 组装 文件、内存索引 、读写器
     /// 组装过程中，构建好线程安全和并发隔离的基础设施
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
-        let path = Arc::new(path.into());
+        KvStore::open_with_options(KvStoreOptions::default().with_path(path))
+    }
+
+    /// Opens a `KvStore` on logs kept in memory instead of files.
+    ///
+    /// The logs are written and replayed like files, so the store behaves like one on
+    /// the disk, and opening it again on the same `logs` brings its content back. Value
+    /// logs aren't supported in memory, and neither are the operations working on the
+    /// files directly, such as `compress_cold_generations`, which return
+    /// `KvsError::Unsupported`.
+    pub fn open_in_memory(logs: &MemoryLogs) -> Result<KvStore> {
+        KvStore::open_with_options(KvStoreOptions {
+            memory: Some(logs.clone()),
+            ..KvStoreOptions::default()
+        })
+    }
+
+    /// Opens a `KvStore` with the given path like `open`, without creating the directory.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Io` with `io::ErrorKind::NotFound` if the directory doesn't
+    /// exist.
+    pub fn open_existing(path: impl Into<PathBuf>) -> Result<KvStore> {
+        let mut options = KvStoreOptions::default().with_path(path);
+        options.create_dir = false;
+        KvStore::open_with_options(options)
+    }
+
+    /// Opens a `KvStore` with the given path, truncating corrupt logs.
+    ///
+    /// Replay of a log stops at its first corrupt record and the log is truncated there,
+    /// whether or not the record is at the end of the log. Records in later logs are
+    /// still replayed. `open` only does so for an incomplete record at the end of a log.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::CorruptLog` if a compressed log is corrupt, as it can't be
+    /// truncated in place.
+    pub fn open_truncate_corrupt(path: impl Into<PathBuf>) -> Result<KvStore> {
+        let mut options = KvStoreOptions::default().with_path(path);
+        options.truncate_corrupt = true;
+        KvStore::open_with_options(options)
+    }
+
+    /// Opens a `KvStore` with the given path like `open`, parsing the log files on
+    /// `threads` threads.
+    ///
+    /// The parsed generations are applied to the index in order, so the result is the
+    /// same as with `open`. It pays off when there are many large generations.
+    pub fn open_parallel(path: impl Into<PathBuf>, threads: u32) -> Result<KvStore> {
+        let mut options = KvStoreOptions::default().with_path(path);
+        options.replay_threads = threads;
+        KvStore::open_with_options(options)
+    }
+
+    /// Opens the store at the given path for reading only.
+    ///
+    /// The index is built from the existing logs, but unlike `open` no new log file is
+    /// created and nothing in the directory is modified. The directory is not locked, so
+    /// it can be opened while a `KvStore` writes to it.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during the log replay, including when the directory
+    /// doesn't exist, and returns `KvsError::CorruptLog` if a log record is corrupt.
+    /// An incomplete record at the end of a log, e.g. one being written, is skipped.
+    ///
+    /// It returns `KvsError::FormatMismatch` if the logs aren't in the default format.
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<ReadOnlyKvStore> {
+        let path = Arc::new(LogDir {
+            path: path.into(),
+            extension: DEFAULT_LOG_EXTENSION.to_owned(),
+            memory: None,
+        });
+        let format = KvStoreOptions::default().log_format;
+        format.check(&path, false)?;
+        let index = Arc::new(SkipMap::new());
+        let inflated = Arc::new(InflatedLogs::default());
+        let mut readers = BTreeMap::new();
+        for gen in sorted_gen_list(&path)? {
+            let mut reader = BufReaderWithPos::new(inflated.open(&path, gen)?)?;
+            load(gen, &mut reader, &index, format)?;
+            readers.insert(gen, reader);
+        }
+
+        let reader = KvStoreReader {
+            path,
+            safe_point: Arc::new(AtomicU64::new(0)),
+            cleaned_safe_point: Cell::new(0),
+            vlog_safe_point: Arc::new(AtomicU64::new(0)),
+            swap_epoch: Arc::new(AtomicU64::new(0)),
+            format,
+            readers: RefCell::new(readers),
+            vlogs: RefCell::new(BTreeMap::new()),
+            cache: Arc::new(ValueCache::default()),
+            inflated,
+            #[cfg(feature = "testing")]
+            reads: Arc::new(AtomicU64::new(0)),
+            #[cfg(feature = "mmap")]
+            mmap: None,
+        };
+        Ok(ReadOnlyKvStore { index, reader })
+    }
+
+    /// Opens a `KvStore` with the given options.
+    ///
+    /// This will create a new directory if the given one does not exist. The directory
+    /// is locked until the store is dropped.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Locked` if another store has the directory open.
+    ///
+    /// It propagates I/O errors during the log replay, and returns `KvsError::CorruptLog`
+    /// if a log record is corrupt.
+    ///
+    /// It returns `KvsError::FormatMismatch` if the store was written with another
+    /// `LogFormat`.
+    pub fn open_with_options(options: KvStoreOptions) -> Result<KvStore> {
+        KvStore::open_with(options, |_| {})
+    }
+
+    /// Opens a `KvStore` with the given path like `open`, reporting the progress of the
+    /// log replay to `progress`.
+    ///
+    /// `progress` is called after each generation and every 64 KiB within a generation.
+    /// The last call has `bytes_processed` equal to `total_bytes`.
+    pub fn open_with_progress(
+        path: impl Into<PathBuf>,
+        progress: impl FnMut(RecoveryProgress),
+    ) -> Result<KvStore> {
+        KvStore::open_with(KvStoreOptions::default().with_path(path), progress)
+    }
+
+    fn open_with(
+        options: KvStoreOptions,
+        mut progress: impl FnMut(RecoveryProgress),
+    ) -> Result<KvStore> {
+        let path = Arc::new(LogDir {
+            path: options.path,
+            extension: options.log_extension,
+            memory: options.memory,
+        });
         // let buf: PathBuf = *path;
         // fs::create_dir_all(path.as_ref())?;
-        fs::create_dir_all(&*path)?;
+        let lock = if path.memory.is_some() {
+            None
+        } else {
+            if options.create_dir {
+                fs::create_dir_all(&path.path)?;
+            } else if !path.path.is_dir() {
+                return Err(KvsError::Io(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{} is not a directory", path.path.display()),
+                )));
+            }
+            Some(lock_file(&path.lock_path())?)
+        };
+        if path.memory.is_none() {
+            recover_replace(&path)?;
+        }
+        options.log_format.check(&path, true)?;
 
         let mut readers = BTreeMap::new();
 
@@ -94,15 +900,85 @@ impl KvStore {
         let gen_list = sorted_gen_list(&path)?;
         let mut uncompacted = 0;
 
+        // open all the generations first to know the total size
+        let inflated = Arc::new(InflatedLogs::default());
+        let mut logs = Vec::with_capacity(gen_list.len());
+        let mut total_bytes = 0;
         for &gen in &gen_list {
-            let mut reader = BufReaderWithPos::new(File::open(log_path(&path, gen))?)?;
-            uncompacted += load(gen, &mut reader, &*index)?;
+            let mut log = inflated.open(&path, gen)?;
+            let len = log.seek(SeekFrom::End(0))?;
+            total_bytes += len;
+            logs.push((gen, BufReaderWithPos::new(log)?, len, None));
+        }
+
+        // With several threads, the logs are parsed up front and only applied in order
+        // below. Otherwise each log is replayed right into the index.
+        if options.replay_threads > 1 && logs.len() > 1 {
+            let lens: Vec<_> = logs.iter().map(|&(gen, _, len, _)| (gen, len)).collect();
+            let readers = logs.into_iter().map(|(gen, reader, ..)| (gen, reader));
+            logs = parse_logs_in_parallel(
+                readers.collect(),
+                options.log_format,
+                options.replay_threads,
+            )?
+            .into_iter()
+            .zip(lens)
+            .map(|((reader, parsed), (gen, len))| (gen, reader, len, Some(parsed)))
+            .collect();
+        }
+
+        let mut bytes_processed = 0;
+        for (gen, mut reader, len, parsed) in logs {
+            let (gen_uncompacted, corrupt_offset) = match parsed {
+                Some(parsed) => {
+                    let uncompacted = parsed
+                        .entries
+                        .into_iter()
+                        .map(|entry| apply_entry(&index, entry))
+                        .sum();
+                    (uncompacted, parsed.corrupt_offset)
+                }
+                None => replay(gen, &mut reader, &index, options.log_format, &mut |pos| {
+                    progress(RecoveryProgress {
+                        current_gen: gen,
+                        bytes_processed: bytes_processed + pos,
+                        total_bytes,
+                    })
+                })?,
+            };
+            // the bytes after a corrupt record count as processed too
+            bytes_processed += len;
+            progress(RecoveryProgress {
+                current_gen: gen,
+                bytes_processed,
+                total_bytes,
+            });
+            uncompacted += gen_uncompacted;
+            if let Some(offset) = corrupt_offset {
+                // a compressed log is written whole, so it can't be torn
+                if path.memory.is_none() && !log_path(&path, gen).is_file() {
+                    return Err(KvsError::CorruptLog { gen, offset });
+                }
+                if is_torn_tail(&mut reader, offset)? {
+                    warn!(
+                        "Truncating incomplete record at the end of log {} at offset {}",
+                        gen, offset
+                    );
+                } else if options.truncate_corrupt {
+                    warn!("Truncating corrupt log {} at offset {}", gen, offset);
+                } else {
+                    return Err(KvsError::CorruptLog { gen, offset });
+                }
+                truncate_log(&path, gen, offset)?;
+            }
 
             // 历史文件的读取器都缓存 起来
             readers.insert(gen, reader);
         }
 
         let current_gen = gen_list.last().unwrap_or(&0) + 1;
+        // like the logs, the value log written to is a new one
+        let vlog_gen = sorted_vlog_gen_list(&path)?.last().unwrap_or(&0) + 1;
 
         // 旧文件只读，不能写入，所以每次重启都生成新的
         let writer = new_log_file(&path, current_gen)?;
@@ -111,397 +987,3505 @@ impl KvStore {
         let reader = KvStoreReader {
             path: Arc::clone(&path),
             safe_point,
+            cleaned_safe_point: Cell::new(0),
+            vlog_safe_point: Arc::new(AtomicU64::new(0)),
+            swap_epoch: Arc::new(AtomicU64::new(0)),
+            format: options.log_format,
             readers: RefCell::new(readers),
+            vlogs: RefCell::new(BTreeMap::new()),
+            cache: Arc::new(ValueCache::default()),
+            inflated: Arc::clone(&inflated),
+            #[cfg(feature = "testing")]
+            reads: Arc::new(AtomicU64::new(0)),
+            #[cfg(feature = "mmap")]
+            mmap: if options.mmap_reads && path.memory.is_none() {
+                Some(MmapReader::new(Arc::clone(&path), inflated))
+            } else {
+                None
+            },
         };
 
         let writer = KvStoreWriter {
-            reader: reader.clone(),// writer 中也装了一个 reader ，因为在压缩时，要使用reader读取旧数据
-            writer,// 当前需要写的
+            reader: reader.clone(), // writer 中也装了一个 reader ，因为在压缩时，要使用reader读取旧数据
+            writer,                 // 当前需要写的
             current_gen,
             uncompacted,
+            unreclaimable: 0,
+            compaction_threshold: options.compaction_threshold,
+            compaction_strategy: options.compaction_strategy,
+            sync_policy: options.sync_policy,
+            compression: options.compression,
+            max_key_bytes: options.max_key_bytes,
+            max_value_bytes: options.max_value_bytes,
+            external_value_threshold: options.external_value_threshold,
+            vlog: None,
+            vlog_gen,
+            last_sync: Instant::now(),
             path: Arc::clone(&path),
             index: Arc::clone(&index),
+            pins: Arc::new(SnapshotPins::default()),
         };
 
+        let writer = Arc::new(Mutex::new(writer));
+        let compactor = Arc::new(Compactor::default());
+        let maintenance = options.maintenance_interval.map(|interval| {
+            Arc::new(Maintenance::start(
+                interval,
+                Arc::clone(&writer),
+                reader.clone(),
+                Arc::clone(&compactor),
+            ))
+        });
+
         Ok(KvStore {
             path,
             reader,
             index,
-            writer: Arc::new(Mutex::new(writer)),
+            writer,
+            key_locks: Arc::new(KeyLocks::new(options.write_lock_stripes)),
+            compactor,
+            background: Arc::new(Mutex::new(None)),
+            _maintenance: maintenance,
+            _lock: lock.map(Arc::new),
         })
     }
-}
 
-impl KvsEngine for KvStore {
-    /// Sets the value of a string key to a string.
+    /// Compacts the log now, instead of waiting for the stale data to exceed the
+    /// compaction threshold.
     ///
-    /// If the key already exists, the previous value will be overwritten.
+    /// Readers and writers keep working while it runs. It does nothing if there is no
+    /// stale data, or with `CompactionStrategy::Tiered` if there is none since the last
+    /// compaction.
+    pub fn compact(&self) -> Result<()> {
+        let _guard = self.compactor.lock.lock().unwrap();
+        compact(&self.writer, &self.reader, 0)
+    }
+
+    /// Compacts the log now like `compact`, reporting the bytes copied to `progress` and
+    /// stopping early once `cancel` is set.
+    ///
+    /// `progress` is called every 64 KiB copied and once all are. `cancel` is checked
+    /// before each record is copied.
     ///
     /// # Errors
     ///
-    /// It propagates I/O or serialization errors during writing the log.
-    fn set(&self, key: String, value: String) -> Result<()> {
-        // 在这一层加锁了，所以下面的 set 不用考虑锁
-        self.writer.lock().unwrap().set(key, value)
+    /// It returns `KvsError::Cancelled` if `cancel` is set before the copy is done. The
+    /// partial compaction file is then removed, and the index and the old generations
+    /// are left as they were.
+    pub fn compact_with_progress(
+        &self,
+        mut progress: impl FnMut(CompactionProgress),
+        cancel: Arc<AtomicBool>,
+    ) -> Result<()> {
+        let _guard = self.compactor.lock.lock().unwrap();
+        compact_with(&self.writer, &self.reader, 0, &mut progress, &cancel)
     }
 
-    /// Gets the string value of a given string key.
+    /// Garbage-collects the value logs of the values stored out of the log.
     ///
-    /// Returns `None` if the given key does not exist.
-    fn get(&self, key: String) -> Result<Option<String>> {
-        // 查索引  skipMap 索引不存在，直接返回
-        if let Some(cmd_pos) = self.index.get(&key) {
-
-            // 索引中有，拿到位置信息，(id offset length) 去 disk 读， read_command 将 disk 二进制 变成 command
-            if let Command::Set { value, .. } = self.reader.read_command(*cmd_pos.value())? {
-                Ok(Some(value))
-            } else {
-                Err(KvsError::UnexpectedCommandType)
-            }
-        } else {
-            Ok(None)
-        }
+    /// The live external values are copied to a new value log, with new pointers to them
+    /// appended to the log, and all the older value logs are removed. Unlike `compact`,
+    /// it holds the writer lock while it runs, so writes wait for it.
+    ///
+    /// Returns the number of bytes of value logs freed.
+    pub fn gc_value_logs(&self) -> Result<u64> {
+        let _guard = self.compactor.lock.lock().unwrap();
+        self.writer.lock().unwrap().gc_value_logs()
     }
 
-    /// Removes a given key.
+    /// Returns the generation of the log being written.
     ///
-    /// # Error
+    /// Only available with the `testing` feature.
+    #[cfg(feature = "testing")]
+    pub fn current_generation(&self) -> u64 {
+        self.writer.lock().unwrap().current_gen
+    }
+
+    /// Compacts the log now like `compact`, even if there is no stale data.
     ///
-    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    /// A compaction writes the live data to a new generation and the writes after it
+    /// to the one after, so the current generation advances by 2. Only available with
+    /// the `testing` feature.
+    #[cfg(feature = "testing")]
+    pub fn force_compact(&self) -> Result<()> {
+        let _guard = self.compactor.lock.lock().unwrap();
+        let compaction = self.writer.lock().unwrap().begin_compaction()?;
+        run_compaction(
+            &self.writer,
+            &self.reader,
+            compaction,
+            &mut |_| {},
+            &AtomicBool::new(false),
+        )
+    }
+
+    /// Returns the number of log files the store's own reader has open.
     ///
-    /// It propagates I/O or serialization errors during writing the log.
-    fn remove(&self, key: String) -> Result<()> {
-        self.writer.lock().unwrap().remove(key)
+    /// Only available with the `testing` feature.
+    #[cfg(feature = "testing")]
+    pub fn open_log_handles(&self) -> usize {
+        self.reader.readers.borrow().len()
     }
-}
+
+    /// Returns the number of reads from the log files and value logs so far, by all the
+    /// readers of the store, including compactions.
+    ///
+    /// Only available with the `testing` feature.
+    #[cfg(feature = "testing")]
+    pub fn file_reads(&self) -> u64 {
+        self.reader.reads.load(Ordering::SeqCst)
+    }
+
+    // Read the live value of a key with its location, following the value if a
+    // compaction moves it meanwhile. The caller holds the lock of the key, so no write
+    // changes it.
+    fn read_locked(&self, key: &str) -> Result<Option<(Vec<u8>, CommandPos)>> {
+        let now = now_millis();
+        loop {
+            let vlog_safe_point = self.reader.vlog_safe_point.load(Ordering::SeqCst);
+            let entry = match self.index.get(key) {
+                Some(entry) => Some(entry),
+                // A compaction or a value log GC moving the value replaces its entry by
+                // removing it first. They do it under the writer lock, so look it up
+                // again once none is running.
+                None => {
+                    let _writer = self.writer.lock().unwrap();
+                    self.index.get(key)
+                }
+            };
+            let cmd_pos = match entry {
+                Some(entry) if !entry.value().is_expired(now) => *entry.value(),
+                _ => return Ok(None),
+            };
+            match self.reader.read_value(cmd_pos) {
+                Ok(value) => return Ok(Some((value, cmd_pos))),
+                Err(_) if self.reader.moved_since(cmd_pos, vlog_safe_point) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // `compare_and_swap` with the lock of the key already held.
+    fn compare_and_swap_locked(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: String,
+    ) -> Result<bool> {
+        let current = self.read_locked(&key)?.map(|(value, _)| value);
+        if current != expected.map(String::into_bytes) {
+            return Ok(false);
+        }
+        self.write(|writer| writer.set(key, new.into_bytes(), None))?;
+        Ok(true)
+    }
+
+    // Run a read again until no `replace_contents_from` swapped the index meanwhile, so
+    // that it sees either the old or the new contents of the store and never a mix.
+    fn consistent<T>(&self, read: impl Fn() -> Result<T>) -> Result<T> {
+        loop {
+            let epoch = self.reader.swap_epoch.load(Ordering::SeqCst);
+            if epoch % 2 == 1 {
+                thread::yield_now();
+                continue;
+            }
+            let res = read();
+            if self.reader.swap_epoch.load(Ordering::SeqCst) == epoch {
+                return res;
+            }
+        }
+    }
+
+    // Run a write on the writer, and schedule a background compaction if the stale data
+    // exceeds the threshold afterwards.
+    fn write<T>(&self, f: impl FnOnce(&mut KvStoreWriter) -> Result<T>) -> Result<T> {
+        let (res, needs_compaction) = {
+            let mut writer = self.writer.lock().unwrap();
+            let res = f(&mut writer);
+            let threshold = writer.compaction_threshold;
+            (res, writer.needs_compaction(threshold))
+        };
+        if needs_compaction {
+            self.schedule_compaction();
+        }
+        res
+    }
+
+    // Compact on a background thread, so that the write triggering it returns immediately.
+    fn schedule_compaction(&self) {
+        if self.compactor.scheduled.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let writer = Arc::clone(&self.writer);
+        let reader = self.reader.clone();
+        let compactor = Arc::clone(&self.compactor);
+        let handle = thread::spawn(move || {
+            let _guard = compactor.lock.lock().unwrap();
+            // writes from now on may schedule the next compaction, which starts after this one
+            compactor.scheduled.store(false, Ordering::SeqCst);
+            let threshold = writer.lock().unwrap().compaction_threshold;
+            if let Err(e) = compact(&writer, &reader, threshold) {
+                error!("Background compaction failed: {}", e);
+            }
+        });
+        // the previous thread has taken the compaction lock already, so it finishes first
+        *self.background.lock().unwrap() = Some(handle);
+    }
+
+    /// Returns the storage statistics of the store.
+    ///
+    /// The writer lock is only held to read the stale data size, so the numbers may be
+    /// slightly inconsistent with each other under concurrent writes. Expired keys and
+    /// the probe of a running `self_check` aren't counted.
+    pub fn stats(&self) -> Result<Stats> {
+        let uncompacted_bytes = self.writer.lock().unwrap().uncompacted;
+        let log_sizes = self.log_sizes()?;
+        Ok(Stats {
+            key_count: self.live_len(),
+            total_log_bytes: log_sizes.values().sum(),
+            uncompacted_bytes,
+            generation_count: log_sizes.len(),
+        })
+    }
+
+    // The number of keys that haven't expired, except the probes of running `self_check`s.
+    fn live_len(&self) -> usize {
+        count_live(&self.index, now_millis()).saturating_sub(self.self_check_probes())
+    }
+
+    // The number of keys written by `self_check`s that are still running.
+    fn self_check_probes(&self) -> usize {
+        let start = Bound::Included(SELF_CHECK_PREFIX.to_owned());
+        self.index
+            .range((start, prefix_end(SELF_CHECK_PREFIX)))
+            .count()
+    }
+
+    /// Returns the size in bytes of the log file of each generation.
+    ///
+    /// The size of a compressed generation is the size of its compressed file.
+    pub fn log_sizes(&self) -> Result<BTreeMap<u64, u64>> {
+        let mut sizes = BTreeMap::new();
+        for gen in sorted_gen_list(&self.path)? {
+            match log_size(&self.path, gen) {
+                Ok(size) => {
+                    sizes.insert(gen, size);
+                }
+                // removed by a concurrent compaction
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(sizes)
+    }
+
+    /// Returns the fraction of the log size taken by stale commands, from 0 to 1.
+    ///
+    /// The stale commands are counted by their uncompressed size, so the ratio is only
+    /// an estimate when some generations are compressed.
+    pub fn dead_byte_ratio(&self) -> Result<f64> {
+        let uncompacted = self.writer.lock().unwrap().uncompacted;
+        let total: u64 = self.log_sizes()?.values().sum();
+        if total == 0 {
+            return Ok(0.0);
+        }
+        Ok((uncompacted as f64 / total as f64).min(1.0))
+    }
+
+    /// Scans all the generations for corruption, like a file system check.
+    ///
+    /// Every record is checked against its checksum and decoded, every key of the index
+    /// must point to a valid record of the key, and the latest record of each key in the
+    /// logs must be the one the index points to. A corrupt record doesn't stop the scan
+    /// of its generation if its header tells where the next one starts. Value logs are
+    /// not checked.
+    ///
+    /// Writes and compactions wait until the scan is done.
+    pub fn verify(&self) -> Result<VerifyReport> {
+        let _guard = self.compactor.lock.lock().unwrap();
+        let _writer = self.writer.lock().unwrap();
+        let safe_point = self.reader.safe_point.load(Ordering::SeqCst);
+        let replayed = SkipMap::new();
+        let mut records = HashMap::new();
+        let mut report = VerifyReport::default();
+        for gen in sorted_gen_list(&self.path)? {
+            if gen < safe_point {
+                continue;
+            }
+            let mut reader = BufReaderWithPos::new(LogFile::open(&self.path, gen)?)?;
+            verify_log(
+                gen,
+                &mut reader,
+                self.reader.format,
+                &replayed,
+                &mut records,
+                &mut report,
+            )?;
+            report.generations += 1;
+        }
+
+        for entry in self.index.iter() {
+            let cmd_pos = *entry.value();
+            match records.get(&(cmd_pos.gen, cmd_pos.pos)) {
+                Some((len, key)) if *len == cmd_pos.len && key == entry.key() => {}
+                _ => report.orphaned_keys.push((
+                    entry.key().clone(),
+                    RecordLocation {
+                        generation: cmd_pos.gen,
+                        offset: cmd_pos.pos,
+                    },
+                )),
+            }
+        }
+        let now = now_millis();
+        for entry in replayed.iter() {
+            let cmd_pos = *entry.value();
+            let referenced = self.index.get(entry.key()).is_some_and(|indexed| {
+                let indexed = indexed.value();
+                indexed.gen == cmd_pos.gen && indexed.pos == cmd_pos.pos
+            });
+            if !referenced && !cmd_pos.is_expired(now) {
+                report.unreferenced_records.push(RecordLocation {
+                    generation: cmd_pos.gen,
+                    offset: cmd_pos.pos,
+                });
+                report.unreferenced_bytes += cmd_pos.len;
+            }
+        }
+        Ok(report)
+    }
+
+    /// Flushes the active log and syncs it to the disk.
+    ///
+    /// All the writes before it are durable once it returns, whatever the `SyncPolicy` is.
+    pub fn flush(&self) -> Result<()> {
+        self.writer.lock().unwrap().sync()
+    }
+
+    /// Enables the value cache with a budget of `max_bytes`, and fills it with the values
+    /// of the store in key order until the budget is used up.
+    ///
+    /// A `get` looks in the cache before reading the log, and caches the values it reads,
+    /// evicting the least recently used ones. Writes and compactions keep the cache up
+    /// to date. The cache is shared by all the clones of the store. A budget of 0
+    /// disables it.
+    ///
+    /// A value counts towards the budget with the size of its key. Returns the number of
+    /// values cached.
+    pub fn warm_cache(&self, max_bytes: usize) -> Result<usize> {
+        let cache = &self.reader.cache;
+        cache.resize(max_bytes);
+        let now = now_millis();
+        let mut cached = 0;
+        let mut bytes = 0;
+        // loaded before the iterator reads any entry, see `moved_since`
+        let vlog_safe_point = self.reader.vlog_safe_point.load(Ordering::SeqCst);
+        for entry in self.index.iter() {
+            let cmd_pos = *entry.value();
+            if cmd_pos.is_expired(now) {
+                continue;
+            }
+            let value = match self.reader.read_value(cmd_pos) {
+                Ok(value) => value,
+                // moved by a concurrent compaction or value log GC, the stale log may be
+                // gone already
+                Err(_) if self.reader.moved_since(cmd_pos, vlog_safe_point) => continue,
+                Err(e) => return Err(e),
+            };
+            bytes += entry.key().len() + value.len();
+            if bytes > max_bytes {
+                break;
+            }
+            cache.insert(entry.key().clone(), cmd_pos, value);
+            cached += 1;
+        }
+        Ok(cached)
+    }
+
+    /// Compresses cold generations in place.
+    ///
+    /// Every generation that is at least `min_age` generations older than the active one
+    /// is rewritten into a gzip-compressed `<gen>.log.gz` file and the plain log is removed.
+    /// The active generation is never compressed, so writes stay uncompressed.
+    ///
+    /// Compressed generations remain readable: a reader decompresses a generation into
+    /// memory the first time it needs it.
+    ///
+    /// Returns the number of generations compressed.
+    pub fn compress_cold_generations(&self, min_age: u64) -> Result<usize> {
+        if self.path.memory.is_some() {
+            return Err(KvsError::Unsupported);
+        }
+        // hold the compaction lock so that a compaction cannot delete the files under us
+        let _guard = self.compactor.lock.lock().unwrap();
+        let writer = self.writer.lock().unwrap();
+        let mut compressed = 0;
+        for gen in sorted_gen_list(&self.path)? {
+            if gen + min_age.max(1) > writer.current_gen {
+                break;
+            }
+            let plain_path = log_path(&self.path, gen);
+            if !plain_path.is_file() {
+                continue;
+            }
+            let gz_path = compressed_log_path(&self.path, gen);
+            let tmp_path = gz_path.with_extension("gz.tmp");
+            let mut encoder =
+                GzEncoder::new(File::create(&tmp_path)?, flate2::Compression::default());
+            io::copy(&mut File::open(&plain_path)?, &mut encoder)?;
+            encoder.finish()?.sync_all()?;
+            fs::rename(&tmp_path, &gz_path)?;
+            // Readers that still hold the plain file open keep reading it. New readers
+            // fall back to the compressed file once the plain one is gone.
+            fs::remove_file(&plain_path)?;
+            compressed += 1;
+        }
+        Ok(compressed)
+    }
+
+    /// Replaces the whole content of the store with the data of the store in `other_dir`.
+    ///
+    /// The log files of `other_dir` are copied into this store and validated by replaying
+    /// them before anything is swapped, so an invalid `other_dir` leaves the store as it
+    /// was. Afterwards the old generations are treated like stale generations after a
+    /// compaction: readers close their handles to them and the files are deleted.
+    ///
+    /// The new generations are staged in a separate directory, and a marker file commits
+    /// them before any old generation is touched. If the process crashes before the
+    /// marker is written the store reopens with its old contents, and afterwards with
+    /// the new ones: the next open finishes the swap.
+    ///
+    /// Gets, scans and `len` running during the swap wait for it, so they see either the
+    /// old or the new contents. Only an iterator from `iter` may see a mix of both.
+    pub fn replace_contents_from(&self, other_dir: &Path) -> Result<()> {
+        let _keys = self.key_locks.lock_all();
+        let _guard = self.compactor.lock.lock().unwrap();
+        self.writer.lock().unwrap().replace_contents_from(other_dir)
+    }
+
+    /// Removes all the keys of the store.
+    ///
+    /// Writes switch to a new, empty generation and all the older generations are
+    /// deleted, like the stale generations after a compaction. Readers close their
+    /// handles to them the next time they are used.
+    ///
+    /// A removal of every purged key is written to the new generation and synced before
+    /// any log is deleted, so that the keys don't come back when the store is reopened
+    /// from an old log that is left: pinned by a snapshot, still open on Windows, where
+    /// it can't be deleted, or not deleted yet when the process crashed.
+    pub fn purge(&self) -> Result<()> {
+        let _keys = self.key_locks.lock_all();
+        let _guard = self.compactor.lock.lock().unwrap();
+        self.writer.lock().unwrap().purge()
+    }
+
+    /// Sets the value of a string key to raw bytes.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    pub fn set_bytes(&self, key: String, value: Vec<u8>) -> Result<()> {
+        let _key = self.key_locks.lock(&key);
+        self.write(|writer| writer.set(key, value, None))
+    }
+
+    /// Sets the value of a string key to a string, expiring after `ttl`.
+    ///
+    /// An expired key is treated as absent and is dropped by the next compaction.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    pub fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let expires_at = now_millis().saturating_add(ttl.as_millis() as u64);
+        let _key = self.key_locks.lock(&key);
+        self.write(|writer| writer.set(key, value.into_bytes(), Some(expires_at)))
+    }
+
+    /// Hints that about `keys` keys with values of about `avg_value_bytes` bytes each are
+    /// going to be written, e.g. before a bulk import.
+    ///
+    /// It's a best-effort hint that changes nothing but performance, and never fails
+    /// because of the sizes. The write buffer is grown to hold a whole record, up to
+    /// 1 MiB, so that each write takes a single system call. A store in memory also
+    /// reserves room for the records in its active log, up to 256 MiB, if the memory is
+    /// available. The index is a skip list and the stale data is tracked as a byte
+    /// count, so neither has anything to pre-size.
+    pub fn hint_capacity(&self, keys: usize, avg_value_bytes: usize) -> Result<()> {
+        // room for the key and the serialization, where JSON has the value in base64
+        let record_bytes = RECORD_HEADER_LEN
+            .saturating_add(avg_value_bytes.div_ceil(3).saturating_mul(4))
+            .saturating_add(128);
+        let mut writer = self.writer.lock().unwrap();
+        writer.writer.reserve(record_bytes.min(MAX_WRITE_BUFFER))?;
+        if let LogWriter::Memory(file) = writer.writer.writer.get_ref() {
+            file.reserve(keys.saturating_mul(record_bytes).min(MAX_MEMORY_RESERVE));
+        }
+        Ok(())
+    }
+
+    /// Applies all the writes in `batch` with a single log flush.
+    ///
+    /// The index is only updated after the whole batch is written, and no other write
+    /// can happen in between. Concurrent readers may still see part of the batch while
+    /// the index is being updated.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the batch removes a key that doesn't exist
+    /// at that point of the batch. Nothing is written in this case.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    pub fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let _keys = self
+            .key_locks
+            .lock_keys(batch.cmds.iter().map(Command::key));
+        self.write(|writer| writer.write_batch(batch))
+    }
+
+    /// Returns whether the given key exists, looking it up in the in-memory index only.
+    ///
+    /// Unlike `get`, it never reads the value from the log. An expired key doesn't exist.
+    pub fn contains_key(&self, key: &str) -> bool {
+        let exists = self.consistent(|| {
+            Ok(match self.index.get(key) {
+                Some(entry) => !entry.value().is_expired(now_millis()),
+                None => false,
+            })
+        });
+        exists.unwrap_or(false)
+    }
+
+    /// Gets the raw byte value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    pub fn get_bytes(&self, key: String) -> Result<Option<Vec<u8>>> {
+        self.consistent(|| {
+            if let Some(cmd_pos) = self.index.get(&key) {
+                let cmd_pos = *cmd_pos.value();
+                if cmd_pos.is_expired(now_millis()) {
+                    self.writer.lock().unwrap().expire(&key, cmd_pos);
+                    return Ok(None);
+                }
+                Ok(Some(self.reader.read_value_cached(&key, cmd_pos)?))
+            } else {
+                Ok(None)
+            }
+        })
+    }
+
+    /// Gets the string value of a given string key together with where it is stored.
+    ///
+    /// Returns `None` if the given key does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Utf8` if the value is not valid UTF-8.
+    pub fn get_with_meta(&self, key: String) -> Result<Option<(String, ValueMeta)>> {
+        self.consistent(|| {
+            if let Some(cmd_pos) = self.index.get(&key) {
+                let cmd_pos = *cmd_pos.value();
+                if cmd_pos.is_expired(now_millis()) {
+                    self.writer.lock().unwrap().expire(&key, cmd_pos);
+                    return Ok(None);
+                }
+                let value = String::from_utf8(self.reader.read_value(cmd_pos)?)?;
+                let meta = ValueMeta {
+                    generation: cmd_pos.gen,
+                    offset: cmd_pos.pos,
+                    length: cmd_pos.len,
+                };
+                Ok(Some((value, meta)))
+            } else {
+                Ok(None)
+            }
+        })
+    }
+
+    /// Returns an iterator over all the key/value pairs in key order, reading each value
+    /// when the iterator reaches it.
+    ///
+    /// The iterator is weakly consistent, like the iterator of the index: writes made
+    /// while iterating may or may not be seen. A key removed before its value is read is
+    /// skipped.
+    pub fn iter(&self) -> KvIter<'_> {
+        KvIter {
+            store: self,
+            entries: self.index.iter(),
+            now: now_millis(),
+        }
+    }
+
+    /// Returns a view of the store as of now, which the writes from now on don't change.
+    ///
+    /// The index is copied under the writer lock, so the snapshot sees every write made
+    /// before it and none after. The generations it reads from are kept until it's
+    /// dropped, even if a compaction makes them stale; the next compaction after that
+    /// removes them. Expiry is checked against the time the snapshot is taken.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        let writer = self.writer.lock().unwrap();
+        let now = now_millis();
+        let entries = self
+            .index
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .filter(|(_, cmd_pos)| !cmd_pos.is_expired(now))
+            .collect();
+        let id = writer.pins.pin(
+            self.reader.safe_point.load(Ordering::SeqCst),
+            self.reader.vlog_safe_point.load(Ordering::SeqCst),
+        );
+        Ok(Snapshot {
+            entries,
+            reader: self.reader.pinned(),
+            pins: Arc::clone(&writer.pins),
+            id,
+        })
+    }
+
+    /// Returns all the key/value pairs, the most recently written first.
+    ///
+    /// The order follows the location of each value in the log, i.e. its generation and
+    /// offset. Note that a compaction rewrites all the live values in key order, so the
+    /// order only reflects the writes after the latest compaction.
+    pub fn iter_by_recency(&self) -> Result<Vec<(String, String)>> {
+        self.consistent(|| self.iter_by_recency_once())
+    }
+
+    fn iter_by_recency_once(&self) -> Result<Vec<(String, String)>> {
+        let now = now_millis();
+        let vlog_safe_point = self.reader.vlog_safe_point.load(Ordering::SeqCst);
+        let mut entries: Vec<(String, CommandPos)> = self
+            .index
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .filter(|(_, cmd_pos)| !cmd_pos.is_expired(now))
+            .collect();
+        entries.sort_unstable_by_key(|(_, cmd_pos)| Reverse((cmd_pos.gen, cmd_pos.pos)));
+        let mut pairs = Vec::with_capacity(entries.len());
+        for (key, cmd_pos) in entries {
+            let value = match self.reader.read_value(cmd_pos) {
+                Ok(value) => value,
+                // A compaction or value log GC has moved the value since the index was
+                // copied and the stale log may be gone already, so look it up again, like
+                // `scan_bytes_once`. The pair keeps its place in the order.
+                Err(_) if self.reader.moved_since(cmd_pos, vlog_safe_point) => {
+                    match self.index.get(&key) {
+                        Some(entry) if !entry.value().is_expired(now) => {
+                            self.reader.read_value(*entry.value())?
+                        }
+                        _ => continue,
+                    }
+                }
+                Err(e) => return Err(e),
+            };
+            pairs.push((key, String::from_utf8(value)?));
+        }
+        Ok(pairs)
+    }
+
+    /// Returns the key/value pairs with keys in the given range, in key order.
+    ///
+    /// The scan doesn't work on a snapshot: a key being overwritten concurrently may be
+    /// missed, because the index replaces an entry by removing it first.
+    ///
+    /// # Errors
+    ///
+    /// It aborts the scan on the first value that fails to be read.
+    pub fn scan(&self, start: Bound<String>, end: Bound<String>) -> Result<Vec<(String, String)>> {
+        self.scan_bytes(start, end)?
+            .into_iter()
+            .map(|(key, value)| Ok((key, String::from_utf8(value)?)))
+            .collect()
+    }
+
+    /// Returns the key/value pairs with keys starting with `prefix`, in key order.
+    ///
+    /// An empty prefix returns all the pairs.
+    pub fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        self.scan(Bound::Included(prefix.to_owned()), prefix_end(prefix))
+    }
+
+    // Scan with raw byte values.
+    fn scan_bytes(
+        &self,
+        start: Bound<String>,
+        end: Bound<String>,
+    ) -> Result<Vec<(String, Vec<u8>)>> {
+        self.consistent(|| self.scan_bytes_once(start.clone(), end.clone()))
+    }
+
+    fn scan_bytes_once(
+        &self,
+        start: Bound<String>,
+        end: Bound<String>,
+    ) -> Result<Vec<(String, Vec<u8>)>> {
+        let now = now_millis();
+        let mut pairs = Vec::new();
+        // loaded before the iterator reads any entry, see `moved_since`
+        let vlog_safe_point = self.reader.vlog_safe_point.load(Ordering::SeqCst);
+        for entry in self.index.range((start, end)) {
+            let cmd_pos = *entry.value();
+            if cmd_pos.is_expired(now) {
+                continue;
+            }
+            let value = match self.reader.read_value(cmd_pos) {
+                Ok(value) => value,
+                // A concurrent compaction or value log GC has moved the value and the
+                // stale log may be gone already, so look it up again.
+                Err(_) if self.reader.moved_since(cmd_pos, vlog_safe_point) => {
+                    match self.index.get(entry.key()) {
+                        Some(entry) => self.reader.read_value(*entry.value())?,
+                        None => continue,
+                    }
+                }
+                Err(e) => return Err(e),
+            };
+            pairs.push((entry.key().clone(), value));
+        }
+        Ok(pairs)
+    }
+}
+
+impl KvsEngine for KvStore {
+    /// Sets the value of a string key to a string.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key, value.into_bytes())
+    }
+
+    /// Gets the string value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Utf8` if the value is not valid UTF-8.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key)? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Sets the value of a string key to `new` only if its current value is `expected`.
+    ///
+    /// The comparison and the write happen under the lock of the key, so no other write
+    /// to it can come in between. Only the write takes the writer lock.
+    fn compare_and_swap(&self, key: String, expected: Option<String>, new: String) -> Result<bool> {
+        let _key = self.key_locks.lock(&key);
+        self.compare_and_swap_locked(key, expected, new)
+    }
+
+    /// Runs the operations of a batch in order, holding the locks of all their keys.
+    ///
+    /// No other write to the keys can come in between the operations, so a batch can read
+    /// a key and set it depending on its value, e.g. with an `Op::CompareAndSwap`. Each
+    /// write takes the writer lock on its own, and readers may see the first writes of a
+    /// batch before the last ones, like with `write_batch`. A failing operation doesn't
+    /// undo the ones before it.
+    fn batch(&self, ops: Vec<Op>) -> Result<Vec<OpResult>> {
+        let _keys = self.key_locks.lock_keys(ops.iter().map(Op::key));
+        let results = ops.into_iter().map(|op| {
+            let res = match op {
+                Op::Get { key } => self.read_locked(&key).and_then(|value| match value {
+                    Some((value, _)) => Ok(OpResult::Get(Some(String::from_utf8(value)?))),
+                    None => Ok(OpResult::Get(None)),
+                }),
+                Op::Set { key, value } => self
+                    .write(|writer| writer.set(key, value.into_bytes(), None))
+                    .map(|_| OpResult::Set),
+                Op::Remove { key } => self
+                    .write(|writer| writer.remove(key))
+                    .map(|_| OpResult::Remove),
+                Op::CompareAndSwap { key, expected, new } => self
+                    .compare_and_swap_locked(key, expected, new)
+                    .map(OpResult::CompareAndSwap),
+            };
+            res.unwrap_or_else(|e| OpResult::Err(e.into()))
+        });
+        Ok(results.collect())
+    }
+
+    /// Adds `delta` to the integer value of a key and returns the new value.
+    ///
+    /// The read and the write happen under the lock of the key, like `compare_and_swap`.
+    /// The counter keeps its expiry, and an expired one starts again from 0 without any.
+    fn increment(&self, key: String, delta: i64) -> Result<i64> {
+        let _key = self.key_locks.lock(&key);
+        let (current, expires_at) = match self.read_locked(&key)? {
+            Some((value, cmd_pos)) => {
+                let current = std::str::from_utf8(&value)
+                    .ok()
+                    .and_then(|value| value.parse::<i64>().ok())
+                    .ok_or(KvsError::NotANumber)?;
+                (current, cmd_pos.expires_at)
+            }
+            None => (0, None),
+        };
+        let new = current.checked_add(delta).ok_or(KvsError::Overflow)?;
+        self.write(|writer| writer.set(key, new.to_string().into_bytes(), expires_at))?;
+        Ok(new)
+    }
+
+    /// Sets a counter, expiring after `ttl` if it's given, like `set_with_ttl`.
+    fn set_counter(&self, key: String, value: i64, ttl: Option<Duration>) -> Result<()> {
+        let expires_at = ttl.map(|ttl| now_millis().saturating_add(ttl.as_millis() as u64));
+        let _key = self.key_locks.lock(&key);
+        self.write(|writer| writer.set(key, value.to_string().into_bytes(), expires_at))
+    }
+
+    /// Returns the value of a key, or sets it to `default` if it doesn't exist.
+    ///
+    /// The read and the write happen under the lock of the key, like `compare_and_swap`.
+    fn get_or_set(&self, key: String, default: String) -> Result<String> {
+        let _key = self.key_locks.lock(&key);
+        if let Some((value, _)) = self.read_locked(&key)? {
+            return Ok(String::from_utf8(value)?);
+        }
+        self.write(|writer| writer.set(key, default.clone().into_bytes(), None))?;
+        Ok(default)
+    }
+
+    /// Returns the key/value pairs with keys in the given range, in key order.
+    fn scan(&self, start: Bound<String>, end: Bound<String>) -> Result<Vec<(String, String)>> {
+        KvStore::scan(self, start, end)
+    }
+
+    /// Writes all the live key/value pairs to `writer`.
+    ///
+    /// Values are exported as raw bytes, so binary values are kept. Expiry times are not.
+    fn export(&self, mut writer: impl Write) -> Result<()> {
+        writer.write_all(EXPORT_MAGIC)?;
+        for (key, value) in self.scan_bytes(Bound::Unbounded, Bound::Unbounded)? {
+            if is_self_check_key(&key) {
+                continue;
+            }
+            write_export_pair(&mut writer, key.as_bytes(), &value)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Sets all the key/value pairs read from `reader`, returning how many are set.
+    fn import(&self, mut reader: impl Read) -> Result<usize> {
+        read_export_magic(&mut reader)?;
+        let mut count = 0;
+        while let Some((key, value)) = read_export_pair(&mut reader)? {
+            self.set_bytes(String::from_utf8(key)?, value)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Returns whether the given key exists, without reading its value from the log.
+    fn exists(&self, key: String) -> Result<bool> {
+        Ok(self.contains_key(&key))
+    }
+
+    /// Returns the number of keys that haven't expired, except the probe of a running
+    /// `self_check`.
+    ///
+    /// It walks the index to skip the expired keys, which stay in it until a read or a
+    /// compaction drops them.
+    fn len(&self) -> Result<usize> {
+        self.consistent(|| Ok(self.live_len()))
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        self.len().map(|len| len == 0)
+    }
+
+    /// Removes a given key.
+    ///
+    /// # Error
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn remove(&self, key: String) -> Result<()> {
+        let _key = self.key_locks.lock(&key);
+        self.write(|writer| writer.remove(key))
+    }
+
+    fn stats(&self) -> Result<Stats> {
+        KvStore::stats(self)
+    }
+}
+
+impl Drop for KvStore {
+    fn drop(&mut self) {
+        // let a running compaction finish, so that the store can be reopened right away
+        if Arc::strong_count(&self.background) == 1 {
+            if let Some(handle) = self.background.lock().unwrap().take() {
+                if handle.join().is_err() {
+                    error!("Background compaction panicked");
+                }
+            }
+        }
+    }
+}
+
+/// An iterator over the key/value pairs of a `KvStore`, returned by `KvStore::iter`.
+pub struct KvIter<'a> {
+    store: &'a KvStore,
+    entries: map::Iter<'a, String, CommandPos>,
+    // the time expiry is checked against
+    now: u64,
+}
+
+impl KvIter<'_> {
+    // Read the value of a key at `cmd_pos`, or `None` if the key is removed meanwhile.
+    fn read(store: &KvStore, key: &str, mut cmd_pos: CommandPos) -> Result<Option<String>> {
+        loop {
+            match store.reader.read_value(cmd_pos) {
+                Ok(value) => return Ok(Some(String::from_utf8(value)?)),
+                // A compaction may have moved the value and deleted the log it was in.
+                // Only the current location of the value is worth an error.
+                Err(e) => match store.index.get(key) {
+                    None => return Ok(None),
+                    Some(entry) if *entry.value() == cmd_pos => return Err(e),
+                    Some(entry) => cmd_pos = *entry.value(),
+                },
+            }
+        }
+    }
+}
+
+impl Iterator for KvIter<'_> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let store = self.store;
+        for entry in &mut self.entries {
+            let cmd_pos = *entry.value();
+            if entry.is_removed() || cmd_pos.is_expired(self.now) {
+                continue;
+            }
+            match Self::read(store, entry.key(), cmd_pos) {
+                Ok(Some(value)) => return Some(Ok((entry.key().clone(), value))),
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        None
+    }
+}
+
+/// A consistent view of a `KvStore`, returned by `KvStore::snapshot`.
+///
+/// It reads the values as they were when it was taken, whatever is written to the store
+/// afterwards. It keeps the log generations it reads from until it's dropped.
+pub struct Snapshot {
+    // the index as of the snapshot
+    entries: BTreeMap<String, CommandPos>,
+    reader: KvStoreReader,
+    pins: Arc<SnapshotPins>,
+    // the id of the pin of the snapshot in `pins`
+    id: u64,
+}
+
+impl Snapshot {
+    /// Gets the string value of a given string key as of the snapshot.
+    ///
+    /// Returns `None` if the given key did not exist.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Utf8` if the value is not valid UTF-8.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key)? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Gets the raw byte value of a given string key as of the snapshot.
+    ///
+    /// Returns `None` if the given key did not exist.
+    pub fn get_bytes(&self, key: String) -> Result<Option<Vec<u8>>> {
+        match self.entries.get(&key) {
+            Some(&cmd_pos) => Ok(Some(self.reader.read_value(cmd_pos)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns an iterator over the key/value pairs of the snapshot in key order.
+    pub fn iter(&self) -> SnapshotIter<'_> {
+        SnapshotIter {
+            snapshot: self,
+            entries: self.entries.iter(),
+        }
+    }
+
+    /// Returns the number of keys in the snapshot.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns whether the snapshot has no key.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.pins.unpin(self.id);
+    }
+}
+
+/// An iterator over the key/value pairs of a `Snapshot`, returned by `Snapshot::iter`.
+pub struct SnapshotIter<'a> {
+    snapshot: &'a Snapshot,
+    entries: std::collections::btree_map::Iter<'a, String, CommandPos>,
+}
+
+impl Iterator for SnapshotIter<'_> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, &cmd_pos) = self.entries.next()?;
+        let value = self
+            .snapshot
+            .reader
+            .read_value(cmd_pos)
+            .and_then(|value| Ok(String::from_utf8(value)?));
+        Some(value.map(|value| (key.clone(), value)))
+    }
+}
+
+/// A `KvStore` opened with `KvStore::open_read_only`.
+///
+/// Reads work like on a `KvStore`, while `set` and `remove` return `KvsError::ReadOnly`.
+/// Writes made to the directory after it is opened are not seen.
+#[derive(Clone)]
+pub struct ReadOnlyKvStore {
+    index: Arc<SkipMap<String, CommandPos>>,
+    reader: KvStoreReader,
+}
+
+impl ReadOnlyKvStore {
+    /// Gets the raw byte value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    pub fn get_bytes(&self, key: String) -> Result<Option<Vec<u8>>> {
+        match self.index.get(&key) {
+            Some(entry) if !entry.value().is_expired(now_millis()) => {
+                Ok(Some(self.reader.read_value(*entry.value())?))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+impl KvsEngine for ReadOnlyKvStore {
+    /// Always returns `KvsError::ReadOnly`.
+    fn set(&self, _key: String, _value: String) -> Result<()> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key)? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Always returns `KvsError::ReadOnly`.
+    fn remove(&self, _key: String) -> Result<()> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn exists(&self, key: String) -> Result<bool> {
+        match self.index.get(&key) {
+            Some(entry) => Ok(!entry.value().is_expired(now_millis())),
+            None => Ok(false),
+        }
+    }
+
+    /// Returns the number of keys that haven't expired.
+    fn len(&self) -> Result<usize> {
+        Ok(count_live(&self.index, now_millis()))
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        self.len().map(|len| len == 0)
+    }
+
+    fn scan(&self, start: Bound<String>, end: Bound<String>) -> Result<Vec<(String, String)>> {
+        let now = now_millis();
+        self.index
+            .range((start, end))
+            .filter(|entry| !entry.value().is_expired(now))
+            .map(|entry| {
+                let value = String::from_utf8(self.reader.read_value(*entry.value())?)?;
+                Ok((entry.key().clone(), value))
+            })
+            .collect()
+    }
+}
 
 /// A single thread reader.
 ///
-/// Each `KvStore` instance has its own `KvStoreReader` and
-/// `KvStoreReader`s open the same files separately. So the user
-/// can read concurrently through multiple `KvStore`s in different
-/// threads.
-// 详细中文注释（补充）：
-// KvStoreReader 的目的与行为：
-// - 每个 `KvStore` 实例包含一个 `KvStoreReader`，用于按需打开并复用文件句柄以便读取日志中的命令。
-// - `KvStoreReader` 内部使用 `RefCell<BTreeMap<u64, BufReaderWithPos<File>>>` 缓存已经打开的文件句柄，避免频繁 open/close。
-// - `safe_point` 表示最近一次 compaction 生成的代数（generation），当某个文件的代数小于 `safe_point` 时，意味着该文件已经是陈旧的，
-//   可以在保证没有并发读取的前提下关闭对应句柄并删除物理文件（在 Windows 上文件删除需要句柄释放后才能完成）。
-// - 设计要点：读取路径要尽量避免阻塞写路径，`KvStoreReader` 的 `read_and` 方法采用借用（borrow_mut）打开/复用句柄并定位到指定偏移再读取固定长度，
-//   从而保证读取的局部性和效率。
-struct KvStoreReader {
-    // arc 共享所有权，共享路径对象
-    path: Arc<PathBuf>,
+/// Each `KvStore` instance has its own `KvStoreReader` and
+/// `KvStoreReader`s open the same files separately. So the user
+/// can read concurrently through multiple `KvStore`s in different
+/// threads.
+// 详细中文注释（补充）：
+// KvStoreReader 的目的与行为：
+// - 每个 `KvStore` 实例包含一个 `KvStoreReader`，用于按需打开并复用文件句柄以便读取日志中的命令。
+// - `KvStoreReader` 内部使用 `RefCell<BTreeMap<u64, BufReaderWithPos<File>>>` 缓存已经打开的文件句柄，避免频繁 open/close。
+// - `safe_point` 表示最近一次 compaction 生成的代数（generation），当某个文件的代数小于 `safe_point` 时，意味着该文件已经是陈旧的，
+//   可以在保证没有并发读取的前提下关闭对应句柄并删除物理文件（在 Windows 上文件删除需要句柄释放后才能完成）。
+// - 设计要点：读取路径要尽量避免阻塞写路径，`KvStoreReader` 的 `read_and` 方法采用借用（borrow_mut）打开/复用句柄并定位到指定偏移再读取固定长度，
+//   从而保证读取的局部性和效率。
+struct KvStoreReader {
+    // arc 共享所有权，共享路径对象
+    path: Arc<LogDir>,
+
+    // 安全水位线，记录了最新一次压缩产生的文件代号民，是读线程和写线程之间的信号号
+    // 压缩发生时，旧的日志文件会被合并成一个新的大文件
+    // atomic64 保证了原子性，多个线程可以安全的读取
+    // 作用：防止读取已经失效或被删除的旧文件，如果reader试图访问一个小于 safe_point 的是文件id，或能需要重定向去读新的压缩文件，或者直接报错
+    // generation of the latest compaction file
+    safe_point: Arc<AtomicU64>,
+    // the `safe_point` the handles were last closed at, so that reads only close them
+    // again after a compaction
+    cleaned_safe_point: Cell<u64>,
+    // value logs before this generation are removed by a garbage collection
+    vlog_safe_point: Arc<AtomicU64>,
+    // odd while `replace_contents_from` swaps the index, bumped again when it's done
+    swap_epoch: Arc<AtomicU64>,
+    format: LogFormat,
+
+    // 在读的时候，还要修改reader的位置，但get方法的签名是 &self
+    // 这里还是没太懂
+    readers: RefCell<BTreeMap<u64, BufReaderWithPos<LogFile>>>,
+    // the value logs opened so far
+    vlogs: RefCell<BTreeMap<u64, File>>,
+    // the values cached by `KvStore::warm_cache`, shared by all the readers
+    cache: Arc<ValueCache>,
+    // the compressed generations opened by the readers, inflated once for all of them
+    inflated: Arc<InflatedLogs>,
+    // the number of reads from the files, shared by all the readers
+    #[cfg(feature = "testing")]
+    reads: Arc<AtomicU64>,
+    // set if commands are read through memory maps instead of `readers`
+    #[cfg(feature = "mmap")]
+    mmap: Option<MmapReader>,
+}
+
+impl KvStoreReader {
+    /// Close file handles with generation number less than safe_point.
+    ///
+    /// `safe_point` is updated to the latest compaction gen after a compaction finishes.
+    /// The compaction generation contains the sum of all operations before it and the
+    /// in-memory index contains no entries with generation number less than safe_point.
+    /// So we can safely close those file handles and the stale files can be deleted.
+    /// Nothing is done if `safe_point` hasn't moved since the last call, which is the
+    /// case of most reads.
+    /// 关闭交移除那些已经被压缩过的，过期的文件handler，防止 handler 泄露
+    /// bitcask 中，执行 compact 后，旧文件中的有效数据搬到新的，
+    /// 更新水位，全局变量 safe_point 更新为3，id < 3的都是垃圾
+    ///
+    fn close_stale_handles(&self) {
+        let safe_point = self.safe_point.load(Ordering::SeqCst);
+        if safe_point == self.cleaned_safe_point.get() {
+            return;
+        }
+        // 获取写锁，RefCell
+        // 要从map 中删除元素，所以需要可变借用
+        let mut readers = self.readers.borrow_mut();
+
+        while !readers.is_empty() {
+            // 拿出 map 中id 最小的言论的 id,BTreemap 是有序的，next 返回的永远是最小的
+            let first_gen = *readers.keys().next().unwrap();
+            if safe_point <= first_gen {
+                break;
+            }
+
+            // remove 会 drop file 对象
+            readers.remove(&first_gen);
+        }
+        #[cfg(feature = "mmap")]
+        {
+            if let Some(mmap) = &self.mmap {
+                mmap.close_stale_maps(safe_point);
+            }
+        }
+        self.cleaned_safe_point.set(safe_point);
+    }
+
+    // Make the next read close the handles again if generation `gen` was just opened
+    // though it's before the safe point they were closed at. It happens to a read that
+    // took its position from the index before a compaction finished.
+    fn opened(&self, gen: u64) {
+        if gen < self.cleaned_safe_point.get() {
+            self.cleaned_safe_point.set(0);
+        }
+    }
+
+    // A reader that never closes its handles, for a snapshot: the generations it reads
+    // are pinned, so they stay readable after they become stale.
+    fn pinned(&self) -> KvStoreReader {
+        let mut reader = self.clone();
+        reader.safe_point = Arc::new(AtomicU64::new(0));
+        reader.vlog_safe_point = Arc::new(AtomicU64::new(0));
+        reader
+    }
+
+    // Whether a failed read at `cmd_pos` may have raced with a compaction or a value log
+    // GC that moved the value, so that it should be looked up again in the index.
+    // `vlog_safe_point` is the one loaded before `cmd_pos` was looked up: the new
+    // pointers of a GC are in the index before the safe point moves.
+    fn moved_since(&self, cmd_pos: CommandPos, vlog_safe_point: u64) -> bool {
+        cmd_pos.gen < self.safe_point.load(Ordering::SeqCst)
+            || self.vlog_safe_point.load(Ordering::SeqCst) != vlog_safe_point
+    }
+
+    /// Close the value logs before `vlog_safe_point`, like `close_stale_handles`.
+    fn close_stale_vlogs(&self) {
+        let mut vlogs = self.vlogs.borrow_mut();
+        let live = vlogs.split_off(&self.vlog_safe_point.load(Ordering::SeqCst));
+        *vlogs = live;
+    }
+
+    /// Read the log file at the given `CommandPos`.
+    fn read_and<F, R>(&self, cmd_pos: CommandPos, f: F) -> Result<R>
+    where
+        // 定义闭包类型，接收一个受限的文件流，返回任意结果 R
+        // read_and 不关心读出来的数据做什么，只读
+        // io::Take 划定安全边界，防止多读
+        F: FnOnce(io::Take<&mut BufReaderWithPos<LogFile>>) -> Result<R>,
+    {
+        // 清理过期文件句柄，如果有压缩发生
+        self.close_stale_handles();
+        #[cfg(feature = "testing")]
+        self.reads.fetch_add(1, Ordering::SeqCst);
+
+        // borrow_mut 拿到独占访问权，可以insert 或者 seek 指针
+        let mut readers = self.readers.borrow_mut();
+        // Open the file if we haven't opened it in this `KvStoreReader`.
+        // We don't use entry API here because we want the errors to be propogated.
+        // 懒加载，如果这个 id 的文件还没打开过，现在打开并存入 缓存
+        if !readers.contains_key(&cmd_pos.gen) {
+            let reader = BufReaderWithPos::new(self.inflated.open(&self.path, cmd_pos.gen)?)?;
+            readers.insert(cmd_pos.gen, reader);
+            self.opened(cmd_pos.gen);
+        }
+
+        // 拿到文件 handler
+        let reader = readers.get_mut(&cmd_pos.gen).unwrap();
+        // 定位
+        reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+        // 读取固定长度
+        let cmd_reader = reader.take(cmd_pos.len);
+
+        // 执行回调
+        // 把准备好的，对准了位置的，限制了长度的 reader 交给闭包处理
+        f(cmd_reader)
+    }
+
+    // Read the log file at the given `CommandPos` and deserialize it to `Command`.
+    fn read_command(&self, cmd_pos: CommandPos) -> Result<Command> {
+        #[cfg(feature = "mmap")]
+        {
+            if let Some(mmap) = &self.mmap {
+                self.close_stale_handles();
+                #[cfg(feature = "testing")]
+                self.reads.fetch_add(1, Ordering::SeqCst);
+                self.opened(cmd_pos.gen);
+                return mmap.read_and(cmd_pos, |mut bytes| {
+                    read_record(&mut bytes, cmd_pos.gen, cmd_pos.pos, self.format)?.ok_or(
+                        KvsError::CorruptLog {
+                            gen: cmd_pos.gen,
+                            offset: cmd_pos.pos,
+                        },
+                    )
+                });
+            }
+        }
+        // 调用底层的读取器 read_and
+        self.read_and(cmd_pos, |mut cmd_reader| {
+            // 传入一个闭包，（回调函数 ）
+            // 给你一个已经对准标准公交车的文件流，把它解析为 json command
+            read_record(&mut cmd_reader, cmd_pos.gen, cmd_pos.pos, self.format)?.ok_or(
+                KvsError::CorruptLog {
+                    gen: cmd_pos.gen,
+                    offset: cmd_pos.pos,
+                },
+            )
+        })
+    }
+
+    // Read the value of the `Command::Set` at the given `CommandPos`, decompressed.
+    fn read_value(&self, cmd_pos: CommandPos) -> Result<Vec<u8>> {
+        let (value, compressed) = match self.read_command(cmd_pos)? {
+            Command::Set {
+                value, compressed, ..
+            } => (value, compressed),
+            Command::SetExternal {
+                value, compressed, ..
+            } => (self.read_external(value)?, compressed),
+            Command::Remove { .. } => return Err(KvsError::UnexpectedCommandType),
+        };
+        if compressed {
+            Ok(zstd::decode_all(&value[..])?)
+        } else {
+            Ok(value)
+        }
+    }
+
+    // Read the value of `key` at the given `CommandPos` like `read_value`, through the
+    // value cache if it's enabled.
+    fn read_value_cached(&self, key: &str, cmd_pos: CommandPos) -> Result<Vec<u8>> {
+        if !self.cache.is_enabled() {
+            return self.read_value(cmd_pos);
+        }
+        if let Some(value) = self.cache.get(key, cmd_pos) {
+            return Ok(value);
+        }
+        let value = self.read_value(cmd_pos)?;
+        self.cache.insert(key.to_owned(), cmd_pos, value.clone());
+        Ok(value)
+    }
+
+    // Read the stored bytes of an external value.
+    fn read_external(&self, ptr: ValuePointer) -> Result<Vec<u8>> {
+        self.close_stale_vlogs();
+        #[cfg(feature = "testing")]
+        self.reads.fetch_add(1, Ordering::SeqCst);
+        let mut vlogs = self.vlogs.borrow_mut();
+        let vlog = match vlogs.entry(ptr.gen) {
+            btree_map::Entry::Occupied(entry) => entry.into_mut(),
+            btree_map::Entry::Vacant(entry) => {
+                entry.insert(File::open(vlog_path(&self.path, ptr.gen))?)
+            }
+        };
+        vlog.seek(SeekFrom::Start(ptr.offset))?;
+        let mut value = Vec::new();
+        vlog.take(ptr.len).read_to_end(&mut value)?;
+        if value.len() as u64 != ptr.len {
+            return Err(KvsError::Io(io::ErrorKind::UnexpectedEof.into()));
+        }
+        Ok(value)
+    }
+}
+
+impl Clone for KvStoreReader {
+    fn clone(&self) -> KvStoreReader {
+        KvStoreReader {
+            path: Arc::clone(&self.path),
+            safe_point: Arc::clone(&self.safe_point),
+            cleaned_safe_point: Cell::new(0),
+            vlog_safe_point: Arc::clone(&self.vlog_safe_point),
+            swap_epoch: Arc::clone(&self.swap_epoch),
+            format: self.format,
+            // don't use other KvStoreReader's readers
+            readers: RefCell::new(BTreeMap::new()),
+            vlogs: RefCell::new(BTreeMap::new()),
+            cache: Arc::clone(&self.cache),
+            inflated: Arc::clone(&self.inflated),
+            #[cfg(feature = "testing")]
+            reads: Arc::clone(&self.reads),
+            #[cfg(feature = "mmap")]
+            mmap: self
+                .mmap
+                .as_ref()
+                .map(|mmap| MmapReader::new(Arc::clone(&mmap.path), Arc::clone(&self.inflated))),
+        }
+    }
+}
+
+/// A size-bounded LRU cache of values, shared by all the readers of a store.
+///
+/// An entry records where its value is stored and is only used while the index still
+/// points there, so a value replaced while it is being cached is never returned.
+#[derive(Default)]
+struct ValueCache {
+    // the budget in bytes of the cached keys and values, 0 while the cache is disabled
+    max_bytes: AtomicUsize,
+    entries: Mutex<CacheEntries>,
+}
+
+#[derive(Default)]
+struct CacheEntries {
+    values: HashMap<String, CachedValue>,
+    // the cached keys by their last use, the least recently used first
+    lru: BTreeMap<u64, String>,
+    next_use: u64,
+    bytes: usize,
+}
+
+struct CachedValue {
+    cmd_pos: CommandPos,
+    value: Vec<u8>,
+    last_use: u64,
+}
+
+impl ValueCache {
+    fn is_enabled(&self) -> bool {
+        self.max_bytes.load(Ordering::SeqCst) > 0
+    }
+
+    /// Sets the budget, evicting the least recently used values over it.
+    fn resize(&self, max_bytes: usize) {
+        self.max_bytes.store(max_bytes, Ordering::SeqCst);
+        let mut entries = self.entries.lock().unwrap();
+        while entries.bytes > max_bytes {
+            entries.evict();
+        }
+    }
+
+    /// Returns the cached value of `key` if it's the one at `cmd_pos`.
+    fn get(&self, key: &str, cmd_pos: CommandPos) -> Option<Vec<u8>> {
+        let mut entries = self.entries.lock().unwrap();
+        let entries = &mut *entries;
+        let cached = entries.values.get_mut(key)?;
+        if cached.cmd_pos != cmd_pos {
+            return None;
+        }
+        entries.lru.remove(&cached.last_use);
+        cached.last_use = entries.next_use;
+        entries.next_use += 1;
+        entries.lru.insert(cached.last_use, key.to_owned());
+        Some(cached.value.clone())
+    }
+
+    /// Caches the value of `key` at `cmd_pos`, evicting the least recently used values
+    /// to make room. Returns `false` if the value alone is over the budget.
+    fn insert(&self, key: String, cmd_pos: CommandPos, value: Vec<u8>) -> bool {
+        let max_bytes = self.max_bytes.load(Ordering::SeqCst);
+        let size = key.len() + value.len();
+        if size > max_bytes {
+            return false;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.remove(&key);
+        while entries.bytes + size > max_bytes {
+            entries.evict();
+        }
+        let last_use = entries.next_use;
+        entries.next_use += 1;
+        entries.lru.insert(last_use, key.clone());
+        entries.values.insert(
+            key,
+            CachedValue {
+                cmd_pos,
+                value,
+                last_use,
+            },
+        );
+        entries.bytes += size;
+        true
+    }
+
+    /// Drops the cached value of `key`.
+    fn remove(&self, key: &str) {
+        if self.is_enabled() {
+            self.entries.lock().unwrap().remove(key);
+        }
+    }
+
+    /// Follows the value of `key` moved by a compaction from `old_pos` to `new_pos`.
+    fn moved(&self, key: &str, old_pos: CommandPos, new_pos: CommandPos) {
+        if !self.is_enabled() {
+            return;
+        }
+        if let Some(cached) = self.entries.lock().unwrap().values.get_mut(key) {
+            if cached.cmd_pos == old_pos {
+                cached.cmd_pos = new_pos;
+            }
+        }
+    }
+
+    /// Drops all the cached values.
+    fn clear(&self) {
+        *self.entries.lock().unwrap() = CacheEntries::default();
+    }
+}
+
+impl CacheEntries {
+    fn remove(&mut self, key: &str) {
+        if let Some(cached) = self.values.remove(key) {
+            self.lru.remove(&cached.last_use);
+            self.bytes -= key.len() + cached.value.len();
+        }
+    }
+
+    // Drop the least recently used value.
+    fn evict(&mut self) {
+        if let Some((_, key)) = self.lru.pop_first() {
+            let cached = self.values.remove(&key).unwrap();
+            self.bytes -= key.len() + cached.value.len();
+        }
+    }
+}
+
+/// Reads commands out of memory-mapped log files, an alternative to the buffered
+/// readers of `KvStoreReader`.
+///
+/// Like those readers, the maps belong to a single thread.
+#[cfg(feature = "mmap")]
+struct MmapReader {
+    path: Arc<LogDir>,
+    maps: RefCell<BTreeMap<u64, MappedLog>>,
+    inflated: Arc<InflatedLogs>,
+}
+
+#[cfg(feature = "mmap")]
+impl MmapReader {
+    fn new(path: Arc<LogDir>, inflated: Arc<InflatedLogs>) -> MmapReader {
+        MmapReader {
+            path,
+            maps: RefCell::new(BTreeMap::new()),
+            inflated,
+        }
+    }
+
+    /// Drop the maps of the generations before `safe_point`, as their files may be
+    /// deleted.
+    fn close_stale_maps(&self, safe_point: u64) {
+        let mut maps = self.maps.borrow_mut();
+        let live = maps.split_off(&safe_point);
+        *maps = live;
+    }
+
+    /// Pass the bytes of the command at the given `CommandPos` to `f`.
+    fn read_and<F, R>(&self, cmd_pos: CommandPos, f: F) -> Result<R>
+    where
+        F: FnOnce(&[u8]) -> Result<R>,
+    {
+        let mut maps = self.maps.borrow_mut();
+        let end = cmd_pos.pos + cmd_pos.len;
+        // The active generation keeps growing after it is mapped, so a command past the
+        // end of the map means it needs to be mapped again.
+        if !matches!(maps.get(&cmd_pos.gen), Some(map) if map.len() as u64 >= end) {
+            let map = MappedLog::open(&self.path, cmd_pos.gen, &self.inflated)?;
+            maps.insert(cmd_pos.gen, map);
+        }
+        let bytes = maps[&cmd_pos.gen]
+            .get(cmd_pos.pos as usize..end as usize)
+            .ok_or(KvsError::CorruptLog {
+                gen: cmd_pos.gen,
+                offset: cmd_pos.pos,
+            })?;
+        f(bytes)
+    }
+}
+
+/// A generation in memory: mapped if it's a plain log, or decompressed if it's not.
+#[cfg(feature = "mmap")]
+enum MappedLog {
+    Mapped(Mmap),
+    Decompressed(Arc<[u8]>),
+}
+
+#[cfg(feature = "mmap")]
+impl MappedLog {
+    fn open(dir: &LogDir, gen: u64, inflated: &InflatedLogs) -> Result<MappedLog> {
+        match inflated.open(dir, gen)? {
+            // SAFETY: log files are only appended to while a store has them open, so the
+            // mapped bytes never change. The store's directory lock keeps other stores
+            // from writing to them.
+            LogFile::Plain(file) => Ok(MappedLog::Mapped(unsafe { Mmap::map(&file)? })),
+            LogFile::Compressed(cursor) => Ok(MappedLog::Decompressed(cursor.into_inner())),
+            // memory stores don't map their logs
+            LogFile::Memory(_) => Err(KvsError::Unsupported),
+        }
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl std::ops::Deref for MappedLog {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            MappedLog::Mapped(map) => map,
+            MappedLog::Decompressed(buf) => buf,
+        }
+    }
+}
+
+// 详细中文注释（补充）：
+// KvStoreWriter 的职责与重要设计点：
+// - 负责将 `set`/`remove` 命令序列化并追加写入当前代数日志文件，维护 `index`（内存索引）以及记录 `uncompacted` 大小。
+// - 写入必须串行：因此 `KvStoreWriter` 被放在 `Arc<Mutex<...>>` 之内，外部在执行 `set`/`remove` 时会获取互斥锁，
+//   保证不会同时有多个写线程破坏日志顺序或索引一致性。
+// - `uncompacted`：统计可以回收的“垃圾”字节数（旧的被覆盖或删除的记录占用的空间），用来触发 `compact()`。
+// - `compact()`：将当前 index 指向的有效数据搬运到新的 compaction 文件中，更新 index 并删除旧日志文件，释放空间。
+// - 关于为什么读写分离：读者通过 `KvStoreReader` 使用 `SkipMap` 无锁读取索引并定位到磁盘位置，然后直接读磁盘数据；写操作走串行化路径，避免了复杂的并发控制。
+// - 对新手的提示：保证 `KvStoreWriter` 的操作尽量短小（快速 append + flush），避免在持锁期间做大量 CPU 或阻塞 IO 操作，以减少对读操作的影响。
+struct KvStoreWriter {
+    reader: KvStoreReader,
+    writer: BufWriterWithPos<LogWriter>,
+    current_gen: u64,
+    // the number of bytes representing "stale" commands that could be
+    // deleted during a compaction
+    uncompacted: u64,
+    // the part of `uncompacted` a tiered compaction left in place, not counted towards
+    // the next compaction
+    unreclaimable: u64,
+    // compact when `uncompacted` exceeds it
+    compaction_threshold: u64,
+    compaction_strategy: CompactionStrategy,
+    sync_policy: SyncPolicy,
+    compression: Compression,
+    max_key_bytes: Option<usize>,
+    max_value_bytes: Option<usize>,
+    // values stored larger than it go to the value log
+    external_value_threshold: Option<usize>,
+    // the value log being written, created on the first external value
+    vlog: Option<BufWriterWithPos<LogWriter>>,
+    vlog_gen: u64,
+    last_sync: Instant,
+    path: Arc<LogDir>,
+    index: Arc<SkipMap<String, CommandPos>>,
+    // the generations the snapshots still read from
+    pins: Arc<SnapshotPins>,
+}
+
+impl KvStoreWriter {
+    // Check a key and its value against the size limits.
+    fn check_size(&self, key: &str, value: &[u8]) -> Result<()> {
+        if let Some(limit) = self.max_key_bytes.filter(|&limit| key.len() > limit) {
+            return Err(KvsError::KeyTooLarge {
+                size: key.len(),
+                limit,
+            });
+        }
+        if let Some(limit) = self.max_value_bytes.filter(|&limit| value.len() > limit) {
+            return Err(KvsError::ValueTooLarge {
+                size: value.len(),
+                limit,
+            });
+        }
+        Ok(())
+    }
+
+    // Compress the value of a `Command::Set` as configured.
+    fn compress(&self, cmd: Command) -> Result<Command> {
+        match cmd {
+            Command::Set {
+                key,
+                value,
+                expires_at,
+                compressed: false,
+            } => {
+                let (value, compressed) = self.compression.compress(value)?;
+                Ok(Command::Set {
+                    key,
+                    value,
+                    expires_at,
+                    compressed,
+                })
+            }
+            cmd => Ok(cmd),
+        }
+    }
+
+    // Move the value of a `Command::Set` to the value log if it's over the threshold.
+    fn externalize(&mut self, cmd: Command) -> Result<Command> {
+        match cmd {
+            Command::Set {
+                key,
+                value,
+                expires_at,
+                compressed,
+            } if matches!(self.external_value_threshold, Some(threshold) if value.len() > threshold) =>
+            {
+                let value = self.write_external(&value)?;
+                Ok(Command::SetExternal {
+                    key,
+                    value,
+                    expires_at,
+                    compressed,
+                })
+            }
+            cmd => Ok(cmd),
+        }
+    }
+
+    // Append stored value bytes to the value log, flushed so that readers can see them.
+    fn write_external(&mut self, value: &[u8]) -> Result<ValuePointer> {
+        if self.vlog.is_none() {
+            self.vlog = Some(new_vlog_file(&self.path, self.vlog_gen)?);
+        }
+        let vlog = self.vlog.as_mut().unwrap();
+        let offset = vlog.pos;
+        vlog.write_all(value)?;
+        vlog.flush()?;
+        Ok(ValuePointer {
+            gen: self.vlog_gen,
+            offset,
+            len: value.len() as u64,
+        })
+    }
+
+    fn set(&mut self, key: String, value: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
+        self.check_size(&key, &value)?;
+        let cmd = self.compress(Command::set(key, value, expires_at))?;
+        let cmd = self.externalize(cmd)?;
+
+        // writer 当前写到哪个位置了
+        let pos = self.writer.pos;
+        write_record(&mut self.writer, &cmd, self.reader.format)?;
+
+        self.writer.flush()?;
+        self.sync_after_write()?;
+        if let Command::Set { key, .. } | Command::SetExternal { key, .. } = cmd {
+            if let Some(old_cmd) = self.index.get(&key) {
+                self.uncompacted += old_cmd.value().len;
+            }
+            self.reader.cache.remove(&key);
+            let cmd_pos = CommandPos::from((self.current_gen, pos..self.writer.pos));
+            self.index.insert(key, cmd_pos.expiring_at(expires_at));
+        }
+
+        Ok(())
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        if self.is_live(&key) {
+            // 先将命令 log，再append log
+            let cmd = Command::remove(key);
+            let pos = self.writer.pos;
+            write_record(&mut self.writer, &cmd, self.reader.format)?;
+            self.writer.flush()?;
+            self.sync_after_write()?;
+
+            if let Command::Remove { key } = cmd {
+                let old_cmd = self.index.remove(&key).expect("key not found");
+                self.reader.cache.remove(&key);
+
+                // set 命令的长度
+                self.uncompacted += old_cmd.value().len;
+                // the "remove" command itself can be deleted in the next compaction
+                // so we add its length to `uncompacted`
+                // remove 命令的长度
+                self.uncompacted += self.writer.pos - pos;
+            }
+
+            Ok(())
+        } else {
+            Err(KvsError::KeyNotFound)
+        }
+    }
+
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        // check the removes against the index and the earlier writes in the batch
+        let mut exists: HashMap<&str, bool> = HashMap::new();
+        for cmd in &batch.cmds {
+            match cmd {
+                Command::Set { key, value, .. } => {
+                    self.check_size(key, value)?;
+                    exists.insert(key, true);
+                }
+                Command::SetExternal { key, .. } => {
+                    exists.insert(key, true);
+                }
+                Command::Remove { key } => {
+                    let present = match exists.get(key.as_str()) {
+                        Some(&present) => present,
+                        None => self.is_live(key),
+                    };
+                    if !present {
+                        return Err(KvsError::KeyNotFound);
+                    }
+                    exists.insert(key, false);
+                }
+            }
+        }
+
+        // serialize the whole batch first, so a failure leaves both the log and the index
+        // untouched
+        let mut cmds = Vec::with_capacity(batch.cmds.len());
+        for cmd in batch.cmds {
+            let cmd = self.compress(cmd)?;
+            cmds.push(self.externalize(cmd)?);
+        }
+        let mut buf = Vec::new();
+        let mut ranges = Vec::with_capacity(cmds.len());
+        for cmd in &cmds {
+            let start = buf.len() as u64;
+            write_record(&mut buf, cmd, self.reader.format)?;
+            ranges.push(start..buf.len() as u64);
+        }
+
+        let base = self.writer.pos;
+        self.writer.write_all(&buf)?;
+        self.writer.flush()?;
+        self.sync_after_write()?;
+
+        for (cmd, range) in cmds.into_iter().zip(ranges) {
+            let range = base + range.start..base + range.end;
+            match cmd {
+                Command::Set {
+                    key, expires_at, ..
+                }
+                | Command::SetExternal {
+                    key, expires_at, ..
+                } => {
+                    if let Some(old_cmd) = self.index.get(&key) {
+                        self.uncompacted += old_cmd.value().len;
+                    }
+                    self.reader.cache.remove(&key);
+                    let cmd_pos = CommandPos::from((self.current_gen, range));
+                    self.index.insert(key, cmd_pos.expiring_at(expires_at));
+                }
+                Command::Remove { key } => {
+                    if let Some(old_cmd) = self.index.remove(&key) {
+                        self.uncompacted += old_cmd.value().len;
+                    }
+                    self.reader.cache.remove(&key);
+                    self.uncompacted += range.end - range.start;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Whether the key exists and hasn't expired. An expired key is dropped from the index.
+    fn is_live(&mut self, key: &str) -> bool {
+        match self.index.get(key).map(|entry| *entry.value()) {
+            Some(cmd_pos) => {
+                if cmd_pos.is_expired(now_millis()) {
+                    self.expire(key, cmd_pos);
+                    false
+                } else {
+                    true
+                }
+            }
+            None => false,
+        }
+    }
+
+    // Drop an expired key from the index, unless it has been written again since its
+    // expired position was read.
+    fn expire(&mut self, key: &str, cmd_pos: CommandPos) {
+        if let Some(entry) = self.index.get(key) {
+            if *entry.value() == cmd_pos {
+                entry.remove();
+                self.reader.cache.remove(key);
+                self.uncompacted += cmd_pos.len;
+            }
+        }
+    }
+
+    // Flush the active log and sync it to the disk, after the value log it points to.
+    fn sync(&mut self) -> Result<()> {
+        if let Some(vlog) = &mut self.vlog {
+            vlog.sync_data()?;
+        }
+        self.writer.sync_data()?;
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    // Sync the active log if the policy asks to after a write.
+    fn sync_after_write(&mut self) -> Result<()> {
+        match self.sync_policy {
+            SyncPolicy::Never => Ok(()),
+            SyncPolicy::EveryWrite => self.sync(),
+            SyncPolicy::Interval(interval) => {
+                if self.last_sync.elapsed() >= interval {
+                    self.sync()
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    // Whether the stale data not yet found unreclaimable exceeds `threshold`.
+    fn needs_compaction(&self, threshold: u64) -> bool {
+        self.uncompacted.saturating_sub(self.unreclaimable) > threshold
+    }
+
+    /// Starts a compaction following the compaction strategy, or returns `None` if there
+    /// is nothing worth compacting.
+    fn begin_compaction_by_strategy(&mut self) -> Result<Option<Compaction>> {
+        match self.compaction_strategy {
+            CompactionStrategy::Full => self.begin_compaction().map(Some),
+            CompactionStrategy::Tiered {
+                max_gens,
+                dead_ratio,
+            } => self.begin_tiered_compaction(max_gens, dead_ratio),
+        }
+    }
+
+    /// Starts a compaction by switching writes to a new generation and taking a snapshot
+    /// of the index.
+    fn begin_compaction(&mut self) -> Result<Compaction> {
+        // increase current gen by 2. current_gen + 1 is for the compaction file
+        let gen = self.current_gen + 1;
+        self.current_gen += 2;
+        self.writer = new_log_file(&self.path, self.current_gen)?;
+
+        let entries = self
+            .index
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+        // the writes from now on go to the new generation, so the stale data counted so
+        // far is reclaimed by this compaction
+        let uncompacted = self.uncompacted;
+        self.uncompacted = 0;
+        self.unreclaimable = 0;
+        Ok(Compaction {
+            gen,
+            safe_point: gen,
+            entries,
+            uncompacted,
+            sync: self.sync_policy != SyncPolicy::Never,
+        })
+    }
+
+    /// Starts a compaction of the oldest `max_gens` generations if at least `dead_ratio`
+    /// of their size is stale, by switching writes to a new generation and taking a
+    /// snapshot of the index entries in them.
+    ///
+    /// Writes switch to a new generation in any case, so that the stale data of the
+    /// active one can be merged by a later compaction.
+    fn begin_tiered_compaction(
+        &mut self,
+        max_gens: usize,
+        dead_ratio: f64,
+    ) -> Result<Option<Compaction>> {
+        // like `begin_compaction`, current_gen + 1 is for the compaction file
+        let gen = self.current_gen + 1;
+        self.current_gen += 2;
+        self.writer = new_log_file(&self.path, self.current_gen)?;
+        // whatever happens below, the stale data so far doesn't trigger another compaction
+        self.unreclaimable = self.uncompacted;
+
+        // Only a run of the oldest generations can be merged: a remove in them may hide
+        // a value in any older generation, so there must be none left.
+        let safe_point = self.reader.safe_point.load(Ordering::SeqCst);
+        let gens: Vec<u64> = sorted_gen_list(&self.path)?
+            .into_iter()
+            .filter(|&g| g >= safe_point && g < gen)
+            .take(max_gens.max(1))
+            .collect();
+        let last = match gens.last() {
+            Some(&last) => last,
+            None => return Ok(None),
+        };
+        let mut total = 0;
+        for &g in &gens {
+            total += log_size(&self.path, g)?;
+        }
+        let entries: Vec<(String, CommandPos)> = self
+            .index
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .filter(|(_, cmd_pos)| cmd_pos.gen <= last)
+            .collect();
+        let live: u64 = entries.iter().map(|(_, cmd_pos)| cmd_pos.len).sum();
+        // a compressed generation is smaller than its records, so this is an estimate
+        let dead = total.saturating_sub(live);
+        if total == 0 || (dead as f64) < dead_ratio * total as f64 {
+            return Ok(None);
+        }
+
+        self.uncompacted = self.uncompacted.saturating_sub(dead);
+        self.unreclaimable = self.uncompacted;
+        Ok(Some(Compaction {
+            gen,
+            safe_point: last + 1,
+            entries,
+            uncompacted: dead,
+            sync: self.sync_policy != SyncPolicy::Never,
+        }))
+    }
+
+    /// Points the index to the compaction file and removes the logs before `safe_point`.
+    ///
+    /// Keys written or removed since the compaction began are left alone.
+    fn finish_compaction(&mut self, safe_point: u64, moved: Vec<(String, CommandPos, CommandPos)>) {
+        for (key, old_pos, new_pos) in moved {
+            if let Some(entry) = self.index.get(&key) {
+                if *entry.value() == old_pos {
+                    self.reader.cache.moved(&key, old_pos, new_pos);
+                    self.index.insert(key, new_pos);
+                }
+            }
+        }
+        // whatever still points to a stale generation is an expired entry
+        for entry in self.index.iter() {
+            if entry.value().gen < safe_point {
+                entry.remove();
+                self.reader.cache.remove(entry.key());
+            }
+        }
+
+        self.reader.safe_point.store(safe_point, Ordering::SeqCst);
+        self.reader.close_stale_handles();
+
+        // remove stale log files
+        // Note that actually these files are not deleted immediately because `KvStoreReader`s
+        // still keep open file handles. When `KvStoreReader` is used next time, it will clear
+        // its stale file handles. On Unix, the files will be deleted after all the handles
+        // are closed. On Windows, the deletions below will fail and stale files are expected
+        // to be deleted in the next compaction.
+        if let Err(e) = self.remove_stale_logs(safe_point) {
+            error!("Stale logs cannot be removed: {}", e);
+        }
+    }
+
+    /// Copies the live external values to a new value log and removes the older ones.
+    ///
+    /// Returns the number of bytes of value logs freed.
+    fn gc_value_logs(&mut self) -> Result<u64> {
+        let old_gens = sorted_vlog_gen_list(&self.path)?;
+        if old_gens.is_empty() {
+            return Ok(0);
+        }
+        let mut old_bytes = 0;
+        for &gen in &old_gens {
+            old_bytes += fs::metadata(vlog_path(&self.path, gen))?.len();
+        }
+        self.vlog_gen += 1;
+        self.vlog = None;
+
+        // copy the values and serialize the new pointers first, so that a failure leaves
+        // both the log and the index untouched
+        let entries: Vec<(String, CommandPos)> = self
+            .index
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+        let now = now_millis();
+        let mut buf = Vec::new();
+        let mut moved = Vec::new();
+        for (key, cmd_pos) in entries {
+            if cmd_pos.is_expired(now) {
+                continue;
+            }
+            if let Command::SetExternal {
+                value,
+                expires_at,
+                compressed,
+                ..
+            } = self.reader.read_command(cmd_pos)?
+            {
+                let value = self.reader.read_external(value)?;
+                let value = self.write_external(&value)?;
+                let cmd = Command::SetExternal {
+                    key: key.clone(),
+                    value,
+                    expires_at,
+                    compressed,
+                };
+                let start = buf.len() as u64;
+                write_record(&mut buf, &cmd, self.reader.format)?;
+                moved.push((key, cmd_pos, start..buf.len() as u64));
+            }
+        }
+
+        let base = self.writer.pos;
+        self.writer.write_all(&buf)?;
+        self.writer.flush()?;
+        // the old value logs are removed below, so the new pointers must not be lost
+        // whatever the sync policy
+        self.sync()?;
+        for (key, old_pos, range) in moved {
+            self.uncompacted += old_pos.len;
+            let cmd_pos =
+                CommandPos::from((self.current_gen, base + range.start..base + range.end))
+                    .expiring_at(old_pos.expires_at);
+            self.reader.cache.moved(&key, old_pos, cmd_pos);
+            self.index.insert(key, cmd_pos);
+        }
+
+        let new_bytes = self.vlog.as_ref().map_or(0, |vlog| vlog.pos);
+        self.reader
+            .vlog_safe_point
+            .store(self.vlog_gen, Ordering::SeqCst);
+        self.reader.close_stale_vlogs();
+        self.remove_stale_vlogs(self.vlog_gen)?;
+        Ok(old_bytes.saturating_sub(new_bytes))
+    }
+
+    /// Replaces the whole content of the store with the generations in `other_dir`.
+    ///
+    /// The generations are staged under their numbers behind the current one and
+    /// replayed into a new index first, so the store is left untouched if `other_dir` is
+    /// not a valid store. Writing the replace marker commits the new generations, and the
+    /// index is swapped with the swap epoch odd.
+    ///
+    /// It returns `KvsError::Unsupported` if `other_dir` has value logs, as the pointers
+    /// to them don't survive the renumbering of the generations.
+    fn replace_contents_from(&mut self, other_dir: &Path) -> Result<()> {
+        if self.path.memory.is_some() {
+            return Err(KvsError::Unsupported);
+        }
+        let other_dir = &LogDir {
+            path: other_dir.to_owned(),
+            extension: self.path.extension.clone(),
+            memory: None,
+        };
+        if !sorted_vlog_gen_list(other_dir)?.is_empty() {
+            return Err(KvsError::Unsupported);
+        }
+        let other_gens = sorted_gen_list(other_dir)?;
+        let first_gen = self.current_gen + 1;
+        let staging = LogDir {
+            path: self.path.staging_path(),
+            extension: self.path.extension.clone(),
+            memory: None,
+        };
+        // left by a replacement that failed before it was committed
+        if staging.path.exists() {
+            fs::remove_dir_all(&staging.path)?;
+        }
+        fs::create_dir(&staging.path)?;
+        let new_index = SkipMap::new();
+        let mut uncompacted = 0;
+
+        let res = (|| -> Result<()> {
+            for (i, &gen) in other_gens.iter().enumerate() {
+                let new_gen = first_gen + i as u64;
+                let (src, dst) = if log_path(other_dir, gen).is_file() {
+                    (log_path(other_dir, gen), log_path(&staging, new_gen))
+                } else {
+                    (
+                        compressed_log_path(other_dir, gen),
+                        compressed_log_path(&staging, new_gen),
+                    )
+                };
+                fs::copy(&src, &dst)?;
+                File::open(&dst)?.sync_all()?;
+                let mut reader = BufReaderWithPos::new(LogFile::open(&staging, new_gen)?)?;
+                uncompacted += load(new_gen, &mut reader, &new_index, self.reader.format)?;
+            }
+            sync_dir(&staging.path)?;
+            Ok(())
+        })();
+        if let Err(e) = res {
+            if let Err(e) = fs::remove_dir_all(&staging.path) {
+                error!("{:?} cannot be deleted: {}", staging.path, e);
+            }
+            return Err(e);
+        }
+
+        // From here on an open after a crash finishes the replacement.
+        write_replace_marker(&self.path, first_gen)?;
+        move_staged_logs(&self.path)?;
+        self.current_gen = first_gen + other_gens.len() as u64;
+        self.writer = new_log_file(&self.path, self.current_gen)?;
+
+        // Readers wait while the epoch is odd and read again if it changed, so none of
+        // them sees part of the old and part of the new index.
+        self.reader.swap_epoch.fetch_add(1, Ordering::SeqCst);
+        for entry in new_index.iter() {
+            self.index.insert(entry.key().clone(), *entry.value());
+        }
+        for entry in self.index.iter() {
+            if !new_index.contains_key(entry.key()) {
+                self.index.remove(entry.key());
+            }
+        }
+        self.reader.cache.clear();
+        // All the old generations are obsolete now, which is the same situation as
+        // after a compaction.
+        self.reader.safe_point.store(first_gen, Ordering::SeqCst);
+        self.reader.swap_epoch.fetch_add(1, Ordering::SeqCst);
+
+        self.reader.close_stale_handles();
+        self.remove_stale_logs(first_gen)?;
+        self.uncompacted = uncompacted;
+        self.unreclaimable = 0;
+
+        // The marker has to stay while a snapshot pins an old generation, so that the
+        // next open deletes it instead of replaying it.
+        if sorted_gen_list(&self.path)?.first() >= Some(&first_gen) {
+            fs::remove_file(self.path.replace_marker_path())?;
+        }
+        Ok(())
+    }
+
+    /// Empties the index and removes all the generations before a new one.
+    fn purge(&mut self) -> Result<()> {
+        let keys: Vec<String> = self.index.iter().map(|entry| entry.key().clone()).collect();
+        self.current_gen += 1;
+        self.writer = new_log_file(&self.path, self.current_gen)?;
+
+        // the old logs may outlive the purge, see `KvStore::purge`
+        let current_gen = self.current_gen;
+        let mut buf = Vec::new();
+        if sorted_gen_list(&self.path)?
+            .into_iter()
+            .any(|gen| gen < current_gen)
+        {
+            for key in keys {
+                write_record(&mut buf, &Command::remove(key), self.reader.format)?;
+            }
+            self.writer.write_all(&buf)?;
+            self.writer.flush()?;
+            self.sync()?;
+        }
+
+        self.index.clear();
+        self.reader.cache.clear();
+        self.uncompacted = buf.len() as u64;
+        self.unreclaimable = 0;
+
+        self.reader
+            .safe_point
+            .store(self.current_gen, Ordering::SeqCst);
+        self.reader.close_stale_handles();
+        self.remove_stale_logs(self.current_gen)?;
+
+        self.vlog_gen += 1;
+        self.vlog = None;
+        self.reader
+            .vlog_safe_point
+            .store(self.vlog_gen, Ordering::SeqCst);
+        self.reader.close_stale_vlogs();
+        self.remove_stale_vlogs(self.vlog_gen)?;
+        self.sync_after_write()
+    }
+
+    /// Removes the log files with generation number less than `safe_point`, except the
+    /// ones pinned by a snapshot.
+    fn remove_stale_logs(&self, safe_point: u64) -> Result<()> {
+        let safe_point = self.pins.removable_before(safe_point);
+        let stale_gens = sorted_gen_list(&self.path)?
+            .into_iter()
+            .filter(|&gen| gen < safe_point);
+        for stale_gen in stale_gens {
+            if let Some(logs) = &self.path.memory {
+                logs.remove(stale_gen);
+                continue;
+            }
+            for file_path in &[
+                log_path(&self.path, stale_gen),
+                compressed_log_path(&self.path, stale_gen),
+            ] {
+                if !file_path.exists() {
+                    continue;
+                }
+                if let Err(e) = fs::remove_file(file_path) {
+                    error!("{:?} cannot be deleted: {}", file_path, e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the value logs with generation number less than `safe_point`, except the
+    /// ones pinned by a snapshot.
+    fn remove_stale_vlogs(&self, safe_point: u64) -> Result<()> {
+        let safe_point = self.pins.removable_vlogs_before(safe_point);
+        for stale_gen in sorted_vlog_gen_list(&self.path)? {
+            if stale_gen >= safe_point {
+                break;
+            }
+            let file_path = vlog_path(&self.path, stale_gen);
+            if let Err(e) = fs::remove_file(&file_path) {
+                error!("{:?} cannot be deleted: {}", file_path, e);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for KvStoreWriter {
+    // Every write flushes already, this keeps buffered data from being lost if one
+    // doesn't. Unless syncing is left to the OS, the log is synced as well.
+    fn drop(&mut self) {
+        // the values are flushed as they are written
+        let res = match self.sync_policy {
+            SyncPolicy::Never => self.writer.flush().map_err(KvsError::from),
+            _ => self.sync(),
+        };
+        if let Err(e) = res {
+            error!("Log {} cannot be flushed on drop: {}", self.current_gen, e);
+        }
+    }
+}
+
+/// A compaction in progress.
+///
+/// It begins and finishes under the writer lock, but copies the live entries into the
+/// compaction file without holding it, so writes can go on meanwhile.
+struct Compaction {
+    // generation of the compaction file
+    gen: u64,
+    // the generations before it are obsolete once the compaction finishes
+    safe_point: u64,
+    // snapshot of the index when the compaction began
+    entries: Vec<(String, CommandPos)>,
+    // stale data reclaimed by the compaction
+    uncompacted: u64,
+    // whether to sync the compaction file before the stale logs are removed
+    sync: bool,
+}
 
-    // 安全水位线，记录了最新一次压缩产生的文件代号民，是读线程和写线程之间的信号号
-    // 压缩发生时，旧的日志文件会被合并成一个新的大文件
-    // atomic64 保证了原子性，多个线程可以安全的读取
-    // 作用：防止读取已经失效或被删除的旧文件，如果reader试图访问一个小于 safe_point 的是文件id，或能需要重定向去读新的压缩文件，或者直接报错
-    // generation of the latest compaction file
-    safe_point: Arc<AtomicU64>,
+impl Compaction {
+    /// Copies the live entries of the snapshot into the compaction file, reporting the
+    /// bytes copied to `progress`.
+    ///
+    /// Returns the key, old position and new position of each copied entry, or
+    /// `KvsError::Cancelled` as soon as `cancel` is set.
+    fn copy(
+        &self,
+        reader: &KvStoreReader,
+        progress: &mut dyn FnMut(CompactionProgress),
+        cancel: &AtomicBool,
+    ) -> Result<Vec<(String, CommandPos, CommandPos)>> {
+        let mut compaction_writer = new_log_file(&reader.path, self.gen)?;
+        let now = now_millis();
+        // expired entries are not copied forward
+        let live: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|(_, cmd_pos)| !cmd_pos.is_expired(now))
+            .collect();
+        let total_bytes = live.iter().map(|(_, cmd_pos)| cmd_pos.len).sum();
+        let mut new_pos = 0; // pos in the new log file
+        let mut reported = 0;
+        let mut moved = Vec::new();
+        for (key, cmd_pos) in live {
+            if cancel.load(Ordering::SeqCst) {
+                return Err(KvsError::Cancelled);
+            }
+            let len = reader.read_and(*cmd_pos, |mut entry_reader| {
+                Ok(io::copy(&mut entry_reader, &mut compaction_writer)?)
+            })?;
+            let new_cmd_pos = CommandPos::from((self.gen, new_pos..new_pos + len));
+            moved.push((
+                key.clone(),
+                *cmd_pos,
+                new_cmd_pos.expiring_at(cmd_pos.expires_at),
+            ));
+            new_pos += len;
+            if new_pos - reported >= COMPACTION_PROGRESS_INTERVAL {
+                reported = new_pos;
+                progress(CompactionProgress {
+                    bytes_copied: new_pos,
+                    total_bytes,
+                });
+            }
+        }
+        // Only point the index to the compaction file after it's flushed, otherwise
+        // concurrent readers may see a truncated command.
+        compaction_writer.flush()?;
+        // The stale logs are removed afterwards, so the compacted data must not be lost
+        if self.sync {
+            compaction_writer.sync_data()?;
+        }
+        progress(CompactionProgress {
+            bytes_copied: new_pos,
+            total_bytes,
+        });
+        Ok(moved)
+    }
+}
+
+/// Compacts the store following its compaction strategy if its stale data exceeds
+/// `threshold`.
+///
+/// The caller must hold the compaction lock.
+fn compact(writer: &Mutex<KvStoreWriter>, reader: &KvStoreReader, threshold: u64) -> Result<()> {
+    compact_with(
+        writer,
+        reader,
+        threshold,
+        &mut |_| {},
+        &AtomicBool::new(false),
+    )
+}
+
+/// Compacts the store like `compact`, reporting its progress and stopping if `cancel` is
+/// set, see `Compaction::copy`.
+fn compact_with(
+    writer: &Mutex<KvStoreWriter>,
+    reader: &KvStoreReader,
+    threshold: u64,
+    progress: &mut dyn FnMut(CompactionProgress),
+    cancel: &AtomicBool,
+) -> Result<()> {
+    let compaction = {
+        let mut writer = writer.lock().unwrap();
+        if !writer.needs_compaction(threshold) {
+            return Ok(());
+        }
+        match writer.begin_compaction_by_strategy()? {
+            Some(compaction) => compaction,
+            None => return Ok(()),
+        }
+    };
+    run_compaction(writer, reader, compaction, progress, cancel)
+}
+
+/// Copies the live data of a compaction begun by the `KvStoreWriter`, then switches the
+/// index to the copy.
+///
+/// If the copy fails or is cancelled, the compaction file is removed and nothing else
+/// changes: the index still points to the old generations.
+fn run_compaction(
+    writer: &Mutex<KvStoreWriter>,
+    reader: &KvStoreReader,
+    compaction: Compaction,
+    progress: &mut dyn FnMut(CompactionProgress),
+    cancel: &AtomicBool,
+) -> Result<()> {
+    match compaction.copy(reader, progress, cancel) {
+        Ok(moved) => {
+            writer
+                .lock()
+                .unwrap()
+                .finish_compaction(compaction.safe_point, moved);
+            Ok(())
+        }
+        Err(e) => {
+            if let Some(logs) = &reader.path.memory {
+                logs.remove(compaction.gen);
+            } else {
+                let file_path = log_path(&reader.path, compaction.gen);
+                if let Err(e) = fs::remove_file(&file_path) {
+                    error!("{:?} cannot be deleted: {}", file_path, e);
+                }
+            }
+            writer.lock().unwrap().uncompacted += compaction.uncompacted;
+            Err(e)
+        }
+    }
+}
+
+/// Create a new log file with given generation number and add the reader to the readers map.
+///
+/// Returns the writer to the log.
+fn new_log_file(dir: &LogDir, gen: u64) -> Result<BufWriterWithPos<LogWriter>> {
+    if let Some(logs) = &dir.memory {
+        return BufWriterWithPos::new(LogWriter::Memory(logs.create(gen)));
+    }
+    let path = log_path(dir, gen);
+    let writer = BufWriterWithPos::new(LogWriter::File(
+        OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(true)
+            .open(&path)?,
+    ))?;
+    Ok(writer)
+}
+
+/// Create a new value log with given generation number.
+fn new_vlog_file(dir: &LogDir, gen: u64) -> Result<BufWriterWithPos<LogWriter>> {
+    if dir.memory.is_some() {
+        return Err(KvsError::Unsupported);
+    }
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(vlog_path(dir, gen))?;
+    BufWriterWithPos::new(LogWriter::File(file))
+}
+
+/// Returns sorted generation numbers of the value logs in the given directory.
+fn sorted_vlog_gen_list(dir: &LogDir) -> Result<Vec<u64>> {
+    if dir.memory.is_some() {
+        return Ok(Vec::new());
+    }
+    let suffix = vlog_suffix(dir);
+    let mut gen_list: Vec<u64> = fs::read_dir(&dir.path)?
+        .flat_map(|res| -> Result<_> { Ok(res?.path()) })
+        .filter(|path| path.is_file())
+        .flat_map(|path| {
+            path.file_name()
+                .and_then(OsStr::to_str)
+                .and_then(|s| s.strip_suffix(&suffix))
+                .map(str::parse::<u64>)
+        })
+        .flatten()
+        .collect();
+    gen_list.sort_unstable();
+    Ok(gen_list)
+}
+
+/// Returns sorted generation numbers in the given directory
+///
+/// Both plain (`.log`) and compressed (`.log.gz`) generations are listed, with the
+/// extension of `dir` in place of `log`.
+fn sorted_gen_list(dir: &LogDir) -> Result<Vec<u64>> {
+    if let Some(logs) = &dir.memory {
+        return Ok(logs.logs.lock().unwrap().keys().copied().collect());
+    }
+    let plain_suffix = format!(".{}", dir.extension);
+    let compressed_suffix = format!(".{}.gz", dir.extension);
+    let mut gen_list: Vec<u64> = fs::read_dir(&dir.path)?
+        .flat_map(|res| -> Result<_> { Ok(res?.path()) })
+        .filter(|path| path.is_file())
+        .flat_map(|path| {
+            path.file_name()
+                .and_then(OsStr::to_str)
+                .and_then(|s| {
+                    s.strip_suffix(&plain_suffix)
+                        .or_else(|| s.strip_suffix(&compressed_suffix))
+                })
+                .map(str::parse::<u64>)
+        })
+        .flatten()
+        .collect();
+    gen_list.sort_unstable();
+    // a generation may briefly exist in both forms while it is being compressed
+    gen_list.dedup();
+    Ok(gen_list)
+}
+
+/// Load the whole log file and store value locations in the index map.
+///
+/// Returns how many bytes can be saved after a compaction.
+fn load(
+    gen: u64,
+    reader: &mut BufReaderWithPos<LogFile>,
+    index: &SkipMap<String, CommandPos>,
+    format: LogFormat,
+) -> Result<u64> {
+    match replay(gen, reader, index, format, &mut |_| {})? {
+        (uncompacted, None) => Ok(uncompacted),
+        (uncompacted, Some(offset)) if is_torn_tail(reader, offset)? => Ok(uncompacted),
+        (_, Some(offset)) => Err(KvsError::CorruptLog { gen, offset }),
+    }
+}
+
+/// Returns whether the corrupt record at `offset` was cut short by a crash while it was
+/// appended: it's the last record and runs past the end of the log, or there are only
+/// zeros from `offset` on, as some file systems leave after a crash. Any other corrupt
+/// record is corruption in the middle of the log.
+fn is_torn_tail(reader: &mut BufReaderWithPos<LogFile>, offset: u64) -> Result<bool> {
+    let remaining = reader.seek(SeekFrom::End(0))? - offset;
+    if remaining < RECORD_HEADER_LEN as u64 {
+        return Ok(true);
+    }
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut header = [0; RECORD_HEADER_LEN];
+    reader.read_exact(&mut header)?;
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap());
+    if RECORD_HEADER_LEN as u64 + u64::from(len) > remaining {
+        // a corrupt length in the middle of the log runs past the end as well, but is
+        // followed by intact records
+        return Ok(!contains_record(reader)?);
+    }
+    if header != [0; RECORD_HEADER_LEN] {
+        return Ok(false);
+    }
+    let mut buf = [0; 4096];
+    loop {
+        match reader.read(&mut buf)? {
+            0 => return Ok(true),
+            n if buf[..n].iter().any(|&b| b != 0) => return Ok(false),
+            _ => {}
+        }
+    }
+}
+
+/// Returns whether an intact record starts anywhere in the rest of `reader`.
+///
+/// Every offset is a candidate, so the rest is streamed through a window holding the
+/// longest record looked for, and only the candidates of at most `MAX_RESYNC_LEN` bytes
+/// are hashed. This keeps a corrupt length early in a large log from reading the whole
+/// log into memory.
+fn contains_record(reader: &mut impl Read) -> Result<bool> {
+    let window_len = 2 * (RECORD_HEADER_LEN + MAX_RESYNC_LEN);
+    let mut window = Vec::new();
+    let mut start = 0;
+    let mut eof = false;
+    loop {
+        if !eof && window.len() - start < RECORD_HEADER_LEN + MAX_RESYNC_LEN {
+            window.drain(..start);
+            start = 0;
+            let want = window_len - window.len();
+            eof = reader.by_ref().take(want as u64).read_to_end(&mut window)? < want;
+        }
+        let bytes = &window[start..];
+        if bytes.len() <= RECORD_HEADER_LEN {
+            return Ok(false);
+        }
+        let len = u32::from_le_bytes(bytes[..4].try_into().unwrap()) as usize;
+        let checksum = u32::from_le_bytes(bytes[4..RECORD_HEADER_LEN].try_into().unwrap());
+        let payload = &bytes[RECORD_HEADER_LEN..];
+        if len > 0
+            && len <= MAX_RESYNC_LEN
+            && len <= payload.len()
+            && crc32fast::hash(&payload[..len]) == checksum
+        {
+            return Ok(true);
+        }
+        start += 1;
+    }
+}
+
+/// Replay the log file into the index until its end or its first corrupt record.
+///
+/// `progress` is called with the offset replayed so far every
+/// `RECOVERY_PROGRESS_INTERVAL` bytes.
+///
+/// Returns how many bytes can be saved after a compaction and the offset of the
+/// first corrupt record if there is one.
+fn replay(
+    gen: u64,
+    reader: &mut BufReaderWithPos<LogFile>,
+    index: &SkipMap<String, CommandPos>,
+    format: LogFormat,
+    progress: &mut dyn FnMut(u64),
+) -> Result<(u64, Option<u64>)> {
+    // To make sure we read from the beginning of the file
+    let mut pos = reader.seek(SeekFrom::Start(0))?;
+    let mut last_progress = pos;
+    let mut uncompacted = 0; // number of bytes that can be saved after a compaction
+    loop {
+        let entry = match read_entry(reader, gen, pos, format) {
+            Ok(Some(entry)) => entry,
+            Ok(None) => return Ok((uncompacted, None)),
+            Err(KvsError::CorruptLog { offset, .. }) => return Ok((uncompacted, Some(offset))),
+            Err(e) => return Err(e),
+        };
+        uncompacted += apply_entry(index, entry);
+        pos = reader.pos;
+        if pos - last_progress >= RECOVERY_PROGRESS_INTERVAL {
+            progress(pos);
+            last_progress = pos;
+        }
+    }
+}
+
+/// Scan the log file of a generation for `KvStore::verify`.
+///
+/// The valid records are applied to `replayed`, and the location of the sets recorded
+/// in `records` with their length and key. Unlike `replay`, a corrupt record is skipped
+/// by the length in its header, which may be corrupt as well.
+fn verify_log(
+    gen: u64,
+    reader: &mut BufReaderWithPos<LogFile>,
+    format: LogFormat,
+    replayed: &SkipMap<String, CommandPos>,
+    records: &mut HashMap<(u64, u64), (u64, String)>,
+    report: &mut VerifyReport,
+) -> Result<()> {
+    let end = reader.seek(SeekFrom::End(0))?;
+    let mut pos = reader.seek(SeekFrom::Start(0))?;
+    while pos < end {
+        match read_entry(reader, gen, pos, format) {
+            Ok(Some(entry)) => {
+                report.valid_records += 1;
+                if let LogEntry::Set { ref key, cmd_pos } = entry {
+                    records.insert((gen, pos), (cmd_pos.len, key.clone()));
+                }
+                apply_entry(replayed, entry);
+                pos = reader.pos;
+            }
+            Ok(None) => break,
+            Err(KvsError::CorruptLog { .. })
+            | Err(KvsError::Serde(_))
+            | Err(KvsError::Bincode(_)) => {
+                report.corrupt_records.push(RecordLocation {
+                    generation: gen,
+                    offset: pos,
+                });
+                if end - pos < RECORD_HEADER_LEN as u64 {
+                    break;
+                }
+                reader.seek(SeekFrom::Start(pos))?;
+                let mut header = [0; RECORD_HEADER_LEN];
+                reader.read_exact(&mut header)?;
+                let len = u32::from_le_bytes(header[..4].try_into().unwrap());
+                pos += RECORD_HEADER_LEN as u64 + u64::from(len);
+                reader.seek(SeekFrom::Start(pos))?;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// The commands of a log file, parsed without touching the index.
+struct ParsedLog {
+    entries: Vec<LogEntry>,
+    // the offset of the first corrupt record, where parsing stopped
+    corrupt_offset: Option<u64>,
+}
+
+/// Parse the whole log file like `replay`, but into a list of entries to apply later.
+fn parse_log(
+    gen: u64,
+    reader: &mut BufReaderWithPos<LogFile>,
+    format: LogFormat,
+) -> Result<ParsedLog> {
+    let mut pos = reader.seek(SeekFrom::Start(0))?;
+    let mut entries = Vec::new();
+    loop {
+        let corrupt_offset = match read_entry(reader, gen, pos, format) {
+            Ok(Some(entry)) => {
+                entries.push(entry);
+                pos = reader.pos;
+                continue;
+            }
+            Ok(None) => None,
+            Err(KvsError::CorruptLog { offset, .. }) => Some(offset),
+            Err(e) => return Err(e),
+        };
+        return Ok(ParsedLog {
+            entries,
+            corrupt_offset,
+        });
+    }
+}
+
+/// Parse the log files on a pool of `threads` threads.
+///
+/// The readers are handed back along with the parsed logs, in the same order.
+fn parse_logs_in_parallel(
+    logs: Vec<(u64, BufReaderWithPos<LogFile>)>,
+    format: LogFormat,
+    threads: u32,
+) -> Result<Vec<(BufReaderWithPos<LogFile>, ParsedLog)>> {
+    let pool = SharedQueueThreadPool::new(threads)?;
+    let (tx, rx) = channel::unbounded();
+    let count = logs.len();
+    for (i, (gen, mut reader)) in logs.into_iter().enumerate() {
+        let tx = tx.clone();
+        pool.spawn(move || {
+            let res = parse_log(gen, &mut reader, format).map(|parsed| (reader, parsed));
+            // the receiver only goes away after an error in another log
+            let _ = tx.send((i, res));
+        });
+    }
+    drop(tx);
+
+    let mut parsed: Vec<_> = (0..count).map(|_| None).collect();
+    for _ in 0..count {
+        let (i, res) = rx
+            .recv()
+            .map_err(|_| KvsError::StringError("Log replay thread panicked".to_owned()))?;
+        parsed[i] = Some(res?);
+    }
+    Ok(parsed.into_iter().map(Option::unwrap).collect())
+}
+
+/// A command read from the log, along with its location.
+enum LogEntry {
+    Set { key: String, cmd_pos: CommandPos },
+    Remove { key: String, len: u64 },
+}
+
+/// Read the record at `pos` of generation `gen` as a `LogEntry`.
+fn read_entry(
+    reader: &mut BufReaderWithPos<LogFile>,
+    gen: u64,
+    pos: u64,
+    format: LogFormat,
+) -> Result<Option<LogEntry>> {
+    let cmd = match read_record(reader, gen, pos, format)? {
+        Some(cmd) => cmd,
+        None => return Ok(None),
+    };
+    let new_pos = reader.pos;
+    Ok(Some(match cmd {
+        Command::Set {
+            key, expires_at, ..
+        }
+        | Command::SetExternal {
+            key, expires_at, ..
+        } => LogEntry::Set {
+            key,
+            cmd_pos: CommandPos::from((gen, pos..new_pos)).expiring_at(expires_at),
+        },
+        Command::Remove { key } => LogEntry::Remove {
+            key,
+            len: new_pos - pos,
+        },
+    }))
+}
+
+/// Apply a replayed entry to the index.
+///
+// The number of keys of the index that haven't expired at `now`.
+fn count_live(index: &SkipMap<String, CommandPos>, now: u64) -> usize {
+    index
+        .iter()
+        .filter(|entry| !entry.value().is_expired(now))
+        .count()
+}
+
+/// Returns how many more bytes can be saved after a compaction: the length of the
+/// record it replaces, which may be in the same generation, and of a remove record.
+/// A record already in the index is logged as a bug and counts nothing, so that it
+/// can't skew when compactions are triggered.
+fn apply_entry(index: &SkipMap<String, CommandPos>, entry: LogEntry) -> u64 {
+    let mut uncompacted = 0;
+    match entry {
+        LogEntry::Set { key, cmd_pos } => {
+            if let Some(old_cmd) = index.get(&key) {
+                let old_cmd = *old_cmd.value();
+                if old_cmd.gen == cmd_pos.gen && old_cmd.pos == cmd_pos.pos {
+                    // the same record applied twice, which makes nothing stale
+                    error!(
+                        "Record at {} of generation {} loaded twice",
+                        cmd_pos.pos, cmd_pos.gen
+                    );
+                    return 0;
+                }
+                uncompacted += old_cmd.len;
+            }
+            if cmd_pos.is_expired(now_millis()) {
+                // already expired, so it's as good as removed
+                index.remove(&key);
+                uncompacted += cmd_pos.len;
+            } else {
+                index.insert(key, cmd_pos);
+            }
+        }
+        LogEntry::Remove { key, len } => {
+            if let Some(old_cmd) = index.remove(&key) {
+                uncompacted += old_cmd.value().len;
+            }
+            // the "remove" command itself can be deleted in the next compaction
+            // so we add its length to `uncompacted`
+            uncompacted += len;
+        }
+    }
+    uncompacted
+}
+
+// Length of the record header: the payload length and its CRC32 checksum, both
+// little-endian `u32`s.
+const RECORD_HEADER_LEN: usize = 8;
+
+/// Write a command as a record: the header followed by the serialized command.
+fn write_record<W: Write>(writer: &mut W, cmd: &Command, format: LogFormat) -> Result<()> {
+    let payload = format.serialize(cmd)?;
+    let mut header = [0; RECORD_HEADER_LEN];
+    header[..4].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+    header[4..].copy_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    writer.write_all(&header)?;
+    writer.write_all(&payload)?;
+    Ok(())
+}
+
+/// Read a record written by `write_record` at `offset` of generation `gen`.
+///
+/// Returns `None` at the end of the log, and `KvsError::CorruptLog` if the record is
+/// truncated, empty or fails its checksum. A record that passes its checksum but can't be
+/// parsed is not corrupt, e.g. the store is opened with the wrong `LogFormat`, so a
+/// serialization error is returned instead.
+fn read_record<R: Read>(
+    reader: &mut R,
+    gen: u64,
+    offset: u64,
+    format: LogFormat,
+) -> Result<Option<Command>> {
+    let corrupt = || KvsError::CorruptLog { gen, offset };
+
+    let mut header = [0; RECORD_HEADER_LEN];
+    let mut header_len = 0;
+    while header_len < RECORD_HEADER_LEN {
+        match reader.read(&mut header[header_len..]) {
+            Ok(0) => break,
+            Ok(n) => header_len += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    if header_len == 0 {
+        return Ok(None);
+    } else if header_len < RECORD_HEADER_LEN {
+        return Err(corrupt());
+    }
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let checksum = u32::from_le_bytes(header[4..].try_into().unwrap());
+
+    // don't trust `len` to allocate the buffer, it may be corrupt as well
+    let mut payload = Vec::new();
+    reader.take(u64::from(len)).read_to_end(&mut payload)?;
+    // a command is never serialized to nothing, an empty record is zeros on the disk
+    if len == 0 || payload.len() != len as usize || crc32fast::hash(&payload) != checksum {
+        return Err(corrupt());
+    }
+    format.deserialize(&payload).map(Some)
+}
+
+// Current unix time in milliseconds.
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Returns the exclusive upper bound of the keys starting with `prefix`.
+///
+/// UTF-8 preserves the order of code points, so it's the prefix with its last char
+/// incremented. A last char that can't be incremented is dropped and the one before it is
+/// incremented instead, and no such char left means there is no upper bound.
+fn prefix_end(prefix: &str) -> Bound<String> {
+    let mut end = prefix.to_owned();
+    while let Some(last) = end.pop() {
+        let next = (last as u32 + 1..=char::MAX as u32).find_map(std::char::from_u32);
+        if let Some(next) = next {
+            end.push(next);
+            return Bound::Excluded(end);
+        }
+    }
+    Bound::Unbounded
+}
+
+/// The directory of a store, and the extension of its log files.
+///
+/// Stores using different extensions don't see each other's logs, so they can share a
+/// directory.
+#[derive(Debug)]
+struct LogDir {
+    path: PathBuf,
+    extension: String,
+    // set if the logs are kept in memory, leaving `path` unused
+    memory: Option<MemoryLogs>,
+}
+
+impl LogDir {
+    // A file of the store other than a log, suffixed with the extension unless it's the
+    // default one.
+    fn meta_path(&self, name: &str) -> PathBuf {
+        if self.extension == DEFAULT_LOG_EXTENSION {
+            self.path.join(name)
+        } else {
+            self.path.join(format!("{}.{}", name, self.extension))
+        }
+    }
+
+    // The file locked by the store. The default extension keeps the `LOCK` file that
+    // `SledKvsEngine` locks as well.
+    fn lock_path(&self) -> PathBuf {
+        self.meta_path(LOCK_FILE)
+    }
+
+    // The file recording the format of the logs, named like the lock file.
+    fn format_path(&self) -> PathBuf {
+        self.meta_path(FORMAT_FILE)
+    }
+
+    // The file recording the first generation of a committed replacement, see
+    // `KvStore::replace_contents_from`.
+    fn replace_marker_path(&self) -> PathBuf {
+        self.meta_path(REPLACE_FILE)
+    }
+
+    // The directory the generations of a replacement are staged in.
+    fn staging_path(&self) -> PathBuf {
+        self.meta_path(STAGING_DIR)
+    }
+}
+
+// Sync a directory, so that the files created, renamed or removed in it survive a
+// crash. A directory can't be opened on Windows, where it does nothing.
+fn sync_dir(path: &Path) -> io::Result<()> {
+    if cfg!(unix) {
+        File::open(path)?.sync_all()?;
+    }
+    Ok(())
+}
+
+// Commit a replacement whose generations start at `first_gen`. The marker is written
+// under a temporary name and renamed, so it is either whole or missing after a crash.
+fn write_replace_marker(dir: &LogDir, first_gen: u64) -> Result<()> {
+    let marker = dir.replace_marker_path();
+    let mut tmp_path = marker.clone().into_os_string();
+    tmp_path.push(".tmp");
+    let mut file = File::create(&tmp_path)?;
+    write!(file, "{}", first_gen)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, &marker)?;
+    sync_dir(&dir.path)?;
+    Ok(())
+}
+
+// Move the staged generations of a committed replacement into the store.
+fn move_staged_logs(dir: &LogDir) -> Result<()> {
+    let staging = dir.staging_path();
+    if !staging.is_dir() {
+        return Ok(());
+    }
+    for entry in fs::read_dir(&staging)? {
+        let entry = entry?;
+        fs::rename(entry.path(), dir.path.join(entry.file_name()))?;
+    }
+    sync_dir(&dir.path)?;
+    fs::remove_dir(&staging)?;
+    Ok(())
+}
+
+// Finish a replacement the process crashed in, before the logs are replayed: once the
+// marker is written, the staged generations are moved in and the generations before
+// them deleted. Without the marker the staged ones are discarded.
+fn recover_replace(dir: &LogDir) -> Result<()> {
+    let marker = dir.replace_marker_path();
+    let first_gen = match fs::read_to_string(&marker) {
+        Ok(contents) => contents.trim().parse::<u64>().map_err(|_| {
+            KvsError::StringError(format!("Invalid replace marker {}", marker.display()))
+        })?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            let staging = dir.staging_path();
+            if staging.exists() {
+                warn!(
+                    "Discarding uncommitted replacement in {}",
+                    staging.display()
+                );
+                fs::remove_dir_all(&staging)?;
+            }
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    };
+    warn!(
+        "Finishing interrupted replacement from generation {}",
+        first_gen
+    );
+    move_staged_logs(dir)?;
+    for gen in sorted_gen_list(dir)? {
+        if gen >= first_gen {
+            break;
+        }
+        for file_path in &[log_path(dir, gen), compressed_log_path(dir, gen)] {
+            if file_path.exists() {
+                fs::remove_file(file_path)?;
+            }
+        }
+    }
+    fs::remove_file(&marker)?;
+    sync_dir(&dir.path)?;
+    Ok(())
+}
+
+fn log_path(dir: &LogDir, gen: u64) -> PathBuf {
+    dir.path.join(format!("{}.{}", gen, dir.extension))
+}
+
+fn compressed_log_path(dir: &LogDir, gen: u64) -> PathBuf {
+    dir.path.join(format!("{}.{}.gz", gen, dir.extension))
+}
+
+// Cut the log of a generation down to `len` bytes.
+fn truncate_log(dir: &LogDir, gen: u64, len: u64) -> Result<()> {
+    if let Some(logs) = &dir.memory {
+        if let Some(log) = logs.logs.lock().unwrap().get(&gen) {
+            log.write().unwrap().truncate(len as usize);
+        }
+        return Ok(());
+    }
+    let file = OpenOptions::new().write(true).open(log_path(dir, gen))?;
+    file.set_len(len)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+// The size of the file of a generation, compressed or not.
+fn log_size(dir: &LogDir, gen: u64) -> io::Result<u64> {
+    if let Some(logs) = &dir.memory {
+        return match logs.logs.lock().unwrap().get(&gen) {
+            Some(log) => Ok(log.read().unwrap().len() as u64),
+            None => Err(io::ErrorKind::NotFound.into()),
+        };
+    }
+    fs::metadata(log_path(dir, gen))
+        .or_else(|_| fs::metadata(compressed_log_path(dir, gen)))
+        .map(|metadata| metadata.len())
+}
+
+fn vlog_path(dir: &LogDir, gen: u64) -> PathBuf {
+    dir.path.join(format!("{}{}", gen, vlog_suffix(dir)))
+}
 
-    // 在读的时候，还要修改reader的位置，但get方法的签名是 &self
-    // 这里还是没太懂
-    readers: RefCell<BTreeMap<u64, BufReaderWithPos<File>>>,
+// The suffix of the value logs after the generation, `.vlog` with the default log
+// extension.
+fn vlog_suffix(dir: &LogDir) -> String {
+    if dir.extension == DEFAULT_LOG_EXTENSION {
+        format!(".{}", VALUE_LOG_EXTENSION)
+    } else {
+        format!(".{}.{}", dir.extension, VALUE_LOG_EXTENSION)
+    }
 }
 
-impl KvStoreReader {
-    /// Close file handles with generation number less than safe_point.
-    ///
-    /// `safe_point` is updated to the latest compaction gen after a compaction finishes.
-    /// The compaction generation contains the sum of all operations before it and the
-    /// in-memory index contains no entries with generation number less than safe_point.
-    /// So we can safely close those file handles and the stale files can be deleted.
-    /// 关闭交移除那些已经被压缩过的，过期的文件handler，防止 handler 泄露
-    /// bitcask 中，执行 compact 后，旧文件中的有效数据搬到新的，
-    /// 更新水位，全局变量 safe_point 更新为3，id < 3的都是垃圾
-    ///
-    fn close_stale_handles(&self) {
-        // 获取写锁，RefCell
-        // 要从map 中删除元素，所以需要可变借用
-        let mut readers = self.readers.borrow_mut();
+/// A generation file opened for reading.
+///
+/// Compressed generations are decompressed into memory as a whole, so positions in
+/// `CommandPos` refer to the uncompressed bytes in both cases.
+enum LogFile {
+    Plain(File),
+    Compressed(io::Cursor<Arc<[u8]>>),
+    Memory(MemoryFile),
+}
 
-        while !readers.is_empty() {
-            // 拿出 map 中id 最小的言论的 id,BTreemap 是有序的，next 返回的永远是最小的
-            let first_gen = *readers.keys().next().unwrap();
-            if self.safe_point.load(Ordering::SeqCst) <= first_gen {
-                break;
+impl LogFile {
+    /// Opens the generation, preferring the plain log over the compressed one.
+    fn open(dir: &LogDir, gen: u64) -> Result<LogFile> {
+        if let Some(logs) = &dir.memory {
+            return Ok(LogFile::Memory(logs.open(gen)?));
+        }
+        match File::open(log_path(dir, gen)) {
+            Ok(file) => Ok(LogFile::Plain(file)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let mut decoder = GzDecoder::new(File::open(compressed_log_path(dir, gen))?);
+                let mut buf = Vec::new();
+                decoder.read_to_end(&mut buf)?;
+                Ok(LogFile::Compressed(io::Cursor::new(buf.into())))
             }
-
-            // remove 会 drop file 对象
-            readers.remove(&first_gen);
+            Err(e) => Err(e.into()),
         }
     }
+}
 
-    /// Read the log file at the given `CommandPos`.
-    fn read_and<F, R>(&self, cmd_pos: CommandPos, f: F) -> Result<R>
-    where
-        // 定义闭包类型，接收一个受限的文件流，返回任意结果 R
-        // read_and 不关心读出来的数据做什么，只读
-        // io::Take 划定安全边界，防止多读
-        F: FnOnce(io::Take<&mut BufReaderWithPos<File>>) -> Result<R>,
-    {
-        // 清理过期文件句柄，如果有压缩发生
-        self.close_stale_handles();
+/// The decompressed compressed generations, shared by the readers of a store so that
+/// a generation is inflated once however many readers open it.
+///
+/// Only weak references are kept, so a generation is freed once the last reader closes
+/// it, e.g. after a compaction made it stale.
+#[derive(Default)]
+struct InflatedLogs {
+    logs: Mutex<HashMap<u64, Weak<[u8]>>>,
+}
 
-        // borrow_mut 拿到独占访问权，可以insert 或者 seek 指针
-        let mut readers = self.readers.borrow_mut();
-        // Open the file if we haven't opened it in this `KvStoreReader`.
-        // We don't use entry API here because we want the errors to be propogated.
-        // 懒加载，如果这个 id 的文件还没打开过，现在打开并存入 缓存
-        if !readers.contains_key(&cmd_pos.gen) {
-            let reader = BufReaderWithPos::new(File::open(log_path(&self.path, cmd_pos.gen))?)?;
-            readers.insert(cmd_pos.gen, reader);
+impl InflatedLogs {
+    /// Opens the generation like `LogFile::open`, reusing the decompressed contents if
+    /// another reader has it open.
+    fn open(&self, dir: &LogDir, gen: u64) -> Result<LogFile> {
+        // held while inflating, so that readers opening the same generation at once
+        // don't inflate it each
+        let mut logs = self.logs.lock().unwrap();
+        if let Some(buf) = logs.get(&gen).and_then(Weak::upgrade) {
+            return Ok(LogFile::Compressed(io::Cursor::new(buf)));
         }
-
-        // 拿到文件 handler
-        let reader = readers.get_mut(&cmd_pos.gen).unwrap();
-        // 定位
-        reader.seek(SeekFrom::Start(cmd_pos.pos))?;
-        // 读取固定长度
-        let cmd_reader = reader.take(cmd_pos.len);
-
-        // 执行回调
-        // 把准备好的，对准了位置的，限制了长度的 reader 交给闭包处理
-        f(cmd_reader)
+        let log = LogFile::open(dir, gen)?;
+        if let LogFile::Compressed(cursor) = &log {
+            logs.retain(|_, buf| buf.strong_count() > 0);
+            logs.insert(gen, Arc::downgrade(cursor.get_ref()));
+        }
+        Ok(log)
     }
+}
 
-    // Read the log file at the given `CommandPos` and deserialize it to `Command`.
-    fn read_command(&self, cmd_pos: CommandPos) -> Result<Command> {
-        // 调用底层的读取器 read_and
-        self.read_and(cmd_pos, |cmd_reader| {
-            // 传入一个闭包，（回调函数 ）
-            // 给你一个已经对准标准公交车的文件流，把它解析为 json command
-            Ok(serde_json::from_reader(cmd_reader)?)
-        })
+impl Read for LogFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            LogFile::Plain(file) => file.read(buf),
+            LogFile::Compressed(cursor) => cursor.read(buf),
+            LogFile::Memory(file) => file.read(buf),
+        }
     }
 }
 
-impl Clone for KvStoreReader {
-    fn clone(&self) -> KvStoreReader {
-        KvStoreReader {
-            path: Arc::clone(&self.path),
-            safe_point: Arc::clone(&self.safe_point),
-            // don't use other KvStoreReader's readers
-            readers: RefCell::new(BTreeMap::new()),
+impl Seek for LogFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            LogFile::Plain(file) => file.seek(pos),
+            LogFile::Compressed(cursor) => cursor.seek(pos),
+            LogFile::Memory(file) => file.seek(pos),
         }
     }
 }
 
-// 详细中文注释（补充）：
-// KvStoreWriter 的职责与重要设计点：
-// - 负责将 `set`/`remove` 命令序列化并追加写入当前代数日志文件，维护 `index`（内存索引）以及记录 `uncompacted` 大小。
-// - 写入必须串行：因此 `KvStoreWriter` 被放在 `Arc<Mutex<...>>` 之内，外部在执行 `set`/`remove` 时会获取互斥锁，
-//   保证不会同时有多个写线程破坏日志顺序或索引一致性。
-// - `uncompacted`：统计可以回收的“垃圾”字节数（旧的被覆盖或删除的记录占用的空间），用来触发 `compact()`。
-// - `compact()`：将当前 index 指向的有效数据搬运到新的 compaction 文件中，更新 index 并删除旧日志文件，释放空间。
-// - 关于为什么读写分离：读者通过 `KvStoreReader` 使用 `SkipMap` 无锁读取索引并定位到磁盘位置，然后直接读磁盘数据；写操作走串行化路径，避免了复杂的并发控制。
-// - 对新手的提示：保证 `KvStoreWriter` 的操作尽量短小（快速 append + flush），避免在持锁期间做大量 CPU 或阻塞 IO 操作，以减少对读操作的影响。
-struct KvStoreWriter {
-    reader: KvStoreReader,
-    writer: BufWriterWithPos<File>,
-    current_gen: u64,
-    // the number of bytes representing "stale" commands that could be
-    // deleted during a compaction
-    uncompacted: u64,
-    path: Arc<PathBuf>,
-    index: Arc<SkipMap<String, CommandPos>>,
+/// A generation file opened for writing.
+enum LogWriter {
+    File(File),
+    Memory(MemoryFile),
 }
 
-impl KvStoreWriter {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::set(key, value);
+impl LogWriter {
+    // Sync the written data to the disk, which memory doesn't need.
+    fn sync_data(&self) -> io::Result<()> {
+        match self {
+            LogWriter::File(file) => file.sync_data(),
+            LogWriter::Memory(_) => Ok(()),
+        }
+    }
 
-        // writer 当前写到哪个位置了
-        let pos = self.writer.pos;
-        serde_json::to_writer(&mut self.writer, &cmd)?;
+    fn try_clone(&self) -> io::Result<LogWriter> {
+        match self {
+            LogWriter::File(file) => Ok(LogWriter::File(file.try_clone()?)),
+            LogWriter::Memory(file) => Ok(LogWriter::Memory(MemoryFile {
+                log: Arc::clone(&file.log),
+                pos: file.pos,
+                fail_writes: Arc::clone(&file.fail_writes),
+            })),
+        }
+    }
+}
 
-        self.writer.flush()?;
-        if let Command::Set { key, .. } = cmd {
-            if let Some(old_cmd) = self.index.get(&key) {
-                self.uncompacted += old_cmd.value().len;
-            }
-            self.index
-                .insert(key, (self.current_gen, pos..self.writer.pos).into());
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            LogWriter::File(file) => file.write(buf),
+            LogWriter::Memory(file) => file.write(buf),
         }
+    }
 
-        if self.uncompacted > COMPACTION_THRESHOLD {
-            self.compact()?;
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            LogWriter::File(file) => file.flush(),
+            LogWriter::Memory(file) => file.flush(),
         }
-        Ok(())
     }
+}
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        if self.index.contains_key(&key) {
-            // 先将命令 log，再append log
-            let cmd = Command::remove(key);
-            let pos = self.writer.pos;
-            serde_json::to_writer(&mut self.writer, &cmd)?;
-            self.writer.flush()?;
+impl Seek for LogWriter {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            LogWriter::File(file) => file.seek(pos),
+            LogWriter::Memory(file) => file.seek(pos),
+        }
+    }
+}
 
-            if let Command::Remove { key } = cmd {
-                let old_cmd = self.index.remove(&key).expect("key not found");
+/// The bytes of a log in memory, shared by its `MemoryFile`s.
+type MemoryLog = Arc<RwLock<Vec<u8>>>;
 
-                // set 命令的长度
-                self.uncompacted += old_cmd.value().len;
-                // the "remove" command itself can be deleted in the next compaction
-                // so we add its length to `uncompacted`
-                // remove 命令的长度
-                self.uncompacted += self.writer.pos - pos;
-            }
+/// The logs of a store opened with `KvStore::open_in_memory`.
+///
+/// Clones share the same logs, so a store can be dropped and opened again on them.
+#[derive(Clone, Default)]
+pub struct MemoryLogs {
+    logs: Arc<Mutex<BTreeMap<u64, MemoryLog>>>,
+    // the `LogFormat` of the logs, like the `FORMAT` file of a directory
+    format: Arc<Mutex<Option<LogFormat>>>,
+    // set by `fail_writes`
+    fail_writes: Arc<AtomicBool>,
+}
 
-            if self.uncompacted > COMPACTION_THRESHOLD {
-                self.compact()?;
-            }
-            Ok(())
-        } else {
-            Err(KvsError::KeyNotFound)
-        }
+impl MemoryLogs {
+    /// Creates empty logs.
+    pub fn new() -> MemoryLogs {
+        MemoryLogs::default()
     }
 
-    /// Clears stale entries in the log.
-    fn compact(&mut self) -> Result<()> {
-        // increase current gen by 2. current_gen + 1 is for the compaction file
-        let compaction_gen = self.current_gen + 1;
-        self.current_gen += 2;
-        self.writer = new_log_file(&self.path, self.current_gen)?;
+    /// Makes every write to the logs fail with an I/O error while `fail` is set, as a
+    /// full disk would.
+    ///
+    /// Only available with the `testing` feature.
+    #[cfg(feature = "testing")]
+    pub fn fail_writes(&self, fail: bool) {
+        self.fail_writes.store(fail, Ordering::SeqCst);
+    }
 
-        let mut compaction_writer = new_log_file(&self.path, compaction_gen)?;
+    fn open(&self, gen: u64) -> Result<MemoryFile> {
+        match self.logs.lock().unwrap().get(&gen) {
+            Some(log) => Ok(MemoryFile {
+                log: Arc::clone(log),
+                pos: 0,
+                fail_writes: Arc::clone(&self.fail_writes),
+            }),
+            None => Err(KvsError::Io(io::ErrorKind::NotFound.into())),
+        }
+    }
 
-        let mut new_pos = 0; // pos in the new log file
-        for entry in self.index.iter() {
-            let len = self.reader.read_and(*entry.value(), |mut entry_reader| {
-                Ok(io::copy(&mut entry_reader, &mut compaction_writer)?)
-            })?;
-            self.index.insert(
-                entry.key().clone(),
-                (compaction_gen, new_pos..new_pos + len).into(),
-            );
-            new_pos += len;
+    // Open the log of a generation for appending, creating it if needed.
+    fn create(&self, gen: u64) -> MemoryFile {
+        let log = Arc::clone(self.logs.lock().unwrap().entry(gen).or_default());
+        let pos = log.read().unwrap().len() as u64;
+        MemoryFile {
+            log,
+            pos,
+            fail_writes: Arc::clone(&self.fail_writes),
         }
-        compaction_writer.flush()?;
+    }
 
-        self.reader
-            .safe_point
-            .store(compaction_gen, Ordering::SeqCst);
-        self.reader.close_stale_handles();
+    fn remove(&self, gen: u64) {
+        self.logs.lock().unwrap().remove(&gen);
+    }
+}
 
-        // remove stale log files
-        // Note that actually these files are not deleted immediately because `KvStoreReader`s
-        // still keep open file handles. When `KvStoreReader` is used next time, it will clear
-        // its stale file handles. On Unix, the files will be deleted after all the handles
-        // are closed. On Windows, the deletions below will fail and stale files are expected
-        // to be deleted in the next compaction.
+impl fmt::Debug for MemoryLogs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MemoryLogs")
+            .field("gens", &self.logs.lock().unwrap().keys())
+            .finish()
+    }
+}
 
-        let stale_gens = sorted_gen_list(&self.path)?
-            .into_iter()
-            .filter(|&gen| gen < compaction_gen);
-        for stale_gen in stale_gens {
-            let file_path = log_path(&self.path, stale_gen);
-            if let Err(e) = fs::remove_file(&file_path) {
-                error!("{:?} cannot be deleted: {}", file_path, e);
-            }
-        }
-        self.uncompacted = 0;
+/// A log in memory, read and written like a file.
+///
+/// The bytes are shared with the `MemoryLogs` and the other handles on the log, so a
+/// reader sees what a writer appends.
+struct MemoryFile {
+    log: MemoryLog,
+    pos: u64,
+    fail_writes: Arc<AtomicBool>,
+}
 
-        Ok(())
+impl MemoryFile {
+    // Reserve room for `additional` more bytes in the log if the memory is available.
+    fn reserve(&self, additional: usize) {
+        // only a hint, the log grows as needed anyway
+        let _ = self.log.write().unwrap().try_reserve(additional);
     }
 }
 
-/// Create a new log file with given generation number and add the reader to the readers map.
-///
-/// Returns the writer to the log.
-fn new_log_file(path: &Path, gen: u64) -> Result<BufWriterWithPos<File>> {
-    let path = log_path(&path, gen);
-    let writer = BufWriterWithPos::new(
-        OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(&path)?,
-    )?;
-    Ok(writer)
+impl Read for MemoryFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let log = self.log.read().unwrap();
+        let start = (self.pos as usize).min(log.len());
+        let len = buf.len().min(log.len() - start);
+        buf[..len].copy_from_slice(&log[start..start + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
 }
 
-/// Returns sorted generation numbers in the given directory
-fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
-    let mut gen_list: Vec<u64> = fs::read_dir(&path)?
-        .flat_map(|res| -> Result<_> { Ok(res?.path()) })
-        .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
-        .flat_map(|path| {
-            path.file_name()
-                .and_then(OsStr::to_str)
-                .map(|s| s.trim_end_matches(".log"))
-                .map(str::parse::<u64>)
-        })
-        .flatten()
-        .collect();
-    gen_list.sort_unstable();
-    Ok(gen_list)
+impl Write for MemoryFile {
+    // Appends to the log, wherever the position is, like a file opened to append.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.fail_writes.load(Ordering::SeqCst) {
+            return Err(io::Error::other("Write failure injected"));
+        }
+        let mut log = self.log.write().unwrap();
+        log.extend_from_slice(buf);
+        self.pos = log.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
 }
 
-/// Load the whole log file and store value locations in the index map.
-///
-/// Returns how many bytes can be saved after a compaction.
-fn load(
-    gen: u64,
-    reader: &mut BufReaderWithPos<File>,
-    index: &SkipMap<String, CommandPos>,
-) -> Result<u64> {
-    // To make sure we read from the beginning of the file
-    let mut pos = reader.seek(SeekFrom::Start(0))?;
-    let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
-    let mut uncompacted = 0; // number of bytes that can be saved after a compaction
-    while let Some(cmd) = stream.next() {
-        let new_pos = stream.byte_offset() as u64;
-        match cmd? {
-            Command::Set { key, .. } => {
-                if let Some(old_cmd) = index.get(&key) {
-                    uncompacted += old_cmd.value().len;
-                }
-                index.insert(key, (gen, pos..new_pos).into());
+impl Seek for MemoryFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => {
+                self.pos = offset;
+                return Ok(offset);
             }
-            Command::Remove { key } => {
-                if let Some(old_cmd) = index.remove(&key) {
-                    uncompacted += old_cmd.value().len;
-                }
-                // the "remove" command itself can be deleted in the next compaction
-                // so we add its length to `uncompacted`
-                uncompacted += new_pos - pos;
+            SeekFrom::End(offset) => (self.log.read().unwrap().len() as u64, offset),
+            SeekFrom::Current(offset) => (self.pos, offset),
+        };
+        match (base as i64).checked_add(offset) {
+            Some(pos) if pos >= 0 => {
+                self.pos = pos as u64;
+                Ok(self.pos)
             }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            )),
         }
-        pos = new_pos;
     }
-    Ok(uncompacted)
-}
-
-fn log_path(dir: &Path, gen: u64) -> PathBuf {
-    dir.join(format!("{}.log", gen))
 }
 
 /// Struct representing a command
 #[derive(Serialize, Deserialize, Debug)]
 enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+    Set {
+        key: String,
+        #[serde(with = "base64_value")]
+        value: Vec<u8>,
+        // unix time in milliseconds after which the key is expired, absent in old logs
+        #[serde(default)]
+        expires_at: Option<u64>,
+        // whether `value` is compressed by zstd, absent in old logs
+        #[serde(default)]
+        compressed: bool,
+    },
+    Remove {
+        key: String,
+    },
+    // a `Set` with its value in a value log
+    SetExternal {
+        key: String,
+        value: ValuePointer,
+        expires_at: Option<u64>,
+        compressed: bool,
+    },
+}
+
+/// The location of a value stored in a value log.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+struct ValuePointer {
+    gen: u64,
+    offset: u64,
+    len: u64,
 }
 
 impl Command {
-    fn set(key: String, value: String) -> Command {
-        Command::Set { key, value }
+    fn set(key: String, value: Vec<u8>, expires_at: Option<u64>) -> Command {
+        Command::Set {
+            key,
+            value,
+            expires_at,
+            compressed: false,
+        }
     }
 
     fn remove(key: String) -> Command {
         Command::Remove { key }
     }
+
+    fn key(&self) -> &str {
+        match self {
+            Command::Set { key, .. }
+            | Command::SetExternal { key, .. }
+            | Command::Remove { key } => key,
+        }
+    }
+}
+
+/// Stores byte values as base64 strings in human readable formats, so that the log stays
+/// valid JSON. Binary formats store the raw bytes.
+mod base64_value {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&base64::encode(value))
+        } else {
+            serializer.serialize_bytes(value)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        if deserializer.is_human_readable() {
+            let encoded = String::deserialize(deserializer)?;
+            base64::decode(&encoded).map_err(D::Error::custom)
+        } else {
+            Vec::deserialize(deserializer)
+        }
+    }
 }
 
 /// Represents the position and length of a json-serialized command in the log
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 struct CommandPos {
     gen: u64,
     pos: u64,
     len: u64,
+    // expiry of a `Command::Set`, kept here so that it can be checked without reading
+    // the log
+    expires_at: Option<u64>,
+}
+
+impl CommandPos {
+    fn expiring_at(self, expires_at: Option<u64>) -> CommandPos {
+        CommandPos { expires_at, ..self }
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        matches!(self.expires_at, Some(expires_at) if expires_at <= now)
+    }
 }
 
 impl From<(u64, Range<u64>)> for CommandPos {
@@ -510,6 +4494,7 @@ impl From<(u64, Range<u64>)> for CommandPos {
             gen,
             pos: range.start,
             len: range.end - range.start,
+            expires_at: None,
         }
     }
 }
@@ -559,6 +4544,26 @@ impl<W: Write + Seek> BufWriterWithPos<W> {
     }
 }
 
+impl BufWriterWithPos<LogWriter> {
+    // Flush the buffer and sync the file data to the disk.
+    fn sync_data(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()
+    }
+
+    // Grow the buffer to `capacity` bytes, if it's smaller. The buffered data is flushed
+    // and the new buffer writes through another handle on the same log.
+    fn reserve(&mut self, capacity: usize) -> io::Result<()> {
+        if capacity <= self.writer.capacity() {
+            return Ok(());
+        }
+        self.writer.flush()?;
+        let inner = self.writer.get_ref().try_clone()?;
+        self.writer = BufWriter::with_capacity(capacity, inner);
+        Ok(())
+    }
+}
+
 impl<W: Write + Seek> Write for BufWriterWithPos<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let len = self.writer.write(buf)?;
@@ -0,0 +1,59 @@
+use super::{is_self_check_key, KvsEngine, SELF_CHECK_PREFIX};
+use crate::{KvsError, Result};
+use crossbeam_skiplist::SkipMap;
+use std::ops::Bound;
+use std::sync::Arc;
+
+/// A key value store kept only in memory.
+///
+/// Nothing is written to disk, so the contents are lost when the last clone is dropped.
+/// It is meant for tests and caches that don't need persistence.
+#[derive(Clone, Default)]
+pub struct MemoryKvsEngine {
+    map: Arc<SkipMap<String, String>>,
+}
+
+impl MemoryKvsEngine {
+    /// Creates an empty `MemoryKvsEngine`.
+    pub fn new() -> Self {
+        MemoryKvsEngine::default()
+    }
+}
+
+impl KvsEngine for MemoryKvsEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.map.insert(key, value);
+        Ok(())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.map.get(&key).map(|entry| entry.value().clone()))
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.map.remove(&key).ok_or(KvsError::KeyNotFound)?;
+        Ok(())
+    }
+
+    /// Returns the number of keys, except the probe of a running `self_check`.
+    fn len(&self) -> Result<usize> {
+        let probes = self
+            .map
+            .range(SELF_CHECK_PREFIX.to_owned()..)
+            .take_while(|entry| is_self_check_key(entry.key()))
+            .count();
+        Ok(self.map.len().saturating_sub(probes))
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        self.len().map(|len| len == 0)
+    }
+
+    fn scan(&self, start: Bound<String>, end: Bound<String>) -> Result<Vec<(String, String)>> {
+        Ok(self
+            .map
+            .range((start, end))
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect())
+    }
+}
@@ -1,8 +1,23 @@
-pub use self::kvs::KvStore;
+pub use self::kvs::{
+    CompactionProgress, CompactionStrategy, Compression, KvIter, KvStore, KvStoreOptions,
+    LogFormat, MemoryLogs, ReadOnlyKvStore, RecordLocation, RecoveryProgress, Snapshot,
+    SnapshotIter, Stats, SyncPolicy, ValueMeta, VerifyReport, WriteBatch,
+};
+pub use self::memory::MemoryKvsEngine;
 pub use self::sled::SledKvsEngine;
-use crate::Result;
+use crate::common::{Op, OpResult};
+use crate::{KvsError, Result};
+use fs2::FileExt;
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
 
 mod kvs;
+mod memory;
 mod sled;
 
 /// Trait for a key value storage engine.
@@ -23,6 +38,299 @@ pub trait KvsEngine: Clone + Send + 'static {
     ///
     /// It returns `KvsError::KeyNotFound` if the given key is not found.
     fn remove(&self, key: String) -> Result<()>;
+
+    /// Returns whether the given key exists.
+    fn exists(&self, key: String) -> Result<bool> {
+        self.get(key).map(|value| value.is_some())
+    }
+
+    /// Sets the value of a string key to `new` only if its current value is `expected`.
+    ///
+    /// `None` as `expected` means the key must not exist. Returns whether the value is set.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Unsupported` if the engine doesn't implement it.
+    fn compare_and_swap(&self, key: String, expected: Option<String>, new: String) -> Result<bool> {
+        let _ = (key, expected, new);
+        Err(KvsError::Unsupported)
+    }
+
+    /// Sets the value of a key only if it doesn't exist, and returns whether it's set.
+    ///
+    /// The check and the write are atomic with respect to other writes, so of concurrent
+    /// callers on a missing key exactly one sets it. It defaults to a `compare_and_swap`
+    /// expecting no value.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Unsupported` if the engine doesn't implement it.
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        self.compare_and_swap(key, None, value)
+    }
+
+    /// Adds `delta` to the integer value of a key and returns the new value.
+    ///
+    /// A missing key counts as 0. The read and the write are atomic with respect to other
+    /// writes.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::NotANumber` if the current value isn't an `i64`,
+    /// `KvsError::Overflow` if the new value doesn't fit in one, and
+    /// `KvsError::Unsupported` if the engine doesn't implement it.
+    fn increment(&self, key: String, delta: i64) -> Result<i64> {
+        let _ = (key, delta);
+        Err(KvsError::Unsupported)
+    }
+
+    /// Subtracts `delta` from the integer value of a key and returns the new value.
+    ///
+    /// It's an `increment` by `-delta`, with the same atomicity and errors.
+    fn decrement(&self, key: String, delta: i64) -> Result<i64> {
+        let delta = delta.checked_neg().ok_or(KvsError::Overflow)?;
+        self.increment(key, delta)
+    }
+
+    /// Sets a key to the integer `value`, expiring after `ttl` if it's given.
+    ///
+    /// An `increment` or a `decrement` keeps the expiry of the counter, and one on an
+    /// expired counter starts again from 0, so a counter with a TTL counts within a time
+    /// window.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Unsupported` if a `ttl` is given and the engine doesn't
+    /// support expiry.
+    fn set_counter(&self, key: String, value: i64, ttl: Option<Duration>) -> Result<()> {
+        match ttl {
+            Some(_) => Err(KvsError::Unsupported),
+            None => self.set(key, value.to_string()),
+        }
+    }
+
+    /// Returns the value of a key, or sets it to `default` and returns that if the key
+    /// doesn't exist.
+    ///
+    /// The read and the write are atomic with respect to other writes, so concurrent
+    /// callers on a missing key all get the same value.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Unsupported` if the engine doesn't implement it.
+    fn get_or_set(&self, key: String, default: String) -> Result<String> {
+        let _ = (key, default);
+        Err(KvsError::Unsupported)
+    }
+
+    /// Runs the operations of a batch in order and returns one `OpResult` for each.
+    ///
+    /// A failing operation doesn't abort the rest of the batch. The default implementation
+    /// runs the operations one by one, so other writes may come in between them.
+    /// `KvStore` runs the whole batch under the locks of its keys instead, so that it can
+    /// read, compare and write keys in one go.
+    fn batch(&self, ops: Vec<Op>) -> Result<Vec<OpResult>> {
+        Ok(ops.into_iter().map(|op| execute(self, op)).collect())
+    }
+
+    /// Returns the key/value pairs with keys in the given range, in key order.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Unsupported` if the engine doesn't implement it.
+    fn scan(&self, start: Bound<String>, end: Bound<String>) -> Result<Vec<(String, String)>> {
+        let _ = (start, end);
+        Err(KvsError::Unsupported)
+    }
+
+    /// Returns the number of keys in the engine.
+    ///
+    /// The default implementation counts the pairs returned by `scan`, except the probe
+    /// of a running `self_check`.
+    fn len(&self) -> Result<usize> {
+        let pairs = self.scan(Bound::Unbounded, Bound::Unbounded)?;
+        Ok(pairs
+            .iter()
+            .filter(|(key, _)| !is_self_check_key(key))
+            .count())
+    }
+
+    /// Returns whether the engine holds no key.
+    fn is_empty(&self) -> Result<bool> {
+        self.len().map(|len| len == 0)
+    }
+
+    /// Returns the storage statistics of the engine.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Unsupported` if the engine doesn't implement it.
+    fn stats(&self) -> Result<Stats> {
+        Err(KvsError::Unsupported)
+    }
+
+    /// Writes all the key/value pairs to `writer`, so that any engine can import them.
+    ///
+    /// The export starts with a magic header, then each pair is written as the key and
+    /// the value, each preceded by its length as a 4-byte big-endian integer. The probe of
+    /// a running `self_check` is left out.
+    fn export(&self, mut writer: impl Write) -> Result<()> {
+        writer.write_all(EXPORT_MAGIC)?;
+        for (key, value) in self.scan(Bound::Unbounded, Bound::Unbounded)? {
+            if is_self_check_key(&key) {
+                continue;
+            }
+            write_export_pair(&mut writer, key.as_bytes(), value.as_bytes())?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Sets all the key/value pairs read from an export, returning how many are set.
+    ///
+    /// # Errors
+    ///
+    /// It returns an error if `reader` is not a valid export. The pairs read before the
+    /// error are already set.
+    fn import(&self, mut reader: impl Read) -> Result<usize> {
+        read_export_magic(&mut reader)?;
+        let mut count = 0;
+        while let Some((key, value)) = read_export_pair(&mut reader)? {
+            self.set(String::from_utf8(key)?, String::from_utf8(value)?)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Checks that the engine is able to serve writes and reads.
+    ///
+    /// It writes a key starting with `__kvs_self_check__`, reads it back and removes it.
+    /// Each check uses a key of its own, so concurrent checks don't see each other's
+    /// probes. The prefix is reserved: `KvsServer` rejects the requests from clients that
+    /// use it, so a check through the server never clobbers their data.
+    ///
+    /// # Errors
+    ///
+    /// It returns an error if any step fails or the value read back doesn't match
+    /// the written one.
+    fn self_check(&self) -> Result<()> {
+        let id = SELF_CHECK_ID.fetch_add(1, Ordering::Relaxed);
+        let key = format!("{}{}", SELF_CHECK_PREFIX, id);
+        let probe = format!("{:?}", SystemTime::now());
+        self.set(key.clone(), probe.clone())?;
+        let value = self.get(key.clone())?;
+        self.remove(key)?;
+        if value.as_ref() != Some(&probe) {
+            return Err(KvsError::StringError(format!(
+                "Self check read {:?}, expected {:?}",
+                value, probe
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Execute a single operation of a batch, turning an error into `OpResult::Err`.
+fn execute<E: KvsEngine>(engine: &E, op: Op) -> OpResult {
+    let res = match op {
+        Op::Get { key } => engine.get(key).map(OpResult::Get),
+        Op::Set { key, value } => engine.set(key, value).map(|_| OpResult::Set),
+        Op::Remove { key } => engine.remove(key).map(|_| OpResult::Remove),
+        Op::CompareAndSwap { key, expected, new } => engine
+            .compare_and_swap(key, expected, new)
+            .map(OpResult::CompareAndSwap),
+    };
+    res.unwrap_or_else(|e| OpResult::Err(e.into()))
+}
+
+/// The name of the file locked by the store using a directory.
+const LOCK_FILE: &str = "LOCK";
+
+/// Takes an exclusive advisory lock on the `LOCK` file in `dir`, held until the returned
+/// file is closed.
+///
+/// The logs assume a single writer, so two stores must never use a directory at the same
+/// time, whether in the same process or not.
+fn lock_dir(dir: &Path) -> Result<File> {
+    lock_file(&dir.join(LOCK_FILE))
+}
+
+/// Takes an exclusive advisory lock on the file at `path` like `lock_dir`, for stores
+/// that lock another file than `LOCK`.
+fn lock_file(path: &Path) -> Result<File> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    match file.try_lock_exclusive() {
+        Ok(()) => Ok(file),
+        Err(ref e) if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() => {
+            Err(KvsError::Locked)
+        }
+        Err(e) => Err(KvsError::Io(e)),
+    }
+}
+
+/// The prefix of the keys reserved for `KvsEngine::self_check`.
+pub(crate) const SELF_CHECK_PREFIX: &str = "__kvs_self_check__";
+
+/// Numbers the keys of `KvsEngine::self_check`, so that no two checks share one.
+static SELF_CHECK_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Returns whether `key` is reserved for `KvsEngine::self_check`.
+pub(crate) fn is_self_check_key(key: &str) -> bool {
+    key.starts_with(SELF_CHECK_PREFIX)
+}
+
+/// The header of `KvsEngine::export`, including the version of the format.
+const EXPORT_MAGIC: &[u8] = b"KVSDUMP1";
+
+fn write_export_pair(writer: &mut impl Write, key: &[u8], value: &[u8]) -> Result<()> {
+    for bytes in &[key, value] {
+        let len = u32::try_from(bytes.len())
+            .map_err(|_| KvsError::StringError("Entry too large to export".to_owned()))?;
+        writer.write_all(&len.to_be_bytes())?;
+        writer.write_all(bytes)?;
+    }
+    Ok(())
+}
+
+fn read_export_magic(reader: &mut impl Read) -> Result<()> {
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic)?;
+    if magic != EXPORT_MAGIC {
+        return Err(KvsError::StringError("Not a kvs export".to_owned()));
+    }
+    Ok(())
+}
+
+/// Reads a pair written by `write_export_pair`, or `None` at the end of the export.
+fn read_export_pair(reader: &mut impl Read) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    let mut len_buf = [0; 4];
+    match reader.read_exact(&mut len_buf) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let key = read_export_bytes(reader, u32::from_be_bytes(len_buf))?;
+    reader.read_exact(&mut len_buf)?;
+    let value = read_export_bytes(reader, u32::from_be_bytes(len_buf))?;
+    Ok(Some((key, value)))
+}
+
+// Read `len` bytes of a pair. The buffer grows with the bytes actually read, so a
+// corrupt length doesn't allocate more than the export holds.
+fn read_export_bytes(reader: &mut impl Read, len: u32) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    reader
+        .by_ref()
+        .take(u64::from(len))
+        .read_to_end(&mut bytes)?;
+    if bytes.len() != len as usize {
+        return Err(KvsError::StringError("Truncated kvs export".to_owned()));
+    }
+    Ok(bytes)
 }
 
 // 详细中文注释（补充）：
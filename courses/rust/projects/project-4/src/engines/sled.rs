@@ -1,10 +1,18 @@
-use super::KvsEngine;
+use super::{lock_dir, KvsEngine, SELF_CHECK_PREFIX};
 use crate::{KvsError, Result};
 use sled::{Db, Tree};
+use std::fs::{self, File};
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::Arc;
 
 /// Wrapper of `sled::Db`
 #[derive(Clone)]
-pub struct SledKvsEngine(Db);
+pub struct SledKvsEngine {
+    db: Db,
+    // the lock on the directory when opened with `open`
+    _lock: Option<Arc<File>>,
+}
 
 // 详细中文注释（补充）：
 // 1. 目的：`SledKvsEngine` 是对 `sled::Db` 的轻量封装，使其实现 `KvsEngine` 接口，从而可以在同一套服务器逻辑中
@@ -13,39 +21,107 @@ pub struct SledKvsEngine(Db);
 //    - `SledKvsEngine` 包含 `sled::Db`，实现了 `set/get/remove`，并且所有方法返回 `Result<T, KvsError>`，
 //      通过 `impl From<sled::Error> for KvsError` 把 `sled` 的错误映射为通用错误类型。
 //    - `set` 会调用 `tree.insert` 并 `flush`，以确保数据落盘；`get` 会把 `sled` 返回的字节向量尝试转换为 `String`（UTF-8），
-//      若转换失败则返回 `KvsError::Utf8`（上层会处理该错误）。
+//      若转换失败则返回 `KvsError::Utf8`（上层会处理该错误）。需要原始字节时可使用 `get_bytes`/`set_bytes` 绕过 UTF-8 转换。
 // 3. 对新手的建议：
 //    - 使用第三方存储引擎可以节省实现细节，但需要关注数据模型与 API 语义差异（例如 `sled` 的原子性、事务支持等）。
 //    - 在高并发场景下，`sled` 的表现通常优于手写单文件日志实现，因为它针对并发与磁盘访问做了许多优化。
 
 impl SledKvsEngine {
     /// Creates a `SledKvsEngine` from `sled::Db`.
+    ///
+    /// The directory of the database can't be found from `db`, so it isn't locked and
+    /// nothing keeps a `KvStore` from using it at the same time.
+    #[deprecated(note = "use `SledKvsEngine::open`, which locks the directory")]
     pub fn new(db: Db) -> Self {
-        SledKvsEngine(db)
+        SledKvsEngine { db, _lock: None }
     }
-}
 
-impl KvsEngine for SledKvsEngine {
-    fn set(&self, key: String, value: String) -> Result<()> {
-        let tree: &Tree = &self.0;
-        tree.insert(key, value.into_bytes()).map(|_| ())?;
+    /// Opens a sled database in the given directory, locking the directory the same way
+    /// `KvStore` does until the engine is dropped.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Locked` if another store has the directory open.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        fs::create_dir_all(path)?;
+        let lock = lock_dir(path)?;
+        Ok(SledKvsEngine {
+            db: sled::open(path)?,
+            _lock: Some(Arc::new(lock)),
+        })
+    }
+
+    /// Sets the value of a string key to raw bytes.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    pub fn set_bytes(&self, key: String, value: Vec<u8>) -> Result<()> {
+        let tree: &Tree = &self.db;
+        tree.insert(key, value).map(|_| ())?;
         tree.flush()?;
         Ok(())
     }
 
-    fn get(&self, key: String) -> Result<Option<String>> {
-        let tree: &Tree = &self.0;
+    /// Gets the raw byte value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist. Unlike `get`, the value doesn't
+    /// need to be valid UTF-8, e.g. when it's written by another tool.
+    pub fn get_bytes(&self, key: String) -> Result<Option<Vec<u8>>> {
+        let tree: &Tree = &self.db;
         Ok(tree
             .get(key)?
-            .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
-            .map(String::from_utf8)
-            .transpose()?)
+            .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec()))
+    }
+}
+
+impl KvsEngine for SledKvsEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key, value.into_bytes())
+    }
+
+    /// Returns `KvsError::Utf8` if the value isn't valid UTF-8, see `get_bytes`.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.get_bytes(key)?.map(String::from_utf8).transpose()?)
+    }
+
+    /// Sets the key through sled's compare-and-swap expecting no value.
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        let tree: &Tree = &self.db;
+        let res = tree.compare_and_swap(key, None as Option<&[u8]>, Some(value.into_bytes()))?;
+        if res.is_err() {
+            return Ok(false);
+        }
+        tree.flush()?;
+        Ok(true)
     }
 
     fn remove(&self, key: String) -> Result<()> {
-        let tree: &Tree = &self.0;
+        let tree: &Tree = &self.db;
         tree.remove(key)?.ok_or(KvsError::KeyNotFound)?;
         tree.flush()?;
         Ok(())
     }
+
+    /// Returns the number of keys, except the probe of a running `self_check`.
+    fn len(&self) -> Result<usize> {
+        let probes = self.db.scan_prefix(SELF_CHECK_PREFIX).count();
+        Ok(self.db.len().saturating_sub(probes))
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        self.len().map(|len| len == 0)
+    }
+
+    fn scan(&self, start: Bound<String>, end: Bound<String>) -> Result<Vec<(String, String)>> {
+        let tree: &Tree = &self.db;
+        tree.range((start, end))
+            .map(|pair| {
+                let (key, value) = pair?;
+                Ok((
+                    String::from_utf8(key.to_vec())?,
+                    String::from_utf8(value.to_vec())?,
+                ))
+            })
+            .collect()
+    }
 }
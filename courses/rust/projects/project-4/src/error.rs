@@ -1,32 +1,99 @@
-use failure::Fail;
+use crate::common::ServerError;
 use std::io;
 use std::string::FromUtf8Error;
+use thiserror::Error;
 
 /// Error type for kvs
-#[derive(Fail, Debug)]
+#[derive(Error, Debug)]
 pub enum KvsError {
     /// IO error
-    #[fail(display = "IO error: {}", _0)]
-    Io(#[cause] io::Error),
+    #[error("IO error: {0}")]
+    Io(#[source] io::Error),
     /// Serialization or deserialization error
-    #[fail(display = "serde_json error: {}", _0)]
-    Serde(#[cause] serde_json::Error),
+    #[error("serde_json error: {0}")]
+    Serde(#[source] serde_json::Error),
+    /// Binary serialization or deserialization error
+    #[error("bincode error: {0}")]
+    Bincode(#[source] bincode::Error),
     /// Removing non-existent key error
-    #[fail(display = "Key not found")]
+    #[error("Key not found")]
     KeyNotFound,
     /// Unexpected command type error.
     /// It indicated a corrupted log or a program bug.
-    #[fail(display = "Unexpected command type")]
+    #[error("Unexpected command type")]
     UnexpectedCommandType,
     /// Key or value is invalid UTF-8 sequence
-    #[fail(display = "UTF-8 error: {}", _0)]
-    Utf8(#[cause] FromUtf8Error),
+    #[error("UTF-8 error: {0}")]
+    Utf8(#[source] FromUtf8Error),
     /// Sled error
-    #[fail(display = "sled error: {}", _0)]
-    Sled(#[cause] sled::Error),
+    #[error("sled error: {0}")]
+    Sled(#[source] sled::Error),
     /// Error with a string message
-    #[fail(display = "{}", _0)]
+    #[error("{0}")]
     StringError(String),
+    /// The engine doesn't support the operation
+    #[error("Operation not supported")]
+    Unsupported,
+    /// A log record fails its checksum or can't be parsed.
+    /// It indicates a corrupted or partially written log.
+    #[error("Corrupt log record in generation {gen} at offset {offset}")]
+    CorruptLog {
+        /// The generation of the corrupt log
+        gen: u64,
+        /// The offset of the corrupt record in the log
+        offset: u64,
+    },
+    /// A connection or socket operation timed out
+    #[error("Operation timed out")]
+    Timeout,
+    /// Writing to a store opened read-only
+    #[error("Store is read-only")]
+    ReadOnly,
+    /// The store directory is already opened by another store
+    #[error("Store directory is locked by another store")]
+    Locked,
+    /// A key is larger than the limit of the store
+    #[error("Key of {size} bytes exceeds the limit of {limit} bytes")]
+    KeyTooLarge {
+        /// The size of the key in bytes
+        size: usize,
+        /// The maximum size of a key in bytes
+        limit: usize,
+    },
+    /// A value is larger than the limit of the store
+    #[error("Value of {size} bytes exceeds the limit of {limit} bytes")]
+    ValueTooLarge {
+        /// The size of the value in bytes
+        size: usize,
+        /// The maximum size of a value in bytes
+        limit: usize,
+    },
+    /// A store is opened with another log format than the one it was written with
+    #[error("Store is written in {found} format, not {requested}")]
+    FormatMismatch {
+        /// The format the store is opened with
+        requested: String,
+        /// The format recorded in the store directory
+        found: String,
+    },
+    /// Incrementing a value that isn't an integer
+    #[error("Value is not a number")]
+    NotANumber,
+    /// Incrementing or decrementing a counter beyond the range of `i64`
+    #[error("Counter overflows i64")]
+    Overflow,
+    /// A response carries the id of another request than the one it was read for.
+    /// The connection is out of step and can't be used any more.
+    #[error("Protocol desync: expected response {expected}, got {found}")]
+    ProtocolDesync {
+        /// The id of the request sent
+        expected: u64,
+        /// The id of the response received
+        found: u64,
+    },
+    /// A long-running operation was cancelled by the caller, e.g. a compaction
+    #[error("Operation cancelled")]
+    Cancelled,
 }
 
 // 详细中文注释（补充）：
@@ -43,11 +110,35 @@ pub enum KvsError {
 //    - 通过实现 `From<...>`，可以方便地用 `?` 操作符将底层错误自动转换为 `KvsError` 并向上传播，简化错误处理。
 // 4. 对新手的建议：
 //    - 在扩展库或增加新的错误场景时，优先考虑是否应该新增 `KvsError` 的变体或复用现有的 `StringError`。
-//    - 使用 `failure::Fail` 能够提供 `Display` 与 `cause` 信息，但在新工程中也可考虑使用 `thiserror` 或 `anyhow` 等现代错误处理库。
+//    - `thiserror` 派生的 `Display` 与 `std::error::Error::source()` 让 `KvsError` 能与标准库及 `anyhow` 等错误处理库配合使用，
+//      包装的底层错误（如 `Io`、`Serde`、`Sled`）可以通过 `source()` 取回。
+
+impl KvsError {
+    /// Returns whether the error is transient, i.e. retrying the same operation may succeed.
+    ///
+    /// Timeouts and network hiccups such as a reset connection are retryable;
+    /// deterministic errors like `KeyNotFound` are not.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            KvsError::Io(err) => matches!(
+                err.kind(),
+                io::ErrorKind::TimedOut
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::WouldBlock
+            ),
+            KvsError::Timeout => true,
+            _ => false,
+        }
+    }
+}
 
 impl From<io::Error> for KvsError {
     fn from(err: io::Error) -> KvsError {
-        KvsError::Io(err)
+        match err.kind() {
+            // a socket timeout is reported as either kind, depending on the platform
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => KvsError::Timeout,
+            _ => KvsError::Io(err),
+        }
     }
 }
 
@@ -57,6 +148,12 @@ impl From<serde_json::Error> for KvsError {
     }
 }
 
+impl From<bincode::Error> for KvsError {
+    fn from(err: bincode::Error) -> KvsError {
+        KvsError::Bincode(err)
+    }
+}
+
 impl From<FromUtf8Error> for KvsError {
     fn from(err: FromUtf8Error) -> KvsError {
         KvsError::Utf8(err)
@@ -69,5 +166,24 @@ impl From<sled::Error> for KvsError {
     }
 }
 
+impl From<ServerError> for KvsError {
+    fn from(err: ServerError) -> KvsError {
+        match err {
+            ServerError::KeyNotFound => KvsError::KeyNotFound,
+            ServerError::InvalidCommand(msg) | ServerError::Internal(msg) => {
+                KvsError::StringError(msg)
+            }
+        }
+    }
+}
+
+// the same as how rustls reports TLS errors while reading or writing a stream
+#[cfg(feature = "tls")]
+impl From<rustls::Error> for KvsError {
+    fn from(err: rustls::Error) -> KvsError {
+        KvsError::Io(io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
+
 /// Result type for kvs
 pub type Result<T> = std::result::Result<T, KvsError>;
@@ -0,0 +1,107 @@
+use hdrhistogram::Histogram;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The latency of the engine calls of a `KvsServer`, shared by all its connections.
+///
+/// Each connection records into its own histogram, so recording only takes a lock
+/// that nobody else holds, except while a snapshot is taken. The histograms are merged
+/// on `snapshot`.
+#[derive(Clone)]
+pub struct LatencyMetrics {
+    state: Arc<Mutex<LatencyState>>,
+}
+
+struct LatencyState {
+    // the calls of the connections that are closed
+    closed: Histogram<u64>,
+    // the histograms of the open connections
+    open: Vec<Arc<Mutex<Histogram<u64>>>>,
+}
+
+impl LatencyMetrics {
+    pub(crate) fn new() -> Self {
+        LatencyMetrics {
+            state: Arc::new(Mutex::new(LatencyState {
+                closed: new_histogram(),
+                open: Vec::new(),
+            })),
+        }
+    }
+
+    /// Returns the latency of all the calls recorded so far.
+    pub fn snapshot(&self) -> LatencySnapshot {
+        let state = self.state.lock().unwrap();
+        let mut merged = state.closed.clone();
+        for histogram in &state.open {
+            merged
+                .add(&*histogram.lock().unwrap())
+                .expect("histograms with the same bounds");
+        }
+        LatencySnapshot {
+            count: merged.len(),
+            p50: Duration::from_nanos(merged.value_at_quantile(0.5)),
+            p99: Duration::from_nanos(merged.value_at_quantile(0.99)),
+            max: Duration::from_nanos(merged.max()),
+        }
+    }
+
+    // Start recording the calls of a new connection.
+    pub(crate) fn connection(&self) -> ConnectionLatency {
+        let histogram = Arc::new(Mutex::new(new_histogram()));
+        let mut state = self.state.lock().unwrap();
+        state.open.push(Arc::clone(&histogram));
+        ConnectionLatency {
+            metrics: self.clone(),
+            histogram,
+        }
+    }
+}
+
+/// Latency percentiles of the engine calls of a `KvsServer`, see
+/// `KvsServer::latency_snapshot`.
+///
+/// The percentiles are zero if no call has been recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencySnapshot {
+    /// The number of calls recorded.
+    pub count: u64,
+    /// The median latency.
+    pub p50: Duration,
+    /// The 99th percentile latency.
+    pub p99: Duration,
+    /// The highest latency.
+    pub max: Duration,
+}
+
+// The histogram of a connection, merged into the closed calls when it is dropped.
+pub(crate) struct ConnectionLatency {
+    metrics: LatencyMetrics,
+    histogram: Arc<Mutex<Histogram<u64>>>,
+}
+
+impl ConnectionLatency {
+    pub(crate) fn record(&self, latency: Duration) {
+        let nanos = latency.as_nanos().min(u128::from(u64::MAX)) as u64;
+        self.histogram.lock().unwrap().saturating_record(nanos);
+    }
+}
+
+impl Drop for ConnectionLatency {
+    fn drop(&mut self) {
+        let mut state = self.metrics.state.lock().unwrap();
+        state
+            .open
+            .retain(|histogram| !Arc::ptr_eq(histogram, &self.histogram));
+        let histogram = self.histogram.lock().unwrap();
+        state
+            .closed
+            .add(&*histogram)
+            .expect("histograms with the same bounds");
+    }
+}
+
+// A histogram of nanoseconds with 3 significant digits, growing to fit any value.
+fn new_histogram() -> Histogram<u64> {
+    Histogram::new(3).expect("valid significant digits")
+}
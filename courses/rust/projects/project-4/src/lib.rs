@@ -1,14 +1,26 @@
 #![deny(missing_docs)]
 //! A simple key/value store.
 
-pub use client::KvsClient;
-pub use engines::{KvStore, KvsEngine, SledKvsEngine};
+pub use access_log::{AccessLog, AccessLogFormat, AccessLogKeys};
+pub use client::{KvsClient, KvsClientPool, Pipeline, PooledClient};
+pub use codec::{BincodeCodec, Codec, JsonCodec};
+pub use common::{Op, OpResult, PongInfo, ServerError};
+pub use engines::{
+    CompactionProgress, CompactionStrategy, Compression, KvIter, KvStore, KvStoreOptions,
+    KvsEngine, LogFormat, MemoryKvsEngine, MemoryLogs, ReadOnlyKvStore, RecordLocation,
+    RecoveryProgress, SledKvsEngine, Snapshot, SnapshotIter, Stats, SyncPolicy, ValueMeta,
+    VerifyReport, WriteBatch,
+};
 pub use error::{KvsError, Result};
-pub use server::KvsServer;
+pub use latency::{LatencyMetrics, LatencySnapshot};
+pub use server::{KvsServer, KvsServerConfig, RateLimit};
 
+mod access_log;
 mod client;
+mod codec;
 mod common;
 mod engines;
 mod error;
+mod latency;
 mod server;
 pub mod thread_pool;
@@ -1,77 +1,793 @@
-use crate::common::{GetResponse, RemoveResponse, Request, SetResponse};
+use crate::access_log::AccessLog;
+use crate::codec::Codec;
+use crate::common::{
+    queue_frame, read_frame, BatchResponse, Envelope, EnvelopeId, ExistsResponse, GetOrSetResponse,
+    GetResponse, HealthResponse, IncrementResponse, MalformedResponse, PingResponse,
+    RemoveResponse, Request, ResponseStatus, ScanResponse, ServerError, SetIfAbsentResponse,
+    SetResponse, StatsResponse,
+};
+use crate::engines::{is_self_check_key, SELF_CHECK_PREFIX};
+use crate::latency::{ConnectionLatency, LatencyMetrics, LatencySnapshot};
 use crate::thread_pool::ThreadPool;
-use crate::{KvsEngine, Result};
+use crate::{KvsEngine, KvsError, Result};
+use crossbeam::channel::{self, Receiver, TryRecvError};
+use crossbeam::sync::WaitGroup;
+#[cfg(not(feature = "tracing"))]
 use log::{debug, error};
-use serde_json::Deserializer;
-use std::io::{BufReader, BufWriter, Write};
-use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(feature = "tls")]
+use rustls::{Certificate, PrivateKey, ServerConfig, ServerConnection, StreamOwned};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::collections::HashMap;
+#[cfg(unix)]
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::ops::Bound;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+#[cfg(feature = "tracing")]
+use tracing::{debug, error};
+
+// How long `run_with_shutdown` waits for a connection before checking for shutdown again.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Limits and buffer sizes of the connections a `KvsServer` takes.
+#[derive(Clone, Copy, Debug)]
+pub struct KvsServerConfig {
+    /// The maximum number of connections served at the same time.
+    ///
+    /// A connection accepted above the limit is closed immediately.
+    pub max_connections: usize,
+    /// The length of the queue of pending connections of the listening socket.
+    pub backlog: i32,
+    /// The rate at which each connection may send requests, unlimited if `None`.
+    pub rate_limit: Option<RateLimit>,
+    /// The size in bytes of the read buffer and of the write buffer of a connection.
+    ///
+    /// Both buffers are allocated for every connection, so each one costs twice this
+    /// size in memory. Larger buffers take fewer system calls to serve many requests
+    /// sent at once.
+    pub io_buffer_size: usize,
+    /// Whether to record the latency of the `get`, `set` and `remove` engine calls, see
+    /// `KvsServer::latency_snapshot`.
+    pub record_latency: bool,
+}
+
+impl Default for KvsServerConfig {
+    fn default() -> Self {
+        KvsServerConfig {
+            max_connections: 1024,
+            backlog: 128,
+            rate_limit: None,
+            io_buffer_size: 8 * 1024,
+            record_latency: false,
+        }
+    }
+}
+
+/// A token bucket limiting the requests of a connection.
+///
+/// A request over the limit is answered with a "rate limited" error and the connection
+/// stays open. Pings are not limited.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    /// The rate at which the bucket refills.
+    pub requests_per_second: u32,
+    /// The capacity of the bucket, i.e. how many requests may come at once.
+    pub burst: u32,
+}
+
+// The state of the `RateLimit` of a connection.
+struct RateLimiter {
+    limit: RateLimit,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    fn new(limit: RateLimit) -> Self {
+        RateLimiter {
+            limit,
+            tokens: f64::from(limit.burst),
+            last_refill: Instant::now(),
+        }
+    }
+
+    // Take a token for a request, or return `false` if there is none left.
+    fn try_acquire(&mut self) -> bool {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last_refill).as_secs_f64()
+            * f64::from(self.limit.requests_per_second);
+        self.tokens = (self.tokens + refill).min(f64::from(self.limit.burst));
+        self.last_refill = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
 
 /// The server of a key value store.
-pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
+///
+/// With the `tracing` feature, each connection is served in a `connection` span with the
+/// `peer` address, and each request in a `request` span with its `id`. Without a tracing
+/// subscriber, the events still go to `log`.
+pub struct KvsServer<E: KvsEngine, P: ThreadPool, C: Codec> {
     engine: E,
     pool: P,
+    codec: C,
+    config: KvsServerConfig,
+    // the number of connections being served
+    connections: Arc<AtomicUsize>,
+    latency: LatencyMetrics,
+    access_log: Option<AccessLog>,
 }
 
-impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
-    /// Create a `KvsServer` with a given storage engine.
-    pub fn new(engine: E, pool: P) -> Self {
-        KvsServer { engine, pool }
+impl<E: KvsEngine, P: ThreadPool, C: Codec> KvsServer<E, P, C> {
+    /// Create a `KvsServer` with a given storage engine, serving the clients that use
+    /// the same codec.
+    pub fn new(engine: E, pool: P, codec: C) -> Self {
+        Self::with_config(engine, pool, codec, KvsServerConfig::default())
+    }
+
+    /// Create a `KvsServer` like `new`, with the given limits on connections.
+    pub fn with_config(engine: E, pool: P, codec: C, config: KvsServerConfig) -> Self {
+        KvsServer {
+            engine,
+            pool,
+            codec,
+            config,
+            connections: Arc::new(AtomicUsize::new(0)),
+            latency: LatencyMetrics::new(),
+            access_log: None,
+        }
+    }
+
+    /// Writes a record of every request served to `access_log`.
+    ///
+    /// A request over the rate limit is recorded with the `rate_limited` status, and one
+    /// that can't be decoded with the `malformed` operation.
+    pub fn with_access_log(mut self, access_log: AccessLog) -> Self {
+        self.access_log = Some(access_log);
+        self
+    }
+
+    /// Returns the latency percentiles of the engine calls served so far.
+    ///
+    /// Only `get`, `set` and `remove` requests are recorded, and only if
+    /// `KvsServerConfig::record_latency` is set.
+    pub fn latency_snapshot(&self) -> LatencySnapshot {
+        self.latency.snapshot()
+    }
+
+    /// Returns a handle to the latency metrics of the server, to take snapshots while it
+    /// runs.
+    pub fn latency_metrics(&self) -> LatencyMetrics {
+        self.latency.clone()
+    }
+
+    // The latency metrics to pass to a new connection, if latency is recorded.
+    fn connection_latency(&self) -> Option<LatencyMetrics> {
+        if self.config.record_latency {
+            Some(self.latency.clone())
+        } else {
+            None
+        }
     }
 
     /// Run the server listening on the given address
     pub fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
-        let listener = TcpListener::bind(addr)?;
-        for stream in listener.incoming() {
-            let engine = self.engine.clone();
-            self.pool.spawn(move || match stream {
-                Ok(stream) => {
-                    if let Err(e) = serve(engine, stream) {
-                        error!("Error on serving client: {}", e);
+        self.run_wrapped(addr, Ok)
+    }
+
+    /// Run the server on a new thread, listening on the given address.
+    ///
+    /// The address the listener is bound to is returned once it listens, so binding to
+    /// port 0 lets the OS pick a free port and the caller learns which one. The thread
+    /// runs the server like `run` and its handle returns the result.
+    pub fn run_with_addr<A: ToSocketAddrs>(
+        self,
+        addr: A,
+    ) -> Result<(SocketAddr, JoinHandle<Result<()>>)>
+    where
+        P: Send + 'static,
+    {
+        let listener = self.bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let handle = thread::spawn(move || self.serve_incoming(listener.incoming(), Ok));
+        Ok((local_addr, handle))
+    }
+
+    /// Run the server listening on all the given addresses, e.g. an IPv4 and an IPv6 one.
+    ///
+    /// Each listener accepts connections on its own thread, and the connections from all
+    /// of them are served by the same thread pool and engine. It fails if any address
+    /// can't be bound.
+    pub fn run_multi(self, addrs: Vec<SocketAddr>) -> Result<()> {
+        let listeners = addrs
+            .into_iter()
+            .map(|addr| self.bind(addr))
+            .collect::<Result<Vec<_>>>()?;
+        let (sender, receiver) = channel::unbounded();
+        for listener in listeners {
+            let sender = sender.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if sender.send(stream).is_err() {
+                        break;
                     }
                 }
-                Err(e) => error!("Connection failed: {}", e),
+            });
+        }
+        drop(sender);
+        self.serve_incoming(receiver, Ok)
+    }
+
+    /// Run the server like `run`, encrypting every connection with TLS.
+    ///
+    /// `cert` is the certificate chain of the server, starting with its own certificate,
+    /// and `key` is the private key of that certificate. The clients connect with
+    /// `KvsClient::connect_tls`.
+    #[cfg(feature = "tls")]
+    pub fn run_tls<A: ToSocketAddrs>(
+        self,
+        addr: A,
+        cert: Vec<Certificate>,
+        key: PrivateKey,
+    ) -> Result<()> {
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(cert, key)?;
+        let config = Arc::new(config);
+        self.run_wrapped(addr, move |tcp| {
+            let conn = ServerConnection::new(Arc::clone(&config))?;
+            Ok(StreamOwned::new(conn, tcp))
+        })
+    }
+
+    /// Run the server listening on a Unix domain socket at `path`, for clients on the same
+    /// host that connect with `KvsClient::connect_unix`.
+    ///
+    /// It fails if a file already exists at `path`. The socket file is removed when the
+    /// server stops.
+    #[cfg(unix)]
+    pub fn run_unix(self, path: &Path) -> Result<()> {
+        let listener = UnixListener::bind(path)?;
+        let _socket_file = SocketFile(path.to_owned());
+        self.serve_incoming(listener.incoming(), Ok)
+    }
+
+    /// Serve a single connection over `stream` on the current thread, until the client
+    /// closes it.
+    ///
+    /// Only available with the `testing` feature.
+    #[cfg(feature = "testing")]
+    pub fn serve_stream<S: Read + Write>(&self, stream: S) -> Result<()> {
+        serve(
+            self.engine.clone(),
+            self.codec.clone(),
+            stream,
+            "test stream",
+            Instant::now(),
+            self.config,
+            self.connection_latency(),
+            self.access_log.clone(),
+        )
+    }
+
+    // Run the server, serving each connection through the stream `wrap` makes of it.
+    // `wrap` is called on the thread pool, so it may block, e.g. on a handshake.
+    fn run_wrapped<A, S, F>(self, addr: A, wrap: F) -> Result<()>
+    where
+        A: ToSocketAddrs,
+        S: Read + Write,
+        F: Fn(TcpStream) -> Result<S> + Clone + Send + 'static,
+    {
+        let listener = self.bind(addr)?;
+        self.serve_incoming(listener.incoming(), wrap)
+    }
+
+    // Serve the connections of `incoming` until it ends, like `run_wrapped`.
+    fn serve_incoming<I, T, S, F>(self, incoming: I, wrap: F) -> Result<()>
+    where
+        I: IntoIterator<Item = io::Result<T>>,
+        T: Connection,
+        S: Read + Write,
+        F: Fn(T) -> Result<S> + Clone + Send + 'static,
+    {
+        let started = Instant::now();
+        for stream in incoming {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("Connection failed: {}", e);
+                    continue;
+                }
+            };
+            let permit = match self.admit(&stream) {
+                Some(permit) => permit,
+                None => continue,
+            };
+            let engine = self.engine.clone();
+            let codec = self.codec.clone();
+            let wrap = wrap.clone();
+            let config = self.config;
+            let latency = self.connection_latency();
+            let access_log = self.access_log.clone();
+            self.pool.spawn(move || {
+                let serve_stream = || {
+                    let peer_addr = stream.peer()?;
+                    let stream = wrap(stream)?;
+                    serve(
+                        engine, codec, stream, &peer_addr, started, config, latency, access_log,
+                    )
+                };
+                if let Err(e) = serve_stream() {
+                    error!("Error on serving client: {}", e);
+                }
+                drop(permit);
+            })
+        }
+        Ok(())
+    }
+
+    /// Run the server listening on the given address until `shutdown` receives a message
+    /// or its sender is dropped.
+    ///
+    /// After the shutdown signal, no new connection is accepted. Each open connection
+    /// finishes the request it is serving and is then closed. It returns when all the
+    /// connections are done.
+    pub fn run_with_shutdown<A: ToSocketAddrs>(
+        self,
+        addr: A,
+        shutdown: Receiver<()>,
+    ) -> Result<()> {
+        let listener = self.bind(addr)?;
+        let started = Instant::now();
+        // accept without blocking so that the shutdown signal is noticed
+        listener.set_nonblocking(true)?;
+        // clones of the open connections, used to close them on shutdown
+        let connections = Arc::new(Mutex::new(HashMap::new()));
+        let wg = WaitGroup::new();
+        let mut next_id: u64 = 0;
+        while let Err(TryRecvError::Empty) = shutdown.try_recv() {
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(ACCEPT_POLL_INTERVAL);
+                    continue;
+                }
+                Err(e) => {
+                    error!("Connection failed: {}", e);
+                    continue;
+                }
+            };
+            let permit = match self.admit(&stream) {
+                Some(permit) => permit,
+                None => continue,
+            };
+            let clone = match stream
+                .set_nonblocking(false)
+                .and_then(|_| stream.try_clone())
+            {
+                Ok(clone) => clone,
+                Err(e) => {
+                    error!("Connection failed: {}", e);
+                    continue;
+                }
+            };
+            let id = next_id;
+            next_id += 1;
+            connections.lock().unwrap().insert(id, clone);
+
+            let engine = self.engine.clone();
+            let codec = self.codec.clone();
+            let connections = Arc::clone(&connections);
+            let wg = wg.clone();
+            let config = self.config;
+            let latency = self.connection_latency();
+            let access_log = self.access_log.clone();
+            self.pool.spawn(move || {
+                let serve_stream = || {
+                    let peer_addr = stream.peer()?;
+                    serve(
+                        engine, codec, stream, &peer_addr, started, config, latency, access_log,
+                    )
+                };
+                if let Err(e) = serve_stream() {
+                    error!("Error on serving client: {}", e);
+                }
+                connections.lock().unwrap().remove(&id);
+                drop(permit);
+                drop(wg);
             })
         }
+
+        // The clients see the end of the connection once their current request is served
+        for stream in connections.lock().unwrap().values() {
+            if let Err(e) = stream.shutdown(Shutdown::Read) {
+                error!("Connection cannot be shut down: {}", e);
+            }
+        }
+        wg.wait();
         Ok(())
     }
+
+    // Bind a listener to the first of the addresses that works, with the configured backlog.
+    fn bind<A: ToSocketAddrs>(&self, addr: A) -> Result<TcpListener> {
+        let mut last_err = None;
+        for addr in addr.to_socket_addrs()? {
+            match listen(addr, self.config.backlog) {
+                Ok(listener) => return Ok(listener),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err
+            .unwrap_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "could not resolve to any addresses",
+                )
+            })
+            .into())
+    }
+
+    // Take a slot for a newly accepted connection. If all the slots are taken, the
+    // connection is closed and `None` is returned.
+    fn admit<T: Connection>(&self, stream: &T) -> Option<ConnectionPermit> {
+        let permit = ConnectionPermit::acquire(&self.connections);
+        if permit.count > self.config.max_connections {
+            debug!(
+                "Reject connection from {:?}: {} connections already",
+                stream.peer(),
+                self.config.max_connections
+            );
+            if let Err(e) = stream.shutdown(Shutdown::Both) {
+                error!("Connection cannot be shut down: {}", e);
+            }
+            return None;
+        }
+        Some(permit)
+    }
+}
+
+fn listen(addr: SocketAddr, backlog: i32) -> io::Result<TcpListener> {
+    let domain = if addr.is_ipv4() {
+        Domain::ipv4()
+    } else {
+        Domain::ipv6()
+    };
+    let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+    // same as std's TcpListener, which lets the server restart on a port in TIME_WAIT
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&SockAddr::from(addr))?;
+    socket.listen(backlog)?;
+    Ok(socket.into_tcp_listener())
+}
+
+// A slot in the connection count, released when it is dropped.
+struct ConnectionPermit {
+    connections: Arc<AtomicUsize>,
+    // the number of connections including this one
+    count: usize,
+}
+
+impl ConnectionPermit {
+    fn acquire(connections: &Arc<AtomicUsize>) -> Self {
+        let count = connections.fetch_add(1, Ordering::SeqCst) + 1;
+        ConnectionPermit {
+            connections: Arc::clone(connections),
+            count,
+        }
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// A stream accepted by a listener of the server.
+trait Connection: Send + 'static {
+    // The address of the client, as shown in the logs.
+    fn peer(&self) -> io::Result<String>;
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn peer(&self) -> io::Result<String> {
+        Ok(self.peer_addr()?.to_string())
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
 }
 
-fn serve<E: KvsEngine>(engine: E, tcp: TcpStream) -> Result<()> {
-    let peer_addr = tcp.peer_addr()?;
-    let reader = BufReader::new(&tcp);
-    let mut writer = BufWriter::new(&tcp);
-    let req_reader = Deserializer::from_reader(reader).into_iter::<Request>();
+#[cfg(unix)]
+impl Connection for UnixStream {
+    // the clients of a Unix socket are usually unnamed
+    fn peer(&self) -> io::Result<String> {
+        Ok(format!("{:?}", self.peer_addr()?))
+    }
 
-    macro_rules! send_resp {
-        ($resp:expr) => {{
-            let resp = $resp;
-            serde_json::to_writer(&mut writer, &resp)?;
-            writer.flush()?;
-            debug!("Response sent to {}: {:?}", peer_addr, resp);
-        };};
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        UnixStream::shutdown(self, how)
     }
+}
 
-    for req in req_reader {
-        let req = req?;
+// The socket file of `KvsServer::run_unix`, removed when it is dropped.
+#[cfg(unix)]
+struct SocketFile(PathBuf);
+
+#[cfg(unix)]
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.0) {
+            error!("Socket file {} cannot be removed: {}", self.0.display(), e);
+        }
+    }
+}
+
+// `started` is when the server started, to report its uptime.
+#[allow(clippy::too_many_arguments)]
+fn serve<E: KvsEngine, C: Codec, S: Read + Write>(
+    engine: E,
+    codec: C,
+    stream: S,
+    peer_addr: &str,
+    started: Instant,
+    config: KvsServerConfig,
+    latency: Option<LatencyMetrics>,
+    access_log: Option<AccessLog>,
+) -> Result<()> {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::info_span!("connection", peer = %peer_addr).entered();
+    // lives as long as the connection
+    let mut limiter = config.rate_limit.map(RateLimiter::new);
+    let latency = latency.map(|metrics| metrics.connection());
+    let stream = BufWriteStream(BufWriter::with_capacity(config.io_buffer_size, stream));
+    let mut stream = BufReader::with_capacity(config.io_buffer_size, stream);
+
+    // The client sends the ID of its codec first, then learns ours
+    let mut client_codec = [0; 1];
+    match stream.read_exact(&mut client_codec) {
+        Ok(()) => {}
+        // the client left, or the server is shutting down
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+        Err(e) => return Err(e.into()),
+    }
+    stream.get_mut().write_all(&[C::ID])?;
+    stream.get_mut().flush()?;
+    if client_codec[0] != C::ID {
+        return Err(KvsError::StringError(format!(
+            "Client codec {} doesn't match server codec {}",
+            client_codec[0],
+            C::ID
+        )));
+    }
+
+    loop {
+        // answer all the requests read so far before waiting for more
+        if stream.buffer().is_empty() {
+            stream.get_mut().flush()?;
+        }
+        let payload = match read_frame(&mut stream)? {
+            Some(payload) => payload,
+            None => break,
+        };
+        let req: Envelope<Request> = match codec.decode(&payload) {
+            Ok(req) => req,
+            Err(e) => {
+                // the frame is consumed whole, so the next request is read from its start
+                debug!("Malformed request from {}: {}", peer_addr, e);
+                let id = codec.decode::<EnvelopeId>(&payload).map_or(0, |req| req.id);
+                let msg = ServerError::InvalidCommand("malformed request".to_owned());
+                let resp = Envelope {
+                    id,
+                    body: MalformedResponse::Err(msg),
+                };
+                queue_frame(stream.get_mut(), &codec.encode(&resp)?)?;
+                if let Some(access_log) = &access_log {
+                    access_log.record(peer_addr, "malformed", None, resp.body.status());
+                }
+                continue;
+            }
+        };
+        // covers the engine call and the response
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("request", id = req.id).entered();
         debug!("Receive request from {}: {:?}", peer_addr, req);
-        match req {
-            Request::Get { key } => send_resp!(match engine.get(key) {
+
+        // every response carries the id of the request it answers
+        let id = req.id;
+        // taken before the request is consumed, for the access log
+        let (op, key) = match &access_log {
+            Some(_) => {
+                let (op, key) = req.body.op_and_key();
+                (op, key.map(str::to_owned))
+            }
+            None => ("", None),
+        };
+        macro_rules! send_resp {
+            ($resp:expr) => {
+                send_resp!($resp, None)
+            };
+            // `$status` overrides the status of the response in the access log
+            ($resp:expr, $status:expr) => {{
+                let resp = Envelope { id, body: $resp };
+                queue_frame(stream.get_mut(), &codec.encode(&resp)?)?;
+                debug!("Response sent to {}: {:?}", peer_addr, resp);
+                if let Some(access_log) = &access_log {
+                    let status: Option<&str> = $status;
+                    let status = status.unwrap_or_else(|| resp.body.status());
+                    access_log.record(peer_addr, op, key.as_deref(), status);
+                }
+            }};
+        }
+
+        let limited = match &mut limiter {
+            Some(limiter) => !matches!(req.body, Request::Ping) && !limiter.try_acquire(),
+            None => false,
+        };
+        let rejection = if limited {
+            debug!("Rate limit exceeded by {}", peer_addr);
+            let msg = ServerError::Internal("rate limited".to_owned());
+            Some((msg, Some("rate_limited")))
+        } else if req.body.uses_key_prefix(SELF_CHECK_PREFIX) {
+            let msg = format!("Keys starting with {} are reserved", SELF_CHECK_PREFIX);
+            Some((ServerError::InvalidCommand(msg), None))
+        } else {
+            None
+        };
+        if let Some((msg, status)) = rejection {
+            match req.body {
+                Request::Get { .. } => send_resp!(GetResponse::Err(msg), status),
+                Request::Set { .. } => send_resp!(SetResponse::Err(msg), status),
+                Request::Remove { .. } => send_resp!(RemoveResponse::Err(msg), status),
+                Request::Exists { .. } => send_resp!(ExistsResponse::Err(msg), status),
+                Request::Batch(_) => send_resp!(BatchResponse::Err(msg), status),
+                Request::Health => send_resp!(HealthResponse::Err(msg), status),
+                Request::Scan { .. } => send_resp!(ScanResponse::Err(msg), status),
+                Request::Increment { .. } | Request::Decrement { .. } => {
+                    send_resp!(IncrementResponse::Err(msg), status)
+                }
+                Request::SetCounter { .. } => send_resp!(SetResponse::Err(msg), status),
+                Request::GetOrSet { .. } => send_resp!(GetOrSetResponse::Err(msg), status),
+                Request::SetIfAbsent { .. } => send_resp!(SetIfAbsentResponse::Err(msg), status),
+                Request::Stats => send_resp!(StatsResponse::Err(msg), status),
+                // never limited
+                Request::Ping => {}
+            }
+            continue;
+        }
+
+        match req.body {
+            Request::Get { key } => send_resp!(match timed(&latency, || engine.get(key)) {
                 Ok(value) => GetResponse::Ok(value),
-                Err(e) => GetResponse::Err(format!("{}", e)),
-            }),
-            Request::Set { key, value } => send_resp!(match engine.set(key, value) {
-                Ok(_) => SetResponse::Ok(()),
-                Err(e) => SetResponse::Err(format!("{}", e)),
+                Err(e) => GetResponse::Err(e.into()),
             }),
-            Request::Remove { key } => send_resp!(match engine.remove(key) {
+            Request::Set { key, value } => {
+                send_resp!(match timed(&latency, || engine.set(key, value)) {
+                    Ok(_) => SetResponse::Ok(()),
+                    Err(e) => SetResponse::Err(e.into()),
+                })
+            }
+            Request::Remove { key } => send_resp!(match timed(&latency, || engine.remove(key)) {
                 Ok(_) => RemoveResponse::Ok(()),
-                Err(e) => RemoveResponse::Err(format!("{}", e)),
+                Err(e) => RemoveResponse::Err(e.into()),
+            }),
+            Request::Exists { key } => send_resp!(match engine.exists(key) {
+                Ok(exists) => ExistsResponse::Ok(exists),
+                Err(e) => ExistsResponse::Err(e.into()),
+            }),
+            Request::Batch(ops) => send_resp!(match engine.batch(ops) {
+                Ok(results) => BatchResponse::Ok(results),
+                Err(e) => BatchResponse::Err(e.into()),
+            }),
+            // answered without the engine, so it stays cheap
+            Request::Ping => send_resp!(PingResponse::Pong {
+                version: env!("CARGO_PKG_VERSION").to_owned(),
+                uptime_secs: started.elapsed().as_secs(),
+            }),
+            Request::Health => send_resp!(match engine.self_check() {
+                Ok(_) => HealthResponse::Ok(()),
+                Err(e) => HealthResponse::Err(e.into()),
+            }),
+            Request::Scan { start, end, limit } => {
+                let start = start.map_or(Bound::Unbounded, Bound::Included);
+                let end = end.map_or(Bound::Unbounded, Bound::Excluded);
+                send_resp!(match engine.scan(start, end) {
+                    Ok(mut pairs) => {
+                        // visible while a health check runs
+                        pairs.retain(|(key, _)| !is_self_check_key(key));
+                        if let Some(limit) = limit {
+                            pairs.truncate(limit);
+                        }
+                        ScanResponse::Ok(pairs)
+                    }
+                    Err(e) => ScanResponse::Err(e.into()),
+                })
+            }
+            Request::Increment { key, delta } => send_resp!(match engine.increment(key, delta) {
+                Ok(value) => IncrementResponse::Ok(value),
+                Err(e) => IncrementResponse::Err(e.into()),
+            }),
+            Request::Decrement { key, delta } => send_resp!(match engine.decrement(key, delta) {
+                Ok(value) => IncrementResponse::Ok(value),
+                Err(e) => IncrementResponse::Err(e.into()),
+            }),
+            Request::SetCounter { key, value, ttl_ms } => {
+                let ttl = ttl_ms.map(Duration::from_millis);
+                send_resp!(match engine.set_counter(key, value, ttl) {
+                    Ok(_) => SetResponse::Ok(()),
+                    Err(e) => SetResponse::Err(e.into()),
+                })
+            }
+            Request::GetOrSet { key, default } => {
+                send_resp!(match engine.get_or_set(key, default) {
+                    Ok(value) => GetOrSetResponse::Ok(value),
+                    Err(e) => GetOrSetResponse::Err(e.into()),
+                })
+            }
+            Request::SetIfAbsent { key, value } => {
+                send_resp!(match engine.set_if_absent(key, value) {
+                    Ok(set) => SetIfAbsentResponse::Ok(set),
+                    Err(e) => SetIfAbsentResponse::Err(e.into()),
+                })
+            }
+            Request::Stats => send_resp!(match engine.stats() {
+                Ok(stats) => StatsResponse::Ok(stats.into()),
+                Err(e) => StatsResponse::Err(e.into()),
             }),
         };
     }
+    stream.get_mut().flush()?;
     Ok(())
 }
 
+// Buffers the writes to a stream, while reads go straight to the stream so that a
+// `BufReader` on top buffers them.
+struct BufWriteStream<S: Write>(BufWriter<S>);
+
+impl<S: Read + Write> Read for BufWriteStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.get_mut().read(buf)
+    }
+}
+
+impl<S: Write> Write for BufWriteStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+// Run an engine call, recording how long it takes if the connection records latency.
+fn timed<T>(latency: &Option<ConnectionLatency>, call: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let res = call();
+    if let Some(latency) = latency {
+        latency.record(start.elapsed());
+    }
+    res
+}
+
 // 详细中文注释（补充，不删除已有注释）：
 // 1. 设计概述：
 //    - `KvsServer` 是处理网络请求的入口，使用泛型 `E: KvsEngine` 表示存储引擎，`P: ThreadPool` 表示并发任务执行策略。
@@ -85,9 +801,10 @@ fn serve<E: KvsEngine>(engine: E, tcp: TcpStream) -> Result<()> {
 //    - 每个连接都在独立的任务（线程）中处理，若 `engine` 包含内部共享的状态（比如 `Arc<Mutex<...>>`），克隆通常只是复制 `Arc`，不会复制实际数据，
 //      因此多个任务可以并发访问同一个底层资源（需要内部同步）。因此，实现 `KvsEngine` 时通常会用 `Arc` 等类型来保证安全共享。
 // 4. serve 函数如何工作：
-//    - 使用 `BufReader` 从 TCP 流中通过 `serde_json::Deserializer` 解析一系列 `Request`（基于 stream 的 JSON 解析）。
-//    - 对于每个 `Request`，根据类型调用 `engine.get/set/remove`，并通过 `serde_json::to_writer` 将响应写回客户端。
-//    - 使用宏 `send_resp!` 统一序列化与 flush，保证在发送每个响应后数据被推进到网络。
+//    - 使用 `BufReader` 从 TCP 流中通过 `read_frame` 逐帧（4 字节大端长度 + JSON 负载）解析一系列 `Request`。
+//    - 对于每个 `Request`，根据类型调用 `engine.get/set/remove`，并通过 `queue_frame` 将响应写入 `BufWriter`。
+//    - 读缓冲区中没有剩余请求时（即将阻塞等待客户端）才 flush，这样一次发来的多个请求的响应可以合并写出；
+//      两个缓冲区的大小由 `KvsServerConfig::io_buffer_size` 决定。
 // 5. 错误与健壮性考虑：
 //    - 连接级别出错或请求反序列化出错会导致该连接的任务返回错误，但不会影响其他连接（错误被记录）。
 //    - 如果 `engine` 在并发访问时使用 `Mutex`，要注意不要在持锁状态下进行阻塞或长时间 IO，以免影响其他请求。
@@ -7,9 +7,9 @@ mod naive;
 mod rayon;
 mod shared_queue;
 
-pub use self::naive::NaiveThreadPool;
+pub use self::naive::{BoundedNaivePool, NaiveThreadPool};
 pub use self::rayon::RayonThreadPool;
-pub use self::shared_queue::SharedQueueThreadPool;
+pub use self::shared_queue::{PoolMetrics, SharedQueueThreadPool, SharedQueueThreadPoolBuilder};
 
 /// The trait that all thread pools should implement.
 /// 标准线程池的两个核心行为：初始化 new 和 派发任务 spawn
@@ -47,8 +47,6 @@ pub trait ThreadPool {
             + 'static;
 }
 
-
-
 // 详细说明（中文）：
 // 1. 线程池的目的：线程创建与销毁开销大，尤其在高并发场景下频繁创建线程会极大影响性能和延迟。
 //    线程池通过复用固定数量的线程来处理多个任务，从而将线程管理与任务调度解耦，降低上下文切换成本。
@@ -1,7 +1,8 @@
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
 
 use super::ThreadPool;
-use crate::Result;
+use crate::{KvsError, Result};
 
 /// It is actually not a thread pool. It spawns a new thread every time
 /// the `spawn` method is called.
@@ -20,6 +21,72 @@ impl ThreadPool for NaiveThreadPool {
     }
 }
 
+/// Like `NaiveThreadPool`, it spawns a new thread for every job, but at most `threads`
+/// of them run at the same time.
+///
+/// `spawn` blocks until a running job finishes when the limit is reached.
+pub struct BoundedNaivePool {
+    permits: Arc<Permits>,
+}
+
+// The number of threads that may still be spawned, and a condvar signaled when it grows.
+struct Permits {
+    available: Mutex<u32>,
+    released: Condvar,
+}
+
+impl Permits {
+    fn acquire(&self) {
+        let mut available = self.available.lock().unwrap();
+        while *available == 0 {
+            available = self.released.wait(available).unwrap();
+        }
+        *available -= 1;
+    }
+
+    fn release(&self) {
+        *self.available.lock().unwrap() += 1;
+        self.released.notify_one();
+    }
+}
+
+// Gives the permit back when the job ends, even if it panics.
+struct PermitGuard(Arc<Permits>);
+
+impl Drop for PermitGuard {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+impl ThreadPool for BoundedNaivePool {
+    fn new(threads: u32) -> Result<Self> {
+        if threads == 0 {
+            return Err(KvsError::StringError(
+                "A thread pool needs at least one thread".to_owned(),
+            ));
+        }
+        Ok(BoundedNaivePool {
+            permits: Arc::new(Permits {
+                available: Mutex::new(threads),
+                released: Condvar::new(),
+            }),
+        })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.permits.acquire();
+        let guard = PermitGuard(Arc::clone(&self.permits));
+        thread::spawn(move || {
+            let _guard = guard;
+            job();
+        });
+    }
+}
+
 // 详细中文注释（补充，不删除已有注释）：
 // 1. 本实现名为 "NaiveThreadPool"，但严格来说并不是一个真正的线程池：
 //    每次调用 `spawn` 都会创建一个新的操作系统线程并立即运行任务。
@@ -32,3 +99,5 @@ impl ThreadPool for NaiveThreadPool {
 // 4. 扩展建议：
 //    - 想要实现真正的线程池，需要保持一组固定线程并提供一个任务队列（如 channel），线程从队列中取任务并执行。
 //    - 可参考本仓库的 `SharedQueueThreadPool` 作为一个更现实的实现。
+//    - `BoundedNaivePool` 保留了“每个任务一个线程”的简单做法，但用计数信号量（`Mutex<u32>` + `Condvar`）限制同时运行的线程数，
+//      达到上限时 `spawn` 会阻塞，直到某个任务结束（包括 panic）归还许可，从而避免线程数量爆炸。
@@ -1,9 +1,12 @@
-use std::thread;
+use std::io;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
 
 use super::ThreadPool;
-use crate::Result;
+use crate::{KvsError, Result};
 
-use crossbeam::channel::{self, Receiver, Sender};
+use crossbeam::channel::{self, select, Receiver, Sender};
 
 use log::{debug, error};
 
@@ -22,20 +25,189 @@ use log::{debug, error};
 pub struct SharedQueueThreadPool {
     // 发送端，专门发送 装箱的闭包 // 线程池本身不拥有线程，只是任务的发射器
     tx: Sender<Box<dyn FnOnce() + Send + 'static>>,
+    // each message stops one worker thread, see `set_size`
+    stop_tx: Sender<()>,
+    stop_rx: Receiver<()>,
+    rx: Receiver<Box<dyn FnOnce() + Send + 'static>>,
+    // the number of worker threads the pool is meant to have
+    size: Mutex<u32>,
+    state: Arc<PoolState>,
 }
 
-impl ThreadPool for SharedQueueThreadPool {
-    fn new(threads: u32) -> Result<Self> {
+/// A snapshot of the counters of a `SharedQueueThreadPool`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolMetrics {
+    /// The number of worker threads alive.
+    pub live_threads: usize,
+    /// The number of tasks that panicked.
+    pub total_panics: u64,
+    /// The number of tasks that returned normally.
+    pub tasks_completed: u64,
+}
+
+/// Builds a `SharedQueueThreadPool` with custom worker thread settings.
+///
+/// # Example
+///
+/// ```rust
+/// # use kvs::thread_pool::SharedQueueThreadPoolBuilder;
+/// let pool = SharedQueueThreadPoolBuilder::new(4)
+///     .name_prefix("my-worker")
+///     .stack_size(4 * 1024 * 1024)
+///     .build()?;
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct SharedQueueThreadPoolBuilder {
+    threads: u32,
+    name_prefix: String,
+    stack_size: Option<usize>,
+}
+
+impl SharedQueueThreadPoolBuilder {
+    /// Creates a builder for a pool of `threads` worker threads named `kvs-worker-<index>`
+    /// with the default stack size.
+    pub fn new(threads: u32) -> SharedQueueThreadPoolBuilder {
+        SharedQueueThreadPoolBuilder {
+            threads,
+            name_prefix: "kvs-worker".to_owned(),
+            stack_size: None,
+        }
+    }
+
+    /// Sets the prefix of the worker thread names, which are `<prefix>-<index>`.
+    pub fn name_prefix(mut self, prefix: impl Into<String>) -> SharedQueueThreadPoolBuilder {
+        self.name_prefix = prefix.into();
+        self
+    }
+
+    /// Sets the stack size of the worker threads in bytes.
+    pub fn stack_size(mut self, bytes: usize) -> SharedQueueThreadPoolBuilder {
+        self.stack_size = Some(bytes);
+        self
+    }
+
+    /// Creates the pool, immediately spawning its worker threads.
+    ///
+    /// Returns an error if any thread fails to spawn.
+    pub fn build(self) -> Result<SharedQueueThreadPool> {
         // 创建一个无界通道
         // 如果任务生产速度远已于消费速度，内存会爆炸
         let (tx, rx) = channel::unbounded::<Box<dyn FnOnce() + Send + 'static>>();
 
-        for _ in 0..threads {
-            // taskReceiver 包装
-            let rx = TaskReceiver(rx.clone());
-            thread::Builder::new().spawn(move || run_tasks(rx))?;
+        let (stop_tx, stop_rx) = channel::unbounded();
+
+        let state = Arc::new(PoolState {
+            name_prefix: self.name_prefix,
+            stack_size: self.stack_size,
+            handles: Mutex::new(Vec::new()),
+            next_index: AtomicUsize::new(0),
+            live_threads: AtomicUsize::new(0),
+            total_panics: AtomicU64::new(0),
+            tasks_completed: AtomicU64::new(0),
+        });
+        let pool = SharedQueueThreadPool {
+            tx,
+            stop_tx,
+            stop_rx,
+            rx,
+            size: Mutex::new(self.threads),
+            state,
+        };
+        for _ in 0..self.threads {
+            pool.spawn_worker()?;
+        }
+        Ok(pool)
+    }
+}
+
+// State shared by the pool and its worker threads.
+struct PoolState {
+    name_prefix: String,
+    stack_size: Option<usize>,
+    // handles of the worker threads, including the ones respawned after a panic
+    handles: Mutex<Vec<JoinHandle<()>>>,
+    // index of the next worker thread added to the pool
+    next_index: AtomicUsize,
+    live_threads: AtomicUsize,
+    total_panics: AtomicU64,
+    tasks_completed: AtomicU64,
+}
+
+impl SharedQueueThreadPool {
+    /// Returns the current counters of the pool.
+    ///
+    /// A `total_panics` growing steadily means a task keeps crashing the workers.
+    pub fn metrics(&self) -> PoolMetrics {
+        PoolMetrics {
+            live_threads: self.state.live_threads.load(Ordering::SeqCst),
+            total_panics: self.state.total_panics.load(Ordering::SeqCst),
+            tasks_completed: self.state.tasks_completed.load(Ordering::SeqCst),
         }
-        Ok(SharedQueueThreadPool { tx })
+    }
+
+    /// Resizes the pool to `threads` worker threads.
+    ///
+    /// Growing spawns the new threads right away. Shrinking asks the surplus threads to
+    /// exit: each of them finishes the task it's running first, so it may take a while
+    /// until `metrics` shows the new size.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `threads` is 0, or if a thread fails to spawn. In the latter
+    /// case the pool keeps the threads spawned so far.
+    pub fn set_size(&self, threads: u32) -> Result<()> {
+        if threads == 0 {
+            return Err(KvsError::StringError(
+                "A thread pool needs at least one thread".to_owned(),
+            ));
+        }
+        let mut size = self.size.lock().unwrap();
+        while *size < threads {
+            self.spawn_worker()?;
+            *size += 1;
+        }
+        while *size > threads {
+            // the receiver lives in `self`, so the send can't fail
+            self.stop_tx.send(()).unwrap();
+            *size -= 1;
+        }
+        Ok(())
+    }
+
+    fn spawn_worker(&self) -> Result<()> {
+        spawn_worker(TaskReceiver {
+            rx: self.rx.clone(),
+            stop_rx: self.stop_rx.clone(),
+            state: Arc::clone(&self.state),
+            index: self.state.next_index.fetch_add(1, Ordering::SeqCst),
+        })?;
+        Ok(())
+    }
+
+    /// Stops accepting tasks and waits for all the submitted tasks to finish.
+    ///
+    /// Tasks still in the queue are run before the worker threads exit.
+    pub fn shutdown(self) {
+        let SharedQueueThreadPool { tx, state, .. } = self;
+        // the workers exit once the queue is closed and empty
+        drop(tx);
+        loop {
+            // A worker panicking meanwhile pushes the handle of its replacement before it
+            // exits, so it's joined as well.
+            let handle = state.handles.lock().unwrap().pop();
+            match handle {
+                // a worker that panicked has been replaced, so its result is ignored
+                Some(handle) => drop(handle.join()),
+                None => break,
+            }
+        }
+    }
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        SharedQueueThreadPoolBuilder::new(threads).build()
     }
 
     /// Spawns a function into the thread pool.
@@ -54,26 +226,63 @@ impl ThreadPool for SharedQueueThreadPool {
 }
 
 #[derive(Clone)]
-struct TaskReceiver(Receiver<Box<dyn FnOnce() + Send + 'static>>);
+struct TaskReceiver {
+    rx: Receiver<Box<dyn FnOnce() + Send + 'static>>,
+    stop_rx: Receiver<()>,
+    state: Arc<PoolState>,
+    // index of the worker, kept by its replacement after a panic
+    index: usize,
+}
 
+// A worker thread is counted as live from right before it's spawned until its
+// `TaskReceiver` is dropped.
 impl Drop for TaskReceiver {
     fn drop(&mut self) {
+        self.state.live_threads.fetch_sub(1, Ordering::SeqCst);
         if thread::panicking() {
-            let rx = self.clone();
-            if let Err(e) = thread::Builder::new().spawn(move || run_tasks(rx)) {
+            self.state.total_panics.fetch_add(1, Ordering::SeqCst);
+            if let Err(e) = spawn_worker(self.clone()) {
                 error!("Failed to spawn a thread: {}", e);
             }
         }
     }
 }
 
+/// Spawns a worker thread running the tasks received by `rx`.
+fn spawn_worker(rx: TaskReceiver) -> io::Result<()> {
+    let state = Arc::clone(&rx.state);
+    let mut builder = thread::Builder::new().name(format!("{}-{}", state.name_prefix, rx.index));
+    if let Some(bytes) = state.stack_size {
+        builder = builder.stack_size(bytes);
+    }
+    state.live_threads.fetch_add(1, Ordering::SeqCst);
+    let handle = builder.spawn(move || run_tasks(rx))?;
+    state.handles.lock().unwrap().push(handle);
+    Ok(())
+}
+
 fn run_tasks(rx: TaskReceiver) {
     loop {
-        match rx.0.recv() {
+        let task = select! {
+            recv(rx.rx) -> task => task,
+            recv(rx.stop_rx) -> stop => match stop {
+                Ok(()) => {
+                    debug!("Thread exits because the thread pool shrinks.");
+                    return;
+                }
+                // the pool is dropped, so the queued tasks are still run
+                Err(_) => rx.rx.recv(),
+            },
+        };
+        match task {
             Ok(task) => {
                 task();
+                rx.state.tasks_completed.fetch_add(1, Ordering::SeqCst);
+            }
+            Err(_) => {
+                debug!("Thread exits because the thread pool is destroyed.");
+                return;
             }
-            Err(_) => debug!("Thread exits because the thread pool is destroyed."),
         }
     }
 }
@@ -335,3 +335,49 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_stats() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    for (key, value) in &[("key1", "value1"), ("key2", "value2"), ("key3", "value3")] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", key, value, "--addr", "127.0.0.1:4006"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["rm", "key2", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["stats", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("keys: 2\n"))
+        .stdout(contains("total log bytes: "))
+        .stdout(contains("uncompacted bytes: "))
+        .stdout(contains("generations: "));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
@@ -0,0 +1,62 @@
+use kvs::KvsError;
+use std::error::Error;
+use std::io;
+
+// Transient network errors and timeouts should be retryable
+#[test]
+fn retryable_errors() {
+    for kind in &[
+        io::ErrorKind::TimedOut,
+        io::ErrorKind::ConnectionReset,
+        io::ErrorKind::WouldBlock,
+    ] {
+        assert!(KvsError::Io(io::Error::from(*kind)).is_retryable());
+    }
+    assert!(KvsError::Timeout.is_retryable());
+}
+
+// Deterministic errors should not be retried
+#[test]
+fn non_retryable_errors() {
+    let utf8_err = String::from_utf8(vec![0xff]).unwrap_err();
+    let errors = vec![
+        KvsError::KeyNotFound,
+        KvsError::UnexpectedCommandType,
+        KvsError::Utf8(utf8_err),
+        KvsError::StringError("oops".to_owned()),
+        KvsError::ProtocolDesync {
+            expected: 2,
+            found: 1,
+        },
+        KvsError::Io(io::Error::from(io::ErrorKind::NotFound)),
+        KvsError::Io(io::Error::from(io::ErrorKind::ConnectionRefused)),
+    ];
+    for err in errors {
+        assert!(!err.is_retryable(), "{} should not be retryable", err);
+    }
+}
+
+// An io::Error reporting a timeout should become KvsError::Timeout
+#[test]
+fn io_timeout_converts_to_timeout() {
+    let err: KvsError = io::Error::from(io::ErrorKind::TimedOut).into();
+    assert!(matches!(err, KvsError::Timeout));
+    let err: KvsError = io::Error::from(io::ErrorKind::BrokenPipe).into();
+    assert!(matches!(err, KvsError::Io(_)));
+    assert!(!err.is_retryable());
+}
+
+// The wrapped errors should be reachable through `source`
+#[test]
+fn error_source() {
+    let err = KvsError::Io(io::Error::new(io::ErrorKind::NotFound, "no such log"));
+    let source = err.source().expect("an I/O error has a source");
+    let io_err = source.downcast_ref::<io::Error>().unwrap();
+    assert_eq!(io_err.kind(), io::ErrorKind::NotFound);
+    assert_eq!(err.to_string(), "IO error: no such log");
+
+    let serde_err = serde_json::from_str::<u32>("x").unwrap_err();
+    let err = KvsError::from(serde_err);
+    assert!(err.source().unwrap().is::<serde_json::Error>());
+    assert!(KvsError::KeyNotFound.source().is_none());
+}
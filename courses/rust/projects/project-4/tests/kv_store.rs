@@ -1,6 +1,15 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    CompactionProgress, CompactionStrategy, Compression, KvStore, KvStoreOptions, KvsEngine,
+    KvsError, LogFormat, MemoryKvsEngine, MemoryLogs, RecordLocation, RecoveryProgress, Result,
+    SledKvsEngine, Stats, SyncPolicy, WriteBatch,
+};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::ops::Bound;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -132,15 +141,16 @@ fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
     let barrier = Arc::new(Barrier::new(1001));
+    let mut handles = Vec::new();
     for i in 0..1000 {
         let store = store.clone();
         let barrier = barrier.clone();
-        thread::spawn(move || {
+        handles.push(thread::spawn(move || {
             store
                 .set(format!("key{}", i), format!("value{}", i))
                 .unwrap();
             barrier.wait();
-        });
+        }));
     }
     barrier.wait();
 
@@ -148,7 +158,11 @@ fn concurrent_set() -> Result<()> {
         assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
     }
 
-    // Open from disk again and check persistent data
+    // Open from disk again and check persistent data, once the threads have dropped
+    // their clones and with them the lock of the directory
+    for handle in handles {
+        handle.join().unwrap();
+    }
     drop(store);
     let store = KvStore::open(temp_dir.path())?;
     for i in 0..1000 {
@@ -209,3 +223,2435 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Compress an old generation and check it is still readable with less disk usage.
+#[test]
+fn compress_cold_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir_size = || {
+        let entries = WalkDir::new(temp_dir.path()).into_iter();
+        let len: walkdir::Result<u64> = entries
+            .map(|res| {
+                res.and_then(|entry| entry.metadata())
+                    .map(|metadata| metadata.len())
+            })
+            .sum();
+        len.expect("fail to get directory size")
+    };
+
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    // reopen so that the written generation is no longer the active one
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("fresh".to_owned(), "value".to_owned())?;
+
+    let size_before = dir_size();
+    assert_eq!(store.compress_cold_generations(1)?, 1);
+    assert!(dir_size() < size_before);
+    assert!(temp_dir.path().join("1.log.gz").exists());
+    assert!(!temp_dir.path().join("1.log").exists());
+
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert_eq!(store.get("fresh".to_owned())?, Some("value".to_owned()));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    // readers on other threads share the decompressed generation
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in (0..1000).rev() {
+                    assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    Ok(())
+}
+
+// Swap in the data of another store and check that existing handles see the new data.
+#[test]
+fn replace_contents_from() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("old{}", i))?;
+    }
+    store.set("old_only".to_owned(), "value".to_owned())?;
+
+    let other = KvStore::open(other_dir.path())?;
+    for i in 0..100 {
+        other.set(format!("key{}", i), format!("new{}", i))?;
+    }
+    other.set("new_only".to_owned(), "value".to_owned())?;
+    drop(other);
+
+    // an old reader with cached file handles
+    let reader = store.clone();
+    assert_eq!(reader.get("key0".to_owned())?, Some("old0".to_owned()));
+
+    store.replace_contents_from(other_dir.path())?;
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("new{}", i)));
+        assert_eq!(reader.get(format!("key{}", i))?, Some(format!("new{}", i)));
+    }
+    assert_eq!(store.get("old_only".to_owned())?, None);
+    assert_eq!(reader.get("new_only".to_owned())?, Some("value".to_owned()));
+
+    // an invalid source leaves the store untouched
+    assert!(store
+        .replace_contents_from(&temp_dir.path().join("missing"))
+        .is_err());
+    assert_eq!(store.get("key1".to_owned())?, Some("new1".to_owned()));
+
+    // writes after the swap persist together with the swapped data
+    store.set("after".to_owned(), "swap".to_owned())?;
+    drop(reader);
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key99".to_owned())?, Some("new99".to_owned()));
+    assert_eq!(store.get("after".to_owned())?, Some("swap".to_owned()));
+    assert_eq!(store.get("old_only".to_owned())?, None);
+
+    Ok(())
+}
+
+// A replacement interrupted by a crash is discarded before its marker is written and
+// finished by the next open afterwards.
+#[test]
+fn replace_contents_from_recovers_after_crash() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "old".to_owned())?;
+    store.set("old_only".to_owned(), "value".to_owned())?;
+    drop(store);
+    let other = KvStore::open(other_dir.path())?;
+    other.set("key".to_owned(), "new".to_owned())?;
+    drop(other);
+
+    // what a crash leaves behind after staging the generations of `other_dir`
+    let stage = || -> io::Result<()> {
+        let staging = temp_dir.path().join("staging");
+        fs::create_dir(&staging)?;
+        for entry in fs::read_dir(other_dir.path())? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "log") {
+                let gen: u64 = path.file_stem().unwrap().to_str().unwrap().parse().unwrap();
+                fs::copy(&path, staging.join(format!("{}.log", 100 + gen)))?;
+            }
+        }
+        Ok(())
+    };
+
+    stage()?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("old".to_owned()));
+    assert!(!temp_dir.path().join("staging").exists());
+    drop(store);
+
+    stage()?;
+    fs::write(temp_dir.path().join("REPLACE"), "100")?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("old_only".to_owned())?, None);
+    assert!(!temp_dir.path().join("staging").exists());
+    assert!(!temp_dir.path().join("REPLACE").exists());
+
+    Ok(())
+}
+
+// Entries should come back in the order they were last written.
+#[test]
+fn iter_by_recency() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.set("b".to_owned(), "2".to_owned())?;
+    store.set("c".to_owned(), "3".to_owned())?;
+    store.set("a".to_owned(), "4".to_owned())?;
+    store.set("d".to_owned(), "5".to_owned())?;
+    store.remove("c".to_owned())?;
+
+    let expected = vec![
+        ("d".to_owned(), "5".to_owned()),
+        ("a".to_owned(), "4".to_owned()),
+        ("b".to_owned(), "2".to_owned()),
+    ];
+    assert_eq!(store.iter_by_recency()?, expected);
+
+    // Writes in a newer generation come first
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("b".to_owned(), "6".to_owned())?;
+    let expected = vec![
+        ("b".to_owned(), "6".to_owned()),
+        ("d".to_owned(), "5".to_owned()),
+        ("a".to_owned(), "4".to_owned()),
+    ];
+    assert_eq!(store.iter_by_recency()?, expected);
+
+    Ok(())
+}
+
+// Self check should pass on a healthy store and leave no trace behind.
+#[test]
+fn self_check_healthy_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    store.self_check()?;
+    store.self_check()?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(store.scan_prefix("__kvs_self_check__")?.is_empty());
+    assert_eq!(store.len()?, 1);
+    Ok(())
+}
+
+// Self check should report an error when the active log can't be written.
+#[cfg(feature = "testing")]
+#[test]
+fn self_check_failing_writer() -> Result<()> {
+    let logs = MemoryLogs::new();
+    let store = KvStore::open_in_memory(&logs)?;
+    store.self_check()?;
+
+    logs.fail_writes(true);
+    assert!(store.self_check().is_err());
+    logs.fail_writes(false);
+    store.self_check()?;
+    Ok(())
+}
+
+// Scan should return live keys in the range in key order.
+#[test]
+fn scan_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for key in &["a", "b", "c", "d", "e"] {
+        store.set(key.to_string(), format!("value_{}", key))?;
+    }
+    store.remove("c".to_owned())?;
+
+    let pairs = store.scan(
+        Bound::Included("b".to_owned()),
+        Bound::Excluded("e".to_owned()),
+    )?;
+    let expected = vec![
+        ("b".to_owned(), "value_b".to_owned()),
+        ("d".to_owned(), "value_d".to_owned()),
+    ];
+    assert_eq!(pairs, expected);
+
+    let keys: Vec<String> = store
+        .scan(Bound::Excluded("a".to_owned()), Bound::Unbounded)?
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect();
+    assert_eq!(keys, vec!["b", "d", "e"]);
+    assert_eq!(store.scan(Bound::Unbounded, Bound::Unbounded)?.len(), 4);
+
+    Ok(())
+}
+
+// Scan should not fail when compactions happen concurrently.
+#[test]
+fn scan_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("{}", key_id))?;
+    }
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            // Overwrite the same keys with large values to trigger several compactions
+            let value = "v".repeat(1000);
+            for _ in 0..100 {
+                for key_id in 0..100 {
+                    store.set(format!("key{}", key_id), value.clone())?;
+                }
+            }
+            Ok(())
+        })
+    };
+
+    let value = "v".repeat(1000);
+    for _ in 0..100 {
+        // Keys being overwritten at the moment may be missed, but every value read
+        // must be a valid one.
+        let pairs = store.scan(Bound::Unbounded, Bound::Unbounded)?;
+        for (key, v) in pairs {
+            assert!(v == value || format!("key{}", v) == key);
+        }
+    }
+    writer.join().unwrap()?;
+    assert_eq!(store.scan(Bound::Unbounded, Bound::Unbounded)?.len(), 100);
+    Ok(())
+}
+
+// Iterating by recency should not fail when background compactions delete the logs it
+// reads from.
+#[test]
+fn iter_by_recency_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("{}", key_id))?;
+    }
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            // Overwrite the same keys with large values to trigger several compactions
+            let value = "v".repeat(1000);
+            for _ in 0..100 {
+                for key_id in 0..100 {
+                    store.set(format!("key{}", key_id), value.clone())?;
+                }
+            }
+            Ok(())
+        })
+    };
+
+    let value = "v".repeat(1000);
+    for _ in 0..100 {
+        for (key, v) in store.iter_by_recency()? {
+            assert!(v == value || format!("key{}", v) == key);
+        }
+    }
+    writer.join().unwrap()?;
+    assert_eq!(store.iter_by_recency()?.len(), 100);
+    Ok(())
+}
+
+// Binary values should survive reopening and compaction unchanged.
+#[test]
+fn binary_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let blob: Vec<u8> = (0..=255).collect();
+    store.set_bytes("blob".to_owned(), blob.clone())?;
+    store.set("text".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get_bytes("blob".to_owned())?, Some(blob.clone()));
+    assert_eq!(store.get_bytes("text".to_owned())?, Some(b"value".to_vec()));
+    assert_eq!(store.get_bytes("none".to_owned())?, None);
+    // The string API refuses non-UTF-8 values
+    assert!(store.get("blob".to_owned()).is_err());
+
+    // Overwrite another key until a compaction happens
+    for iter in 0..1000 {
+        store.set_bytes("other".to_owned(), vec![iter as u8; 1024])?;
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes("blob".to_owned())?, Some(blob));
+    assert_eq!(store.get("text".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get_bytes("other".to_owned())?, Some(vec![231; 1024]));
+    Ok(())
+}
+
+// Sled engine should store raw bytes and read them back losslessly.
+#[test]
+fn sled_binary_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+
+    let blob: Vec<u8> = (0..=255).collect();
+    engine.set_bytes("blob".to_owned(), blob.clone())?;
+    engine.set("text".to_owned(), "value".to_owned())?;
+    assert_eq!(engine.get_bytes("blob".to_owned())?, Some(blob.clone()));
+    assert_eq!(
+        engine.get_bytes("text".to_owned())?,
+        Some(b"value".to_vec())
+    );
+    assert_eq!(engine.get_bytes("none".to_owned())?, None);
+    // The string API refuses non-UTF-8 values
+    assert!(matches!(
+        engine.get("blob".to_owned()),
+        Err(KvsError::Utf8(_))
+    ));
+    drop(engine);
+
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    assert_eq!(engine.get_bytes("blob".to_owned())?, Some(blob));
+    assert_eq!(engine.get("text".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// A smaller compaction threshold should trigger compaction earlier.
+#[test]
+fn custom_compaction_threshold() -> Result<()> {
+    let log_files = |temp_dir: &TempDir| -> Vec<String> {
+        let mut names: Vec<String> = WalkDir::new(temp_dir.path())
+            .min_depth(1)
+            .into_iter()
+            .map(|entry| {
+                let entry = entry.expect("fail to read directory entry");
+                entry.file_name().to_string_lossy().into_owned()
+            })
+            .filter(|name| name.ends_with(".log"))
+            .collect();
+        names.sort();
+        names
+    };
+    let overwrite = |store: &KvStore| -> Result<()> {
+        for iter in 0..10 {
+            store.set("key".to_owned(), format!("value{}", iter))?;
+        }
+        Ok(())
+    };
+
+    let default_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(default_dir.path())?;
+    overwrite(&store)?;
+    assert_eq!(log_files(&default_dir), vec!["1.log"]);
+
+    let tiny_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .with_path(tiny_dir.path())
+        .with_compaction_threshold(100);
+    let store = KvStore::open_with_options(options)?;
+    overwrite(&store)?;
+    assert_eq!(store.get("key".to_owned())?, Some("value9".to_owned()));
+    // Dropping the store waits for the background compaction
+    drop(store);
+    // The first generation has been compacted away
+    let files = log_files(&tiny_dir);
+    assert!(!files.contains(&"1.log".to_owned()));
+    assert!(files.len() <= 2);
+
+    Ok(())
+}
+
+// Writes should be readable after reopening with every sync policy.
+#[test]
+fn sync_policies() -> Result<()> {
+    let policies = vec![
+        SyncPolicy::Never,
+        SyncPolicy::EveryWrite,
+        SyncPolicy::Interval(Duration::from_millis(10)),
+    ];
+    for policy in policies {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::default()
+            .with_path(temp_dir.path())
+            .with_sync_policy(policy)
+            .with_compaction_threshold(1024);
+        let store = KvStore::open_with_options(options)?;
+        for iter in 0..100 {
+            store.set(format!("key{}", iter % 10), format!("value{}", iter))?;
+        }
+        store.remove("key0".to_owned())?;
+        store.flush()?;
+        drop(store);
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key0".to_owned())?, None);
+        for key_id in 1..10 {
+            let value = format!("value{}", 90 + key_id);
+            assert_eq!(store.get(format!("key{}", key_id))?, Some(value));
+        }
+    }
+    Ok(())
+}
+
+// Manual compaction should reclaim space and be harmless when there is nothing to reclaim.
+#[test]
+fn manual_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let dir_size = || -> u64 {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|entry| entry.unwrap().metadata().unwrap().len())
+            .sum()
+    };
+
+    // Nothing to reclaim in an empty store
+    store.compact()?;
+
+    for iter in 0..100 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+    let size_before = dir_size();
+
+    let reader = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for _ in 0..100 {
+                for key_id in 1..10 {
+                    assert_eq!(
+                        store.get(format!("key{}", key_id))?,
+                        Some("value99".to_owned())
+                    );
+                }
+            }
+            Ok(())
+        })
+    };
+    store.compact()?;
+    store.compact()?;
+    reader.join().unwrap()?;
+    assert!(dir_size() < size_before);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..10 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value99".to_owned())
+        );
+    }
+    Ok(())
+}
+
+// Overwrites within a single generation should count exactly the overwritten records as
+// stale, both while writing and when the generation is loaded again.
+#[test]
+fn uncompacted_within_generation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let log_bytes = || -> Result<u64> { Ok(store.stats()?.total_log_bytes) };
+
+    let start = log_bytes()?;
+    store.set("key".to_owned(), "value1".to_owned())?;
+    let first_set = log_bytes()? - start;
+    store.set("key".to_owned(), "value22".to_owned())?;
+    let before_other = log_bytes()?;
+    store.set("other".to_owned(), "value".to_owned())?;
+    store.set("other".to_owned(), "value333".to_owned())?;
+    store.remove("other".to_owned())?;
+    // the first set of `key`, then all the commands on `other`
+    let expected = first_set + (log_bytes()? - before_other);
+
+    let stats = store.stats()?;
+    assert_eq!(stats.generation_count, 1);
+    assert_eq!(stats.uncompacted_bytes, expected);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats()?.uncompacted_bytes, expected);
+    assert_eq!(store.get("key".to_owned())?, Some("value22".to_owned()));
+    Ok(())
+}
+
+// Statistics should follow a known sequence of sets and removes.
+#[test]
+fn stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let empty = Stats {
+        key_count: 0,
+        total_log_bytes: 0,
+        uncompacted_bytes: 0,
+        generation_count: 1,
+    };
+    assert_eq!(store.stats()?, empty);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.key_count, 2);
+    assert_eq!(stats.uncompacted_bytes, 0);
+    let set_bytes = stats.total_log_bytes;
+    assert!(set_bytes > 0);
+
+    // Overwriting and removing leave stale commands behind
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.key_count, 1);
+    assert_eq!(stats.generation_count, 1);
+    assert!(stats.uncompacted_bytes >= set_bytes);
+
+    store.compact()?;
+    let stats = store.stats()?;
+    assert_eq!(stats.key_count, 1);
+    assert_eq!(stats.uncompacted_bytes, 0);
+    assert_eq!(stats.generation_count, 2);
+    assert_eq!(stats.total_log_bytes, set_bytes / 2);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats()?.generation_count, 3);
+    Ok(())
+}
+
+// A corrupt record should be reported, and truncated away on request.
+#[test]
+fn corrupt_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("1.log");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let valid_len = fs::metadata(&log)?.len();
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    // Flip the last byte of the second generation
+    let log2 = temp_dir.path().join("2.log");
+    let mut bytes = fs::read(&log2)?;
+    *bytes.last_mut().unwrap() ^= 0xff;
+    fs::write(&log2, bytes)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::CorruptLog { gen: 2, offset: 0 }) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("corruption not detected"),
+    }
+
+    let store = KvStore::open_truncate_corrupt(temp_dir.path())?;
+    assert_eq!(fs::metadata(&log2)?.len(), 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+
+    // A partially written record at the end of a log is dropped by a plain `open`
+    let file = OpenOptions::new().write(true).open(&log)?;
+    file.set_len(valid_len - 3)?;
+    drop(file);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(fs::metadata(&log)?.len(), 0);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Verify should flag a corrupt record in the middle of a log and nothing else.
+#[test]
+fn verify() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key1".to_owned())?;
+    let report = store.verify()?;
+    assert!(report.is_ok(), "{:?}", report);
+    assert_eq!(report.valid_records, 4);
+
+    // Flip a byte of the payload of the record of key2
+    let (_, meta) = store.get_with_meta("key2".to_owned())?.unwrap();
+    let log = temp_dir.path().join(format!("{}.log", meta.generation));
+    let mut bytes = fs::read(&log)?;
+    bytes[meta.offset as usize + 10] ^= 0xff;
+    fs::write(&log, bytes)?;
+
+    let location = RecordLocation {
+        generation: meta.generation,
+        offset: meta.offset,
+    };
+    let report = store.verify()?;
+    assert!(!report.is_ok());
+    assert_eq!(report.corrupt_records, vec![location]);
+    assert_eq!(report.orphaned_keys, vec![("key2".to_owned(), location)]);
+    assert!(report.unreferenced_records.is_empty());
+    assert_eq!(report.valid_records, 3);
+    Ok(())
+}
+
+// A store in memory should keep its content across reopens and compactions.
+#[test]
+fn open_in_memory() -> Result<()> {
+    let logs = MemoryLogs::new();
+    let store = KvStore::open_in_memory(&logs)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    drop(store);
+
+    let store = KvStore::open_in_memory(&logs)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.stats()?.generation_count, 2);
+    store.compact()?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    assert!(matches!(
+        store.compress_cold_generations(1),
+        Err(KvsError::Unsupported)
+    ));
+    drop(store);
+
+    let store = KvStore::open_in_memory(&logs)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.stats()?.uncompacted_bytes, 0);
+    assert!(store.verify()?.is_ok());
+
+    // other logs start empty
+    let store = KvStore::open_in_memory(&MemoryLogs::new())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// A bincode store should recover its content and take less space than JSON.
+#[test]
+fn bincode_log_format() -> Result<()> {
+    let open = |temp_dir: &TempDir, format: LogFormat| {
+        let options = KvStoreOptions::default()
+            .with_path(temp_dir.path())
+            .with_log_format(format);
+        KvStore::open_with_options(options)
+    };
+    let json_dir = TempDir::new().expect("unable to create temporary working directory");
+    let bincode_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    for &(temp_dir, format) in &[
+        (&json_dir, LogFormat::Json),
+        (&bincode_dir, LogFormat::Bincode),
+    ] {
+        let store = open(temp_dir, format)?;
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        store.set_bytes("blob".to_owned(), vec![0, 159, 146, 150])?;
+        store.remove("key0".to_owned())?;
+    }
+
+    let store = open(&bincode_dir, LogFormat::Bincode)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..100 {
+        let value = format!("value{}", key_id);
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value));
+    }
+    assert_eq!(
+        store.get_bytes("blob".to_owned())?,
+        Some(vec![0, 159, 146, 150])
+    );
+    store.compact()?;
+    drop(store);
+    let store = open(&bincode_dir, LogFormat::Bincode)?;
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+
+    let json_size = store_size(&json_dir);
+    assert!(store_size(&bincode_dir) < json_size);
+
+    // Opening with the wrong format is an error, not a corruption
+    drop(store);
+    match open(&bincode_dir, LogFormat::Json) {
+        Err(KvsError::CorruptLog { .. }) => panic!("reported as corruption"),
+        Err(_) => {}
+        Ok(_) => panic!("wrong format not detected"),
+    }
+    Ok(())
+}
+
+// A store should refuse to open with another format than the one it was written with.
+#[test]
+fn log_format_mismatch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = |format: LogFormat| {
+        let options = KvStoreOptions::default()
+            .with_path(temp_dir.path())
+            .with_log_format(format);
+        KvStore::open_with_options(options)
+    };
+
+    let store = open(LogFormat::Bincode)?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    match open(LogFormat::Json) {
+        Err(KvsError::FormatMismatch { requested, found }) => {
+            assert_eq!(requested, "json");
+            assert_eq!(found, "bincode");
+        }
+        res => panic!("unexpected result {:?}", res.map(|_| ())),
+    }
+    assert!(matches!(
+        KvStore::open_read_only(temp_dir.path()),
+        Err(KvsError::FormatMismatch { .. })
+    ));
+
+    let store = open(LogFormat::Bincode)?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+fn store_size(temp_dir: &TempDir) -> u64 {
+    WalkDir::new(temp_dir.path())
+        .into_iter()
+        .map(|entry| entry.unwrap().metadata().unwrap().len())
+        .sum()
+}
+
+// A write batch should apply all its writes, or none if a remove is invalid.
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("old".to_owned(), "value".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    for key_id in 0..100 {
+        batch.set(format!("key{}", key_id), format!("value{}", key_id));
+    }
+    batch.remove("old".to_owned());
+    batch.remove("key0".to_owned());
+    batch.set_bytes("blob".to_owned(), vec![0xff, 0x00]);
+    assert_eq!(batch.len(), 103);
+    store.write_batch(batch)?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("old".to_owned())?, None);
+        assert_eq!(store.get("key0".to_owned())?, None);
+        for key_id in 1..100 {
+            let value = format!("value{}", key_id);
+            assert_eq!(store.get(format!("key{}", key_id))?, Some(value));
+        }
+        assert_eq!(store.get_bytes("blob".to_owned())?, Some(vec![0xff, 0x00]));
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+
+    // Removing a key removed earlier in the same batch fails the whole batch
+    let mut batch = WriteBatch::new();
+    batch.set("new".to_owned(), "value".to_owned());
+    batch.remove("key1".to_owned());
+    batch.remove("key1".to_owned());
+    match store.write_batch(batch) {
+        Err(KvsError::KeyNotFound) => {}
+        _ => panic!("expected KeyNotFound"),
+    }
+    assert_eq!(store.get("new".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+    assert_eq!(store.get("new".to_owned())?, None);
+
+    store.write_batch(WriteBatch::new())?;
+    Ok(())
+}
+
+// Keys set with a TTL should disappear once expired, also after compaction and reopening.
+#[test]
+fn expiring_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("forever".to_owned(), "value".to_owned())?;
+    store.set_with_ttl(
+        "short".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    store.set_with_ttl(
+        "long".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    store.set_with_ttl(
+        "reset".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    store.set("reset".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("short".to_owned())?, Some("value".to_owned()));
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get("short".to_owned())?, None);
+    assert_eq!(store.get("long".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("reset".to_owned())?, Some("value2".to_owned()));
+    assert!(store.remove("short".to_owned()).is_err());
+    let keys: Vec<String> = store
+        .scan(Bound::Unbounded, Bound::Unbounded)?
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect();
+    assert_eq!(keys, vec!["forever", "long", "reset"]);
+
+    // Expired entries are not copied by a compaction
+    store.set_with_ttl(
+        "other".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(1),
+    )?;
+    thread::sleep(Duration::from_millis(10));
+    store.compact()?;
+    assert_eq!(store.stats()?.key_count, 3);
+    assert_eq!(store.get("other".to_owned())?, None);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("short".to_owned())?, None);
+    assert_eq!(store.get("other".to_owned())?, None);
+    assert_eq!(store.get("long".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("forever".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Compare-and-swap should only write when the current value matches.
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert!(store.compare_and_swap("key".to_owned(), None, "1".to_owned())?);
+    assert!(!store.compare_and_swap("key".to_owned(), None, "2".to_owned())?);
+    assert!(!store.compare_and_swap("key".to_owned(), Some("0".to_owned()), "2".to_owned())?);
+    assert_eq!(store.get("key".to_owned())?, Some("1".to_owned()));
+    assert!(store.compare_and_swap("key".to_owned(), Some("1".to_owned()), "2".to_owned())?);
+    assert_eq!(store.get("key".to_owned())?, Some("2".to_owned()));
+
+    // Concurrent increments must not lose updates
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..50 {
+                    loop {
+                        let current = store.get("counter".to_owned())?;
+                        let next = current.as_ref().map_or(0, |v| v.parse::<u32>().unwrap()) + 1;
+                        if store.compare_and_swap(
+                            "counter".to_owned(),
+                            current,
+                            next.to_string(),
+                        )? {
+                            break;
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("400".to_owned()));
+    Ok(())
+}
+
+// Engines without compare-and-swap should report it as unsupported.
+#[test]
+fn compare_and_swap_unsupported() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    match engine.compare_and_swap("key".to_owned(), None, "value".to_owned()) {
+        Err(KvsError::Unsupported) => Ok(()),
+        _ => panic!("expected Unsupported"),
+    }
+}
+
+// Compaction runs in the background, so no write should wait for a whole compaction.
+#[test]
+fn background_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value = "v".repeat(1024);
+    for key_id in 0..20_000 {
+        store.set(format!("key{}", key_id), value.clone())?;
+    }
+    // Make some stale data and time a full compaction of the store
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), value.clone())?;
+    }
+    let start = Instant::now();
+    store.compact()?;
+    let compaction_time = start.elapsed();
+
+    // Overwriting more than the compaction threshold triggers a background compaction
+    let mut max_set_time = Duration::from_secs(0);
+    for iter in 0..2000 {
+        let start = Instant::now();
+        store.set(format!("key{}", iter % 20_000), format!("{}", iter))?;
+        max_set_time = max_set_time.max(start.elapsed());
+    }
+    assert!(
+        max_set_time < compaction_time,
+        "a set took {:?}, a compaction takes {:?}",
+        max_set_time,
+        compaction_time
+    );
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..2000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("{}", key_id))
+        );
+    }
+    assert_eq!(store.get("key2000".to_owned())?, Some(value));
+    assert_eq!(store.stats()?.key_count, 20_000);
+    Ok(())
+}
+
+// An export should import into a fresh store of either engine.
+#[test]
+fn export_import() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key0".to_owned())?;
+    store.set("key1".to_owned(), "new value".to_owned())?;
+    let mut export = Vec::new();
+    store.export(&mut export)?;
+
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kvs_store = KvStore::open(kvs_dir.path())?;
+    assert_eq!(kvs_store.import(&export[..])?, 99);
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled_store = SledKvsEngine::open(sled_dir.path())?;
+    assert_eq!(sled_store.import(&export[..])?, 99);
+
+    let expected = store.scan(Bound::Unbounded, Bound::Unbounded)?;
+    assert_eq!(
+        kvs_store.scan(Bound::Unbounded, Bound::Unbounded)?,
+        expected
+    );
+    assert_eq!(
+        KvsEngine::scan(&sled_store, Bound::Unbounded, Bound::Unbounded)?,
+        expected
+    );
+    assert_eq!(sled_store.get("key0".to_owned())?, None);
+    assert_eq!(
+        sled_store.get("key1".to_owned())?,
+        Some("new value".to_owned())
+    );
+
+    // Binary values survive a round trip between `KvStore`s
+    store.set_bytes("blob".to_owned(), vec![0, 159, 146, 150])?;
+    let mut export = Vec::new();
+    store.export(&mut export)?;
+    assert_eq!(kvs_store.import(&export[..])?, 100);
+    assert_eq!(
+        kvs_store.get_bytes("blob".to_owned())?,
+        Some(vec![0, 159, 146, 150])
+    );
+
+    assert!(kvs_store.import(&b"not an export"[..]).is_err());
+    // a corrupt length fails the import instead of allocating it
+    let mut corrupt = b"KVSDUMP1".to_vec();
+    corrupt.extend_from_slice(&u32::MAX.to_be_bytes());
+    corrupt.extend_from_slice(b"key");
+    assert!(kvs_store.import(&corrupt[..]).is_err());
+    assert!(sled_store.import(&corrupt[..]).is_err());
+    Ok(())
+}
+
+// Log sizes should add up to the total size, and overwrites should show up as dead bytes.
+#[test]
+fn log_sizes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.log_sizes()?.into_iter().collect::<Vec<_>>(),
+        vec![(1, 0)]
+    );
+    assert_eq!(store.dead_byte_ratio()?, 0.0);
+
+    for iter in 0..10 {
+        store.set("key1".to_owned(), format!("value{}", iter))?;
+    }
+    store.set("key2".to_owned(), "value".to_owned())?;
+    let sizes = store.log_sizes()?;
+    assert_eq!(sizes.len(), 1);
+    assert_eq!(sizes[&1], store.stats()?.total_log_bytes);
+    let ratio = store.dead_byte_ratio()?;
+    assert!(ratio > 0.5 && ratio < 1.0, "{}", ratio);
+
+    store.compact()?;
+    let sizes = store.log_sizes()?;
+    assert_eq!(sizes.keys().copied().collect::<Vec<_>>(), vec![2, 3]);
+    assert_eq!(sizes[&3], 0);
+    assert_eq!(store.dead_byte_ratio()?, 0.0);
+    Ok(())
+}
+
+// A tiered compaction should only merge the oldest generations, and only once they
+// are stale enough, leaving the newer ones as they are.
+#[test]
+fn tiered_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStore::open_with_options(
+            KvStoreOptions::default()
+                .with_path(temp_dir.path())
+                .with_compaction_threshold(u64::MAX)
+                .with_compaction_strategy(CompactionStrategy::Tiered {
+                    max_gens: 2,
+                    dead_ratio: 0.5,
+                }),
+        )
+    };
+
+    // generations 1 and 2 are mostly overwrites, 3 and 4 are all live
+    for gen in 1..=4 {
+        let store = open()?;
+        if gen <= 2 {
+            for iter in 0..20 {
+                for key_id in 0..5 {
+                    store.set(format!("hot{}_{}", gen, key_id), format!("value{}", iter))?;
+                }
+            }
+        } else {
+            for key_id in 0..20 {
+                store.set(format!("cold{}_{}", gen, key_id), "value".to_owned())?;
+            }
+        }
+    }
+
+    let store = open()?;
+    let sizes_before = store.log_sizes()?;
+    assert_eq!(
+        sizes_before.keys().copied().collect::<Vec<_>>(),
+        vec![1, 2, 3, 4, 5]
+    );
+    store.compact()?;
+    // 1 and 2 are merged into 6, the empty 5 is left for a later compaction and writes
+    // go to 7
+    let sizes = store.log_sizes()?;
+    assert_eq!(
+        sizes.keys().copied().collect::<Vec<_>>(),
+        vec![3, 4, 5, 6, 7]
+    );
+    assert_eq!(sizes[&3], sizes_before[&3]);
+    assert_eq!(sizes[&4], sizes_before[&4]);
+    assert!(sizes[&6] < sizes_before[&1] + sizes_before[&2]);
+
+    // 3 and 4 have no stale data, so they are not merged
+    store.set("cold3_0".to_owned(), "new".to_owned())?;
+    store.compact()?;
+    let sizes = store.log_sizes()?;
+    assert!(sizes.contains_key(&3) && sizes.contains_key(&4));
+
+    let check = |store: &KvStore| -> Result<()> {
+        for gen in 1..=2 {
+            for key_id in 0..5 {
+                assert_eq!(
+                    store.get(format!("hot{}_{}", gen, key_id))?,
+                    Some("value19".to_owned())
+                );
+            }
+        }
+        for gen in 3..=4 {
+            for key_id in 0..20 {
+                let expected = if (gen, key_id) == (3, 0) {
+                    "new"
+                } else {
+                    "value"
+                };
+                assert_eq!(
+                    store.get(format!("cold{}_{}", gen, key_id))?,
+                    Some(expected.to_owned())
+                );
+            }
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&open()?)
+}
+
+// A read-only store should see the existing data, reject writes and leave the
+// directory untouched.
+#[test]
+fn open_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    {
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.remove("key2".to_owned())?;
+    }
+    let list_files = || {
+        let mut files: Vec<_> = fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        files.sort();
+        files
+    };
+    let files = list_files();
+
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert!(store.exists("key1".to_owned())?);
+    assert_eq!(
+        store.scan(Bound::Unbounded, Bound::Unbounded)?,
+        vec![("key1".to_owned(), "value1".to_owned())]
+    );
+    assert!(matches!(
+        store.set("key3".to_owned(), "value3".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    drop(store);
+    assert_eq!(list_files(), files);
+
+    // A missing directory is not created
+    let missing = temp_dir.path().join("missing");
+    assert!(KvStore::open_read_only(&missing).is_err());
+    assert!(!missing.exists());
+    Ok(())
+}
+
+// A directory should be used by one store at a time.
+#[test]
+fn open_locked_directory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::Locked)
+    ));
+    assert!(matches!(
+        SledKvsEngine::open(temp_dir.path()),
+        Err(KvsError::Locked)
+    ));
+    // Clones share the lock, which is released when the last one is dropped
+    let clone = store.clone();
+    drop(store);
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::Locked)
+    ));
+    drop(clone);
+    KvStore::open(temp_dir.path())?;
+
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(sled_dir.path())?;
+    assert!(matches!(
+        SledKvsEngine::open(sled_dir.path()),
+        Err(KvsError::Locked)
+    ));
+    assert!(matches!(
+        KvStore::open(sled_dir.path()),
+        Err(KvsError::Locked)
+    ));
+    drop(engine);
+    KvStore::open(sled_dir.path())?;
+    Ok(())
+}
+
+// Increments should start from 0 and reject values that aren't numbers.
+#[test]
+fn increment() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.increment("counter".to_owned(), 5)?, 5);
+    assert_eq!(store.increment("counter".to_owned(), -7)?, -2);
+    assert_eq!(store.get("counter".to_owned())?, Some("-2".to_owned()));
+
+    store.set("key".to_owned(), "value".to_owned())?;
+    assert!(matches!(
+        store.increment("key".to_owned(), 1),
+        Err(KvsError::NotANumber)
+    ));
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+
+    store.set("max".to_owned(), i64::MAX.to_string())?;
+    assert!(matches!(
+        store.increment("max".to_owned(), 1),
+        Err(KvsError::Overflow)
+    ));
+    assert!(matches!(
+        store.decrement("max".to_owned(), i64::MIN),
+        Err(KvsError::Overflow)
+    ));
+    assert_eq!(store.get("max".to_owned())?, Some(i64::MAX.to_string()));
+
+    // Increments from several threads should all be counted
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    store.increment("shared".to_owned(), 1).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get("shared".to_owned())?, Some("800".to_owned()));
+    Ok(())
+}
+
+// A prefix scan should return only the keys with that prefix, in key order.
+#[test]
+fn scan_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in &[
+        "tenant:2:b",
+        "tenant:1:b",
+        "tenant:10:a",
+        "tenant:1:a",
+        "tenant:1",
+        "tenant:1;",
+        "tenant:2:a",
+        "tenant:\u{10FFFF}",
+        "tenant:\u{10FFFF}x",
+        "tenant;",
+    ] {
+        store.set((*key).to_owned(), format!("value of {}", key))?;
+    }
+    store.remove("tenant:2:a".to_owned())?;
+
+    let keys = |prefix: &str| -> Result<Vec<String>> {
+        let pairs = store.scan_prefix(prefix)?;
+        for (key, value) in &pairs {
+            assert_eq!(value, &format!("value of {}", key));
+        }
+        Ok(pairs.into_iter().map(|(key, _)| key).collect())
+    };
+    assert_eq!(keys("tenant:1:")?, vec!["tenant:1:a", "tenant:1:b"]);
+    assert_eq!(
+        keys("tenant:1")?,
+        vec![
+            "tenant:1",
+            "tenant:10:a",
+            "tenant:1:a",
+            "tenant:1:b",
+            "tenant:1;"
+        ]
+    );
+    assert_eq!(keys("tenant:2")?, vec!["tenant:2:b"]);
+    // the last char has no successor, so the bound is on the char before it
+    assert_eq!(
+        keys("tenant:\u{10FFFF}")?,
+        vec!["tenant:\u{10FFFF}", "tenant:\u{10FFFF}x"]
+    );
+    assert!(keys("other")?.is_empty());
+    assert_eq!(keys("")?.len(), 9);
+    Ok(())
+}
+
+// Periodic maintenance should compact stale data below the compaction threshold, and
+// stop when the store is dropped.
+#[test]
+fn periodic_maintenance() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .with_path(temp_dir.path())
+        .with_maintenance_interval(Duration::from_millis(50));
+    let store = KvStore::open_with_options(options)?;
+    for iter in 0..10 {
+        store.set("key".to_owned(), format!("value{}", iter))?;
+    }
+    assert!(store.stats()?.uncompacted_bytes > 0);
+
+    let start = Instant::now();
+    while store.stats()?.uncompacted_bytes > 0 {
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "no compaction in time"
+        );
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(store.get("key".to_owned())?, Some("value9".to_owned()));
+    drop(store);
+
+    // Dropping doesn't wait for the next run
+    let options = KvStoreOptions::default()
+        .with_path(temp_dir.path())
+        .with_maintenance_interval(Duration::from_secs(3600));
+    let store = KvStore::open_with_options(options)?;
+    let clone = store.clone();
+    drop(store);
+    let start = Instant::now();
+    drop(clone);
+    assert!(start.elapsed() < Duration::from_secs(1));
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value9".to_owned()));
+    Ok(())
+}
+
+// Memory-mapped reads should see new writes, compactions and compressed generations.
+#[cfg(feature = "mmap")]
+#[test]
+fn mmap_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStore::open_with_options(
+            KvStoreOptions::default()
+                .with_path(temp_dir.path())
+                .with_mmap_reads(true),
+        )
+    };
+
+    let store = open()?;
+    // each get maps the active generation again, as it has grown since the last one
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("new{}", i))?;
+    }
+    store.compact()?;
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("new{}", i)));
+    }
+
+    drop(store);
+    let store = open()?;
+    store.set("fresh".to_owned(), "value".to_owned())?;
+    assert!(store.compress_cold_generations(1)? > 0);
+    let clone = store.clone();
+    thread::spawn(move || -> Result<()> {
+        for i in 0..100 {
+            assert_eq!(clone.get(format!("key{}", i))?, Some(format!("new{}", i)));
+        }
+        Ok(())
+    })
+    .join()
+    .unwrap()?;
+    assert_eq!(store.get("fresh".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Recovery progress should grow steadily up to the total size of the logs.
+#[test]
+fn open_with_progress() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let value = "v".repeat(1024);
+    for gen in 0..3 {
+        let store = KvStore::open(temp_dir.path())?;
+        for key_id in 0..200 {
+            store.set(format!("key{}-{}", gen, key_id), value.clone())?;
+        }
+    }
+
+    let mut events: Vec<RecoveryProgress> = Vec::new();
+    let store = KvStore::open_with_progress(temp_dir.path(), |event| events.push(event))?;
+    assert_eq!(store.get("key2-199".to_owned())?, Some(value));
+
+    let total_bytes = events[0].total_bytes;
+    assert_eq!(total_bytes, store.stats()?.total_log_bytes);
+    assert!(events.iter().all(|event| event.total_bytes == total_bytes));
+    for pair in events.windows(2) {
+        assert!(pair[0].bytes_processed <= pair[1].bytes_processed);
+        assert!(pair[0].current_gen <= pair[1].current_gen);
+    }
+    assert_eq!(events.last().unwrap().bytes_processed, total_bytes);
+    // reported within each of the 3 generations and after each of them
+    assert!(events.len() > 6, "{} events", events.len());
+    Ok(())
+}
+
+// Replaying the logs in parallel should build the same store as replaying them in order.
+#[test]
+fn open_parallel() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // Overwrites and removes across generations, one generation per reopen
+    for gen in 0..6 {
+        let store = KvStore::open(temp_dir.path())?;
+        for key_id in 0..300 {
+            if (key_id + gen) % 5 == 0 {
+                let _ = store.remove(format!("key{}", key_id));
+            } else {
+                store.set(format!("key{}", key_id), format!("value{}-{}", key_id, gen))?;
+            }
+        }
+        store.set_with_ttl(
+            format!("ttl{}", gen),
+            "value".to_owned(),
+            Duration::from_secs(3600),
+        )?;
+    }
+
+    let snapshot = |store: &KvStore| -> Result<_> {
+        let stats = store.stats()?;
+        Ok((
+            store.iter_by_recency()?,
+            stats.key_count,
+            stats.uncompacted_bytes,
+        ))
+    };
+    let store = KvStore::open(temp_dir.path())?;
+    let sequential = snapshot(&store)?;
+    drop(store);
+    // the extra empty generation opened above doesn't change anything
+    let store = KvStore::open_parallel(temp_dir.path(), 4)?;
+    assert_eq!(snapshot(&store)?, sequential);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1-5".to_owned()));
+    assert_eq!(store.get("key0".to_owned())?, None);
+    Ok(())
+}
+
+// The in-memory engine should behave like the persistent ones, shared between clones.
+#[test]
+fn memory_engine() -> Result<()> {
+    let store = MemoryKvsEngine::new();
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    // overwrite, seen through a clone
+    let clone = store.clone();
+    clone.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    store.remove("key1".to_owned())?;
+    assert_eq!(clone.get("key1".to_owned())?, None);
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    assert_eq!(
+        store.scan(Bound::Unbounded, Bound::Unbounded)?,
+        vec![("key2".to_owned(), "value2".to_owned())]
+    );
+    store.self_check()?;
+    Ok(())
+}
+
+fn check_len(store: impl KvsEngine) -> Result<()> {
+    assert_eq!(store.len()?, 0);
+    assert!(store.is_empty()?);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.len()?, 2);
+    assert!(!store.is_empty()?);
+
+    // overwriting doesn't add a key
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.len()?, 2);
+
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.len()?, 1);
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.len()?, 0);
+    assert!(store.is_empty()?);
+    Ok(())
+}
+
+// `len` should follow sets and removes in every engine.
+#[test]
+fn len() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_len(KvStore::open(temp_dir.path())?)?;
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_len(SledKvsEngine::open(sled_dir.path())?)?;
+    check_len(MemoryKvsEngine::new())?;
+
+    // reopening rebuilds the same count
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    assert_eq!(KvStore::open(temp_dir.path())?.len()?, 1);
+
+    // expired keys aren't counted, even before a read drops them
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl(
+        "key2".to_owned(),
+        "value2".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    assert_eq!(store.len()?, 2);
+    thread::sleep(Duration::from_millis(200));
+    assert!(!store.exists("key2".to_owned())?);
+    assert_eq!(store.len()?, 1);
+    assert_eq!(store.stats()?.key_count, 1);
+    drop(store);
+    assert_eq!(KvStore::open_read_only(temp_dir.path())?.len()?, 1);
+    Ok(())
+}
+
+// Compressed values should take less space on the disk and stay readable after the
+// compression setting changes.
+#[test]
+fn value_compression() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let value = "{\"name\": \"kvs\", \"tags\": [\"a\", \"b\"]}".repeat(1000);
+    let options = KvStoreOptions::default()
+        .with_path(temp_dir.path())
+        .with_compression(Compression::Zstd(3));
+    let store = KvStore::open_with_options(options)?;
+    store.set("key1".to_owned(), value.clone())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(value.clone()));
+    assert!(
+        store_size(&temp_dir) < value.len() as u64,
+        "log of {} bytes for a value of {} bytes",
+        store_size(&temp_dir),
+        value.len()
+    );
+    // a random value doesn't shrink, and is stored as it is
+    store.set_bytes("key2".to_owned(), vec![0xab])?;
+    drop(store);
+
+    // the store now mixes compressed and uncompressed records
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key3".to_owned(), value.clone())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(value.clone()));
+    assert_eq!(store.get_bytes("key2".to_owned())?, Some(vec![0xab]));
+    assert_eq!(store.get("key3".to_owned())?, Some(value.clone()));
+
+    // compaction keeps the stored bytes
+    store.set("key3".to_owned(), "small".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.get("key1".to_owned())?, Some(value));
+    assert_eq!(store.get("key3".to_owned())?, Some("small".to_owned()));
+    Ok(())
+}
+
+// Garbage after the last complete record, as left by a crash during a write, should be
+// truncated by `open` while the records before it are kept.
+#[test]
+fn torn_tail_recovery() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("1.log");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let valid_len = fs::metadata(&log)?.len();
+
+    for garbage in &[
+        // a header cut short
+        vec![0x12, 0x34, 0x56],
+        // a header announcing a longer payload than what follows
+        vec![0x40, 0, 0, 0, 0xde, 0xad, 0xbe, 0xef, b'{', b'"'],
+        // zeros, as some file systems leave after a crash
+        vec![0; 64],
+    ] {
+        let mut file = OpenOptions::new().append(true).open(&log)?;
+        file.write_all(garbage)?;
+        drop(file);
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(fs::metadata(&log)?.len(), valid_len);
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        drop(store);
+    }
+
+    // a read-only store skips a torn tail without truncating it
+    let mut file = OpenOptions::new().append(true).open(&log)?;
+    file.write_all(&[0x12, 0x34, 0x56])?;
+    drop(file);
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(fs::metadata(&log)?.len(), valid_len + 3);
+    drop(store);
+
+    // a length running past the end is corruption if intact records follow it
+    let mut bytes = fs::read(&log)?;
+    bytes[3] ^= 0xff;
+    fs::write(&log, bytes)?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::CorruptLog { gen: 1, offset: 0 }) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("corruption not detected"),
+    }
+    Ok(())
+}
+
+// A corrupt length early in a large log should be told apart from a torn tail without
+// hashing the rest of the log at every offset.
+#[test]
+fn corrupt_length_in_large_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("1.log");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let record = fs::read(&log)?;
+
+    // a length running past the end, then bytes that read as lengths of 16 MiB at every
+    // offset, shorter than the rest of the log
+    let mut bytes = record.clone();
+    bytes.extend_from_slice(&[0xff, 0xff, 0xff, 0x7f, 0, 0, 0, 0]);
+    bytes.resize(bytes.len() + 20 * 1024 * 1024, 0x01);
+    fs::write(&log, &bytes)?;
+    let start = Instant::now();
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(start.elapsed() < Duration::from_secs(60));
+    assert_eq!(fs::metadata(&log)?.len(), record.len() as u64);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    // an intact record after all of it makes it corruption
+    bytes.extend_from_slice(&record);
+    fs::write(&log, &bytes)?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::CorruptLog { gen: 1, offset }) => assert_eq!(offset, record.len() as u64),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("corruption not detected"),
+    }
+    Ok(())
+}
+
+// Writes should be on the disk once the last handle of a store is dropped, without an
+// explicit flush.
+#[test]
+fn flush_on_drop() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .with_path(temp_dir.path())
+        .with_sync_policy(SyncPolicy::Interval(Duration::from_secs(3600)));
+    let store = KvStore::open_with_options(options)?;
+    let clone = store.clone();
+
+    let mut batch = WriteBatch::new();
+    for key_id in 0..10 {
+        batch.set(format!("key{}", key_id), format!("value{}", key_id));
+    }
+    store.write_batch(batch)?;
+    drop(store);
+    // the clone still uses the writer
+    clone.set("key10".to_owned(), "value10".to_owned())?;
+    drop(clone);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..=10 {
+        let value = format!("value{}", key_id);
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value));
+    }
+    Ok(())
+}
+
+// Keys and values up to the limits should be written, larger ones rejected without
+// touching the log.
+#[test]
+fn size_limits() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .with_path(temp_dir.path())
+        .with_max_key_bytes(8)
+        .with_max_value_bytes(16);
+    let store = KvStore::open_with_options(options)?;
+
+    store.set("k".repeat(8), "v".repeat(16))?;
+    assert_eq!(store.get("k".repeat(8))?, Some("v".repeat(16)));
+    let size = store_size(&temp_dir);
+
+    match store.set("k".repeat(9), "value".to_owned()) {
+        Err(KvsError::KeyTooLarge { size: 9, limit: 8 }) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+    match store.set("key".to_owned(), "v".repeat(17)) {
+        Err(KvsError::ValueTooLarge {
+            size: 17,
+            limit: 16,
+        }) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+    let mut batch = WriteBatch::new();
+    batch.set("key".to_owned(), "value".to_owned());
+    batch.set("key".to_owned(), "v".repeat(17));
+    assert!(matches!(
+        store.write_batch(batch),
+        Err(KvsError::ValueTooLarge { .. })
+    ));
+    assert_eq!(store_size(&temp_dir), size);
+    assert_eq!(store.get("key".to_owned())?, None);
+    Ok(())
+}
+
+// A forced compaction should move the writes to a new pair of generations and reclaim
+// the space of the overwritten values.
+#[cfg(feature = "testing")]
+#[test]
+fn force_compact() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let gen = store.current_generation();
+    for iter in 0..100 {
+        store.set("key".to_owned(), format!("value{}", iter))?;
+    }
+    let size = store_size(&temp_dir);
+
+    store.force_compact()?;
+    assert_eq!(store.current_generation(), gen + 2);
+    assert!(store_size(&temp_dir) < size);
+    assert_eq!(store.get("key".to_owned())?, Some("value99".to_owned()));
+
+    // without stale data, it still compacts
+    store.force_compact()?;
+    assert_eq!(store.current_generation(), gen + 4);
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value99".to_owned()));
+    Ok(())
+}
+
+// After warming the cache, gets should not read the logs, also after writes and a
+// compaction. A small budget should only cache some of the values.
+#[cfg(feature = "testing")]
+#[test]
+fn warm_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let check = |store: &KvStore| -> Result<()> {
+        for key_id in 1..10 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+        Ok(())
+    };
+
+    assert_eq!(store.warm_cache(1024 * 1024)?, 10);
+    let reads = store.file_reads();
+    check(&store)?;
+    assert_eq!(store.file_reads(), reads);
+
+    // a new value is read once, then cached
+    store.set("key0".to_owned(), "new".to_owned())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("key0".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.file_reads(), reads + 1);
+
+    // the cached values follow the compaction
+    store.compact()?;
+    let reads = store.file_reads();
+    check(&store)?;
+    assert_eq!(store.get("key0".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.file_reads(), reads);
+
+    store.remove("key0".to_owned())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+
+    let cached = store.warm_cache(30)?;
+    assert!(cached > 0 && cached < 9, "{}", cached);
+    check(&store)?;
+    Ok(())
+}
+
+// The handles on the generations removed by a compaction should be closed by the next
+// read, and opening a generation again shouldn't keep stale ones open.
+#[cfg(feature = "testing")]
+#[test]
+fn stale_handles_closed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for key_id in 0..3 {
+        let store = KvStore::open(temp_dir.path())?;
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..3 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value".to_owned())
+        );
+    }
+    assert_eq!(store.open_log_handles(), 3);
+    // reads without a compaction keep them
+    assert_eq!(store.get("key0".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.open_log_handles(), 3);
+
+    store.force_compact()?;
+    for key_id in 0..3 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value".to_owned())
+        );
+    }
+    assert_eq!(store.open_log_handles(), 1);
+
+    store.set("key3".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.open_log_handles(), 2);
+    store.force_compact()?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.open_log_handles(), 1);
+    Ok(())
+}
+
+// Stores with different log extensions should share a directory without seeing each
+// other's generations.
+#[test]
+fn custom_log_extension() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = |extension: &str| {
+        KvStore::open_with_options(
+            KvStoreOptions::default()
+                .with_path(temp_dir.path())
+                .with_log_extension(extension),
+        )
+    };
+    let log_store = open("log")?;
+    let dat_store = open("dat")?;
+    log_store.set("key".to_owned(), "log".to_owned())?;
+    dat_store.set("key".to_owned(), "dat".to_owned())?;
+    dat_store.set("dat_only".to_owned(), "value".to_owned())?;
+    dat_store.compact()?;
+
+    assert_eq!(log_store.get("key".to_owned())?, Some("log".to_owned()));
+    assert_eq!(log_store.get("dat_only".to_owned())?, None);
+    assert_eq!(dat_store.get("key".to_owned())?, Some("dat".to_owned()));
+    drop(log_store);
+    drop(dat_store);
+
+    let file_names = |extension: &str| -> Vec<String> {
+        fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.ends_with(&format!(".{}", extension)))
+            .collect()
+    };
+    assert!(!file_names("log").is_empty());
+    assert!(!file_names("dat").is_empty());
+
+    let dat_store = open("dat")?;
+    assert_eq!(dat_store.get("key".to_owned())?, Some("dat".to_owned()));
+    assert_eq!(
+        dat_store.get("dat_only".to_owned())?,
+        Some("value".to_owned())
+    );
+    assert_eq!(open("log")?.get("key".to_owned())?, Some("log".to_owned()));
+    // the same extension is still locked against a second store
+    assert!(matches!(open("dat"), Err(KvsError::Locked)));
+    Ok(())
+}
+
+// `open_existing` should only open directories that already exist.
+#[test]
+fn open_existing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let missing = temp_dir.path().join("missing");
+    match KvStore::open_existing(&missing) {
+        Err(KvsError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::NotFound),
+        res => panic!("unexpected result {:?}", res.map(|_| ())),
+    }
+    assert!(!missing.exists());
+
+    let store = KvStore::open_existing(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    drop(store);
+    let store = KvStore::open_existing(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    drop(store);
+
+    // `open` still creates the directory
+    KvStore::open(&missing)?;
+    assert!(missing.is_dir());
+    Ok(())
+}
+
+// `contains_key` should follow sets and removes without reading values.
+#[test]
+fn contains_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert!(!store.contains_key("key"));
+    store.set("key".to_owned(), "value".to_owned())?;
+    assert!(store.contains_key("key"));
+    store.remove("key".to_owned())?;
+    assert!(!store.contains_key("key"));
+    assert!(!store.contains_key("never"));
+
+    store.set_with_ttl(
+        "short".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(50),
+    )?;
+    assert!(store.contains_key("short"));
+    thread::sleep(Duration::from_millis(100));
+    assert!(!store.contains_key("short"));
+    Ok(())
+}
+
+// The metadata of a value should point into the log, and follow it when a compaction
+// moves it to a new generation.
+#[test]
+fn get_with_meta() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_with_meta("key".to_owned())?, None);
+
+    store.set("other".to_owned(), "value".to_owned())?;
+    store.set("key".to_owned(), "value1".to_owned())?;
+    store.set("key".to_owned(), "value2".to_owned())?;
+    let (value, meta) = store.get_with_meta("key".to_owned())?.unwrap();
+    assert_eq!(value, "value2");
+    assert!(meta.offset > 0);
+    assert!(meta.length > 0);
+    assert_eq!(
+        meta.offset + meta.length,
+        store.log_sizes()?[&meta.generation]
+    );
+
+    store.compact()?;
+    let (value, moved) = store.get_with_meta("key".to_owned())?.unwrap();
+    assert_eq!(value, "value2");
+    assert!(moved.generation > meta.generation);
+    assert_eq!(moved.length, meta.length);
+    Ok(())
+}
+
+// `purge` should remove every key and delete the old logs.
+#[test]
+fn purge() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), "value".repeat(100))?;
+    }
+    let size_before = store_size(&temp_dir);
+
+    store.purge()?;
+    for key_id in 0..1000 {
+        assert_eq!(store.get(format!("key{}", key_id))?, None);
+    }
+    assert_eq!(store.len()?, 0);
+    let size_after = store_size(&temp_dir);
+    assert!(
+        size_after < size_before,
+        "{} bytes before purge, {} after",
+        size_before,
+        size_after
+    );
+
+    // the store is usable afterwards and the keys stay purged after a reopen
+    store.set("new".to_owned(), "value".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("new".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.len()?, 1);
+    Ok(())
+}
+
+fn check_remove_not_found(engine: impl KvsEngine) -> Result<()> {
+    assert!(matches!(
+        engine.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.remove("key1".to_owned())?;
+    assert!(matches!(
+        engine.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    Ok(())
+}
+
+// Removing a missing key should fail with `KeyNotFound` in every engine.
+#[test]
+fn remove_not_found_engines() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_remove_not_found(KvStore::open(temp_dir.path())?)?;
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_remove_not_found(SledKvsEngine::open(sled_dir.path())?)?;
+    check_remove_not_found(MemoryKvsEngine::new())
+}
+
+// `iter` should read values lazily and skip keys removed while iterating.
+#[test]
+fn iter_during_removes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .with_path(temp_dir.path())
+        .with_compaction_threshold(64 * 1024);
+    let store = KvStore::open_with_options(options)?;
+    for key_id in 0..1000 {
+        store.set(format!("key{:04}", key_id), format!("{}", key_id))?;
+    }
+    let pairs = store.iter().collect::<Result<Vec<_>>>()?;
+    assert_eq!(pairs.len(), 1000);
+    assert_eq!(pairs[0], ("key0000".to_owned(), "0".to_owned()));
+    assert_eq!(pairs[999], ("key0999".to_owned(), "999".to_owned()));
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            // remove the odd keys while overwriting the even ones to trigger compactions
+            let value = "v".repeat(100);
+            for key_id in 0..1000 {
+                if key_id % 2 == 1 {
+                    store.remove(format!("key{:04}", key_id))?;
+                } else {
+                    store.set(format!("key{:04}", key_id), value.clone())?;
+                }
+            }
+            Ok(())
+        })
+    };
+
+    let value = "v".repeat(100);
+    while !writer.is_finished() {
+        let mut last_key = None;
+        for pair in store.iter() {
+            let (key, v) = pair?;
+            let key_id: usize = key[3..].parse().unwrap();
+            assert!(v == value || v == key_id.to_string());
+            // in key order without duplicates
+            assert!(last_key.as_ref() < Some(&key));
+            last_key = Some(key);
+        }
+    }
+    writer.join().unwrap()?;
+    let keys: Vec<String> = store
+        .iter()
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    let expected: Vec<String> = (0..1000)
+        .step_by(2)
+        .map(|key_id| format!("key{:04}", key_id))
+        .collect();
+    assert_eq!(keys, expected);
+    Ok(())
+}
+
+// Values over the external threshold should be stored in value logs and survive
+// compaction and reopen like inline values.
+#[test]
+fn external_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .with_path(temp_dir.path())
+        .with_external_value_threshold(64);
+    let store = KvStore::open_with_options(options.clone())?;
+    let large = "x".repeat(10_000);
+    for key_id in 0..10 {
+        store.set(format!("small{}", key_id), format!("value{}", key_id))?;
+        store.set(format!("large{}", key_id), format!("{}{}", large, key_id))?;
+    }
+    assert!(temp_dir.path().join("1.vlog").is_file());
+    // the log only holds pointers to the large values
+    assert!(store.log_sizes()?.values().sum::<u64>() < 10_000);
+
+    let check = |store: &KvStore| -> Result<()> {
+        for key_id in 0..10 {
+            assert_eq!(
+                store.get(format!("small{}", key_id))?,
+                Some(format!("value{}", key_id))
+            );
+            assert_eq!(
+                store.get(format!("large{}", key_id))?,
+                Some(format!("{}{}", large, key_id))
+            );
+        }
+        Ok(())
+    };
+    check(&store)?;
+    store.remove("small0".to_owned())?;
+    store.set("small0".to_owned(), "value0".to_owned())?;
+    store.compact()?;
+    check(&store)?;
+
+    drop(store);
+    let store = KvStore::open_with_options(options)?;
+    check(&store)?;
+    store.compact()?;
+    check(&store)?;
+    Ok(())
+}
+
+// `gc_value_logs` should free the overwritten external values and keep the live ones.
+#[test]
+fn value_log_gc() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .with_path(temp_dir.path())
+        .with_external_value_threshold(64);
+    let store = KvStore::open_with_options(options.clone())?;
+    for iter in 0..10 {
+        for key_id in 0..10 {
+            store.set(
+                format!("key{}", key_id),
+                format!("{}{}", "x".repeat(1000), iter),
+            )?;
+        }
+    }
+    store.set("small".to_owned(), "value".to_owned())?;
+    store.set("removed".to_owned(), "y".repeat(1000))?;
+    store.remove("removed".to_owned())?;
+
+    let freed = store.gc_value_logs()?;
+    assert!(freed > 90_000, "freed {} bytes", freed);
+    assert!(!temp_dir.path().join("1.vlog").exists());
+    assert!(temp_dir.path().join("2.vlog").is_file());
+
+    let check = |store: &KvStore| -> Result<()> {
+        for key_id in 0..10 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("{}9", "x".repeat(1000)))
+            );
+        }
+        assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+        assert_eq!(store.get("removed".to_owned())?, None);
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    let store = KvStore::open_with_options(options)?;
+    check(&store)?;
+    store.compact()?;
+    check(&store)?;
+    Ok(())
+}
+
+// Reads racing with `gc_value_logs` should follow the moved values rather than fail on
+// the removed value logs.
+#[test]
+fn value_log_gc_concurrent_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .with_path(temp_dir.path())
+        .with_external_value_threshold(64);
+    let store = KvStore::open_with_options(options)?;
+    let value = "x".repeat(1000);
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), value.clone())?;
+    }
+
+    let done = Arc::new(AtomicBool::new(false));
+    let reader = {
+        let store = store.clone();
+        let done = Arc::clone(&done);
+        let value = value.clone();
+        thread::spawn(move || -> Result<()> {
+            while !done.load(Ordering::SeqCst) {
+                for key_id in 0..10 {
+                    let key = format!("key{}", key_id);
+                    assert_eq!(store.get_or_set(key, "default".to_owned())?, value);
+                }
+                store.warm_cache(1 << 20)?;
+            }
+            Ok(())
+        })
+    };
+    for _ in 0..50 {
+        store.gc_value_logs()?;
+    }
+    done.store(true, Ordering::SeqCst);
+    reader.join().unwrap()?;
+    Ok(())
+}
+
+// A snapshot should keep returning the values as of when it was taken, after writes
+// and a compaction, and hold back the removal of the stale logs until it's dropped.
+#[test]
+fn snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("old{}", key_id))?;
+    }
+
+    let snapshot = store.snapshot()?;
+    for key_id in 0..5 {
+        store.set(format!("key{}", key_id), format!("new{}", key_id))?;
+    }
+    store.remove("key5".to_owned())?;
+    store.set("added".to_owned(), "value".to_owned())?;
+    // the generation the snapshot reads from becomes stale
+    store.compact()?;
+    assert_eq!(store.get("key0".to_owned())?, Some("new0".to_owned()));
+    assert_eq!(store.get("key5".to_owned())?, None);
+
+    assert_eq!(snapshot.len(), 10);
+    for key_id in 0..10 {
+        assert_eq!(
+            snapshot.get(format!("key{}", key_id))?,
+            Some(format!("old{}", key_id))
+        );
+    }
+    assert_eq!(snapshot.get("added".to_owned())?, None);
+    let pairs = snapshot.iter().collect::<Result<Vec<_>>>()?;
+    let expected: Vec<_> = (0..10)
+        .map(|key_id| (format!("key{}", key_id), format!("old{}", key_id)))
+        .collect();
+    assert_eq!(pairs, expected);
+
+    assert!(temp_dir.path().join("1.log").is_file());
+    drop(snapshot);
+    store.set("key0".to_owned(), "newer".to_owned())?;
+    store.compact()?;
+    assert!(!temp_dir.path().join("1.log").exists());
+    assert_eq!(store.get("key0".to_owned())?, Some("newer".to_owned()));
+    assert_eq!(store.get("key9".to_owned())?, Some("old9".to_owned()));
+    Ok(())
+}
+
+// Increments on hot and spread keys should not lose updates with striped write locks,
+// racing with batches over several stripes.
+#[test]
+fn striped_write_locks() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .with_path(temp_dir.path())
+        .with_write_lock_stripes(4);
+    let store = KvStore::open_with_options(options)?;
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..50 {
+                    store.increment("hot".to_owned(), 1)?;
+                    store.increment(format!("key{}", i % 10), 1)?;
+                    let mut batch = WriteBatch::new();
+                    batch.set(format!("batch{}_a", thread_id), i.to_string());
+                    batch.set(format!("batch{}_b", thread_id), i.to_string());
+                    store.write_batch(batch)?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    assert_eq!(store.get("hot".to_owned())?, Some("400".to_owned()));
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("40".to_owned()));
+    }
+    for thread_id in 0..8 {
+        assert_eq!(
+            store.get(format!("batch{}_b", thread_id))?,
+            Some("49".to_owned())
+        );
+    }
+    Ok(())
+}
+
+// A compaction cancelled halfway should leave the store as it was, readable from the
+// original generations, and a later one should complete.
+#[test]
+fn cancel_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value = "x".repeat(200);
+    for iter in 0..2 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}{}", value, iter))?;
+        }
+    }
+    let check = |store: &KvStore| -> Result<()> {
+        for key_id in 0..1000 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("{}1", value))
+            );
+        }
+        Ok(())
+    };
+
+    let cancel = Arc::new(AtomicBool::new(false));
+    let mut reports = Vec::new();
+    let res = store.compact_with_progress(
+        |progress| {
+            if progress.bytes_copied * 2 >= progress.total_bytes {
+                cancel.store(true, Ordering::SeqCst);
+            }
+            reports.push(progress);
+        },
+        Arc::clone(&cancel),
+    );
+    assert!(matches!(res, Err(KvsError::Cancelled)));
+    let last = *reports.last().unwrap();
+    assert!(last.bytes_copied < last.total_bytes);
+    // the partial compaction file is gone and the first generation is still there
+    assert!(temp_dir.path().join("1.log").is_file());
+    assert!(!temp_dir.path().join("2.log").exists());
+    check(&store)?;
+
+    let mut reports = Vec::new();
+    store.compact_with_progress(
+        |progress| reports.push(progress),
+        Arc::new(AtomicBool::new(false)),
+    )?;
+    let last: CompactionProgress = *reports.last().unwrap();
+    assert_eq!(last.bytes_copied, last.total_bytes);
+    assert!(!temp_dir.path().join("1.log").exists());
+    check(&store)?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+    Ok(())
+}
+
+// Writes after a capacity hint should read back the same, on disk and in memory.
+#[test]
+fn hint_capacity() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logs = MemoryLogs::new();
+    let value = "x".repeat(20_000);
+    for store in [
+        KvStore::open(temp_dir.path())?,
+        KvStore::open_in_memory(&logs)?,
+    ] {
+        store.set("before".to_owned(), "value".to_owned())?;
+        // absurd sizes are only capped, not an overflow or an allocation failure
+        store.hint_capacity(usize::MAX, usize::MAX)?;
+        store.hint_capacity(100, value.len())?;
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}{}", value, key_id))?;
+        }
+        assert_eq!(store.get("before".to_owned())?, Some("value".to_owned()));
+        assert_eq!(store.get("key99".to_owned())?, Some(format!("{}99", value)));
+    }
+
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("{}{}", value, key_id))
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,1356 @@
+use crossbeam::channel;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    AccessLog, AccessLogFormat, AccessLogKeys, BincodeCodec, Codec, JsonCodec, KvStore,
+    KvStoreOptions, KvsClient, KvsClientPool, KvsEngine, KvsError, KvsServer, KvsServerConfig, Op,
+    OpResult, RateLimit, Result, ServerError, SledKvsEngine,
+};
+use serde_json::{json, Value};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Barrier, Mutex};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Start a `KvStore` server on `addr` in a background thread.
+fn start_server(temp_dir: &TempDir, addr: &'static str) -> Result<()> {
+    let engine = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(4)?;
+    thread::spawn(move || {
+        KvsServer::new(engine, pool, JsonCodec).run(addr).unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+    Ok(())
+}
+
+// A server bound to port 0 should report the port it got.
+#[test]
+fn run_with_ephemeral_port() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(4)?;
+    let (addr, _handle) = KvsServer::new(engine, pool, JsonCodec).run_with_addr("127.0.0.1:0")?;
+    assert!(addr.ip().is_loopback());
+    assert_ne!(addr.port(), 0);
+
+    let mut client = KvsClient::connect(addr, JsonCodec)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Results of a batch should line up with its operations.
+#[test]
+fn batch_mixed_operations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4101";
+    start_server(&temp_dir, addr)?;
+
+    let mut client = KvsClient::connect(addr, JsonCodec)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+
+    let results = client.batch(vec![
+        Op::Get {
+            key: "key1".to_owned(),
+        },
+        Op::Set {
+            key: "key2".to_owned(),
+            value: "value2".to_owned(),
+        },
+        Op::Remove {
+            key: "key3".to_owned(),
+        },
+        Op::CompareAndSwap {
+            key: "key2".to_owned(),
+            expected: Some("value1".to_owned()),
+            new: "value3".to_owned(),
+        },
+        Op::CompareAndSwap {
+            key: "key2".to_owned(),
+            expected: Some("value2".to_owned()),
+            new: "value3".to_owned(),
+        },
+        Op::Get {
+            key: "key2".to_owned(),
+        },
+        Op::Remove {
+            key: "key1".to_owned(),
+        },
+    ])?;
+
+    assert_eq!(results.len(), 7);
+    assert_eq!(results[0], OpResult::Get(Some("value1".to_owned())));
+    assert_eq!(results[1], OpResult::Set);
+    assert_eq!(results[2], OpResult::Err(ServerError::KeyNotFound));
+    assert_eq!(results[3], OpResult::CompareAndSwap(false));
+    assert_eq!(results[4], OpResult::CompareAndSwap(true));
+    assert_eq!(results[5], OpResult::Get(Some("value3".to_owned())));
+    assert_eq!(results[6], OpResult::Remove);
+
+    assert_eq!(client.get("key1".to_owned())?, None);
+    assert_eq!(client.get("key2".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// No write of another client should come in between the operations of a batch.
+#[test]
+fn concurrent_batches() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4132";
+    start_server(&temp_dir, addr)?;
+
+    let handles: Vec<_> = (0..4)
+        .map(|client_id| {
+            thread::spawn(move || -> Result<()> {
+                let mut client = KvsClient::connect(addr, JsonCodec)?;
+                let value = format!("client{}", client_id);
+                for _ in 0..100 {
+                    let results = client
+                        .pipeline()
+                        .set("key".to_owned(), value.clone())
+                        .get("key".to_owned())
+                        .compare_and_swap("key".to_owned(), Some(value.clone()), value.clone())
+                        .execute()?;
+                    assert_eq!(
+                        results,
+                        vec![
+                            OpResult::Set,
+                            OpResult::Get(Some(value.clone())),
+                            OpResult::CompareAndSwap(true),
+                        ]
+                    );
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    Ok(())
+}
+
+// Results of a pipeline should line up with the calls that queued them.
+#[test]
+fn pipeline_mixed_operations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4118";
+    start_server(&temp_dir, addr)?;
+
+    let mut client = KvsClient::connect(addr, JsonCodec)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+
+    let results = client
+        .pipeline()
+        .set("key2".to_owned(), "value2".to_owned())
+        .get("key1".to_owned())
+        .remove("key1".to_owned())
+        .remove("key1".to_owned())
+        .get("key2".to_owned())
+        .get("key1".to_owned())
+        .execute()?;
+    assert_eq!(
+        results,
+        vec![
+            OpResult::Set,
+            OpResult::Get(Some("value1".to_owned())),
+            OpResult::Remove,
+            OpResult::Err(ServerError::KeyNotFound),
+            OpResult::Get(Some("value2".to_owned())),
+            OpResult::Get(None),
+        ]
+    );
+
+    // an empty pipeline is fine, and the connection is still usable afterwards
+    assert_eq!(client.pipeline().execute()?, vec![]);
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Health check should succeed against a running server.
+#[test]
+fn health_check() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4102";
+    start_server(&temp_dir, addr)?;
+
+    let mut client = KvsClient::connect(addr, JsonCodec)?;
+    client.health()?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.health()?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // the keys of the check are reserved
+    let key = "__kvs_self_check__".to_owned();
+    assert!(client.set(key.clone(), "value".to_owned()).is_err());
+    assert!(client.get(key.clone()).is_err());
+    assert!(client.get(format!("{}0", key)).is_err());
+    assert!(client.batch(vec![Op::Get { key }]).is_err());
+    client.health()?;
+    Ok(())
+}
+
+// Health checks from concurrent clients shouldn't interfere with each other.
+#[test]
+fn concurrent_health_checks() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4130";
+    start_server(&temp_dir, addr)?;
+
+    // no more clients than the server has workers, so that all of them run at once
+    let barrier = Arc::new(Barrier::new(4));
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || -> Result<()> {
+                let mut client = KvsClient::connect(addr, JsonCodec)?;
+                barrier.wait();
+                for _ in 0..50 {
+                    client.health()?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    // every check removed its own probe
+    let mut client = KvsClient::connect(addr, JsonCodec)?;
+    assert_eq!(client.stats()?.key_count, 0);
+    Ok(())
+}
+
+// Exists should report the presence of keys through the server.
+#[test]
+fn exists_through_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4103";
+    start_server(&temp_dir, addr)?;
+
+    let mut client = KvsClient::connect(addr, JsonCodec)?;
+    assert!(!client.exists("key1".to_owned())?);
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(client.exists("key1".to_owned())?);
+    assert!(!client.exists("key2".to_owned())?);
+    client.remove("key1".to_owned())?;
+    assert!(!client.exists("key1".to_owned())?);
+    Ok(())
+}
+
+// The server should stop after the shutdown signal, even with a client still connected.
+#[test]
+fn graceful_shutdown() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4104";
+    let engine = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(4)?;
+    let (shutdown_tx, shutdown_rx) = channel::bounded(1);
+    let server = thread::spawn(move || {
+        KvsServer::new(engine, pool, JsonCodec).run_with_shutdown(addr, shutdown_rx)
+    });
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr, JsonCodec)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    shutdown_tx.send(()).unwrap();
+    server.join().unwrap()?;
+    // New connections are refused
+    assert!(KvsClient::connect(addr, JsonCodec).is_err());
+    drop(client);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A server that accepts but never answers should surface as a timeout.
+#[test]
+fn client_timeout() -> Result<()> {
+    let addr = "127.0.0.1:4105";
+    // The connection is established in the backlog, but nothing is ever read from it
+    let _listener = TcpListener::bind(addr)?;
+
+    // The codec handshake already waits for the server
+    match KvsClient::connect_timeout(addr, Duration::from_millis(200), JsonCodec) {
+        Err(KvsError::Timeout) => Ok(()),
+        Err(e) => panic!("expected a timeout, got {:?}", e),
+        Ok(_) => panic!("expected a timeout, got a connection"),
+    }
+}
+
+// Connecting should succeed once the server comes up during the retries.
+#[test]
+fn client_connect_with_retry() -> Result<()> {
+    let addr = "127.0.0.1:4106";
+    assert!(KvsClient::connect_with_retry(addr, 2, Duration::from_millis(10), JsonCodec).is_err());
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path())?;
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(200));
+        let pool = SharedQueueThreadPool::new(1).unwrap();
+        KvsServer::new(engine, pool, JsonCodec).run(addr).unwrap();
+    });
+    let mut client = KvsClient::connect_with_retry(addr, 10, Duration::from_millis(10), JsonCodec)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Values larger than a read buffer should go through the framing intact.
+#[test]
+fn large_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4107";
+    start_server(&temp_dir, addr)?;
+
+    let value = "v".repeat(100 * 1024);
+    let mut client = KvsClient::connect(addr, JsonCodec)?;
+    client.set("key1".to_owned(), value.clone())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some(value));
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Scan should return the pairs in the requested range through the server.
+#[test]
+fn scan_through_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4108";
+    start_server(&temp_dir, addr)?;
+
+    let mut client = KvsClient::connect(addr, JsonCodec)?;
+    for key_id in 0..10 {
+        client.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let pair = |key_id| (format!("key{}", key_id), format!("value{}", key_id));
+
+    let pairs = client.scan(Some("key3".to_owned()), Some("key6".to_owned()), None)?;
+    assert_eq!(pairs, vec![pair(3), pair(4), pair(5)]);
+    let pairs = client.scan(None, Some("key2".to_owned()), None)?;
+    assert_eq!(pairs, vec![pair(0), pair(1)]);
+    let pairs = client.scan(Some("key8".to_owned()), None, None)?;
+    assert_eq!(pairs, vec![pair(8), pair(9)]);
+    let pairs = client.scan(None, None, Some(3))?;
+    assert_eq!(pairs, vec![pair(0), pair(1), pair(2)]);
+    assert!(client.scan(Some("z".to_owned()), None, None)?.is_empty());
+    Ok(())
+}
+
+// Client and server using bincode should work end to end, and a JSON client is refused.
+#[test]
+fn bincode_codec() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4109";
+    let engine = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(4)?;
+    thread::spawn(move || {
+        KvsServer::new(engine, pool, BincodeCodec)
+            .run(addr)
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr, BincodeCodec)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get("key2".to_owned())?, None);
+    assert!(client.remove("key2".to_owned()).is_err());
+    let results = client.batch(vec![Op::Get {
+        key: "key1".to_owned(),
+    }])?;
+    assert_eq!(results, vec![OpResult::Get(Some("value1".to_owned()))]);
+    let pairs = client.scan(None, None, Some(1))?;
+    assert_eq!(pairs, vec![("key1".to_owned(), "value1".to_owned())]);
+
+    assert!(KvsClient::connect(addr, JsonCodec).is_err());
+    Ok(())
+}
+
+// Connections above `max_connections` should be closed right away, and their slots reused
+// once a served connection ends.
+#[test]
+fn max_connections() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4110";
+    let engine = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(4)?;
+    let config = KvsServerConfig {
+        max_connections: 2,
+        ..KvsServerConfig::default()
+    };
+    thread::spawn(move || {
+        KvsServer::with_config(engine, pool, JsonCodec, config)
+            .run(addr)
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client1 = KvsClient::connect(addr, JsonCodec)?;
+    let mut client2 = KvsClient::connect(addr, JsonCodec)?;
+    client1.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut excess: Vec<TcpStream> = (0..3)
+        .map(|_| TcpStream::connect(addr))
+        .collect::<std::io::Result<_>>()?;
+    for stream in &mut excess {
+        stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+        // closed by the server without a handshake: either EOF or a reset
+        let mut buf = [0; 1];
+        match stream.read(&mut buf) {
+            Ok(n) => assert_eq!(n, 0),
+            Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::ConnectionReset),
+        }
+    }
+    assert!(KvsClient::connect_timeout(addr, Duration::from_secs(1), JsonCodec).is_err());
+
+    // The served connections are unaffected
+    assert_eq!(client2.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    drop(client1);
+    // the slot is released once the server sees the client leave
+    let mut client3 = KvsClient::connect_with_retry(addr, 5, Duration::from_millis(50), JsonCodec)?;
+    assert_eq!(client3.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Increments from concurrent clients should all be counted.
+#[test]
+fn concurrent_increments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4111";
+    start_server(&temp_dir, addr)?;
+
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            thread::spawn(move || -> Result<()> {
+                let mut client = KvsClient::connect(addr, JsonCodec)?;
+                for _ in 0..50 {
+                    client.increment("counter".to_owned(), 2)?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    let mut client = KvsClient::connect(addr, JsonCodec)?;
+    assert_eq!(client.increment("counter".to_owned(), 0)?, 400);
+    client.set("key".to_owned(), "value".to_owned())?;
+    match client.increment("key".to_owned(), 1) {
+        Err(KvsError::StringError(msg)) => assert_eq!(msg, "Value is not a number"),
+        res => panic!("unexpected result: {:?}", res.map(|_| ())),
+    }
+    Ok(())
+}
+
+// Of the clients racing to set the same missing key, exactly one should set it.
+#[test]
+fn concurrent_set_if_absent() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4128";
+    start_server(&temp_dir, addr)?;
+
+    let barrier = Arc::new(Barrier::new(8));
+    let handles: Vec<_> = (0..8)
+        .map(|client_id| {
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || -> Result<bool> {
+                // before connecting: the server has fewer workers than clients, and each
+                // holds a worker from the handshake until it disconnects
+                barrier.wait();
+                let mut client = KvsClient::connect(addr, JsonCodec)?;
+                client.set_if_absent("lock".to_owned(), format!("owner{}", client_id))
+            })
+        })
+        .collect();
+    let mut winners = Vec::new();
+    for (client_id, handle) in handles.into_iter().enumerate() {
+        if handle.join().unwrap()? {
+            winners.push(client_id);
+        }
+    }
+    assert_eq!(winners.len(), 1);
+
+    let mut client = KvsClient::connect(addr, JsonCodec)?;
+    assert_eq!(
+        client.get("lock".to_owned())?,
+        Some(format!("owner{}", winners[0]))
+    );
+    assert!(!client.set_if_absent("lock".to_owned(), "other".to_owned())?);
+    client.remove("lock".to_owned())?;
+    assert!(client.set_if_absent("lock".to_owned(), "other".to_owned())?);
+    Ok(())
+}
+
+// A counter with a TTL should keep its expiry when counted, and start from 0 after it.
+#[test]
+fn expiring_counter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4127";
+    start_server(&temp_dir, addr)?;
+
+    let mut client = KvsClient::connect(addr, JsonCodec)?;
+    client.set_counter("counter".to_owned(), 10, Some(Duration::from_millis(300)))?;
+    assert_eq!(client.increment("counter".to_owned(), 5)?, 15);
+    assert_eq!(client.decrement("counter".to_owned(), 3)?, 12);
+
+    thread::sleep(Duration::from_millis(400));
+    assert_eq!(client.get("counter".to_owned())?, None);
+    assert_eq!(client.increment("counter".to_owned(), 1)?, 1);
+    assert_eq!(client.decrement("other".to_owned(), 2)?, -2);
+
+    // without a TTL the counter doesn't expire
+    client.set_counter("counter".to_owned(), 7, None)?;
+    thread::sleep(Duration::from_millis(400));
+    assert_eq!(client.decrement("counter".to_owned(), 7)?, 0);
+    Ok(())
+}
+
+// An overflowing counter should be reported as an invalid command, not a server error.
+#[test]
+fn counter_overflow() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(4)?;
+    let sink = SharedBuffer::default();
+    let access_log = AccessLog::new(sink.clone()).with_format(AccessLogFormat::Json);
+    let (addr, _handle) = KvsServer::new(engine, pool, JsonCodec)
+        .with_access_log(access_log)
+        .run_with_addr("127.0.0.1:0")?;
+
+    let mut client = KvsClient::connect(addr, JsonCodec)?;
+    client.set_counter("counter".to_owned(), i64::MAX, None)?;
+    match client.increment("counter".to_owned(), 1) {
+        Err(KvsError::StringError(msg)) => assert_eq!(msg, "Counter overflows i64"),
+        res => panic!("unexpected result: {:?}", res),
+    }
+    match client.decrement("counter".to_owned(), i64::MIN) {
+        Err(KvsError::StringError(msg)) => assert_eq!(msg, "Counter overflows i64"),
+        res => panic!("unexpected result: {:?}", res),
+    }
+    assert_eq!(
+        client.get("counter".to_owned())?,
+        Some(i64::MAX.to_string())
+    );
+
+    let statuses = sink
+        .lines()
+        .iter()
+        .map(|line| serde_json::from_str::<Value>(line).unwrap()["status"].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(statuses, vec!["ok", "invalid", "invalid", "ok"]);
+    Ok(())
+}
+
+// A sink that the test can read back, shared with the access log.
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    fn lines(&self) -> Vec<String> {
+        let buf = self.0.lock().unwrap();
+        String::from_utf8_lossy(&buf)
+            .lines()
+            .map(str::to_owned)
+            .collect()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// The access log should have a record per request, with its key as configured.
+#[test]
+fn access_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(4)?;
+    let sink = SharedBuffer::default();
+    let access_log = AccessLog::new(sink.clone()).with_format(AccessLogFormat::Json);
+    let (addr, _handle) = KvsServer::new(engine.clone(), pool, JsonCodec)
+        .with_access_log(access_log)
+        .run_with_addr("127.0.0.1:0")?;
+
+    let mut client = KvsClient::connect(addr, JsonCodec)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    client.get("key".to_owned())?;
+    assert!(matches!(
+        client.remove("missing".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    client.ping()?;
+
+    let records = sink
+        .lines()
+        .iter()
+        .map(|line| serde_json::from_str::<Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    let expected = [
+        ("set", Some("key"), "ok"),
+        ("get", Some("key"), "ok"),
+        ("remove", Some("missing"), "not_found"),
+        ("ping", None, "ok"),
+    ];
+    assert_eq!(records.len(), expected.len());
+    for (record, (op, key, status)) in records.iter().zip(expected.iter()) {
+        assert_eq!(record["op"], *op);
+        assert_eq!(record["key"].as_str(), *key);
+        assert_eq!(record["status"], *status);
+        assert!(record["ts_ms"].as_u64().unwrap() > 0);
+        let peer: SocketAddr = record["peer"].as_str().unwrap().parse().unwrap();
+        assert!(peer.ip().is_loopback());
+    }
+
+    // hashed keys are the same for the same key, and don't show it
+    let pool = SharedQueueThreadPool::new(4)?;
+    let sink = SharedBuffer::default();
+    let access_log = AccessLog::new(sink.clone()).with_keys(AccessLogKeys::Hashed);
+    let (addr, _handle) = KvsServer::new(engine, pool, JsonCodec)
+        .with_access_log(access_log)
+        .run_with_addr("127.0.0.1:0")?;
+    let mut client = KvsClient::connect(addr, JsonCodec)?;
+    client.get("key".to_owned())?;
+    client.get("key".to_owned())?;
+    let lines = sink.lines();
+    assert_eq!(lines.len(), 2);
+    let key_field = |line: &str| {
+        line.split(' ')
+            .find(|field| field.starts_with("key="))
+            .unwrap()
+            .to_owned()
+    };
+    assert_eq!(key_field(&lines[0]), key_field(&lines[1]));
+    assert!(!lines[0].contains("\"key\""));
+    assert!(lines[0].starts_with("ts_ms="));
+    assert!(lines[0].ends_with(" status=ok"));
+    Ok(())
+}
+
+// Clients racing to set a missing key should all get the default that won.
+#[test]
+fn concurrent_get_or_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4124";
+    start_server(&temp_dir, addr)?;
+
+    let barrier = Arc::new(Barrier::new(8));
+    let handles: Vec<_> = (0..8)
+        .map(|i| {
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || -> Result<String> {
+                // before connecting: the server has fewer workers than clients, and each
+                // holds a worker from the handshake until it disconnects
+                barrier.wait();
+                let mut client = KvsClient::connect(addr, JsonCodec)?;
+                client.get_or_set("key".to_owned(), format!("default{}", i))
+            })
+        })
+        .collect();
+    let values = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .collect::<Result<Vec<_>>>()?;
+    assert!(values[0].starts_with("default"));
+    assert!(values.iter().all(|value| *value == values[0]));
+
+    let mut client = KvsClient::connect(addr, JsonCodec)?;
+    assert_eq!(client.get("key".to_owned())?, Some(values[0].clone()));
+    assert_eq!(
+        client.get_or_set("key".to_owned(), "other".to_owned())?,
+        values[0]
+    );
+    Ok(())
+}
+
+// Threads sharing a small pool should all get their operations through.
+#[test]
+fn client_pool() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4125";
+    start_server(&temp_dir, addr)?;
+
+    let pool = Arc::new(KvsClientPool::connect(addr, 4, JsonCodec)?);
+    let handles: Vec<_> = (0..8)
+        .map(|i| {
+            let pool = Arc::clone(&pool);
+            thread::spawn(move || -> Result<()> {
+                for j in 0..20 {
+                    let key = format!("key{}_{}", i, j);
+                    pool.set(key.clone(), format!("value{}", j))?;
+                    assert_eq!(pool.get(key.clone())?, Some(format!("value{}", j)));
+                    let mut client = pool.checkout()?;
+                    client.increment("counter".to_owned(), 1)?;
+                    if j % 2 == 0 {
+                        client.remove(key)?;
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    assert_eq!(pool.checkout()?.increment("counter".to_owned(), 0)?, 160);
+    assert_eq!(pool.get("key3_4".to_owned())?, None);
+    assert_eq!(pool.get("key3_5".to_owned())?, Some("value5".to_owned()));
+    Ok(())
+}
+
+// A fresh server should answer a ping with its version and a small uptime.
+#[test]
+fn ping() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4112";
+    start_server(&temp_dir, addr)?;
+
+    let mut client = KvsClient::connect(addr, JsonCodec)?;
+    let pong = client.ping()?;
+    assert_eq!(pong.version, env!("CARGO_PKG_VERSION"));
+    assert!(pong.uptime_secs < 5, "uptime {}", pong.uptime_secs);
+    // the connection is still usable afterwards
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(client.ping()?.version, pong.version);
+    Ok(())
+}
+
+// Write `msg` as a frame: a 4-byte big-endian length followed by the JSON payload.
+fn write_json_frame(stream: &mut TcpStream, msg: &Value) {
+    let payload = serde_json::to_vec(msg).unwrap();
+    stream
+        .write_all(&(payload.len() as u32).to_be_bytes())
+        .unwrap();
+    stream.write_all(&payload).unwrap();
+}
+
+fn read_json_frame(stream: &mut TcpStream) -> Value {
+    let mut len_buf = [0; 4];
+    stream.read_exact(&mut len_buf).unwrap();
+    let mut payload = vec![0; u32::from_be_bytes(len_buf) as usize];
+    stream.read_exact(&mut payload).unwrap();
+    serde_json::from_slice(&payload).unwrap()
+}
+
+// A frame longer than the limit should close the connection without allocating it, and
+// leave the server serving other clients.
+#[test]
+fn oversized_frame() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4129";
+    start_server(&temp_dir, addr)?;
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(&[JsonCodec::ID])?;
+    let mut server_codec = [0; 1];
+    stream.read_exact(&mut server_codec)?;
+    stream.write_all(&u32::MAX.to_be_bytes())?;
+    let mut buf = [0; 1];
+    assert!(matches!(stream.read(&mut buf), Ok(0) | Err(_)));
+
+    let mut client = KvsClient::connect(addr, JsonCodec)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Pipelined requests should be answered in order, each with the id of its request.
+#[test]
+fn pipelined_request_ids() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4113";
+    start_server(&temp_dir, addr)?;
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(&[JsonCodec::ID])?;
+    let mut server_codec = [0; 1];
+    stream.read_exact(&mut server_codec)?;
+    assert_eq!(server_codec[0], JsonCodec::ID);
+
+    // send every request before reading any response
+    write_json_frame(
+        &mut stream,
+        &json!({"id": 7, "body": {"Set": {"key": "key", "value": "value"}}}),
+    );
+    write_json_frame(
+        &mut stream,
+        &json!({"id": 3, "body": {"Get": {"key": "key"}}}),
+    );
+    write_json_frame(
+        &mut stream,
+        &json!({"id": 42, "body": {"Remove": {"key": "key"}}}),
+    );
+    // a request without an id is answered with id 0
+    write_json_frame(&mut stream, &json!({"body": {"Get": {"key": "key"}}}));
+
+    assert_eq!(
+        read_json_frame(&mut stream),
+        json!({"id": 7, "body": {"Ok": null}})
+    );
+    assert_eq!(
+        read_json_frame(&mut stream),
+        json!({"id": 3, "body": {"Ok": "value"}})
+    );
+    assert_eq!(
+        read_json_frame(&mut stream),
+        json!({"id": 42, "body": {"Ok": null}})
+    );
+    assert_eq!(
+        read_json_frame(&mut stream),
+        json!({"id": 0, "body": {"Ok": null}})
+    );
+    Ok(())
+}
+
+// A malformed request should get an error without closing the connection.
+#[test]
+fn malformed_request() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4126";
+    start_server(&temp_dir, addr)?;
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(&[JsonCodec::ID])?;
+    let mut server_codec = [0; 1];
+    stream.read_exact(&mut server_codec)?;
+
+    write_json_frame(
+        &mut stream,
+        &json!({"id": 1, "body": {"Set": {"key": "key", "value": "value"}}}),
+    );
+    // not JSON at all
+    stream.write_all(&5u32.to_be_bytes())?;
+    stream.write_all(b"\x00{]oo")?;
+    // valid JSON with an unknown request, answered with its id
+    write_json_frame(&mut stream, &json!({"id": 3, "body": {"Frobnicate": {}}}));
+    write_json_frame(
+        &mut stream,
+        &json!({"id": 4, "body": {"Get": {"key": "key"}}}),
+    );
+
+    let malformed = json!({"Err": {"InvalidCommand": "malformed request"}});
+    assert_eq!(
+        read_json_frame(&mut stream),
+        json!({"id": 1, "body": {"Ok": null}})
+    );
+    assert_eq!(
+        read_json_frame(&mut stream),
+        json!({"id": 0, "body": malformed})
+    );
+    assert_eq!(
+        read_json_frame(&mut stream),
+        json!({"id": 3, "body": malformed})
+    );
+    assert_eq!(
+        read_json_frame(&mut stream),
+        json!({"id": 4, "body": {"Ok": "value"}})
+    );
+    Ok(())
+}
+
+// A malformed request should be answered with an error the client decodes as the
+// response it expects with bincode too, which encodes variants by index.
+#[test]
+fn malformed_request_bincode() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4131";
+    let engine = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(4)?;
+    thread::spawn(move || {
+        KvsServer::new(engine, pool, BincodeCodec)
+            .run(addr)
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+
+    // a proxy replacing the body of the first request with garbage, keeping its id
+    let proxy = TcpListener::bind("127.0.0.1:0")?;
+    let proxy_addr = proxy.local_addr()?;
+    thread::spawn(move || {
+        let (mut client, _) = proxy.accept().unwrap();
+        let mut server = TcpStream::connect(addr).unwrap();
+        let mut codec_id = [0; 1];
+        client.read_exact(&mut codec_id).unwrap();
+        server.write_all(&codec_id).unwrap();
+        server.read_exact(&mut codec_id).unwrap();
+        client.write_all(&codec_id).unwrap();
+
+        let mut len_buf = [0; 4];
+        client.read_exact(&mut len_buf).unwrap();
+        let mut payload = vec![0; u32::from_be_bytes(len_buf) as usize];
+        client.read_exact(&mut payload).unwrap();
+        payload.truncate(8);
+        payload.extend_from_slice(&[0xff; 4]);
+        server
+            .write_all(&(payload.len() as u32).to_be_bytes())
+            .unwrap();
+        server.write_all(&payload).unwrap();
+        std::io::copy(&mut server, &mut client).unwrap();
+    });
+
+    let mut client = KvsClient::connect(proxy_addr, BincodeCodec)?;
+    match client.get("key".to_owned()) {
+        Err(KvsError::StringError(msg)) => assert_eq!(msg, "malformed request"),
+        res => panic!("unexpected response: {:?}", res),
+    }
+    Ok(())
+}
+
+// A client should set and get through a server using a self-signed certificate.
+#[cfg(feature = "tls")]
+#[test]
+fn set_get_over_tls() -> Result<()> {
+    use rustls::{Certificate, PrivateKey, RootCertStore};
+
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+    let cert_der = Certificate(cert.serialize_der().unwrap());
+    let key = PrivateKey(cert.serialize_private_key_der());
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4114";
+    let engine = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(4)?;
+    let server_cert = cert_der.clone();
+    thread::spawn(move || {
+        KvsServer::new(engine, pool, JsonCodec)
+            .run_tls(addr, vec![server_cert], key)
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+
+    let mut root_certs = RootCertStore::empty();
+    root_certs.add(&cert_der).unwrap();
+    let mut client = KvsClient::connect_tls(addr, "localhost", root_certs, JsonCodec)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    assert_eq!(client.get("missing".to_owned())?, None);
+    Ok(())
+}
+
+// A value over the limit of the store should fail the request, not the connection.
+#[test]
+fn value_too_large() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4115";
+    let options = KvStoreOptions::default()
+        .with_path(temp_dir.path())
+        .with_max_value_bytes(1024);
+    let engine = KvStore::open_with_options(options)?;
+    let pool = SharedQueueThreadPool::new(4)?;
+    thread::spawn(move || {
+        KvsServer::new(engine, pool, JsonCodec).run(addr).unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr, JsonCodec)?;
+    match client.set("key".to_owned(), "v".repeat(1025)) {
+        Err(KvsError::StringError(msg)) => {
+            assert_eq!(msg, "Value of 1025 bytes exceeds the limit of 1024 bytes")
+        }
+        res => panic!("unexpected result: {:?}", res),
+    }
+    client.set("key".to_owned(), "v".repeat(1024))?;
+    assert_eq!(client.get("key".to_owned())?, Some("v".repeat(1024)));
+    Ok(())
+}
+
+// Requests above the rate limit of a connection should get an error, while the
+// connection stays usable and other connections have their own limit.
+#[test]
+fn rate_limit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4116";
+    let engine = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(4)?;
+    let config = KvsServerConfig {
+        rate_limit: Some(RateLimit {
+            requests_per_second: 1,
+            burst: 5,
+        }),
+        ..KvsServerConfig::default()
+    };
+    thread::spawn(move || {
+        KvsServer::with_config(engine, pool, JsonCodec, config)
+            .run(addr)
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr, JsonCodec)?;
+    let mut limited = 0;
+    for _ in 0..20 {
+        match client.get("key".to_owned()) {
+            Ok(_) => {}
+            Err(KvsError::StringError(ref msg)) if msg == "rate limited" => limited += 1,
+            Err(e) => return Err(e),
+        }
+    }
+    // the burst, and maybe one more token refilled meanwhile
+    assert!(limited >= 14, "{} requests limited", limited);
+    // pings are not limited
+    client.ping()?;
+
+    let mut other = KvsClient::connect(addr, JsonCodec)?;
+    other.set("key".to_owned(), "value".to_owned())?;
+
+    // the bucket refills over time
+    thread::sleep(Duration::from_millis(1100));
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// A sled engine should report a missing key the same way as the file engine.
+#[test]
+fn sled_remove_not_found() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4119";
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(4)?;
+    thread::spawn(move || {
+        KvsServer::new(engine, pool, JsonCodec).run(addr).unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr, JsonCodec)?;
+    match client.remove("missing".to_owned()) {
+        Err(KvsError::KeyNotFound) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+    let results = client.batch(vec![Op::Remove {
+        key: "missing".to_owned(),
+    }])?;
+    assert_eq!(results, vec![OpResult::Err(ServerError::KeyNotFound)]);
+    Ok(())
+}
+
+// Server errors should come back as typed errors rather than bare messages.
+#[test]
+fn typed_errors() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4117";
+    start_server(&temp_dir, addr)?;
+
+    let mut client = KvsClient::connect(addr, JsonCodec)?;
+    match client.remove("missing".to_owned()) {
+        Err(KvsError::KeyNotFound) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+    client.set("key".to_owned(), "value".to_owned())?;
+    client.remove("key".to_owned())?;
+    match client.remove("key".to_owned()) {
+        Err(KvsError::KeyNotFound) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+
+    // errors of batch operations are typed as well
+    let results = client.batch(vec![
+        Op::Remove {
+            key: "missing".to_owned(),
+        },
+        Op::Set {
+            key: "key".to_owned(),
+            value: "value".to_owned(),
+        },
+    ])?;
+    assert_eq!(
+        results,
+        vec![OpResult::Err(ServerError::KeyNotFound), OpResult::Set]
+    );
+    Ok(())
+}
+
+// Counts the reads and writes of a connection fed from a buffer.
+#[cfg(feature = "testing")]
+struct CountingStream {
+    input: std::io::Cursor<Vec<u8>>,
+    output: Vec<u8>,
+    reads: usize,
+    writes: usize,
+}
+
+#[cfg(feature = "testing")]
+impl Read for CountingStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.reads += 1;
+        self.input.read(buf)
+    }
+}
+
+#[cfg(feature = "testing")]
+impl Write for CountingStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.writes += 1;
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Serve 1000 requests sent at once with the given buffer size, returning the numbers of
+// reads and writes.
+#[cfg(feature = "testing")]
+fn count_io(io_buffer_size: usize) -> Result<(usize, usize)> {
+    const REQUESTS: u64 = 1000;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvsServerConfig {
+        io_buffer_size,
+        ..KvsServerConfig::default()
+    };
+    let server = KvsServer::with_config(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(1)?,
+        JsonCodec,
+        config,
+    );
+
+    let mut input = vec![JsonCodec::ID];
+    for id in 1..=REQUESTS {
+        let req = json!({ "id": id, "body": { "Set": { "key": format!("key{}", id), "value": "value" } } });
+        let payload = serde_json::to_vec(&req)?;
+        input.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        input.extend_from_slice(&payload);
+    }
+    let mut stream = CountingStream {
+        input: std::io::Cursor::new(input),
+        output: Vec::new(),
+        reads: 0,
+        writes: 0,
+    };
+    server.serve_stream(&mut stream)?;
+
+    // the codec id, then a response for every request
+    let mut output = &stream.output[1..];
+    let mut responses = 0;
+    while !output.is_empty() {
+        let mut len = [0; 4];
+        output.read_exact(&mut len)?;
+        output = &output[u32::from_be_bytes(len) as usize..];
+        responses += 1;
+    }
+    assert_eq!(responses, REQUESTS);
+    Ok((stream.reads, stream.writes))
+}
+
+// Larger buffers should serve pipelined requests with fewer reads and writes.
+#[cfg(feature = "testing")]
+#[test]
+fn io_buffer_size() -> Result<()> {
+    let (small_reads, small_writes) = count_io(1024)?;
+    let (large_reads, large_writes) = count_io(1024 * 1024)?;
+    assert!(
+        large_reads < small_reads,
+        "{} reads with a large buffer, {} with a small one",
+        large_reads,
+        small_reads
+    );
+    assert!(
+        large_writes < small_writes,
+        "{} writes with a large buffer, {} with a small one",
+        large_writes,
+        small_writes
+    );
+    Ok(())
+}
+
+// Counts the `request` spans entered on the current thread.
+#[cfg(all(feature = "tracing", feature = "testing"))]
+struct RequestSpanCounter {
+    next_id: std::sync::atomic::AtomicU64,
+    requests: std::sync::Mutex<std::collections::HashSet<u64>>,
+    entered: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+}
+
+#[cfg(all(feature = "tracing", feature = "testing"))]
+impl tracing::Subscriber for RequestSpanCounter {
+    fn enabled(&self, _metadata: &tracing::Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+        let id = self
+            .next_id
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        if span.metadata().name() == "request" {
+            self.requests.lock().unwrap().insert(id);
+        }
+        tracing::span::Id::from_u64(id)
+    }
+
+    fn record(&self, _span: &tracing::span::Id, _values: &tracing::span::Record<'_>) {}
+
+    fn record_follows_from(&self, _span: &tracing::span::Id, _follows: &tracing::span::Id) {}
+
+    fn event(&self, _event: &tracing::Event<'_>) {}
+
+    fn enter(&self, span: &tracing::span::Id) {
+        if self.requests.lock().unwrap().contains(&span.into_u64()) {
+            self.entered
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        }
+    }
+
+    fn exit(&self, _span: &tracing::span::Id) {}
+}
+
+// Every request should be served in its own span.
+#[cfg(all(feature = "tracing", feature = "testing"))]
+#[test]
+fn request_spans() -> Result<()> {
+    const REQUESTS: u64 = 10;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(1)?,
+        JsonCodec,
+    );
+    let mut input = vec![JsonCodec::ID];
+    for id in 1..=REQUESTS {
+        let req = json!({ "id": id, "body": { "Get": { "key": "key" } } });
+        let payload = serde_json::to_vec(&req)?;
+        input.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        input.extend_from_slice(&payload);
+    }
+    let mut stream = CountingStream {
+        input: std::io::Cursor::new(input),
+        output: Vec::new(),
+        reads: 0,
+        writes: 0,
+    };
+
+    let entered = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let subscriber = RequestSpanCounter {
+        next_id: std::sync::atomic::AtomicU64::new(1),
+        requests: std::sync::Mutex::new(std::collections::HashSet::new()),
+        entered: std::sync::Arc::clone(&entered),
+    };
+    tracing::subscriber::with_default(subscriber, || server.serve_stream(&mut stream))?;
+    assert_eq!(
+        entered.load(std::sync::atomic::Ordering::SeqCst),
+        REQUESTS as usize
+    );
+    Ok(())
+}
+
+// Connections on any of the addresses should reach the same engine.
+#[test]
+fn run_multi() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addrs = vec![
+        "127.0.0.1:4120".parse().unwrap(),
+        "127.0.0.1:4121".parse().unwrap(),
+    ];
+    let engine = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(4)?;
+    thread::spawn(move || {
+        KvsServer::new(engine, pool, JsonCodec)
+            .run_multi(addrs)
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+
+    let mut first = KvsClient::connect("127.0.0.1:4120", JsonCodec)?;
+    let mut second = KvsClient::connect("127.0.0.1:4121", JsonCodec)?;
+    first.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(second.get("key".to_owned())?, Some("value".to_owned()));
+    second.remove("key".to_owned())?;
+    assert_eq!(first.get("key".to_owned())?, None);
+
+    // a taken address fails the whole server
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(other_dir.path())?;
+    let pool = SharedQueueThreadPool::new(1)?;
+    let res = KvsServer::new(engine, pool, JsonCodec).run_multi(vec![
+        "127.0.0.1:4122".parse().unwrap(),
+        "127.0.0.1:4121".parse().unwrap(),
+    ]);
+    assert!(res.is_err());
+    Ok(())
+}
+
+// A client should reach the server over a Unix domain socket.
+#[cfg(unix)]
+#[test]
+fn unix_socket() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let socket_path = temp_dir.path().join("kvs.sock");
+    let engine = KvStore::open(temp_dir.path().join("data"))?;
+    let pool = SharedQueueThreadPool::new(4)?;
+    let server_path = socket_path.clone();
+    thread::spawn(move || {
+        KvsServer::new(engine, pool, JsonCodec)
+            .run_unix(&server_path)
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect_unix(&socket_path, JsonCodec)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    // a second connection sees the same engine
+    let mut other = KvsClient::connect_unix(&socket_path, JsonCodec)?;
+    assert_eq!(other.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Every get, set and remove served should be recorded once in the latency histogram.
+#[test]
+fn latency_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4123";
+    let config = KvsServerConfig {
+        record_latency: true,
+        ..KvsServerConfig::default()
+    };
+    let server = KvsServer::with_config(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(4)?,
+        JsonCodec,
+        config,
+    );
+    let metrics = server.latency_metrics();
+    assert_eq!(server.latency_snapshot().count, 0);
+    thread::spawn(move || server.run(addr).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr, JsonCodec)?;
+    for i in 0..50 {
+        client.set(format!("key{}", i), "value".to_owned())?;
+    }
+    // from another connection, which stays open
+    let mut other = KvsClient::connect(addr, JsonCodec)?;
+    for i in 0..30 {
+        assert_eq!(other.get(format!("key{}", i))?, Some("value".to_owned()));
+    }
+    for i in 0..20 {
+        client.remove(format!("key{}", i))?;
+    }
+    // not an engine call
+    client.ping()?;
+    drop(client);
+
+    let snapshot = metrics.snapshot();
+    assert_eq!(snapshot.count, 100);
+    assert!(snapshot.p50 <= snapshot.p99);
+    assert!(snapshot.p99 <= snapshot.max);
+    assert!(snapshot.max > Duration::from_nanos(0));
+    Ok(())
+}
@@ -1,5 +1,7 @@
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
 use kvs::thread_pool::*;
 use kvs::Result;
@@ -52,6 +54,12 @@ fn naive_thread_pool_spawn_counter() -> Result<()> {
     spawn_counter(pool)
 }
 
+#[test]
+fn bounded_naive_pool_spawn_counter() -> Result<()> {
+    let pool = BoundedNaivePool::new(4)?;
+    spawn_counter(pool)
+}
+
 #[test]
 fn shared_queue_thread_pool_spawn_counter() -> Result<()> {
     let pool = SharedQueueThreadPool::new(4)?;
@@ -68,3 +76,172 @@ fn rayon_thread_pool_spawn_counter() -> Result<()> {
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
 }
+
+#[test]
+fn bounded_naive_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<BoundedNaivePool>()
+}
+
+#[test]
+fn bounded_naive_pool_limits_threads() -> Result<()> {
+    const THREADS: usize = 3;
+
+    let pool = BoundedNaivePool::new(THREADS as u32)?;
+    let wg = WaitGroup::new();
+    let running = Arc::new(AtomicUsize::new(0));
+    let peak = Arc::new(AtomicUsize::new(0));
+    for _ in 0..30 {
+        let running = Arc::clone(&running);
+        let peak = Arc::clone(&peak);
+        let wg = wg.clone();
+        pool.spawn(move || {
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            peak.fetch_max(now, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(10));
+            running.fetch_sub(1, Ordering::SeqCst);
+            drop(wg);
+        })
+    }
+
+    wg.wait();
+    let peak = peak.load(Ordering::SeqCst);
+    assert!(peak <= THREADS, "peak of {} threads", peak);
+    assert!(BoundedNaivePool::new(0).is_err());
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_shutdown() -> Result<()> {
+    const TASK_NUM: usize = 8;
+
+    let pool = SharedQueueThreadPool::new(4)?;
+    let counter = Arc::new(AtomicUsize::new(0));
+    for _ in 0..TASK_NUM {
+        pool.spawn(move || {
+            panic_control::disable_hook_in_current_thread();
+            panic!();
+        });
+        let counter = Arc::clone(&counter);
+        pool.spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+
+    pool.shutdown();
+    assert_eq!(counter.load(Ordering::SeqCst), TASK_NUM);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_metrics() -> Result<()> {
+    const TASK_NUM: u64 = 10;
+
+    // With a single worker, the tasks after the panic run on the respawned thread
+    let pool = SharedQueueThreadPool::new(1)?;
+    pool.spawn(move || {
+        panic_control::disable_hook_in_current_thread();
+        panic!();
+    });
+    for _ in 0..TASK_NUM {
+        pool.spawn(|| {});
+    }
+
+    for _ in 0..500 {
+        if pool.metrics().tasks_completed == TASK_NUM {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    let metrics = pool.metrics();
+    assert_eq!(metrics.tasks_completed, TASK_NUM);
+    assert_eq!(metrics.total_panics, 1);
+    assert_eq!(metrics.live_threads, 1);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_thread_names() -> Result<()> {
+    let pool = SharedQueueThreadPoolBuilder::new(2)
+        .stack_size(256 * 1024)
+        .build()?;
+    // The replacement of a panicked worker is named like the others
+    pool.spawn(move || {
+        panic_control::disable_hook_in_current_thread();
+        panic!();
+    });
+
+    let names = Arc::new(Mutex::new(Vec::new()));
+    for _ in 0..10 {
+        let names = Arc::clone(&names);
+        pool.spawn(move || {
+            let name = thread::current().name().map(str::to_owned);
+            names.lock().unwrap().push(name);
+        });
+    }
+    pool.shutdown();
+
+    let names = names.lock().unwrap();
+    assert_eq!(names.len(), 10);
+    for name in names.iter() {
+        let name = name.as_ref().expect("worker thread is unnamed");
+        assert!(name == "kvs-worker-0" || name == "kvs-worker-1", "{}", name);
+    }
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_set_size() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(2)?;
+    let wait_for_threads = |threads: usize| {
+        for _ in 0..500 {
+            if pool.metrics().live_threads == threads {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(pool.metrics().live_threads, threads);
+    };
+
+    pool.set_size(8)?;
+    wait_for_threads(8);
+    // all the workers can run a task at the same time
+    let wg = WaitGroup::new();
+    let running = Arc::new(AtomicUsize::new(0));
+    for _ in 0..8 {
+        let wg = wg.clone();
+        let running = Arc::clone(&running);
+        pool.spawn(move || {
+            running.fetch_add(1, Ordering::SeqCst);
+            while running.load(Ordering::SeqCst) < 8 {
+                thread::sleep(Duration::from_millis(1));
+            }
+            drop(wg);
+        });
+    }
+    wg.wait();
+
+    // A task running while the pool shrinks is finished
+    let finished = Arc::new(AtomicUsize::new(0));
+    for _ in 0..8 {
+        let finished = Arc::clone(&finished);
+        pool.spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            finished.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+    thread::sleep(Duration::from_millis(20));
+    pool.set_size(2)?;
+    wait_for_threads(2);
+    assert_eq!(finished.load(Ordering::SeqCst), 8);
+
+    spawn_counter(pool)?;
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_set_size_zero() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(2)?;
+    assert!(pool.set_size(0).is_err());
+    spawn_counter(pool)
+}
@@ -1,10 +1,14 @@
-use crate::common::{Request, Response};
+use crate::common::{Change, PongInfo, Request, Response};
 use crate::KvsError;
-use std::net::SocketAddr;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::{Duration, Instant};
 use tokio::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
 use tokio::io::{ReadHalf, WriteHalf};
 use tokio::net::TcpStream;
+use tokio::prelude::future::{self, Loop};
 use tokio::prelude::*;
+use tokio::timer::Delay;
 use tokio_serde_json::{ReadJson, WriteJson};
 
 /// 键值存储客户端，使用异步 I/O 与服务器交互
@@ -37,15 +41,64 @@ impl KvsClient {
             .map_err(|e| e.into())
     }
 
+    /// 连接到 `addr` 以访问 `KvsServer`，失败时按指数退避重试，最多尝试 `max_attempts` 次。
+    ///
+    /// 两次尝试之间的等待从 `base_delay` 开始，每失败一次翻倍。
+    /// 只有连接被拒绝等服务器可能尚未启动的错误才会重试，地址格式错误或无法解析会立即失败。
+    /// 所有尝试都失败时返回最后一次的错误。
+    ///
+    /// 地址在调用时同步解析；等待使用 `tokio::timer`，所以返回的 Future 必须在 tokio 运行时中执行。
+    pub fn connect_retry<A: ToSocketAddrs>(
+        addr: A,
+        max_attempts: u32,
+        base_delay: Duration,
+    ) -> impl Future<Item = Self, Error = KvsError> {
+        let addr = addr
+            .to_socket_addrs()
+            .map_err(KvsError::from)
+            .and_then(|mut addrs| {
+                addrs
+                    .next()
+                    .ok_or_else(|| KvsError::StringError("No address to connect to".to_owned()))
+            });
+        future::result(addr).and_then(move |addr| {
+            future::loop_fn((1, base_delay), move |(attempt, delay)| {
+                KvsClient::connect(addr).then(move |res| -> ConnectAttempt {
+                    match res {
+                        Err(ref e) if attempt < max_attempts && is_connect_retryable(e) => {
+                            Box::new(
+                                Delay::new(Instant::now() + delay)
+                                    .map(move |_| Loop::Continue((attempt + 1, delay * 2)))
+                                    .map_err(|e| KvsError::StringError(e.to_string())),
+                            )
+                        }
+                        res => Box::new(future::result(res.map(Loop::Break))),
+                    }
+                })
+            })
+        })
+    }
+
     /// 从服务器获取给定键的值。
     /// 这里的 API 设计采用了消耗 self 并返回 (Value, Self) 的模式，以符合异步所有权模型。
+    ///
+    /// 大值由服务器分片发送，在这里拼接完整后返回。
     pub fn get(self, key: String) -> impl Future<Item = (Option<String>, Self), Error = KvsError> {
         self.send_request(Request::Get { key })
-            .and_then(move |(resp, client)| match resp {
-                Some(Response::Get(value)) => Ok((value, client)),
-                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
-                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
-                None => Err(KvsError::StringError("No response received".to_owned())),
+            .and_then(move |(resp, client)| -> GetResult {
+                match resp {
+                    Some(Response::Get(value)) => Box::new(future::ok((value, client))),
+                    Some(Response::GetChunk { seq: 0, data, last }) => {
+                        Box::new(client.read_chunks(data, last))
+                    }
+                    Some(Response::Err(msg)) => Box::new(future::err(KvsError::StringError(msg))),
+                    Some(_) => Box::new(future::err(KvsError::StringError(
+                        "Invalid response".to_owned(),
+                    ))),
+                    None => Box::new(future::err(KvsError::StringError(
+                        "No response received".to_owned(),
+                    ))),
+                }
             })
     }
 
@@ -71,6 +124,133 @@ impl KvsClient {
             })
     }
 
+    /// 检查服务器是否存活，服务器不会访问存储引擎。
+    pub fn ping(self) -> impl Future<Item = (PongInfo, Self), Error = KvsError> {
+        self.send_request(Request::Ping)
+            .and_then(move |(resp, client)| match resp {
+                Some(Response::Pong {
+                    version,
+                    uptime_secs,
+                }) => Ok((
+                    PongInfo {
+                        version,
+                        uptime_secs,
+                    },
+                    client,
+                )),
+                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+                None => Err(KvsError::StringError("No response received".to_owned())),
+            })
+    }
+
+    /// 在一次往返中发送一组请求，返回与之一一对应的响应。
+    ///
+    /// 某个请求失败时，对应位置是 `Response::Err`，其余请求照常执行。
+    pub fn batch(
+        self,
+        reqs: Vec<Request>,
+    ) -> impl Future<Item = (Vec<Response>, Self), Error = KvsError> {
+        self.send_request(Request::Batch(reqs))
+            .and_then(move |(resp, client)| match resp {
+                Some(Response::Batch(resps)) => Ok((resps, client)),
+                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+                None => Err(KvsError::StringError("No response received".to_owned())),
+            })
+    }
+
+    /// 在一次往返中设置多个键值对。
+    ///
+    /// 所有键值对都会被尝试设置，若有失败则返回第一个错误。
+    pub fn set_many(
+        self,
+        pairs: Vec<(String, String)>,
+    ) -> impl Future<Item = Self, Error = KvsError> {
+        let reqs = pairs
+            .into_iter()
+            .map(|(key, value)| Request::Set { key, value })
+            .collect();
+        self.batch(reqs).and_then(|(resps, client)| {
+            for resp in resps {
+                match resp {
+                    Response::Set => {}
+                    Response::Err(msg) => return Err(KvsError::StringError(msg)),
+                    _ => return Err(KvsError::StringError("Invalid response".to_owned())),
+                }
+            }
+            Ok(client)
+        })
+    }
+
+    /// 订阅前缀为 `prefix` 的键的变更，返回变更的流。
+    ///
+    /// 返回的 Future 在服务器确认订阅之后完成，此后的变更都会出现在流中。
+    /// 订阅之后这个连接只用于接收变更，所以会消耗客户端。
+    pub fn watch(
+        self,
+        prefix: String,
+    ) -> impl Future<Item = impl Stream<Item = Change, Error = KvsError>, Error = KvsError> {
+        self.send_request(Request::Watch { prefix })
+            .and_then(move |(resp, client)| match resp {
+                Some(Response::Watching) => {
+                    Ok(client
+                        .read_json
+                        .map_err(KvsError::from)
+                        .and_then(|resp| match resp {
+                            Response::Changed { key, value } => Ok(Change { key, value }),
+                            Response::Err(msg) => Err(KvsError::StringError(msg)),
+                            _ => Err(KvsError::StringError("Invalid response".to_owned())),
+                        }))
+                }
+                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+                None => Err(KvsError::StringError("No response received".to_owned())),
+            })
+    }
+
+    /// 内部方法：读取 Get 响应余下的分片，拼接到第一个分片 `value` 之后。
+    fn read_chunks(
+        self,
+        value: String,
+        last: bool,
+    ) -> impl Future<Item = (Option<String>, Self), Error = KvsError> {
+        let write_json = self.write_json;
+        future::loop_fn(
+            (self.read_json, value, last, 0),
+            move |(read_json, mut value, last, seq)| -> ChunkRead {
+                if last {
+                    return Box::new(future::ok(Loop::Break((read_json, value))));
+                }
+                Box::new(
+                    read_json
+                        .into_future()
+                        .map_err(|(err, _)| KvsError::from(err))
+                        .and_then(move |(resp, read_json)| match resp {
+                            Some(Response::GetChunk {
+                                seq: next,
+                                data,
+                                last,
+                            }) if next == seq + 1 => {
+                                value.push_str(&data);
+                                Ok(Loop::Continue((read_json, value, last, next)))
+                            }
+                            Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+                            Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+                            None => Err(KvsError::StringError("No response received".to_owned())),
+                        }),
+                )
+            },
+        )
+        .map(move |(read_json, value)| {
+            let client = KvsClient {
+                read_json,
+                write_json,
+            };
+            (Some(value), client)
+        })
+    }
+
     /// 内部方法：发送请求并异步等待响应。
     fn send_request(
         self,
@@ -94,3 +274,33 @@ impl KvsClient {
             .map_err(|e| e.into())
     }
 }
+
+// `connect_retry` 中一次尝试的结果：连接成功，或者等待之后进行下一次尝试
+type ConnectAttempt =
+    Box<dyn Future<Item = Loop<KvsClient, (u32, Duration)>, Error = KvsError> + Send>;
+
+// `get` 中收到第一个响应之后的处理
+type GetResult = Box<dyn Future<Item = (Option<String>, KvsClient), Error = KvsError> + Send>;
+
+// `read_chunks` 中读取一个分片的结果：值已完整，或者继续读取下一个分片
+type ChunkRead = Box<
+    dyn Future<Item = Loop<(JsonReader, String), (JsonReader, String, bool, u64)>, Error = KvsError>
+        + Send,
+>;
+
+// 用于读取并解析 JSON 响应的流
+type JsonReader = ReadJson<FramedRead<ReadHalf<TcpStream>, LengthDelimitedCodec>, Response>;
+
+// 服务器尚未启动或正在重启时连接会遇到的错误，稍后重试可能成功
+fn is_connect_retryable(err: &KvsError) -> bool {
+    match err {
+        KvsError::Io(e) => matches!(
+            e.kind(),
+            io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+        ),
+        KvsError::Timeout => true,
+        _ => false,
+    }
+}
@@ -1,14 +1,55 @@
 use serde::{Deserialize, Serialize};
 
+// 值超过这个字节数时，`Request::Get` 的响应拆成 `Response::GetChunk` 分片发送，
+// `Request::Batch` 中 Get 的值合计也不能超过它。它远小于帧的默认长度上限 8 MiB，
+// 所以再大的值也不会产生超长的帧。
+pub(crate) const GET_CHUNK_SIZE: usize = 1024 * 1024;
+
 /// 客户端请求枚举，定义了支持的操作
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
     /// 获取键对应的值
-    Get { key: String },
+    Get {
+        /// 要读取的键
+        key: String,
+    },
     /// 设置键值对
-    Set { key: String, value: String },
+    Set {
+        /// 要设置的键
+        key: String,
+        /// 要写入的值
+        value: String,
+    },
     /// 移除键
-    Remove { key: String },
+    Remove {
+        /// 要移除的键
+        key: String,
+    },
+    /// 带有请求 ID 的请求，服务器会在 `Response::Tagged` 中原样返回该 ID
+    ///
+    /// 带 ID 的请求并发执行，响应按完成的先后发回，可能与请求的顺序不同。
+    Tagged {
+        /// 请求 ID
+        id: u64,
+        /// 被包装的请求
+        req: Box<Request>,
+    },
+    /// 存活探测，服务器不访问存储引擎直接回复 `Response::Pong`
+    Ping,
+    /// 一组请求，服务器按顺序执行并在 `Response::Batch` 中逐个返回结果
+    ///
+    /// 其中某个请求失败只会使对应的结果为 `Response::Err`，不影响其余请求。
+    /// 批量响应中的 Get 不分片，值合计超过 1 MiB 时，放不下的 Get 的结果也是 `Response::Err`。
+    Batch(Vec<Request>),
+    /// 订阅前缀为 `prefix` 的键的变更
+    ///
+    /// 服务器先回复 `Response::Watching`，之后每当匹配的键被设置或移除时推送
+    /// `Response::Changed`。订阅之后这个连接不再处理其他请求，它也不能放在
+    /// `Request::Tagged` 或 `Request::Batch` 中。
+    Watch {
+        /// 要订阅的键前缀，空字符串订阅所有的键
+        prefix: String,
+    },
 }
 
 /// 服务器响应枚举，定义了操作的处理结果
@@ -22,4 +63,60 @@ pub enum Response {
     Remove,
     /// 发生错误时的响应，包含错误信息字符串
     Err(String),
+    /// 对 `Request::Tagged` 的响应，带有对应请求的 ID
+    Tagged {
+        /// 对应请求的 ID
+        id: u64,
+        /// 被包装的响应
+        resp: Box<Response>,
+    },
+    /// Ping 的响应，包含服务器的版本号和已运行的秒数
+    Pong {
+        /// 服务器 crate 的版本号
+        version: String,
+        /// 服务器已运行的时间，单位为秒
+        uptime_secs: u64,
+    },
+    /// 对 `Request::Batch` 的响应，与请求一一对应
+    Batch(Vec<Response>),
+    /// 对 `Request::Watch` 的确认，此后的变更都会被推送
+    Watching,
+    /// 订阅的键的一次变更
+    Changed {
+        /// 被修改的键
+        key: String,
+        /// 键的新值，键被移除时为 `None`
+        value: Option<String>,
+    },
+    /// 大值的 Get 响应中的一个分片
+    ///
+    /// 值超过 1 MiB 时，服务器不回复 `Response::Get`，而是把值按顺序拆成若干分片发送，
+    /// 客户端把它们拼接起来。放在 `Request::Tagged` 中的 Get 同样分片，每个分片都包在
+    /// 带相同 ID 的 `Response::Tagged` 中；`Request::Batch` 中的 Get 不分片。
+    GetChunk {
+        /// 分片的序号，从 0 开始
+        seq: u64,
+        /// 值的一段
+        data: String,
+        /// 是否为最后一个分片
+        last: bool,
+    },
+}
+
+/// 键的一次变更，由 `KvsEngine::watch` 和 `KvsClient::watch` 产生
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    /// 被修改的键
+    pub key: String,
+    /// 键的新值，键被移除时为 `None`
+    pub value: Option<String>,
+}
+
+/// 服务器对 `KvsClient::ping` 的回复
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PongInfo {
+    /// 服务器 crate 的版本号
+    pub version: String,
+    /// 服务器已运行的时间，单位为秒
+    pub uptime_secs: u64,
 }
@@ -15,7 +15,7 @@ use serde_json::Deserializer;
 use tokio::prelude::*;
 use tokio::sync::oneshot;
 
-use super::KvsEngine;
+use super::{KvsEngine, Subscription, Watchers};
 use crate::thread_pool::ThreadPool;
 use crate::{KvsError, Result};
 
@@ -54,14 +54,35 @@ pub struct KvStore<P: ThreadPool> {
     thread_pool: P,
     // 读线程池，包含多个可重用的读取器
     reader_pool: Arc<ArrayQueue<KvStoreReader>>,
+    // 读取器池暂时为空时，由它克隆出新的读取器
+    spare_reader: KvStoreReader,
+    // 变更的订阅者，由 writer 在每次修改后通知
+    watchers: Watchers,
 }
 
 impl<P: ThreadPool> KvStore<P> {
     /// 在给定路径打开一个 `KvStore`。
     ///
     /// 如果目录不存在则创建。
-    /// `concurrency` 指定同时可以进行读取操作的最大线程数。
+    /// `concurrency` 指定线程池的线程数，读取器池的大小与之相同。
     pub fn open(path: impl Into<PathBuf>, concurrency: u32) -> Result<Self> {
+        KvStore::open_with_reader_pool(path, concurrency, concurrency)
+    }
+
+    /// 与 `open` 相同，但读取器池的大小由 `reader_pool_size` 单独指定。
+    ///
+    /// 读取器池只是缓存打开的文件句柄：池为空时，读取操作会临时构造一个新的读取器，
+    /// 池已满时多出的读取器会被丢弃，所以池的大小不会限制读取的并发数。
+    pub fn open_with_reader_pool(
+        path: impl Into<PathBuf>,
+        concurrency: u32,
+        reader_pool_size: u32,
+    ) -> Result<Self> {
+        if reader_pool_size == 0 {
+            return Err(KvsError::StringError(
+                "The reader pool needs at least one reader".to_owned(),
+            ));
+        }
         let path = Arc::new(path.into());
         fs::create_dir_all(&*path)?;
 
@@ -91,6 +112,7 @@ impl<P: ThreadPool> KvStore<P> {
             readers: RefCell::new(BTreeMap::new()),
         };
 
+        let watchers = Watchers::default();
         let writer = KvStoreWriter {
             reader: reader.clone(),
             writer,
@@ -98,15 +120,15 @@ impl<P: ThreadPool> KvStore<P> {
             uncompacted,
             path: Arc::clone(&path),
             index: Arc::clone(&index),
+            watchers: watchers.clone(),
         };
 
         let thread_pool = P::new(concurrency)?;
-        let reader_pool = Arc::new(ArrayQueue::new(concurrency as usize));
+        let reader_pool = Arc::new(ArrayQueue::new(reader_pool_size as usize));
         // 将初始化好的读取器放入池中
-        for _ in 1..concurrency {
+        for _ in 0..reader_pool_size {
             reader_pool.push(reader.clone()).unwrap();
         }
-        reader_pool.push(reader).unwrap();
 
         Ok(KvStore {
             path,
@@ -114,15 +136,37 @@ impl<P: ThreadPool> KvStore<P> {
             writer: Arc::new(Mutex::new(writer)),
             thread_pool,
             reader_pool,
+            spare_reader: reader,
+            watchers,
         })
     }
+
+    /// 返回读取器池中空闲的读取器数量。
+    pub fn idle_readers(&self) -> usize {
+        self.reader_pool.len()
+    }
+
+    /// 立即压缩日志，而不必等待过期数据超过压缩阈值。
+    ///
+    /// 压缩期间读取操作不受影响。如果没有过期数据则什么也不做。
+    pub fn compact(&self) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        if writer.uncompacted == 0 {
+            return Ok(());
+        }
+        writer.compact()
+    }
 }
 
 impl<P: ThreadPool> KvsEngine for KvStore<P> {
     /// 设置键的值。
     ///
     /// 此操作是异步的，逻辑被提交到 thread_pool 执行。
-    fn set(&self, key: String, value: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+    fn set(
+        &self,
+        key: String,
+        value: String,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
         let writer = self.writer.clone();
         let (tx, rx) = oneshot::channel();
         self.thread_pool.spawn(move || {
@@ -141,28 +185,28 @@ impl<P: ThreadPool> KvsEngine for KvStore<P> {
     /// 获取给定键的值。
     fn get(&self, key: String) -> Box<dyn Future<Item = Option<String>, Error = KvsError> + Send> {
         let reader_pool = self.reader_pool.clone();
+        let spare_reader = self.spare_reader.clone();
         let index = self.index.clone();
         let (tx, rx) = oneshot::channel();
         self.thread_pool.spawn(move || {
-            let res = (|| {
-                // 先在内存索引中查找位置
-                if let Some(cmd_pos) = index.get(&key) {
-                    // 从读取器池中获取一个可用的读取器
-                    let reader = reader_pool.pop().unwrap();
-                    let res = if let Command::Set { value, .. } =
-                        reader.read_command(*cmd_pos.value())?
-                    {
+            // 先在内存索引中查找位置
+            let res = if let Some(cmd_pos) = index.get(&key) {
+                // 从读取器池中获取一个可用的读取器，池为空时使用新的读取器
+                let reader = reader_pool.pop().unwrap_or(spare_reader);
+                // 读取出错时也不能提前返回，否则读取器不会被放回池中
+                let res = reader.read_command(*cmd_pos.value()).and_then(|cmd| {
+                    if let Command::Set { value, .. } = cmd {
                         Ok(Some(value))
                     } else {
                         Err(KvsError::UnexpectedCommandType)
-                    };
-                    // 用完后放回池中
-                    reader_pool.push(reader).unwrap();
-                    res
-                } else {
-                    Ok(None)
-                }
-            })();
+                    }
+                });
+                // 用完后放回池中，池已满时直接丢弃
+                let _ = reader_pool.push(reader);
+                res
+            } else {
+                Ok(None)
+            };
             if tx.send(res).is_err() {
                 error!("Receiving end is dropped");
             }
@@ -188,6 +232,11 @@ impl<P: ThreadPool> KvsEngine for KvStore<P> {
                 .flatten(),
         )
     }
+
+    /// 订阅前缀为 `prefix` 的键的变更。
+    fn watch(&self, prefix: String) -> Subscription {
+        self.watchers.subscribe(prefix)
+    }
 }
 
 /// 单线程读取器。
@@ -259,6 +308,7 @@ struct KvStoreWriter {
     uncompacted: u64,
     path: Arc<PathBuf>,
     index: Arc<SkipMap<String, CommandPos>>,
+    watchers: Watchers,
 }
 
 impl KvStoreWriter {
@@ -267,14 +317,16 @@ impl KvStoreWriter {
         let pos = self.writer.pos;
         serde_json::to_writer(&mut self.writer, &cmd)?;
         self.writer.flush()?;
-        if let Command::Set { key, .. } = cmd {
+        if let Command::Set { key, value } = cmd {
             if let Some(old_cmd) = self.index.get(&key) {
                 // 如果是覆盖写，记录旧数据为过期数据
                 self.uncompacted += old_cmd.value().len;
             }
-            // 更新索引
-            self.index
+            // 更新索引，之后再通知订阅者，这样订阅者收到变更后总能读到新值
+            let entry = self
+                .index
                 .insert(key, (self.current_gen, pos..self.writer.pos).into());
+            self.watchers.publish(entry.key(), Some(&value));
         }
 
         if self.uncompacted > COMPACTION_THRESHOLD {
@@ -294,6 +346,7 @@ impl KvStoreWriter {
                 self.uncompacted += old_cmd.value().len;
                 // remove 命令本身最终也会被压缩掉
                 self.uncompacted += self.writer.pos - pos;
+                self.watchers.publish(&key, None);
             }
 
             if self.uncompacted > COMPACTION_THRESHOLD {
@@ -317,20 +370,22 @@ impl KvStoreWriter {
         let mut compaction_writer = new_log_file(&self.path, compaction_gen)?;
 
         let mut new_pos = 0;
+        let mut moved = Vec::new();
         for entry in self.index.iter() {
             // 读取旧文件中的活跃数据并拷贝到新压缩文件中
             let len = self.reader.read_and(*entry.value(), |mut entry_reader| {
                 Ok(io::copy(&mut entry_reader, &mut compaction_writer)?)
             })?;
-            // 更新索引指向新文件的位置
-            self.index.insert(
-                entry.key().clone(),
-                (compaction_gen, new_pos..new_pos + len).into(),
-            );
+            moved.push((entry.key().clone(), new_pos..new_pos + len));
             new_pos += len;
         }
         compaction_writer.flush()?;
 
+        // 压缩文件刷新之后才更新索引指向新文件的位置，否则并发的读取可能读到不完整的命令
+        for (key, range) in moved {
+            self.index.insert(key, (compaction_gen, range).into());
+        }
+
         // 更新 safe_point，通知读取器可以安全清理旧句柄
         self.reader
             .safe_point
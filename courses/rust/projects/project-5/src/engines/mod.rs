@@ -1,11 +1,14 @@
 pub use self::kvs::KvStore;
 pub use self::sled::SledKvsEngine;
+pub use self::watch::Subscription;
+use self::watch::Watchers;
 use crate::KvsError;
 
 use tokio::prelude::Future;
 
 mod kvs;
 mod sled;
+mod watch;
 
 /// 键值存储引擎接口。
 /// 所有的引擎方法都返回一个 Future，允许异步处理。
@@ -25,4 +28,11 @@ pub trait KvsEngine: Clone + Send + 'static {
     ///
     /// 如果键不存在，返回 `KvsError::KeyNotFound`。
     fn remove(&self, key: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send>;
+
+    /// 订阅前缀为 `prefix` 的键的变更。
+    ///
+    /// 此后每当匹配的键被设置或移除，返回的流中都会收到一个 `Change`。
+    /// 流的缓冲区是有界的，接收方处理过慢、缓冲区已满时，新的变更会被丢弃。
+    /// 丢弃返回的流即取消订阅。
+    fn watch(&self, prefix: String) -> Subscription;
 }
@@ -1,3 +1,4 @@
+use super::{Subscription, Watchers};
 use crate::thread_pool::ThreadPool;
 use crate::{KvsEngine, KvsError, Result};
 use sled::Db;
@@ -9,6 +10,8 @@ use tokio::sync::oneshot;
 pub struct SledKvsEngine<P: ThreadPool> {
     pool: P,
     db: Db,
+    // 变更的订阅者，在每次修改成功后通知
+    watchers: Watchers,
 }
 
 impl<P: ThreadPool> SledKvsEngine<P> {
@@ -17,7 +20,11 @@ impl<P: ThreadPool> SledKvsEngine<P> {
     /// 操作在给定的线程池中运行。`concurrency` 指定线程池中的线程数。
     pub fn new(db: Db, concurrency: u32) -> Result<Self> {
         let pool = P::new(concurrency)?;
-        Ok(SledKvsEngine { pool, db })
+        Ok(SledKvsEngine {
+            pool,
+            db,
+            watchers: Watchers::default(),
+        })
     }
 }
 
@@ -26,12 +33,13 @@ impl<P: ThreadPool> KvsEngine for SledKvsEngine<P> {
     /// 逻辑提交给线程池执行，因为 sled 的操作是阻塞的。
     fn set(&self, key: String, value: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
         let db = self.db.clone();
+        let watchers = self.watchers.clone();
         let (tx, rx) = oneshot::channel();
         self.pool.spawn(move || {
             let res = db
-                .set(key, value.into_bytes())
+                .set(key.as_str(), value.clone().into_bytes())
                 .and_then(|_| db.flush())
-                .map(|_| ())
+                .map(|_| watchers.publish(&key, Some(&value)))
                 .map_err(KvsError::from);
             if tx.send(res).is_err() {
                 error!("Receiving end is dropped");
@@ -68,11 +76,13 @@ impl<P: ThreadPool> KvsEngine for SledKvsEngine<P> {
     /// 执行异步 remove 操作。
     fn remove(&self, key: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
         let db = self.db.clone();
+        let watchers = self.watchers.clone();
         let (tx, rx) = oneshot::channel();
         self.pool.spawn(move || {
             let res = (|| {
-                db.del(key)?.ok_or(KvsError::KeyNotFound)?;
+                db.del(&key)?.ok_or(KvsError::KeyNotFound)?;
                 db.flush()?;
+                watchers.publish(&key, None);
                 Ok(())
             })();
             if tx.send(res).is_err() {
@@ -84,4 +94,9 @@ impl<P: ThreadPool> KvsEngine for SledKvsEngine<P> {
                 .flatten(),
         )
     }
+
+    /// 订阅前缀为 `prefix` 的键的变更。
+    fn watch(&self, prefix: String) -> Subscription {
+        self.watchers.subscribe(prefix)
+    }
 }
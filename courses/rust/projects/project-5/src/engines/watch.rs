@@ -0,0 +1,108 @@
+use crate::common::Change;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::prelude::*;
+use tokio::sync::mpsc;
+
+// 每个订阅者最多缓冲的变更数，缓冲区满时新的变更会被丢弃
+const WATCH_BUFFER: usize = 1024;
+
+/// 向订阅者广播键的变更。
+///
+/// 每个订阅者有自己的有界通道，发布时只尝试发送，不会阻塞写入。
+#[derive(Clone, Default)]
+pub struct Watchers {
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+}
+
+struct Subscriber {
+    prefix: String,
+    sender: mpsc::Sender<Change>,
+    // `Subscription` 被丢弃时设置
+    closed: Arc<AtomicBool>,
+}
+
+/// `KvsEngine::watch` 返回的变更流。
+///
+/// 丢弃它即取消订阅，下一次发布变更时订阅者会被移除，无论键是否与前缀匹配。
+pub struct Subscription {
+    receiver: mpsc::Receiver<Change>,
+    closed: Arc<AtomicBool>,
+}
+
+impl Stream for Subscription {
+    type Item = Change;
+    type Error = mpsc::error::RecvError;
+
+    fn poll(&mut self) -> Poll<Option<Change>, Self::Error> {
+        self.receiver.poll()
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Watchers {
+    /// 添加一个订阅前缀为 `prefix` 的键的订阅者，返回接收变更的流。
+    pub fn subscribe(&self, prefix: String) -> Subscription {
+        let (sender, receiver) = mpsc::channel(WATCH_BUFFER);
+        let closed = Arc::new(AtomicBool::new(false));
+        self.subscribers.lock().unwrap().push(Subscriber {
+            prefix,
+            sender,
+            closed: Arc::clone(&closed),
+        });
+        Subscription { receiver, closed }
+    }
+
+    /// 向前缀匹配的订阅者发布 `key` 的变更，`value` 为 `None` 表示键被移除。
+    ///
+    /// 已取消订阅的订阅者会被移除，它们的前缀是否匹配都一样。
+    pub fn publish(&self, key: &str, value: Option<&str>) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain_mut(|subscriber| {
+            if subscriber.closed.load(Ordering::SeqCst) {
+                return false;
+            }
+            if !key.starts_with(&subscriber.prefix) {
+                return true;
+            }
+            let change = Change {
+                key: key.to_owned(),
+                value: value.map(str::to_owned),
+            };
+            match subscriber.sender.try_send(change) {
+                Ok(()) => true,
+                // 订阅者处理过慢时丢弃这次变更，而不是让写入等待它
+                Err(ref e) if e.is_full() => {
+                    warn!("Change of {} dropped for a slow subscriber", key);
+                    true
+                }
+                Err(_) => false,
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Watchers;
+
+    // 丢弃的订阅者在下一次发布时被移除，即使发布的键与它的前缀不匹配
+    #[test]
+    fn dropped_subscription_is_removed() {
+        let watchers = Watchers::default();
+        let kept = watchers.subscribe("a".to_owned());
+        let dropped = watchers.subscribe("b".to_owned());
+        assert_eq!(watchers.subscribers.lock().unwrap().len(), 2);
+
+        drop(dropped);
+        watchers.publish("c", Some("value"));
+        assert_eq!(watchers.subscribers.lock().unwrap().len(), 1);
+        assert_eq!(watchers.subscribers.lock().unwrap()[0].prefix, "a");
+        drop(kept);
+    }
+}
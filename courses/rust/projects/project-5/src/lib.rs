@@ -6,13 +6,16 @@ extern crate log;
 
 // 重新导出核心组件，方便外部使用
 pub use client::KvsClient;
-pub use engines::{KvStore, KvsEngine, SledKvsEngine};
+pub use common::{Change, PongInfo, Request, Response};
+pub use engines::{KvStore, KvsEngine, SledKvsEngine, Subscription};
 pub use error::{KvsError, Result};
+pub use multiplex_client::KvsMultiplexClient;
 pub use server::KvsServer;
 
 mod client;
 mod common;
 mod engines;
 mod error;
+mod multiplex_client;
 mod server;
 pub mod thread_pool;
@@ -0,0 +1,179 @@
+use crate::common::{Request, Response};
+use crate::KvsError;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+use tokio::sync::{mpsc, oneshot};
+use tokio_serde_json::{ReadJson, WriteJson};
+
+// 等待响应的请求，按请求 ID 索引。连接断开后变为 `None`
+type Pending = Arc<Mutex<Option<HashMap<u64, oneshot::Sender<Response>>>>>;
+
+/// 在单个连接上复用多个并发请求的键值存储客户端。
+///
+/// 每个请求都带有一个单调递增的 ID，服务器在响应中原样返回该 ID，
+/// 客户端据此把响应交给对应的请求。因此同一连接上可以同时存在任意多个未完成的请求，
+/// 而不必像 `KvsClient` 那样一次只能进行一次请求-响应。
+///
+/// 该客户端可以被克隆，所有克隆共享同一个连接。
+#[derive(Clone)]
+pub struct KvsMultiplexClient {
+    // 下一个请求使用的 ID
+    next_id: Arc<AtomicU64>,
+    pending: Pending,
+    // 发送给写任务的请求队列
+    tx: mpsc::UnboundedSender<Request>,
+}
+
+impl KvsMultiplexClient {
+    /// 连接到指定的地址以访问 `KvsServer`。
+    ///
+    /// 读写连接的后台任务会被 spawn 到当前的 tokio 运行时上，
+    /// 因此返回的 Future 必须在 tokio 运行时中执行。
+    pub fn connect(addr: SocketAddr) -> impl Future<Item = Self, Error = KvsError> {
+        TcpStream::connect(&addr)
+            .map(|tcp| {
+                let (read_half, write_half) = tcp.split();
+                let read_json: ReadJson<_, Response> =
+                    ReadJson::new(FramedRead::new(read_half, LengthDelimitedCodec::new()));
+                let write_json: WriteJson<_, Request> =
+                    WriteJson::new(FramedWrite::new(write_half, LengthDelimitedCodec::new()));
+                let pending: Pending = Arc::new(Mutex::new(Some(HashMap::new())));
+                let (tx, rx) = mpsc::unbounded_channel();
+
+                // 写任务：按提交顺序发送所有请求
+                tokio::spawn(
+                    write_json
+                        .sink_map_err(KvsError::from)
+                        .send_all(rx.map_err(|e| KvsError::StringError(format!("{}", e))))
+                        .map(|_| ())
+                        .map_err(|e| error!("Error on sending requests: {}", e)),
+                );
+
+                // 读任务：根据 ID 把响应交给等待中的请求
+                let dispatch = pending.clone();
+                let closed = pending.clone();
+                // 尚未收全的大值，按请求 ID 索引，记录下一个分片的序号和已拼接的部分
+                let mut chunks: HashMap<u64, (u64, String)> = HashMap::new();
+                tokio::spawn(
+                    read_json
+                        .map_err(KvsError::from)
+                        .for_each(move |resp| {
+                            match resp {
+                                Response::Tagged { id, resp } => {
+                                    let resp = match *resp {
+                                        // 收到最后一个分片后才交给请求
+                                        Response::GetChunk { seq, data, last } => {
+                                            let (next, mut value) =
+                                                chunks.remove(&id).unwrap_or_default();
+                                            if seq != next {
+                                                Response::Err("Invalid response".to_owned())
+                                            } else {
+                                                value.push_str(&data);
+                                                if !last {
+                                                    chunks.insert(id, (next + 1, value));
+                                                    return Ok(());
+                                                }
+                                                Response::Get(Some(value))
+                                            }
+                                        }
+                                        resp => resp,
+                                    };
+                                    let sender = dispatch
+                                        .lock()
+                                        .unwrap()
+                                        .as_mut()
+                                        .and_then(|pending| pending.remove(&id));
+                                    match sender {
+                                        Some(sender) => {
+                                            if sender.send(resp).is_err() {
+                                                debug!("Request {} is no longer waited", id);
+                                            }
+                                        }
+                                        None => warn!("Response to unknown request {}", id),
+                                    }
+                                }
+                                resp => warn!("Unexpected untagged response: {:?}", resp),
+                            }
+                            Ok(())
+                        })
+                        .map_err(|e| error!("Error on receiving responses: {}", e))
+                        .then(move |_| {
+                            // 连接已断开，丢弃所有等待中的请求，使它们以错误结束
+                            closed.lock().unwrap().take();
+                            Ok(())
+                        }),
+                );
+
+                KvsMultiplexClient {
+                    next_id: Arc::new(AtomicU64::new(0)),
+                    pending,
+                    tx,
+                }
+            })
+            .map_err(|e| e.into())
+    }
+
+    /// 从服务器获取给定键的值。
+    ///
+    /// 大值由服务器分片发送，在这里拼接完整后返回。
+    pub fn get(&self, key: String) -> impl Future<Item = Option<String>, Error = KvsError> {
+        self.send_request(Request::Get { key })
+            .and_then(|resp| match resp {
+                Response::Get(value) => Ok(value),
+                Response::Err(msg) => Err(KvsError::StringError(msg)),
+                _ => Err(KvsError::StringError("Invalid response".to_owned())),
+            })
+    }
+
+    /// 在服务器中设置字符串键的值。
+    pub fn set(&self, key: String, value: String) -> impl Future<Item = (), Error = KvsError> {
+        self.send_request(Request::Set { key, value })
+            .and_then(|resp| match resp {
+                Response::Set => Ok(()),
+                Response::Err(msg) => Err(KvsError::StringError(msg)),
+                _ => Err(KvsError::StringError("Invalid response".to_owned())),
+            })
+    }
+
+    /// 移除服务器中的字符串键。
+    pub fn remove(&self, key: String) -> impl Future<Item = (), Error = KvsError> {
+        self.send_request(Request::Remove { key })
+            .and_then(|resp| match resp {
+                Response::Remove => Ok(()),
+                Response::Err(msg) => Err(KvsError::StringError(msg)),
+                _ => Err(KvsError::StringError("Invalid response".to_owned())),
+            })
+    }
+
+    /// 内部方法：为请求分配 ID 并发送，返回的 Future 在收到对应的响应时完成。
+    fn send_request(&self, req: Request) -> impl Future<Item = Response, Error = KvsError> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let (resp_tx, resp_rx) = oneshot::channel();
+        let sent = match self.pending.lock().unwrap().as_mut() {
+            Some(pending) => {
+                pending.insert(id, resp_tx);
+                let req = Request::Tagged {
+                    id,
+                    req: Box::new(req),
+                };
+                if self.tx.clone().try_send(req).is_ok() {
+                    Ok(())
+                } else {
+                    pending.remove(&id);
+                    Err(connection_closed())
+                }
+            }
+            None => Err(connection_closed()),
+        };
+        future::result(sent).and_then(|_| resp_rx.map_err(|_| connection_closed()))
+    }
+}
+
+fn connection_closed() -> KvsError {
+    KvsError::StringError("Connection closed".to_owned())
+}
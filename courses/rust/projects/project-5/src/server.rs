@@ -1,9 +1,11 @@
-use crate::common::{Request, Response};
+use crate::common::{Request, Response, GET_CHUNK_SIZE};
 use crate::{KvsEngine, KvsError, Result};
 use std::net::SocketAddr;
+use std::time::Instant;
 use tokio::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::prelude::*;
+use tokio::sync::mpsc;
 use tokio_serde_json::{ReadJson, WriteJson};
 
 /// 键值存储服务器，使用指定的存储引擎处理请求
@@ -21,14 +23,21 @@ impl<E: KvsEngine> KvsServer<E> {
     pub fn run(self, addr: SocketAddr) -> Result<()> {
         // 绑定监听地址
         let listener = TcpListener::bind(&addr)?;
+        // 记录启动时间，用于在 Ping 中报告运行时长
+        let started = Instant::now();
         // 创建服务器 Future，处理传入的 TCP 连接
         let server = listener
             .incoming() // 获取 TCP 连接流
             .map_err(|e| error!("IO error: {}", e))
             .for_each(move |tcp| {
-                // 为每个连接克隆一份引擎引用，并在异步任务中处理
+                // 为每个连接克隆一份引擎引用，并在异步任务中处理，
+                // 一个未断开的连接不会挡住之后的连接
                 let engine = self.engine.clone();
-                serve(engine, tcp).map_err(|e| error!("Error on serving client: {}", e))
+                tokio::spawn(
+                    serve(engine, tcp, started)
+                        .map_err(|e| error!("Error on serving client: {}", e)),
+                );
+                Ok(())
             });
         // 启动 tokio 运行时驱动服务器运行
         tokio::run(server);
@@ -37,28 +46,70 @@ impl<E: KvsEngine> KvsServer<E> {
 }
 
 /// 内部函数：处理单个客户端连接。
-fn serve<E: KvsEngine>(engine: E, tcp: TcpStream) -> impl Future<Item = (), Error = KvsError> {
+fn serve<E: KvsEngine>(
+    engine: E,
+    tcp: TcpStream,
+    started: Instant,
+) -> impl Future<Item = (), Error = KvsError> {
     // 拆分 TCP 流以便独立读写
     let (read_half, write_half) = tcp.split();
     // 设置读 JSON 的适配层
     let read_json = ReadJson::new(FramedRead::new(read_half, LengthDelimitedCodec::new()));
-    
+
+    // 带 ID 的请求各自在单独的任务中并发执行，完成后立即经这个通道发回响应，
+    // 一个慢请求不会挡住之后的请求。其余请求仍按顺序逐个响应
+    let (tagged_tx, tagged_rx) = mpsc::unbounded_channel();
+    let mut tagged_tx = Some(tagged_tx);
+
     // 创建响应流：读取请求 -> 使用引擎处理 -> 映射为响应
+    // 每个请求对应一个响应流，普通请求只有一个响应，订阅则持续推送变更
     let resp_stream = read_json
         .map_err(KvsError::from)
-        .and_then(
-            move |req| -> Box<dyn Future<Item = Response, Error = KvsError> + Send> {
-                match req {
-                    Request::Get { key } => Box::new(engine.get(key).map(Response::Get)),
-                    Request::Set { key, value } => {
-                        Box::new(engine.set(key, value).map(|_| Response::Set))
-                    }
-                    Request::Remove { key } => {
-                        Box::new(engine.remove(key).map(|_| Response::Remove))
-                    }
+        .map(Some)
+        // 请求读完后以 `None` 标记，释放发送端，所有带 ID 的请求完成后通道随之结束
+        .chain(stream::once(Ok(None)))
+        .map(move |req| -> ResponseStream {
+            let req = match req {
+                Some(req) => req,
+                None => {
+                    tagged_tx.take();
+                    return Box::new(stream::empty::<Response, KvsError>());
+                }
+            };
+            match req {
+                // 订阅流不会结束，所以之后这个连接不再处理其他请求
+                Request::Watch { prefix } => {
+                    let changes = engine
+                        .watch(prefix)
+                        .map(|change| Response::Changed {
+                            key: change.key,
+                            value: change.value,
+                        })
+                        .map_err(|e| KvsError::StringError(format!("{}", e)));
+                    Box::new(stream::once(Ok(Response::Watching)).chain(changes))
                 }
-            },
-        )
+                Request::Tagged { .. } => {
+                    // 每个任务持有一个发送端的克隆，连接断开后丢弃它的响应。
+                    // 同一个请求的分片依次经过通道，顺序保持不变
+                    let mut tx = tagged_tx
+                        .clone()
+                        .expect("request after the end of the stream");
+                    tokio::spawn(
+                        respond(&engine, req, started)
+                            .map_err(|e| error!("Error on a tagged request: {}", e))
+                            .for_each(move |resp| {
+                                tx.try_send(resp).map_err(|_| {
+                                    debug!("Connection closed before a tagged response was sent")
+                                })
+                            }),
+                    );
+                    Box::new(stream::empty::<Response, KvsError>())
+                }
+                req => respond(&engine, req, started),
+            }
+        })
+        .flatten()
+        .select(tagged_rx.map_err(|e| KvsError::StringError(format!("{}", e))))
         // 处理可能发生的错误，并将其包装在 Response::Err 中返回给客户端，而不是直接终止连接
         .then(|resp| -> Result<Response> {
             match resp {
@@ -75,3 +126,130 @@ fn serve<E: KvsEngine>(engine: E, tcp: TcpStream) -> impl Future<Item = (), Erro
         .send_all(resp_stream)
         .map(|_| ())
 }
+
+// 一个请求的所有响应
+type ResponseStream = Box<dyn Stream<Item = Response, Error = KvsError> + Send>;
+
+/// 内部函数：处理单个请求，得到它的所有响应。
+///
+/// Get 的大值拆成分片发送，双方都不必处理整个值大小的帧。带 ID 的 Get 也同样拆分，
+/// 每个分片都包在带相同 ID 的 `Response::Tagged` 中。
+fn respond<E: KvsEngine>(engine: &E, req: Request, started: Instant) -> ResponseStream {
+    match req {
+        Request::Get { key } => Box::new(
+            engine
+                .get(key)
+                .map(|value| -> ResponseStream {
+                    match value {
+                        Some(value) if value.len() > GET_CHUNK_SIZE => Box::new(get_chunks(value)),
+                        value => Box::new(stream::once(Ok(Response::Get(value)))),
+                    }
+                })
+                .flatten_stream(),
+        ),
+        // 内部请求的错误也连同请求 ID 一起返回
+        Request::Tagged { id, req } => Box::new(respond(engine, *req, started).then(move |resp| {
+            let resp = resp.unwrap_or_else(|e| Response::Err(format!("{}", e)));
+            Ok(Response::Tagged {
+                id,
+                resp: Box::new(resp),
+            })
+        })),
+        req => Box::new(process(engine, req, started).into_stream()),
+    }
+}
+
+/// 内部函数：把值拆成 `Response::GetChunk` 分片，每片不超过 `GET_CHUNK_SIZE` 字节。
+///
+/// 分片在发送时才逐个生成，并且总在字符边界处切分。
+fn get_chunks(value: String) -> impl Stream<Item = Response, Error = KvsError> {
+    stream::unfold((0, 0), move |(start, seq)| {
+        if start == value.len() {
+            return None;
+        }
+        let mut end = (start + GET_CHUNK_SIZE).min(value.len());
+        while !value.is_char_boundary(end) {
+            end -= 1;
+        }
+        let chunk = Response::GetChunk {
+            seq,
+            data: value[start..end].to_owned(),
+            last: end == value.len(),
+        };
+        Some(future::ok((chunk, (end, seq + 1))))
+    })
+}
+
+/// 内部函数：使用引擎处理单个请求，得到它唯一的响应。
+///
+/// 响应整体放在一帧里，Get 的值不拆分。
+fn process<E: KvsEngine>(
+    engine: &E,
+    req: Request,
+    started: Instant,
+) -> Box<dyn Future<Item = Response, Error = KvsError> + Send> {
+    match req {
+        Request::Get { key } => Box::new(engine.get(key).map(Response::Get)),
+        Request::Set { key, value } => Box::new(engine.set(key, value).map(|_| Response::Set)),
+        Request::Remove { key } => Box::new(engine.remove(key).map(|_| Response::Remove)),
+        // 处理内部请求，并把结果（包括错误）连同请求 ID 一起返回
+        Request::Tagged { id, req } => Box::new(process(engine, *req, started).then(move |resp| {
+            let resp = resp.unwrap_or_else(|e| Response::Err(format!("{}", e)));
+            Ok(Response::Tagged {
+                id,
+                resp: Box::new(resp),
+            })
+        })),
+        // 不经过存储引擎，保证探测足够轻量
+        Request::Ping => Box::new(future::ok(Response::Pong {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            uptime_secs: started.elapsed().as_secs(),
+        })),
+        // 订阅会占用整个连接，只能单独发送
+        Request::Watch { .. } => Box::new(future::err(KvsError::StringError(
+            "Watch must be sent on its own".to_owned(),
+        ))),
+        // 按顺序逐个执行，单个请求的错误只体现在它自己的结果里
+        Request::Batch(reqs) => {
+            let engine = engine.clone();
+            Box::new(
+                stream::iter_ok(reqs)
+                    .and_then(move |req| {
+                        process(&engine, req, started).then(|resp| {
+                            Ok(resp.unwrap_or_else(|e| Response::Err(format!("{}", e))))
+                        })
+                    })
+                    .collect()
+                    .map(|resps: Vec<Response>| {
+                        // 整个批量响应只有一帧，所以 Get 的值合计不能超过 `GET_CHUNK_SIZE`，
+                        // 放不下的结果以错误代替，这些键需要单独 Get
+                        let mut total = 0;
+                        let resps = resps
+                            .into_iter()
+                            .map(|resp| {
+                                let len = values_len(&resp);
+                                if total + len > GET_CHUNK_SIZE {
+                                    return Response::Err(
+                                        "Value too large for a batch, get it on its own".to_owned(),
+                                    );
+                                }
+                                total += len;
+                                resp
+                            })
+                            .collect();
+                        Response::Batch(resps)
+                    }),
+            )
+        }
+    }
+}
+
+// 响应中 Get 返回的值的总字节数
+fn values_len(resp: &Response) -> usize {
+    match resp {
+        Response::Get(Some(value)) => value.len(),
+        Response::Tagged { resp, .. } => values_len(resp),
+        Response::Batch(resps) => resps.iter().map(values_len).sum(),
+        _ => 0,
+    }
+}
@@ -0,0 +1,23 @@
+use kvs::KvsError;
+use std::io;
+
+// Timeouts and transient network errors should be retryable, deterministic errors not.
+// `KvsError` is the same as in project 3, where the rest of it is tested.
+#[test]
+fn retryable_errors() {
+    for kind in &[io::ErrorKind::TimedOut, io::ErrorKind::WouldBlock] {
+        let err: KvsError = io::Error::from(*kind).into();
+        assert!(matches!(err, KvsError::Timeout));
+        assert!(err.is_retryable());
+    }
+    assert!(KvsError::Io(io::Error::from(io::ErrorKind::ConnectionReset)).is_retryable());
+
+    let errors = vec![
+        KvsError::KeyNotFound,
+        KvsError::StringError("oops".to_owned()),
+        KvsError::Io(io::Error::from(io::ErrorKind::ConnectionRefused)),
+    ];
+    for err in errors {
+        assert!(!err.is_retryable(), "{} should not be retryable", err);
+    }
+}
@@ -1,5 +1,7 @@
 use kvs::thread_pool::RayonThreadPool;
 use kvs::{KvStore, KvsEngine, KvsError, Result};
+use std::ffi::OsStr;
+use std::fs::OpenOptions;
 use tempfile::TempDir;
 use tokio::prelude::*;
 use tokio::runtime::Runtime;
@@ -237,3 +239,99 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// A failing read should still return its reader to the pool.
+#[test]
+fn reader_returned_on_error() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open_with_reader_pool(temp_dir.path(), 2, 2)?;
+    store.set("key".to_owned(), "value".to_owned()).wait()?;
+    assert_eq!(store.idle_readers(), 2);
+
+    // cut off the record behind the back of the store
+    for entry in WalkDir::new(temp_dir.path()) {
+        let entry = entry.unwrap();
+        if entry.path().extension() == Some(OsStr::new("log")) {
+            OpenOptions::new()
+                .write(true)
+                .open(entry.path())?
+                .set_len(0)?;
+        }
+    }
+    for _ in 0..3 {
+        assert!(store.get("key".to_owned()).wait().is_err());
+    }
+    assert_eq!(store.idle_readers(), 2);
+    Ok(())
+}
+
+// A reader pool smaller than the read concurrency should not make gets fail.
+#[test]
+fn small_reader_pool() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open_with_reader_pool(temp_dir.path(), 8, 1)?;
+    for i in 0..100 {
+        store
+            .set(format!("key{}", i), format!("value{}", i))
+            .wait()?;
+    }
+
+    let mut runtime = Runtime::new()?;
+    let gets: Vec<_> = (0..10000)
+        .map(|i| store.get(format!("key{}", i % 100)))
+        .collect();
+    let values = runtime.block_on(future::join_all(gets))?;
+    for (i, value) in values.into_iter().enumerate() {
+        assert_eq!(value, Some(format!("value{}", i % 100)));
+    }
+
+    assert!(KvStore::<RayonThreadPool>::open_with_reader_pool(temp_dir.path(), 8, 0).is_err());
+    Ok(())
+}
+
+// Manual compaction should reclaim space and be harmless when there is nothing to reclaim.
+#[test]
+fn manual_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 4)?;
+    let dir_size = || -> u64 {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|entry| entry.unwrap().metadata().unwrap().len())
+            .sum()
+    };
+
+    // Nothing to reclaim in an empty store
+    store.compact()?;
+
+    for iter in 0..100 {
+        for key_id in 0..10 {
+            store
+                .set(format!("key{}", key_id), format!("value{}", iter))
+                .wait()?;
+        }
+    }
+    store.remove("key0".to_owned()).wait()?;
+    let size_before = dir_size();
+
+    let gets: Vec<_> = (1..10)
+        .map(|key_id| store.get(format!("key{}", key_id)))
+        .collect();
+    store.compact()?;
+    store.compact()?;
+    for value in future::join_all(gets).wait()? {
+        assert_eq!(value, Some("value99".to_owned()));
+    }
+    assert!(dir_size() < size_before);
+
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.get("key0".to_owned()).wait()?, None);
+    for key_id in 1..10 {
+        assert_eq!(
+            store.get(format!("key{}", key_id)).wait()?,
+            Some("value99".to_owned())
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,309 @@
+use kvs::thread_pool::RayonThreadPool;
+use kvs::{
+    Change, KvStore, KvsClient, KvsEngine, KvsMultiplexClient, KvsServer, Request, Response, Result,
+};
+use std::io;
+use std::iter;
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tokio::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+use tokio::runtime::Runtime;
+use tokio_serde_json::{ReadJson, WriteJson};
+
+// Start a `KvStore` server on `addr` in a background thread.
+fn start_server(temp_dir: &TempDir, addr: SocketAddr) -> Result<()> {
+    let engine = KvStore::<RayonThreadPool>::open(temp_dir.path(), 4)?;
+    thread::spawn(move || {
+        KvsServer::new(engine).run(addr).unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+    Ok(())
+}
+
+// Many concurrent requests over one connection should each get their own response.
+#[test]
+fn multiplex_concurrent_requests() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4201".parse().unwrap();
+    start_server(&temp_dir, addr)?;
+
+    let mut rt = Runtime::new()?;
+    let client = rt.block_on(KvsMultiplexClient::connect(addr))?;
+
+    let sets: Vec<_> = (0..100)
+        .map(|i| client.set(format!("key{}", i), format!("value{}", i)))
+        .collect();
+    rt.block_on(future::join_all(sets))?;
+
+    let gets: Vec<_> = (0..100).map(|i| client.get(format!("key{}", i))).collect();
+    let values = rt.block_on(future::join_all(gets))?;
+    for (i, value) in values.into_iter().enumerate() {
+        assert_eq!(value, Some(format!("value{}", i)));
+    }
+
+    // errors are delivered to the request that caused them
+    let results = rt
+        .block_on(future::join_all(vec![
+            client.remove("key0".to_owned()).then(Ok::<_, ()>),
+            client.remove("missing".to_owned()).then(Ok::<_, ()>),
+        ]))
+        .unwrap();
+    assert!(results[0].is_ok());
+    assert!(results[1].is_err());
+
+    // tagged requests run concurrently, so a slow request doesn't hold back the pings
+    // sent after it
+    let tcp = rt.block_on(TcpStream::connect(&addr))?;
+    let (read_half, write_half) = tcp.split();
+    let read_json: ReadJson<_, Response> =
+        ReadJson::new(FramedRead::new(read_half, LengthDelimitedCodec::new()));
+    let write_json: WriteJson<_, Request> =
+        WriteJson::new(FramedWrite::new(write_half, LengthDelimitedCodec::new()));
+    let slow = Request::Batch(
+        (0..1000)
+            .map(|i| Request::Set {
+                key: format!("slow{}", i),
+                value: "value".to_owned(),
+            })
+            .collect(),
+    );
+    let reqs = iter::once(slow)
+        .chain((1..100).map(|_| Request::Ping))
+        .enumerate()
+        .map(|(id, req)| Request::Tagged {
+            id: id as u64,
+            req: Box::new(req),
+        });
+    let (_write_json, _) =
+        rt.block_on(write_json.send_all(stream::iter_ok::<_, io::Error>(reqs)))?;
+    let mut ids = rt.block_on(
+        read_json
+            .take(100)
+            .map(|resp| match resp {
+                Response::Tagged { id, .. } => id,
+                resp => panic!("unexpected response: {:?}", resp),
+            })
+            .collect(),
+    )?;
+    assert_ne!(ids[0], 0, "the slow request was answered first");
+    ids.sort();
+    assert_eq!(ids, (0..100).collect::<Vec<_>>());
+
+    Ok(())
+}
+
+// A fresh server should answer a ping with the crate version and a short uptime.
+#[test]
+fn ping() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4202".parse().unwrap();
+    start_server(&temp_dir, addr)?;
+
+    let mut rt = Runtime::new()?;
+    let client = rt.block_on(KvsClient::connect(addr))?;
+    let (pong, client) = rt.block_on(client.ping())?;
+    assert_eq!(pong.version, env!("CARGO_PKG_VERSION"));
+    assert!(pong.uptime_secs < 5, "uptime {}", pong.uptime_secs);
+    // the connection is still usable afterwards
+    rt.block_on(client.set("key".to_owned(), "value".to_owned()))?;
+    Ok(())
+}
+
+// A failing operation in a batch should only affect its own result.
+#[test]
+fn batch_mixed_operations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4203".parse().unwrap();
+    start_server(&temp_dir, addr)?;
+
+    let mut rt = Runtime::new()?;
+    let client = rt.block_on(KvsClient::connect(addr))?;
+    let client = rt.block_on(client.set_many(vec![
+        ("key1".to_owned(), "value1".to_owned()),
+        ("key2".to_owned(), "value2".to_owned()),
+    ]))?;
+
+    let (resps, client) = rt.block_on(client.batch(vec![
+        Request::Get {
+            key: "key1".to_owned(),
+        },
+        Request::Remove {
+            key: "missing".to_owned(),
+        },
+        Request::Remove {
+            key: "key2".to_owned(),
+        },
+        Request::Set {
+            key: "key3".to_owned(),
+            value: "value3".to_owned(),
+        },
+        Request::Get {
+            key: "key2".to_owned(),
+        },
+    ]))?;
+    assert_eq!(resps.len(), 5);
+    match &resps[0] {
+        Response::Get(value) => assert_eq!(value.as_deref(), Some("value1")),
+        resp => panic!("unexpected response {:?}", resp),
+    }
+    match &resps[1] {
+        Response::Err(_) => {}
+        resp => panic!("unexpected response {:?}", resp),
+    }
+    assert!(matches!(resps[2], Response::Remove));
+    assert!(matches!(resps[3], Response::Set));
+    match &resps[4] {
+        Response::Get(value) => assert_eq!(*value, None),
+        resp => panic!("unexpected response {:?}", resp),
+    }
+
+    let (value, _) = rt.block_on(client.get("key3".to_owned()))?;
+    assert_eq!(value, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A watcher should receive the changes to the keys with its prefix, and no others.
+#[test]
+fn watch_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4204".parse().unwrap();
+    start_server(&temp_dir, addr)?;
+
+    let mut rt = Runtime::new()?;
+    let watcher = rt.block_on(KvsClient::connect(addr))?;
+    let changes = rt.block_on(watcher.watch("user:".to_owned()))?;
+
+    let client = rt.block_on(KvsClient::connect(addr))?;
+    let client = rt.block_on(client.set("other".to_owned(), "value".to_owned()))?;
+    let client = rt.block_on(client.set("user:1".to_owned(), "alice".to_owned()))?;
+    rt.block_on(client.remove("user:1".to_owned()))?;
+
+    let changes = rt.block_on(changes.take(2).collect())?;
+    assert_eq!(
+        changes,
+        vec![
+            Change {
+                key: "user:1".to_owned(),
+                value: Some("alice".to_owned()),
+            },
+            Change {
+                key: "user:1".to_owned(),
+                value: None,
+            },
+        ]
+    );
+    Ok(())
+}
+
+// A client started before the server should connect by retrying once the server is
+// up, while an invalid address fails right away.
+#[test]
+fn connect_retry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4205".parse().unwrap();
+    let engine = KvStore::<RayonThreadPool>::open(temp_dir.path(), 4)?;
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(500));
+        KvsServer::new(engine).run(addr).unwrap();
+    });
+
+    let mut rt = Runtime::new()?;
+    let client = rt.block_on(KvsClient::connect_retry(
+        addr,
+        10,
+        Duration::from_millis(50),
+    ))?;
+    let client = rt.block_on(client.set("key".to_owned(), "value".to_owned()))?;
+    let (value, _) = rt.block_on(client.get("key".to_owned()))?;
+    assert_eq!(value, Some("value".to_owned()));
+
+    let start = Instant::now();
+    let res = rt.block_on(KvsClient::connect_retry(
+        "not an address",
+        10,
+        Duration::from_secs(1),
+    ));
+    assert!(res.is_err());
+    assert!(start.elapsed() < Duration::from_secs(1));
+    Ok(())
+}
+
+// A value above the frame limit should come back whole through chunks, and the
+// connection should be usable afterwards.
+#[test]
+fn get_large_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4206".parse().unwrap();
+    let engine = KvStore::<RayonThreadPool>::open(temp_dir.path(), 4)?;
+    // a single-byte char first, so that the chunk boundaries fall inside multi-byte chars
+    let large = format!("x{}", "值".repeat(7 * 1024 * 1024));
+    engine.set("large".to_owned(), large.clone()).wait()?;
+    engine.set("small".to_owned(), "value".to_owned()).wait()?;
+    thread::spawn(move || {
+        KvsServer::new(engine).run(addr).unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+
+    let mut rt = Runtime::new()?;
+    let client = rt.block_on(KvsClient::connect(addr))?;
+    let (value, client) = rt.block_on(client.get("large".to_owned()))?;
+    assert_eq!(value.as_ref().map(String::len), Some(large.len()));
+    assert!(value == Some(large));
+    let (value, _) = rt.block_on(client.get("small".to_owned()))?;
+    assert_eq!(value, Some("value".to_owned()));
+    Ok(())
+}
+
+// A large value should come back whole through tagged chunks, while the other
+// requests on the connection go on, and a batch should refuse it instead of
+// sending an overlong frame.
+#[test]
+fn get_large_value_tagged() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4207".parse().unwrap();
+    let engine = KvStore::<RayonThreadPool>::open(temp_dir.path(), 4)?;
+    let large = "x".repeat(20 * 1024 * 1024);
+    engine.set("large".to_owned(), large.clone()).wait()?;
+    engine.set("small".to_owned(), "value".to_owned()).wait()?;
+    thread::spawn(move || {
+        KvsServer::new(engine).run(addr).unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+
+    let mut rt = Runtime::new()?;
+    let client = rt.block_on(KvsMultiplexClient::connect(addr))?;
+    let (value, small) = rt.block_on(
+        client
+            .get("large".to_owned())
+            .join(client.get("small".to_owned())),
+    )?;
+    assert_eq!(value.as_ref().map(String::len), Some(large.len()));
+    assert!(value == Some(large));
+    assert_eq!(small, Some("value".to_owned()));
+
+    let client = rt.block_on(KvsClient::connect(addr))?;
+    let (resps, client) = rt.block_on(client.batch(vec![
+        Request::Get {
+            key: "small".to_owned(),
+        },
+        Request::Get {
+            key: "large".to_owned(),
+        },
+    ]))?;
+    assert_eq!(resps.len(), 2);
+    match &resps[0] {
+        Response::Get(value) => assert_eq!(value.as_deref(), Some("value")),
+        resp => panic!("unexpected response {:?}", resp),
+    }
+    match &resps[1] {
+        Response::Err(_) => {}
+        resp => panic!("unexpected response {:?}", resp),
+    }
+    let (value, _) = rt.block_on(client.get("small".to_owned()))?;
+    assert_eq!(value, Some("value".to_owned()));
+    Ok(())
+}